@@ -2,10 +2,21 @@ use std::env;
 
 fn kernel(path: &str) {
     println!("cargo:rerun-if-changed={path}");
+    let mut features = vec![];
+    if env::var("CARGO_FEATURE_STACKLESS").is_ok() {
+        features.push("stackless".to_string());
+    }
+    if env::var("CARGO_FEATURE_SOBOL").is_ok() {
+        features.push("sobol".to_string());
+    }
+    if env::var("CARGO_FEATURE_BURLEY").is_ok() {
+        features.push("burley".to_string());
+    }
     spirv_builder::SpirvBuilder::new(
         format!("{}/{path}", env!("CARGO_MANIFEST_DIR")),
         "spirv-unknown-vulkan1.2",
     )
+    .shader_crate_features(features)
     .build()
     .expect("Kernel failed to compile");
 }
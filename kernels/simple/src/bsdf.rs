@@ -1,10 +1,10 @@
 #[allow(dead_code)]
 use spirv_std::num_traits::{Float, FloatConst};
 use {
-    crate::{rng::RngState, util},
-    shared::{MaterialData, Sampler, TracingConfig},
+    crate::{rng::RngState, texture, util},
+    shared::{MaterialData, Sampler, TextureSlot, TracingConfig},
     spirv_std::{
-        glam::{Vec2, Vec3, Vec4Swizzles},
+        glam::{Mat3, Vec2, Vec3, Vec4Swizzles},
         Image,
     },
 };
@@ -19,6 +19,17 @@ pub enum Lobe {
     SpecularTransmission,
 }
 
+impl Lobe {
+    pub fn from_bits(bits: u32) -> Self {
+        match bits {
+            1 => Lobe::SpecularReflection,
+            2 => Lobe::DiffuseTransmission,
+            3 => Lobe::SpecularTransmission,
+            _ => Lobe::DiffuseReflection,
+        }
+    }
+}
+
 type Spectrum = Vec3;
 
 #[derive(Default, Copy, Clone)]
@@ -80,6 +91,8 @@ impl BSDF for Lambertian {
 pub struct Glass {
     pub albedo: Spectrum,
     pub ior: f32,
+    // IOR of the medium on the other side, see `MediumStack::outer_ior`
+    pub outer_ior: f32,
     pub roughness: f32,
 }
 
@@ -109,8 +122,8 @@ impl BSDF for Glass {
 
         let inside = normal.dot(view) < 0.0;
         let normal = if inside { -normal } else { normal };
-        let in_ior = if inside { self.ior } else { 1.0 };
-        let out_ior = if inside { 1.0 } else { self.ior };
+        let in_ior = if inside { self.ior } else { self.outer_ior };
+        let out_ior = if inside { self.outer_ior } else { self.ior };
 
         let microsurface_normal = util::sample_ggx_microsurface_normal(
             rng_sample.x,
@@ -146,6 +159,32 @@ impl BSDF for Glass {
     }
 }
 
+// Phase function of the fog, `normal` is meaningless in a medium and ignored
+pub struct HenyeyGreenstein {
+    pub g: f32,
+}
+
+impl BSDF for HenyeyGreenstein {
+    fn evaluate(&self, view: Vec3, _normal: Vec3, sample: Vec3, _lobe: Lobe) -> Spectrum {
+        Vec3::splat(util::henyey_greenstein(-view.dot(sample), self.g))
+    }
+
+    fn pdf(&self, view: Vec3, _normal: Vec3, sample: Vec3, _lobe: Lobe) -> f32 {
+        util::henyey_greenstein(-view.dot(sample), self.g)
+    }
+
+    fn sample(&self, view: Vec3, _normal: Vec3, rng: &mut RngState) -> BSDFSample {
+        let rng_sample = rng.gen_r2();
+        let direction = util::sample_henyey_greenstein(-view, self.g, rng_sample.x, rng_sample.y);
+        let pdf = util::henyey_greenstein(-view.dot(direction), self.g);
+        // sampled exactly, spectrum / pdf is one
+        BSDFSample { pdf, lobe: Lobe::DiffuseReflection, spectrum: Vec3::splat(pdf), direction }
+    }
+}
+
+// Keeps the anisotropic NDF finite for mirror-like roughness
+const MIN_ANISOTROPIC_ALPHA: f32 = 1e-4;
+
 // Assume IOR of 1.5 for dielectrics, which works well for most.
 const DIELECTRIC_IOR: f32 = 1.5;
 
@@ -157,14 +196,92 @@ pub struct PBR {
     pub albedo: Spectrum,
     pub roughness: f32,
     pub metallic: f32,
+    // ambient occlusion, only darkens the diffuse lobe
+    pub occlusion: f32,
     pub clamp_weight: Vec2,
+    // KHR_materials_anisotropy strength, 0.0 keeps the isotropic lobe
+    pub anisotropy: f32,
+    // direction the highlight stretches along, not orthogonalized against the shading normal yet
+    pub tangent: Vec3,
+    // KHR_materials_sheen, black disables the lobe
+    pub sheen: Spectrum,
+    pub sheen_roughness: f32,
+    // KHR_materials_iridescence strength, 0.0 keeps plain Schlick
+    pub thin_film: f32,
+    pub thin_film_ior: f32,
+    // in nm
+    pub thin_film_thickness: f32,
 }
 
 impl PBR {
-    fn evaluate_diffuse_fast(&self, cos_theta: f32, specular_weight: f32, ks: Vec3) -> Spectrum {
+    // Schlick, blended towards thin-film interference on iridescent materials
+    fn fresnel(&self, view: Vec3, halfway: Vec3) -> Spectrum {
+        let cos_theta = halfway.dot(view).max(0.0);
+        let f0 = Vec3::splat(DIELECTRIC_F0).lerp(self.albedo, self.metallic);
+        let schlick = util::fresnel_schlick(cos_theta, f0);
+        if self.thin_film == 0.0 {
+            return schlick;
+        }
+
+        // the film sits on a dielectric base matching f0, metals included
+        let f0 = f0.min(Vec3::splat(0.98));
+        let f0_sqrt = Vec3::new(f0.x.sqrt(), f0.y.sqrt(), f0.z.sqrt());
+        let base_ior = (Vec3::ONE + f0_sqrt) / (Vec3::ONE - f0_sqrt);
+        let film = util::thin_film_fresnel(
+            cos_theta,
+            self.thin_film_ior,
+            self.thin_film_thickness,
+            base_ior,
+        );
+        schlick.lerp(film, self.thin_film)
+    }
+
+    #[cfg_attr(not(feature = "burley"), allow(unused_variables))]
+    fn evaluate_diffuse_fast(
+        &self,
+        view: Vec3,
+        normal: Vec3,
+        sample: Vec3,
+        cos_theta: f32,
+        specular_weight: f32,
+        ks: Vec3,
+    ) -> Spectrum {
         let kd = (Vec3::splat(1.0) - ks) * (1.0 - self.metallic);
-        let diffuse = kd * self.albedo / f32::PI();
-        diffuse * cos_theta / (1.0 - specular_weight)
+        let diffuse = kd * self.albedo * self.occlusion / f32::PI();
+        #[cfg(feature = "burley")]
+        let diffuse = diffuse
+            * util::burley_diffuse(
+                normal.dot(view).max(0.0),
+                cos_theta,
+                sample.dot((view + sample).normalize()).max(0.0),
+                self.roughness,
+            );
+        diffuse * self.sheen_scaling() * cos_theta / (1.0 - specular_weight)
+    }
+
+    // Energy the sheen layer takes away from the base, the albedo-scaling approximation
+    // with the sheen directional albedo taken as a constant
+    fn sheen_scaling(&self) -> f32 {
+        1.0 - self.sheen.max_element() * 0.157
+    }
+
+    // Sheen rides along on the diffuse lobe and shares its cosine sampling and pdf
+    fn evaluate_sheen(
+        &self,
+        view: Vec3,
+        normal: Vec3,
+        sample: Vec3,
+        cos_theta: f32,
+        specular_weight: f32,
+    ) -> Spectrum {
+        if self.sheen == Vec3::ZERO {
+            return Vec3::ZERO;
+        }
+        let halfway = (view + sample).normalize();
+        let alpha = self.sheen_roughness * self.sheen_roughness;
+        let d_term = util::charlie_distribution(normal.dot(halfway).max(0.0), alpha);
+        let v_term = util::ashikhmin_visibility(normal.dot(view).max(0.0), cos_theta);
+        self.sheen * d_term * v_term * cos_theta / (1.0 - specular_weight)
     }
 
     fn evaluate_specular_fast(
@@ -181,7 +298,47 @@ impl PBR {
         let specular_numerator = d_term * g_term * ks;
         let specular_denominator = 4.0 * normal.dot(view).max(0.0) * cos_theta;
         let specular = specular_numerator / specular_denominator.max(util::EPS);
-        specular * cos_theta / specular_weight
+        specular * self.sheen_scaling() * cos_theta / specular_weight
+    }
+
+    // glTF's alpha = roughness^2, widened along the tangent as anisotropy grows
+    fn anisotropic_alpha(&self) -> Vec2 {
+        let alpha = (self.roughness * self.roughness).max(MIN_ANISOTROPIC_ALPHA);
+        Vec2::new(util::lerp(alpha, 1.0, self.anisotropy * self.anisotropy), alpha)
+    }
+
+    // world to tangent space, with z along the shading normal
+    fn tangent_space(&self, normal: Vec3) -> Mat3 {
+        let tangent = (self.tangent - normal * normal.dot(self.tangent)).normalize();
+        Mat3::from_cols(tangent, normal.cross(tangent), normal).transpose()
+    }
+
+    fn evaluate_specular_anisotropic(
+        &self,
+        view: Vec3,
+        normal: Vec3,
+        sample: Vec3,
+        cos_theta: f32,
+        specular_weight: f32,
+        ks: Vec3,
+    ) -> Spectrum {
+        let to_local = self.tangent_space(normal);
+        let (view, sample) = (to_local * view, to_local * sample);
+        let halfway = (view + sample).normalize();
+        let alpha = self.anisotropic_alpha();
+        let d_term = util::ggx_distribution_anisotropic(halfway, alpha);
+        let g_term = util::geometry_smith_ggx_anisotropic(view, sample, alpha);
+        let specular_denominator = 4.0 * view.z.max(0.0) * cos_theta;
+        let specular = d_term * g_term * ks / specular_denominator.max(util::EPS);
+        specular * self.sheen_scaling() * cos_theta / specular_weight
+    }
+
+    fn pdf_specular_anisotropic(&self, view: Vec3, normal: Vec3, sample: Vec3) -> f32 {
+        let to_local = self.tangent_space(normal);
+        let (view, sample) = (to_local * view, to_local * sample);
+        let halfway = (view + sample).normalize();
+        let d_term = util::ggx_distribution_anisotropic(halfway, self.anisotropic_alpha());
+        (d_term * halfway.z) / (4.0 * view.dot(halfway)).max(util::EPS)
     }
 
     fn pdf_diffuse_fast(&self, cos_theta: f32) -> f32 {
@@ -211,11 +368,13 @@ impl BSDF for PBR {
         let cos_theta = normal.dot(sample).max(0.0);
         let halfway = (view + sample).normalize();
 
-        let f0 = Vec3::splat(DIELECTRIC_F0).lerp(self.albedo, self.metallic);
-        let ks = util::fresnel_schlick(halfway.dot(view).max(0.0), f0);
+        let ks = self.fresnel(view, halfway);
 
         if lobe_type == Lobe::DiffuseReflection {
-            self.evaluate_diffuse_fast(cos_theta, specular_weight, ks)
+            self.evaluate_diffuse_fast(view, normal, sample, cos_theta, specular_weight, ks)
+                + self.evaluate_sheen(view, normal, sample, cos_theta, specular_weight)
+        } else if self.anisotropy != 0.0 {
+            self.evaluate_specular_anisotropic(view, normal, sample, cos_theta, specular_weight, ks)
         } else {
             let d_term = util::ggx_distribution(normal, halfway, self.roughness);
             self.evaluate_specular_fast(
@@ -251,6 +410,11 @@ impl BSDF for PBR {
             )
             .normalize();
             (sampled_direction, Lobe::DiffuseReflection)
+        } else if self.anisotropy != 0.0 {
+            let alpha = self.anisotropic_alpha();
+            let halfway = util::sample_ggx_anisotropic(rng_sample.x, rng_sample.y, alpha);
+            let halfway = self.tangent_space(normal).transpose() * halfway;
+            (util::reflect(-view, halfway), Lobe::SpecularReflection)
         } else {
             let reflection_direction = util::reflect(-view, normal);
             let sampled_direction =
@@ -261,13 +425,25 @@ impl BSDF for PBR {
         let cos_theta = normal.dot(direction).max(util::EPS);
         let halfway = (view + direction).normalize();
 
-        let f0 = Vec3::splat(DIELECTRIC_F0).lerp(self.albedo, self.metallic);
-        let ks = util::fresnel_schlick(halfway.dot(view).max(0.0), f0);
+        let ks = self.fresnel(view, halfway);
 
         let (direction, lobe, pdf, spectrum) = if lobe == Lobe::DiffuseReflection {
             let pdf = self.pdf_diffuse_fast(cos_theta);
-            let spectrum = self.evaluate_diffuse_fast(cos_theta, specular_weight, ks);
+            let spectrum =
+                self.evaluate_diffuse_fast(view, normal, direction, cos_theta, specular_weight, ks)
+                    + self.evaluate_sheen(view, normal, direction, cos_theta, specular_weight);
             (direction, Lobe::DiffuseReflection, pdf, spectrum)
+        } else if self.anisotropy != 0.0 {
+            let pdf = self.pdf_specular_anisotropic(view, normal, direction);
+            let spectrum = self.evaluate_specular_anisotropic(
+                view,
+                normal,
+                direction,
+                cos_theta,
+                specular_weight,
+                ks,
+            );
+            (direction, Lobe::SpecularReflection, pdf, spectrum)
         } else {
             let d_term = util::ggx_distribution(normal, halfway, self.roughness);
             let pdf = self.pdf_specular_fast(view, normal, halfway, d_term);
@@ -290,6 +466,8 @@ impl BSDF for PBR {
         if lobe_type == Lobe::DiffuseReflection {
             let cos_theta = normal.dot(sample).max(0.0);
             self.pdf_diffuse_fast(cos_theta)
+        } else if self.anisotropy != 0.0 {
+            self.pdf_specular_anisotropic(view, normal, sample)
         } else {
             let halfway = (view + sample).normalize();
             let d_term = util::ggx_distribution(normal, halfway, self.roughness);
@@ -298,38 +476,183 @@ impl BSDF for PBR {
     }
 }
 
+// Stochastic mix of the opaque PBR model and Glass, weighted by `transmission`
+pub struct Transmissive {
+    pub pbr: PBR,
+    pub glass: Glass,
+    pub transmission: f32,
+}
+
+impl BSDF for Transmissive {
+    fn evaluate(&self, view: Vec3, normal: Vec3, sample: Vec3, lobe: Lobe) -> Spectrum {
+        match lobe {
+            Lobe::SpecularTransmission => self.glass.evaluate(view, normal, sample, lobe),
+            _ => self.pbr.evaluate(view, normal, sample, lobe) * (1.0 - self.transmission),
+        }
+    }
+
+    fn pdf(&self, view: Vec3, normal: Vec3, sample: Vec3, lobe: Lobe) -> f32 {
+        match lobe {
+            Lobe::SpecularTransmission => self.glass.pdf(view, normal, sample, lobe),
+            _ => self.pbr.pdf(view, normal, sample, lobe) * (1.0 - self.transmission),
+        }
+    }
+
+    fn sample(&self, view: Vec3, normal: Vec3, rng: &mut RngState) -> BSDFSample {
+        // don't spend a dimension on fully opaque or fully transmissive materials
+        let glass = if self.transmission <= 0.0 {
+            false
+        } else if self.transmission >= 1.0 {
+            true
+        } else {
+            rng.gen_r1() < self.transmission
+        };
+
+        if glass {
+            self.glass.sample(view, normal, rng)
+        } else {
+            // scale both terms so spectrum / pdf is unchanged but MIS sees the mixture pdf
+            let mut sample = self.pbr.sample(view, normal, rng);
+            sample.spectrum *= 1.0 - self.transmission;
+            sample.pdf *= 1.0 - self.transmission;
+            sample
+        }
+    }
+}
+
+pub fn get_bsdf(
+    config: &TracingConfig,
+    material: &MaterialData,
+    uv: Vec2,
+    uv1: Vec2,
+    vertex_color: Vec3,
+    tbn: Mat3,
+    lod_bias: f32,
+    atlas: &Image!(2D, type=f32, sampled),
+    sampler: &Sampler,
+    min_roughness: f32,
+) -> Transmissive {
+    let pbr = get_pbr_bsdf(
+        config,
+        material,
+        uv,
+        uv1,
+        vertex_color,
+        tbn,
+        lod_bias,
+        atlas,
+        sampler,
+        min_roughness,
+    );
+    let ior = get_ior(material, ALL_CHANNELS);
+    let glass = Glass { albedo: pbr.albedo, ior, outer_ior: 1.0, roughness: pbr.roughness };
+    Transmissive { pbr, glass, transmission: material.transmission.clamp(0.0, 1.0) }
+}
+
+// Channel of paths that still carry all of RGB, they split up on dispersive surfaces
+pub const ALL_CHANNELS: u32 = 3;
+
+// Dispersive materials refract every channel with its own IOR, full spectrum paths see the base
+pub fn get_ior(material: &MaterialData, channel: u32) -> f32 {
+    let ior = if material.ior > 0.0 { material.ior } else { DIELECTRIC_IOR };
+    if material.abbe_number > 0.0 && channel < ALL_CHANNELS {
+        util::cauchy_ior(ior, material.abbe_number, util::RGB_WAVELENGTHS[channel as usize])
+    } else {
+        ior
+    }
+}
+
+pub fn get_emission(
+    config: &TracingConfig,
+    material: &MaterialData,
+    uv: Vec2,
+    lod_bias: f32,
+    atlas: &Image!(2D, type=f32, sampled),
+    sampler: &Sampler,
+) -> Spectrum {
+    if material.has_emissive_texture() {
+        let emissive =
+            texture::sample(config, atlas, sampler, material, TextureSlot::Emissive, uv, lod_bias);
+        material.emission() * texture::srgb_to_linear(emissive.xyz())
+    } else {
+        material.emission()
+    }
+}
+
 pub fn get_pbr_bsdf(
     config: &TracingConfig,
     material: &MaterialData,
     uv: Vec2,
+    uv1: Vec2,
+    // multiplies the albedo, white for materials without `has_vertex_colors`
+    vertex_color: Vec3,
+    // only the tangent is used, by anisotropic materials
+    tbn: Mat3,
+    lod_bias: f32,
     atlas: &Image!(2D, type=f32, sampled),
     sampler: &Sampler,
+    // raised by path regularization, see `TracingConfig::regularization`
+    min_roughness: f32,
 ) -> PBR {
     let albedo = if material.has_albedo_texture() {
-        let scaled_uv = material.albedo.xy() + uv * material.albedo.zw();
-        let albedo = atlas.sample_by_lod(*sampler, scaled_uv, 0.0);
-        albedo.xyz()
+        let albedo =
+            texture::sample(config, atlas, sampler, material, TextureSlot::Albedo, uv, lod_bias);
+        texture::srgb_to_linear(albedo.xyz())
     } else {
         material.albedo.xyz()
-    };
+    } * vertex_color;
     let roughness = if material.has_roughness_texture() {
-        let scaled_uv = material.roughness.xy() + uv * material.roughness.zw();
-        let roughness = atlas.sample_by_lod(*sampler, scaled_uv, 0.0);
-        roughness.x
+        let roughness =
+            texture::sample(config, atlas, sampler, material, TextureSlot::Roughness, uv, lod_bias);
+        texture::channel(roughness, material.texture_channel(TextureSlot::Roughness))
     } else {
         material.roughness.x
     };
     let metallic = if material.has_metallic_texture() {
-        let scaled_uv = material.metallic.xy() + uv * material.metallic.zw();
-        let metallic = atlas.sample_by_lod(*sampler, scaled_uv, 0.0);
-        metallic.x
+        let metallic =
+            texture::sample(config, atlas, sampler, material, TextureSlot::Metallic, uv, lod_bias);
+        texture::channel(metallic, material.texture_channel(TextureSlot::Metallic))
     } else {
         material.metallic.x
     };
 
+    let occlusion = if material.has_occlusion_texture() {
+        let uv = if material.occlusion_uses_uv1() { uv1 } else { uv };
+        let occlusion =
+            texture::sample(config, atlas, sampler, material, TextureSlot::Occlusion, uv, lod_bias);
+        occlusion.x
+    } else {
+        1.0
+    };
+
     // Clamp values to avoid NaNs :P
-    let roughness = roughness.max(util::EPS);
+    let roughness = roughness.max(min_roughness).max(util::EPS);
     let metallic = metallic.min(1.0 - util::EPS);
 
-    PBR { albedo, roughness, metallic, clamp_weight: Vec2::new(0.1, 0.9) }
+    let thin_film_thickness = if material.has_thin_film_texture() {
+        let thickness =
+            texture::sample(config, atlas, sampler, material, TextureSlot::ThinFilm, uv, lod_bias);
+        util::lerp(material.thin_film.z, material.thin_film.w, thickness.y)
+    } else {
+        material.thin_film.w
+    };
+
+    // glTF rotates the anisotropy direction counter-clockwise from the tangent
+    let (sin, cos) = material.anisotropy_rotation.sin_cos();
+    let tangent = tbn.x_axis * cos + tbn.y_axis * sin;
+
+    PBR {
+        albedo,
+        roughness,
+        metallic,
+        occlusion,
+        clamp_weight: Vec2::new(0.1, 0.9),
+        anisotropy: material.anisotropy.clamp(0.0, 1.0),
+        tangent,
+        sheen: material.sheen.xyz(),
+        sheen_roughness: material.sheen.w,
+        thin_film: material.thin_film.x.clamp(0.0, 1.0),
+        thin_film_ior: material.thin_film.y,
+        thin_film_thickness,
+    }
 }
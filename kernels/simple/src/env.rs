@@ -0,0 +1,134 @@
+#[allow(unused_imports)]
+use spirv_std::num_traits::{Float, FloatConst};
+use {
+    shared::TracingConfig,
+    spirv_std::glam::{Vec2, Vec3, Vec4, Vec4Swizzles},
+};
+
+// Equirectangular mapping, +y is up and v = 0 is the top row of the image
+pub fn dir_to_uv(dir: Vec3, rotation: f32) -> Vec2 {
+    let phi = dir.z.atan2(dir.x) + rotation;
+    let theta = dir.y.clamp(-1.0, 1.0).acos();
+    let u = phi / (2.0 * f32::PI()) + 0.5;
+    Vec2::new(u - u.floor(), theta / f32::PI())
+}
+
+pub fn uv_to_dir(uv: Vec2, rotation: f32) -> Vec3 {
+    let phi = (uv.x - 0.5) * 2.0 * f32::PI() - rotation;
+    let theta = uv.y * f32::PI();
+    Vec3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin())
+}
+
+// index of the first entry in `cdf[offset..offset + len]` that is greater than `value`
+fn upper_bound(cdf: &[f32], offset: usize, len: usize, value: f32) -> usize {
+    let mut lo = 0;
+    let mut hi = len - 1;
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if cdf[offset + mid] <= value {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    lo
+}
+
+pub struct EnvReference<'a> {
+    pub texels: &'a [Vec4],
+    pub marginal_cdf: &'a [f32],
+    pub conditional_cdf: &'a [f32],
+    pub width: u32,
+    pub height: u32,
+    pub rotation: f32,
+}
+
+impl<'a> EnvReference<'a> {
+    pub fn new(
+        config: &TracingConfig,
+        texels: &'a [Vec4],
+        marginal_cdf: &'a [f32],
+        conditional_cdf: &'a [f32],
+    ) -> Self {
+        Self {
+            texels,
+            marginal_cdf,
+            conditional_cdf,
+            width: config.env_width,
+            height: config.env_height,
+            rotation: config.env_rotation,
+        }
+    }
+
+    fn texel(&self, x: i32, y: i32) -> Vec3 {
+        let width = self.width as i32;
+        let height = self.height as i32;
+        let x = ((x % width) + width) % width;
+        let y = y.clamp(0, height - 1);
+        self.texels[(y * width + x) as usize].xyz()
+    }
+
+    pub fn lookup(&self, dir: Vec3) -> Vec3 {
+        let uv = dir_to_uv(dir, self.rotation);
+        let scaled_uv = uv * Vec2::new(self.width as f32, self.height as f32) - 0.5;
+        let floor_uv = scaled_uv.floor();
+        let frac_uv = scaled_uv - floor_uv;
+        let x = floor_uv.x as i32;
+        let y = floor_uv.y as i32;
+
+        // Bilinear filtering, wrapping horizontally
+        let c00 = self.texel(x, y);
+        let c10 = self.texel(x + 1, y);
+        let c01 = self.texel(x, y + 1);
+        let c11 = self.texel(x + 1, y + 1);
+
+        let a = c00.lerp(c10, frac_uv.x);
+        let b = c01.lerp(c11, frac_uv.x);
+        a.lerp(b, frac_uv.y)
+    }
+
+    fn cdf_step(cdf: &[f32], offset: usize, index: usize) -> (f32, f32) {
+        let lo = if index > 0 { cdf[offset + index - 1] } else { 0.0 };
+        (lo, cdf[offset + index] - lo)
+    }
+
+    // Solid angle pdf of sampling `dir` with `sample`
+    pub fn pdf(&self, dir: Vec3) -> f32 {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let uv = dir_to_uv(dir, self.rotation);
+        let x = ((uv.x * width as f32) as usize).min(width - 1);
+        let y = ((uv.y * height as f32) as usize).min(height - 1);
+
+        let (_, marginal_pdf) = Self::cdf_step(self.marginal_cdf, 0, y);
+        let (_, conditional_pdf) = Self::cdf_step(self.conditional_cdf, y * width, x);
+        let sin_theta = (uv.y * f32::PI()).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        marginal_pdf * conditional_pdf * (width * height) as f32
+            / (2.0 * f32::PI() * f32::PI() * sin_theta)
+    }
+
+    // Picks a direction proportionally to luminance, returns it with its solid angle pdf
+    pub fn sample(&self, rng: Vec2) -> (Vec3, f32) {
+        let (width, height) = (self.width as usize, self.height as usize);
+
+        let y = upper_bound(self.marginal_cdf, 0, height, rng.y);
+        let (y_lo, y_pdf) = Self::cdf_step(self.marginal_cdf, 0, y);
+        let x = upper_bound(self.conditional_cdf, y * width, width, rng.x);
+        let (x_lo, x_pdf) = Self::cdf_step(self.conditional_cdf, y * width, x);
+
+        // place the sample uniformly inside the picked texel
+        let dx = if x_pdf > 0.0 { ((rng.x - x_lo) / x_pdf).clamp(0.0, 1.0) } else { 0.5 };
+        let dy = if y_pdf > 0.0 { ((rng.y - y_lo) / y_pdf).clamp(0.0, 1.0) } else { 0.5 };
+        let uv = Vec2::new((x as f32 + dx) / width as f32, (y as f32 + dy) / height as f32);
+
+        let sin_theta = (uv.y * f32::PI()).sin();
+        if sin_theta <= 0.0 {
+            return (Vec3::Y, 0.0);
+        }
+        let pdf =
+            x_pdf * y_pdf * (width * height) as f32 / (2.0 * f32::PI() * f32::PI() * sin_theta);
+        (uv_to_dir(uv, self.rotation), pdf)
+    }
+}
@@ -1,74 +1,84 @@
 #[allow(unused_imports)]
 use spirv_std::num_traits::Float;
+#[cfg(not(feature = "stackless"))]
+use {crate::vec::FixedVec, core::mem, shared::BVH_STACK_SIZE};
 use {
-    crate::vec::FixedVec,
-    core::mem,
-    shared::{BVHNode, PerVertexData},
+    shared::{
+        intersect_triangle, safe_inverse, BVHNode, GBufferTexel, HitRecord, InstanceData,
+        PerVertexData,
+    },
     spirv_std::{
         glam::{UVec4, Vec3, Vec4, Vec4Swizzles},
         num_traits::Signed,
     },
 };
 
-// https://github.com/pema99/rust-path-tracer/blob/master/kernels/src/intersection.rs
-// https://en.wikipedia.org/wiki/Möller–Trumbore_intersection_algorithm
-fn muller_trumbore(
-    ro: Vec3,
-    rd: Vec3,
-    a: Vec3,
-    b: Vec3,
-    c: Vec3,
-    out_t: &mut f32,
-    out_backface: &mut bool,
-) -> bool {
-    *out_t = 0.0;
-
-    let edge1 = b - a;
-    let edge2 = c - a;
-
-    let pv = rd.cross(edge2);
-    let det = edge1.dot(pv);
-    *out_backface = det < 0.0;
-
-    if det.abs() < 1e-6 {
-        return false;
-    }
-
-    let inv_det = 1.0 / det;
-    let tv = ro - a;
-
-    let u = tv.dot(pv) * inv_det;
-    if u < 0.0 || u > 1.0 {
-        return false;
-    }
-
-    let qv = tv.cross(edge1);
-
-    let v = rd.dot(qv) * inv_det;
-    if v < 0.0 || u + v > 1.0 {
-        return false;
-    }
-
-    let t = edge2.dot(qv) * inv_det;
-    if t < 0.0 {
-        return false;
-    }
-    *out_t = t;
-
-    return true;
-}
-
+#[derive(Clone, Copy)]
 pub struct Trace {
+    pub instance: u32,
     pub triangle: UVec4,
     pub triangle_index: u32,
     pub len: f32,
     pub hit: bool,
     pub backface: bool,
+    // The tree was deeper than the traversal stack and starting over found nothing closer, a
+    // hit behind the nearest one may have been missed
+    pub failed: bool,
 }
 
 impl Trace {
     pub fn miss() -> Self {
-        Self { triangle: UVec4::ZERO, triangle_index: 0, len: 1e6, hit: false, backface: false }
+        Self {
+            instance: 0,
+            triangle: UVec4::ZERO,
+            triangle_index: 0,
+            len: 1e6,
+            hit: false,
+            backface: false,
+            failed: false,
+        }
+    }
+
+    // The camera ray's hit as the primary ray pass left it
+    pub fn from_gbuffer(texel: &GBufferTexel, indices: &[UVec4]) -> Self {
+        if !texel.hit() {
+            return Self::miss();
+        }
+        Self {
+            instance: texel.instance,
+            triangle: indices[texel.triangle_index as usize],
+            triangle_index: texel.triangle_index,
+            len: texel.position.w,
+            hit: true,
+            backface: texel.backface(),
+            failed: false,
+        }
+    }
+
+    // Between the intersection and shading passes of wavefront tracing
+    pub fn from_record(record: &HitRecord, indices: &[UVec4]) -> Self {
+        if !record.hit() {
+            return Self::miss();
+        }
+        Self {
+            instance: record.instance,
+            triangle: indices[record.triangle_index as usize],
+            triangle_index: record.triangle_index,
+            len: record.distance,
+            hit: true,
+            backface: record.backface(),
+            failed: false,
+        }
+    }
+
+    pub fn record(&self) -> HitRecord {
+        let mut record = HitRecord::default();
+        record.distance = self.len;
+        record.instance = self.instance;
+        record.triangle_index = self.triangle_index;
+        record.set_hit(self.hit);
+        record.set_backface(self.backface);
+        record
     }
 }
 
@@ -87,7 +97,8 @@ pub fn intersect_slow_as_shit(
 
         let mut t = 0.0;
         let mut backface = false;
-        if muller_trumbore(ro, rd, a, b, c, &mut t, &mut backface) && t > 0.001 && t < result.len {
+        if intersect_triangle(ro, rd, a, b, c, &mut t, &mut backface) && t > 0.001 && t < result.len
+        {
             result.triangle = triangle;
             result.triangle_index = i as u32;
             result.len = result.len.min(t);
@@ -98,28 +109,12 @@ pub fn intersect_slow_as_shit(
     result
 }
 
-fn intersect_aabb(aabb_min: Vec3, aabb_max: Vec3, ro: Vec3, rd: Vec3, prev_min_t: f32) -> f32 {
-    let tx1 = (aabb_min.x - ro.x) / rd.x;
-    let tx2 = (aabb_max.x - ro.x) / rd.x;
-    let mut tmin = tx1.min(tx2);
-    let mut tmax = tx1.max(tx2);
-    let ty1 = (aabb_min.y - ro.y) / rd.y;
-    let ty2 = (aabb_max.y - ro.y) / rd.y;
-    tmin = tmin.max(ty1.min(ty2));
-    tmax = tmax.min(ty1.max(ty2));
-    let tz1 = (aabb_min.z - ro.z) / rd.z;
-    let tz2 = (aabb_max.z - ro.z) / rd.z;
-    tmin = tmin.max(tz1.min(tz2));
-    tmax = tmax.min(tz1.max(tz2));
-    if tmax >= tmin && tmax > 0.0 && tmin < prev_min_t {
-        tmin
-    } else {
-        f32::MAX
-    }
-}
-
 pub struct BVHReference<'a> {
     pub nodes: &'a [BVHNode],
+    // parent of every node, `u32::MAX` for the root, only read by the stackless traversal
+    #[cfg_attr(not(feature = "stackless"), allow(dead_code))]
+    pub parents: &'a [u32],
+    pub instances: &'a [InstanceData],
 }
 
 impl<'a> BVHReference<'a> {
@@ -144,6 +139,8 @@ impl<'a> BVHReference<'a> {
         self.intersect_front_to_back::<false>(per_vertex_buffer, index_buffer, ro, rd, max_t)
     }
 
+    // Top level leaves hold instances, the ray continues through their bottom level in object
+    // space. Directions aren't renormalized there, so hit distances stay comparable.
     fn intersect_front_to_back<const NEAREST: bool>(
         &self,
         per_vertex_buffer: &[PerVertexData],
@@ -152,37 +149,110 @@ impl<'a> BVHReference<'a> {
         rd: Vec3,
         max_t: f32,
     ) -> Trace {
-        let mut stack = FixedVec::<usize, 32>::new();
-        stack.push(0);
-
         let mut result = Trace::miss();
-        while !stack.is_empty() {
+        self.traverse(0, ro, rd, &mut result, |node, result| {
+            for i in 0..node.triangle_count() {
+                let instance_index = node.first_triangle_index() + i;
+                let instance = &self.instances[instance_index as usize];
+                let (ro, rd) = instance.ray_to_object(ro, rd);
+                let done =
+                    self.traverse(instance.blas_root as usize, ro, rd, result, |node, result| {
+                        Self::intersect_leaf::<NEAREST>(
+                            node,
+                            instance_index,
+                            per_vertex_buffer,
+                            index_buffer,
+                            ro,
+                            rd,
+                            max_t,
+                            result,
+                        )
+                    });
+                if done {
+                    return true;
+                }
+            }
+            false
+        });
+        result
+    }
+
+    // Returns true when the traversal can stop, i.e. any hit is enough and one was found
+    fn intersect_leaf<const NEAREST: bool>(
+        node: &BVHNode,
+        instance: u32,
+        per_vertex_buffer: &[PerVertexData],
+        index_buffer: &[UVec4],
+        ro: Vec3,
+        rd: Vec3,
+        max_t: f32,
+        result: &mut Trace,
+    ) -> bool {
+        for i in 0..node.triangle_count() {
+            let triangle_index = node.first_triangle_index() + i;
+            let triangle = index_buffer[triangle_index as usize];
+            let a = per_vertex_buffer[triangle.x as usize].vertex.xyz();
+            let b = per_vertex_buffer[triangle.y as usize].vertex.xyz();
+            let c = per_vertex_buffer[triangle.z as usize].vertex.xyz();
+
+            let mut t = 0.0;
+            let mut backface = false;
+            if intersect_triangle(ro, rd, a, b, c, &mut t, &mut backface)
+                && t > 0.001
+                && t < result.len
+                && (NEAREST || t <= max_t)
+            {
+                result.instance = instance;
+                result.triangle = triangle;
+                result.triangle_index = triangle_index;
+                result.len = result.len.min(t);
+                result.hit = true;
+                result.backface = backface;
+                if !NEAREST {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    // Visits the leaves under `root` hit by the ray, front to back. `leaf` returns true when
+    // the traversal can stop, which is passed on.
+    #[cfg(not(feature = "stackless"))]
+    fn traverse(
+        &self,
+        root: usize,
+        ro: Vec3,
+        rd: Vec3,
+        result: &mut Trace,
+        mut leaf: impl FnMut(&BVHNode, &mut Trace) -> bool,
+    ) -> bool {
+        let inv_rd = safe_inverse(rd);
+        let mut stack = FixedVec::<usize, BVH_STACK_SIZE>::new();
+        // Far children that don't fit the stack are dropped, the traversal then starts over
+        // from the root bounded by the nearest hit so far. That only helps while it finds closer
+        // hits, otherwise the trace is marked as failed.
+        let mut dropped = !stack.push(root);
+        let mut bound = result.len;
+
+        loop {
+            if stack.is_empty() {
+                if !dropped {
+                    break;
+                }
+                if result.len >= bound {
+                    result.failed = true;
+                    break;
+                }
+                bound = result.len;
+                dropped = !stack.push(root);
+                continue;
+            }
             let node_index = stack.pop().unwrap();
             let node = &self.nodes[node_index];
             if node.is_leaf() {
-                for i in 0..node.triangle_count() {
-                    let triangle_index = node.first_triangle_index() + i;
-                    let triangle = index_buffer[triangle_index as usize];
-                    let a = per_vertex_buffer[triangle.x as usize].vertex.xyz();
-                    let b = per_vertex_buffer[triangle.y as usize].vertex.xyz();
-                    let c = per_vertex_buffer[triangle.z as usize].vertex.xyz();
-
-                    let mut t = 0.0;
-                    let mut backface = false;
-                    if muller_trumbore(ro, rd, a, b, c, &mut t, &mut backface)
-                        && t > 0.001
-                        && t < result.len
-                        && (NEAREST || t <= max_t)
-                    {
-                        result.triangle = triangle;
-                        result.triangle_index = triangle_index;
-                        result.len = result.len.min(t);
-                        result.hit = true;
-                        result.backface = backface;
-                        if !NEAREST {
-                            return result;
-                        }
-                    }
+                if leaf(node, result) {
+                    return true;
                 }
             } else {
                 // find closest child
@@ -191,9 +261,9 @@ impl<'a> BVHReference<'a> {
                 let mut min_child = &self.nodes[min_index];
                 let mut max_child = &self.nodes[max_index];
                 let mut min_dist =
-                    intersect_aabb(min_child.aabb_min(), min_child.aabb_max(), ro, rd, result.len);
+                    min_child.aabb().intersect(ro, inv_rd, result.len).unwrap_or(f32::MAX);
                 let mut max_dist =
-                    intersect_aabb(max_child.aabb_min(), max_child.aabb_max(), ro, rd, result.len);
+                    max_child.aabb().intersect(ro, inv_rd, result.len).unwrap_or(f32::MAX);
                 if min_dist > max_dist {
                     mem::swap(&mut min_index, &mut max_index);
                     mem::swap(&mut min_dist, &mut max_dist);
@@ -205,14 +275,179 @@ impl<'a> BVHReference<'a> {
                     continue;
                 }
 
-                // push valid children in the best order
-                if max_dist.abs() < f32::MAX {
-                    stack.push(max_index);
+                // push valid children in the best order. Trees from `BVHBuilder` always fit the
+                // stack, for deeper ones the far child is dropped and the near one still fits.
+                if max_dist.abs() < f32::MAX
+                    && ((stack.len as usize) + 2 > BVH_STACK_SIZE || !stack.push(max_index))
+                {
+                    dropped = true;
+                }
+                // the current node was just popped and the far child left room, so this always fits
+                dropped |= !stack.push(min_index); // <-- this child will be popped first
+            }
+        }
+
+        false
+    }
+
+    // Child to visit first. Unlike the stack traversal this can't depend on hit distances,
+    // every revisit of a node has to agree on the order, so the ray direction decides.
+    #[cfg(feature = "stackless")]
+    fn near_child(&self, node: &BVHNode, rd: Vec3) -> usize {
+        let left = node.left_node_index() as usize;
+        let (l, r) = (&self.nodes[left], &self.nodes[left + 1]);
+        let axis = r.aabb().centroid() - l.aabb().centroid();
+        if axis.dot(rd) >= 0.0 {
+            left
+        } else {
+            left + 1
+        }
+    }
+
+    // Siblings are always allocated next to each other, see `BVHBuilder::build`
+    #[cfg(feature = "stackless")]
+    fn sibling(&self, index: usize) -> usize {
+        let left = self.nodes[self.parents[index] as usize].left_node_index() as usize;
+        if index == left {
+            left + 1
+        } else {
+            left
+        }
+    }
+
+    // https://doi.org/10.1145/2448196.2448228 (Hapala et al., Efficient Stack-less BVH Traversal)
+    // The whole traversal state is the current node and where we came from.
+    #[cfg(feature = "stackless")]
+    fn traverse(
+        &self,
+        root: usize,
+        ro: Vec3,
+        rd: Vec3,
+        result: &mut Trace,
+        mut leaf: impl FnMut(&BVHNode, &mut Trace) -> bool,
+    ) -> bool {
+        const FROM_PARENT: u32 = 0;
+        const FROM_SIBLING: u32 = 1;
+        const FROM_CHILD: u32 = 2;
+
+        let inv_rd = safe_inverse(rd);
+
+        let root_node = &self.nodes[root];
+        if root_node.is_leaf() {
+            return leaf(root_node, result);
+        }
+
+        let mut current = self.near_child(root_node, rd);
+        let mut state = FROM_PARENT;
+        loop {
+            if state == FROM_CHILD {
+                if current == root {
+                    break;
+                }
+                let parent = self.parents[current] as usize;
+                if current == self.near_child(&self.nodes[parent], rd) {
+                    current = self.sibling(current);
+                    state = FROM_SIBLING;
+                } else {
+                    current = parent;
                 }
-                stack.push(min_index); // <-- this child will be popped first
+                continue;
+            }
+
+            // entered from the parent or the sibling, test the node itself
+            let node = &self.nodes[current];
+            let hit = node.aabb().intersect(ro, inv_rd, result.len).is_some();
+            if hit && !node.is_leaf() {
+                current = self.near_child(node, rd);
+                state = FROM_PARENT;
+                continue;
+            }
+            if hit && leaf(node, result) {
+                return true;
+            }
+
+            // done with this subtree, the near child continues with its sibling,
+            // the far one means both children are done
+            if state == FROM_PARENT {
+                current = self.sibling(current);
+                state = FROM_SIBLING;
+            } else {
+                current = self.parents[current] as usize;
+                state = FROM_CHILD;
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(all(test, not(feature = "stackless")))]
+mod tests {
+    use {super::*, shared::Aabb};
+
+    // A caterpillar of `depth` interior nodes, each with a leaf and the next interior node as
+    // children. The interior nodes are entered first, so every level leaves its leaf on the
+    // stack. Leaf `i` spans x from `distance(i)` along the +x axis, the one closing off the
+    // deepest level lies beside the ray.
+    fn caterpillar(depth: u32, distance: impl Fn(u32) -> f32) -> Vec<BVHNode> {
+        let slab = |min_x: f32, max_x: f32| {
+            Aabb::new(Vec3::new(min_x, -1.0, -1.0), Vec3::new(max_x, 1.0, 1.0))
+        };
+        let mut interior = BVHNode::default();
+        interior.set_aabb(slab(0.0, 1000.0));
+        let mut nodes = vec![interior];
+        for level in 0..depth {
+            let left = nodes.len() as u32;
+            nodes.last_mut().unwrap().set_left_node_index(left);
+            let mut leaf = BVHNode::default();
+            leaf.set_aabb(slab(distance(level), distance(level) + 0.5));
+            leaf.set_triangle_count(1);
+            leaf.set_first_triangle_index(level);
+            nodes.push(leaf);
+            if level + 1 == depth {
+                let mut beside = leaf;
+                beside.set_aabb(Aabb::new(Vec3::new(0.0, 5.0, 0.0), Vec3::new(1.0, 6.0, 1.0)));
+                beside.set_first_triangle_index(depth);
+                nodes.push(beside);
+            } else {
+                nodes.push(interior);
             }
         }
+        nodes
+    }
 
+    // The leaf the ray from x = -1 along +x enters first, taking leaf boxes for triangles
+    fn nearest(nodes: &[BVHNode]) -> Trace {
+        let bvh = BVHReference { nodes, parents: &[], instances: &[] };
+        let (ro, rd) = (Vec3::new(-1.0, 0.0, 0.0), Vec3::X);
+        let inv_rd = safe_inverse(rd);
+        let mut result = Trace::miss();
+        bvh.traverse(0, ro, rd, &mut result, |node, result| {
+            if let Some(t) = node.aabb().intersect(ro, inv_rd, result.len) {
+                result.len = t;
+                result.hit = true;
+                result.triangle_index = node.first_triangle_index();
+            }
+            false
+        });
         result
     }
+
+    #[test]
+    fn deep_tree_fits_the_stack() {
+        let result = nearest(&caterpillar(40, |level| 2.0 + level as f32));
+        assert!(result.hit && !result.failed);
+        assert_eq!((result.triangle_index, result.len), (0, 3.0));
+    }
+
+    #[test]
+    fn deeper_tree_than_the_stack_starts_over() {
+        // the nearest leaf is among those past the stack, dropped on the first pass
+        let depth = 2 * BVH_STACK_SIZE as u32;
+        let nearest_level = BVH_STACK_SIZE as u32 + 10;
+        let distance = |level: u32| 2.0 + level.abs_diff(nearest_level) as f32;
+        let result = nearest(&caterpillar(depth, distance));
+        assert!(result.hit && !result.failed);
+        assert_eq!((result.triangle_index, result.len), (nearest_level, 3.0));
+    }
 }
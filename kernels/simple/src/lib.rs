@@ -3,29 +3,43 @@
 // #![deny(warnings)]
 
 mod bsdf;
+mod env;
 mod inter;
 mod light;
+mod medium;
+mod restir;
 mod rng;
 mod skybox;
+mod texture;
 mod util;
 mod vec;
+mod wavefront;
 
 use {
     crate::{
         bsdf::{Lobe, BSDF},
+        env::EnvReference,
         inter::{BVHReference, Trace},
-        rng::RngState,
+        medium::MediumStack,
+        rng::{self, RngState},
+        texture::RayCone,
     },
     core::{
         cmp::Ordering,
         ops::{Add, Div, Mul, Sub},
     },
-    shared::{BVHNode, LightPick, MaterialData, PerVertexData, Sampler, TracingConfig},
+    shared::{
+        camera_basis, BVHNode, GBufferTexel, HitRecord, InstanceData, LightPick, MaterialData,
+        PathRecord, PerVertexData, PunctualLight, RenderMode, Reservoir, Restir, Sampler,
+        ShadowRay, TextureSlot, TracingConfig, BLOCK_SIZE, BLOCK_WORDS,
+    },
     spirv_std::{
+        arch::{atomic_and, atomic_i_increment, atomic_or},
         glam::{
             vec2, vec3, vec4, Mat2, Mat3, UVec2, UVec3, UVec4, Vec2, Vec3, Vec3Swizzles, Vec4,
             Vec4Swizzles,
         },
+        memory::{Scope, Semantics},
         num_traits::{Float, Pow},
         spirv, Image,
     },
@@ -33,164 +47,1055 @@ use {
 
 pub const EPS: f32 = 0.001;
 
-fn trace_pixel(
-    id: UVec3,
-    config: &TracingConfig,
-    rng: UVec2,
+// Contributions lighting the first vertex stay untouched, so light sources remain correct
+fn clamp_contribution(config: &TracingConfig, indirect: bool, contribution: Vec3) -> Vec3 {
+    let luminance = shared::luminance(contribution);
+    if indirect && config.clamp_indirect > 0.0 && luminance > config.clamp_indirect {
+        contribution * (config.clamp_indirect / luminance)
+    } else {
+        contribution
+    }
+}
+
+// Direct is light reaching the first vertex (plus emitters seen by the camera), the rest is
+// indirect. Both are accumulated into separate buffers and summed for display.
+#[derive(Default, Copy, Clone)]
+struct Radiance {
+    direct: Vec3,
+    indirect: Vec3,
+    // `1 + bounce` of the first non-finite contribution, 0 if there was none
+    nan_bounce: u32,
+}
+
+impl Radiance {
+    // Non-finite contributions are dropped, or recorded with `TracingConfig::debug_nan`
+    fn add(&mut self, config: &TracingConfig, bounce: u32, indirect: bool, contribution: Vec3) {
+        if !contribution.is_finite() {
+            if config.debug_nan() && self.nan_bounce == 0 {
+                self.nan_bounce = bounce + 1;
+            }
+            return;
+        }
+        let contribution = clamp_contribution(config, indirect, contribution);
+        if indirect {
+            self.indirect += contribution;
+        } else {
+            self.direct += contribution;
+        }
+    }
+}
+
+// Denoiser guides, taken at the first vertex that isn't a sharp mirror or glass
+#[derive(Default, Copy, Clone)]
+struct Aov {
+    albedo: Vec3,
+    normal: Vec3,
+}
+
+// Lobes rougher than this are "diffuse enough" for the denoiser to see through
+const AOV_ROUGHNESS: f32 = 0.3;
+
+// Near-mirror specular lobes are too peaked for light sampling to help, BSDF sampling wins
+const NEE_MIN_ROUGHNESS: f32 = 0.1;
+
+// False interfaces stepped through per segment, enough for a few overlapping volumes
+const MAX_FALSE_INTERFACES: u32 = 4;
+
+// Adaptive sampling holds darker pixels to the error of one this bright, so nearly black ones
+// don't keep sampling forever
+const ADAPTIVE_MIN_LUMINANCE: f32 = 0.01;
+
+// Russian roulette on perceived brightness, saturated paths no longer over-survive.
+// The clamp keeps the 1 / prob weights bounded and still kills the odd bright path.
+// Returns whether the path survives, reweighting `throughput` if it does.
+fn russian_roulette(throughput: &mut Vec3, rng_state: &mut RngState) -> bool {
+    let prob = shared::luminance(*throughput).clamp(0.05, 0.95);
+    if rng_state.gen_r1() > prob {
+        return false;
+    }
+    *throughput *= 1.0 / prob;
+    true
+}
+
+// One cosine-weighted occlusion ray off the camera hit `trace`, misses are white
+fn trace_ambient_occlusion(
+    bvh: &BVHReference,
     indices: &[UVec4],
     per_vertex: &[PerVertexData],
-    nodes_buffer: &[BVHNode],
-    materials: &[MaterialData],
-    lights: &[LightPick],
-    sampler: &Sampler,
-    atlas: &Image!(2D, type=f32, sampled),
-) -> (Vec4, UVec2) {
-    let mut rng_state = RngState::new(rng);
+    ori: Vec3,
+    dir: Vec3,
+    trace: Trace,
+    rng_state: &mut RngState,
+) -> (Radiance, Aov) {
+    let mut radiance = Radiance::default();
+    if !trace.hit {
+        radiance.direct = Vec3::ONE;
+        return (radiance, Aov { albedo: Vec3::ONE, normal: Vec3::ZERO });
+    }
+
+    // the geometric normal, facing the camera
+    let instance = bvh.instances[trace.instance as usize];
+    let vert_a = instance.point_to_world(per_vertex[trace.triangle.x as usize].vertex.xyz());
+    let vert_b = instance.point_to_world(per_vertex[trace.triangle.y as usize].vertex.xyz());
+    let vert_c = instance.point_to_world(per_vertex[trace.triangle.z as usize].vertex.xyz());
+    let mut normal = (vert_b - vert_a).cross(vert_c - vert_a).normalize();
+    if normal.dot(dir) > 0.0 {
+        normal = -normal;
+    }
 
-    let suv = id.xy().as_vec2() + rng_state.gen_r2();
-    let mut uv =
-        Vec2::new(suv.x / config.width as f32, 1.0 - suv.y / config.height as f32) * 2.0 - 1.0;
-    uv.y *= config.height as f32 / config.width as f32;
+    let rng = rng_state.gen_r2();
+    let (up, nt, nb) = util::create_cartesian(normal);
+    let sample = util::cos_hemisphere(rng.x, rng.y);
+    let direction = (nb * sample.x + up * sample.y + nt * sample.z).normalize();
+    let hit = ori + dir * trace.len + normal * EPS;
+    let occluded = bvh.intersect_any(per_vertex, indices, hit, direction, shared::AO_RADIUS).hit;
+    if !occluded {
+        radiance.direct = Vec3::ONE;
+    }
+    (radiance, Aov { albedo: Vec3::ONE, normal })
+}
+
+// The camera ray of the sample through pixel `id`, both passes draw the same jitter and lens
+// position from `rng_state` fresh for the pixel
+fn camera_ray(
+    id: UVec3,
+    config: &TracingConfig,
+    jitter: Vec2,
+    rng_state: &mut RngState,
+) -> (Vec3, Vec3) {
+    // the image plane sits at distance 1, `vertical_fov` spans its height
+    let half_height = (config.vertical_fov * 0.5).tan();
+    let aspect = config.width as f32 / config.height as f32;
+    let suv = id.xy().as_vec2() + jitter;
+    let uv = Vec2::new(suv.x / config.width as f32, 1.0 - suv.y / config.height as f32) * 2.0 - 1.0;
+    let uv = uv * Vec2::new(aspect, 1.0) * half_height;
 
     let mut ori = config.cam_pos.xyz();
-    let euler_mat =
-        Mat3::from_rotation_y(config.cam_rot.y) * Mat3::from_rotation_x(config.cam_rot.x);
-    let mut dir = euler_mat * (Vec3::new(uv.x, uv.y, 1.0).normalize());
+    let euler_mat = camera_basis(config.cam_rot.xyz());
+    let cam_dir = Vec3::new(uv.x, uv.y, 1.0).normalize();
+    let mut dir = euler_mat * cam_dir;
 
-    let mut throughput = Vec3::ONE;
-    let mut radiance = Vec3::ZERO;
-    let mut bsdf_sample = bsdf::BSDFSample::default();
-    let mut light_sample = light::LightSample::default();
+    // Thin lens: jitter the origin over the aperture and aim at the point on the focal plane
+    if config.aperture > 0.0 {
+        let focus = ori + dir * (config.focus_distance / cam_dir.z);
+        let rng = rng_state.gen_r2();
+        let lens = util::concentric_disk(rng.x, rng.y) * config.aperture * 0.5;
+        ori += euler_mat * Vec3::new(lens.x, lens.y, 0.0);
+        dir = (focus - ori).normalize();
+    }
+    (ori, dir)
+}
 
-    let bvh = BVHReference { nodes: nodes_buffer };
+// The primary ray pass: what the camera ray of pixel `id` hits, for `trace_pixel` to shade
+fn trace_primary(
+    id: UVec3,
+    config: &TracingConfig,
+    blue_noise: Vec4,
+    indices: &[UVec4],
+    per_vertex: &[PerVertexData],
+    nodes_buffer: &[BVHNode],
+    parents_buffer: &[u32],
+    instances: &[InstanceData],
+    materials: &[MaterialData],
+) -> GBufferTexel {
+    let mut rng_state = RngState::new(id.xy(), config.sample_index);
+    let blue_noise = rng::blue_noise(blue_noise, config.sample_index);
+    let (ori, dir) = camera_ray(id, config, blue_noise.xy(), &mut rng_state);
 
-    for bounce in 0..16 {
-        let trace = bvh.intersect_nearest(per_vertex, indices, ori, dir);
-        let hit = ori + dir * trace.len;
+    let bvh = BVHReference { nodes: nodes_buffer, parents: parents_buffer, instances };
+    let trace = bvh.intersect_nearest(per_vertex, indices, ori, dir);
+    let mut texel = GBufferTexel::default();
+    if !trace.hit {
+        return texel;
+    }
 
-        if !trace.hit {
-            let sun = Vec3::new(0.5, 1.3, 1.0).normalize().extend(15.0);
-            radiance += throughput * skybox::scatter(sun, ori, dir);
-            break;
-        } else {
-            let material = materials[trace.triangle.w as usize];
+    let instance = instances[trace.instance as usize];
+    let vertex_data_a = per_vertex[trace.triangle.x as usize];
+    let vertex_data_b = per_vertex[trace.triangle.y as usize];
+    let vertex_data_c = per_vertex[trace.triangle.z as usize];
+    let hit = ori + dir * trace.len;
+    let bary = util::barycentric(
+        hit,
+        instance.point_to_world(vertex_data_a.vertex.xyz()),
+        instance.point_to_world(vertex_data_b.vertex.xyz()),
+        instance.point_to_world(vertex_data_c.vertex.xyz()),
+    );
+    let normal = bary.x * instance.normal_to_world(vertex_data_a.normal.xyz())
+        + bary.y * instance.normal_to_world(vertex_data_b.normal.xyz())
+        + bary.z * instance.normal_to_world(vertex_data_c.normal.xyz());
 
-            if material.emissive.xyz() != Vec3::ZERO {
-                if trace.backface {
-                    break; // Break since emissives don't bounce light
-                }
+    texel.position = hit.extend(trace.len);
+    texel.normal = normal.normalize().extend(0.0);
+    texel.uv = bary.x * vertex_data_a.uv0 + bary.y * vertex_data_b.uv0 + bary.z * vertex_data_c.uv0;
+    texel.material_index = instance.material_index(trace.triangle, materials.len());
+    texel.instance = trace.instance;
+    texel.triangle_index = trace.triangle_index;
+    texel.set_hit(true);
+    texel.set_backface(trace.backface);
+    texel
+}
 
-                if bounce == 0 || bsdf_sample.lobe != Lobe::DiffuseReflection {
-                    radiance += util::mask_nan(throughput * material.emissive.xyz() * 15.0);
-                    break;
-                }
+// A path between bounces. The megakernel keeps it in registers, wavefront tracing stores it
+// in a `shared::PathRecord` between passes.
+struct Path {
+    // the ray to trace next
+    ori: Vec3,
+    dir: Vec3,
+    bounce: u32,
+    throughput: Vec3,
+    radiance: Radiance,
+    bsdf_sample: bsdf::BSDFSample,
+    light_sample: light::LightSample,
+    // whether the previous vertex sampled lights directly, hits then need the BSDF MIS weight
+    used_nee: bool,
+    aov: Aov,
+    aov_pending: bool,
+    // roughest lobe sampled so far, drives path regularization
+    path_roughness: f32,
+    // dielectrics the path is inside of, the camera is assumed to be in air
+    media: MediumStack,
+    // RGB channel the path carries once it went through a dispersive surface
+    channel: u32,
+    cone: RayCone,
+    rng_state: RngState,
+    // drives the pixel jitter (`xy`) and the first BSDF sample (`zw`)
+    blue_noise: Vec4,
+}
 
-                if bsdf_sample.lobe == Lobe::DiffuseReflection {
-                    let direct_contribution =
-                        light::calculate_bsdf_mis_contribution(&trace, &bsdf_sample, &light_sample);
-                    radiance += util::mask_nan(direct_contribution);
-                    break;
-                }
-            }
+impl Path {
+    // Starts on the camera ray of pixel `id`, the same ray `trace_primary` traces
+    fn new(id: UVec3, config: &TracingConfig, blue_noise: Vec4) -> Self {
+        let mut rng_state = RngState::new(id.xy(), config.sample_index);
+        let blue_noise = rng::blue_noise(blue_noise, config.sample_index);
+        let (ori, dir) = camera_ray(id, config, blue_noise.xy(), &mut rng_state);
 
-            let vertex_data_a = per_vertex[trace.triangle.x as usize];
-            let vertex_data_b = per_vertex[trace.triangle.y as usize];
-            let vertex_data_c = per_vertex[trace.triangle.z as usize];
-            let vert_a = vertex_data_a.vertex.xyz();
-            let vert_b = vertex_data_b.vertex.xyz();
-            let vert_c = vertex_data_c.vertex.xyz();
-            let norm_a = vertex_data_a.normal.xyz();
-            let norm_b = vertex_data_b.normal.xyz();
-            let norm_c = vertex_data_c.normal.xyz();
-            let uv_a = vertex_data_a.uv0;
-            let uv_b = vertex_data_b.uv0;
-            let uv_c = vertex_data_c.uv0;
-            let bary = util::barycentric(hit, vert_a, vert_b, vert_c);
-            let mut norm = bary.x * norm_a + bary.y * norm_b + bary.z * norm_c;
-            let mut uv = bary.x * uv_a + bary.y * uv_b + bary.z * uv_c;
-            if uv.clamp(Vec2::ZERO, Vec2::ONE) != uv {
-                uv = uv.fract(); // wrap UVs
-            }
+        // a pixel spans 2 * half_height / height of the image plane at distance 1
+        let half_height = (config.vertical_fov * 0.5).tan();
+        Self {
+            ori,
+            dir,
+            bounce: 0,
+            throughput: Vec3::ONE,
+            radiance: Radiance::default(),
+            bsdf_sample: bsdf::BSDFSample::default(),
+            light_sample: light::LightSample::default(),
+            used_nee: false,
+            aov: Aov::default(),
+            aov_pending: true,
+            path_roughness: 0.0,
+            media: MediumStack::new(),
+            channel: bsdf::ALL_CHANNELS,
+            cone: RayCone::new(2.0 * half_height / config.height as f32),
+            rng_state,
+            blue_noise,
+        }
+    }
 
-            if material.has_normal_texture() {
-                let scaled_uv = material.normals.xy() + uv * material.normals.zw();
-                let normal_map = atlas.sample_by_lod(*sampler, scaled_uv, 0.0) * 2.0 - 1.0;
-                let tangent_a = vertex_data_a.tangent.xyz();
-                let tangent_b = vertex_data_b.tangent.xyz();
-                let tangent_c = vertex_data_c.tangent.xyz();
-                let tangent = bary.x * tangent_a + bary.y * tangent_b + bary.z * tangent_c;
-                let tbn = Mat3::from_cols(tangent, tangent.cross(norm), norm);
-                norm = (tbn * normal_map.xyz()).normalize();
-            }
+    // Shades what the current ray hit and picks the next ray, returns whether there is one.
+    // Light samples go through `occlusion`, which may hold on to their contribution. `reservoir`
+    // is the ReSTIR pick for the camera hit, only passed with its trace.
+    fn shade(
+        &mut self,
+        mut trace: Trace,
+        config: &TracingConfig,
+        indices: &[UVec4],
+        per_vertex: &[PerVertexData],
+        materials: &[MaterialData],
+        lights: &[LightPick],
+        punctual_lights: &[PunctualLight],
+        sampler: &Sampler,
+        atlas: &Image!(2D, type=f32, sampled),
+        bvh: &BVHReference,
+        env: &EnvReference,
+        occlusion: &mut impl light::Occlusion,
+        reservoir: Option<Reservoir>,
+    ) -> bool {
+        // white diffuse everything under a white sky, skipping lights, fog and media
+        let furnace = config.render_mode() == RenderMode::Furnace;
+        let bounce = self.bounce;
+        let (ori, dir) = (self.ori, self.dir);
 
-            let bsdf = bsdf::get_pbr_bsdf(config, &material, uv, atlas, sampler);
-            // let bsdf = bsdf::Lambertian { albedo: col };
-            // let bsdf = bsdf::Glass { albedo: col, ior: 1.5, roughness: 0.7 };
+        // Step through false interfaces without scattering or spending a bounce. Bounded so a
+        // pile of coplanar surfaces can't stall the path, the last one is then shaded as usual.
+        let mut skipped = 0.0;
+        let mut steps = 0;
+        while !furnace && trace.hit && steps < MAX_FALSE_INTERFACES {
+            let instance = bvh.instances[trace.instance as usize];
+            let material_index = instance.material_index(trace.triangle, materials.len());
+            if materials[material_index as usize].transmission <= 0.0
+                || !self.media.is_false_interface(materials, material_index)
+            {
+                break;
+            }
+            self.media.cross(material_index, trace.backface);
+            skipped += trace.len + EPS;
+            trace = bvh.intersect_nearest(per_vertex, indices, ori + dir * skipped, dir);
+            steps += 1;
+        }
+        trace.len += skipped;
 
-            bsdf_sample = bsdf.sample(-dir, norm, &mut rng_state);
+        // Fog: sample a free-flight distance and scatter in the medium if it ends before the
+        // surface. The fog has no boundary, so rays that would miss always scatter.
+        if config.sigma_t > 0.0 && !furnace {
+            let distance = -(1.0 - self.rng_state.gen_r1()).ln() / config.sigma_t;
+            if !trace.hit || distance < trace.len {
+                let point = ori + dir * distance;
+                self.cone.propagate(distance);
+                self.cone.scatter(1.0);
+                self.path_roughness = 1.0;
 
-            if bsdf_sample.lobe == Lobe::DiffuseReflection {
-                light_sample = light::sample_direct_lighting(
+                // the free-flight pdf sigma_t * T cancels the transmittance T
+                self.throughput *= config.sigma_s / config.sigma_t;
+                let phase = bsdf::HenyeyGreenstein { g: config.phase_g };
+                self.bsdf_sample = phase.sample(-dir, Vec3::ZERO, &mut self.rng_state);
+                self.used_nee = true;
+                self.light_sample = light::sample_direct_lighting(
+                    config,
                     indices,
                     per_vertex,
                     materials,
                     lights,
-                    &bvh,
-                    throughput,
-                    &bsdf,
-                    hit,
-                    norm,
+                    punctual_lights,
+                    sampler,
+                    atlas,
+                    bvh,
+                    env,
+                    occlusion,
+                    // picked for the surface, not a point in the fog before it
+                    None,
+                    self.throughput,
+                    &phase,
+                    self.bsdf_sample.lobe,
+                    point,
+                    Vec3::ZERO,
                     dir,
-                    &mut rng_state,
+                    &mut self.rng_state,
                 );
-                radiance += util::mask_nan(light_sample.contribution);
+                self.add_light_sample(config, occlusion);
+
+                self.dir = self.bsdf_sample.direction;
+                self.ori = point;
+                return self.next_bounce(config);
             }
+        }
 
-            throughput *= bsdf_sample.spectrum / bsdf_sample.pdf;
-            dir = bsdf_sample.direction;
-            ori = hit + dir * EPS;
+        let hit = ori + dir * trace.len;
+        self.cone.propagate(trace.len);
 
-            if bounce > 8 {
-                let prob = throughput.max_element();
-                if rng_state.gen_r1() > prob {
-                    break;
+        if !trace.hit {
+            if furnace {
+                if self.aov_pending {
+                    self.aov.albedo = Vec3::ONE;
+                }
+                self.radiance.add(config, bounce, bounce > 1, self.throughput);
+            } else if config.has_env_map() {
+                let mut env_radiance = env.lookup(dir);
+                if self.aov_pending {
+                    self.aov.albedo = env_radiance.clamp(Vec3::ZERO, Vec3::ONE);
+                }
+                if self.used_nee {
+                    // The environment was also sampled directly, apply the BSDF side MIS weight
+                    let light_pdf = env.pdf(dir) * config.env_pick_pdf;
+                    env_radiance *= light::get_weight(self.bsdf_sample.pdf, light_pdf);
                 }
-                throughput *= 1.0 / prob;
+                self.radiance.add(config, bounce, bounce > 1, self.throughput * env_radiance);
+            } else {
+                let mut sun = skybox::sun_disc(config, ori, dir);
+                if self.used_nee && sun != Vec3::ZERO {
+                    // The sun was also sampled directly, apply the BSDF side MIS weight
+                    let light_pdf =
+                        light::sun_pick_pdf(config, lights) / skybox::sun_solid_angle(config);
+                    sun *= light::get_weight(self.bsdf_sample.pdf, light_pdf);
+                }
+                let sky = skybox::radiance(config, ori, dir) + sun;
+                if self.aov_pending {
+                    self.aov.albedo = sky.clamp(Vec3::ZERO, Vec3::ONE);
+                }
+                self.radiance.add(config, bounce, bounce > 1, self.throughput * sky);
             }
+            return false;
+        }
+
+        let instance = bvh.instances[trace.instance as usize];
+        let material_index = instance.material_index(trace.triangle, materials.len());
+        let material = if furnace {
+            let mut white = MaterialData::default();
+            white.albedo = Vec4::ONE;
+            white.roughness = Vec4::ONE;
+            white
+        } else {
+            materials[material_index as usize]
+        };
+
+        // vertices are stored in object space, shade in world space
+        let vertex_data_a = per_vertex[trace.triangle.x as usize];
+        let vertex_data_b = per_vertex[trace.triangle.y as usize];
+        let vertex_data_c = per_vertex[trace.triangle.z as usize];
+        let vert_a = instance.point_to_world(vertex_data_a.vertex.xyz());
+        let vert_b = instance.point_to_world(vertex_data_b.vertex.xyz());
+        let vert_c = instance.point_to_world(vertex_data_c.vertex.xyz());
+        let norm_a = instance.normal_to_world(vertex_data_a.normal.xyz());
+        let norm_b = instance.normal_to_world(vertex_data_b.normal.xyz());
+        let norm_c = instance.normal_to_world(vertex_data_c.normal.xyz());
+        let uv_a = vertex_data_a.uv0;
+        let uv_b = vertex_data_b.uv0;
+        let uv_c = vertex_data_c.uv0;
+        let bary = util::barycentric(hit, vert_a, vert_b, vert_c);
+        let mut norm = bary.x * norm_a + bary.y * norm_b + bary.z * norm_c;
+        // UVs are wrapped per texture lookup, see `texture::sample`
+        let uv = bary.x * uv_a + bary.y * uv_b + bary.z * uv_c;
+        let uv1 =
+            bary.x * vertex_data_a.uv1 + bary.y * vertex_data_b.uv1 + bary.z * vertex_data_c.uv1;
+        // most materials have none, skip the extra loads for them
+        let vertex_color = if material.has_vertex_colors() {
+            let color = bary.x * vertex_data_a.color
+                + bary.y * vertex_data_b.color
+                + bary.z * vertex_data_c.color;
+            color.xyz()
+        } else {
+            Vec3::ONE
+        };
+        let uv_area = (uv_b - uv_a).perp_dot(uv_c - uv_a).abs() * 0.5;
+        let world_area = (vert_b - vert_a).cross(vert_c - vert_a).length() * 0.5;
+        let lod_bias = self.cone.lod_bias(uv_area, world_area, norm.normalize().dot(dir));
+
+        if material.emission() != Vec3::ZERO {
+            if trace.backface && !material.double_sided() {
+                return false; // emissives don't bounce light
+            }
+
+            let emission = bsdf::get_emission(config, &material, uv, lod_bias, atlas, sampler);
+            if self.aov_pending {
+                let normal = norm.normalize();
+                self.aov = Aov { albedo: emission.clamp(Vec3::ZERO, Vec3::ONE), normal };
+            }
+            if self.used_nee {
+                let direct_contribution = light::calculate_bsdf_mis_contribution(
+                    &trace,
+                    &self.bsdf_sample,
+                    &self.light_sample,
+                    emission,
+                );
+                self.radiance.add(config, bounce, bounce > 1, direct_contribution);
+            } else {
+                self.radiance.add(config, bounce, bounce > 1, self.throughput * emission);
+            }
+            return false;
+        }
+
+        let tangent_a = vertex_data_a.tangent.xyz();
+        let tangent_b = vertex_data_b.tangent.xyz();
+        let tangent_c = vertex_data_c.tangent.xyz();
+        let tangent = bary.x * tangent_a + bary.y * tangent_b + bary.z * tangent_c;
+        let tangent = instance.vector_to_world(tangent).normalize();
+        // handedness is constant across a triangle, mirroring instances flip it
+        let bitangent = instance.handedness * vertex_data_a.tangent.w * norm.cross(tangent);
+
+        if material.has_normal_texture() {
+            let strength = material.normal_strength;
+            let normal_map = texture::sample(
+                config,
+                atlas,
+                sampler,
+                &material,
+                TextureSlot::Normals,
+                uv,
+                lod_bias,
+            );
+            let normal_map = (normal_map.xyz() * 2.0 - 1.0) * vec3(strength, strength, 1.0);
+            let tbn = Mat3::from_cols(tangent, bitangent, norm);
+            norm = (tbn * normal_map).normalize();
+        }
+        // Double-sided surfaces are shaded from the side the ray hits. Glass keeps the front
+        // normal, it tells entering from exiting by it.
+        if trace.backface && material.double_sided() && material.transmission <= 0.0 {
+            norm = -norm;
+        }
+        // anisotropic lobes stretch along the tangent, around the shading normal
+        let tbn = Mat3::from_cols(tangent, bitangent, norm);
+
+        // Dispersion: pick one channel to carry on with, 3x keeps the sum over channels
+        // unbiased. Only paths touching dispersive glass pay for the extra color noise.
+        if self.channel == bsdf::ALL_CHANNELS
+            && material.abbe_number > 0.0
+            && material.transmission > 0.0
+        {
+            self.channel = ((self.rng_state.gen_r1() * 3.0) as u32).min(2);
+            let mut mask = Vec3::ZERO;
+            mask[self.channel as usize] = 3.0;
+            self.throughput *= mask;
+        }
+
+        // zero until the path has scattered off something rough, so the camera hit stays sharp
+        let min_roughness = (config.regularization * self.path_roughness).min(1.0) * 0.3;
+        let mut bsdf = bsdf::get_bsdf(
+            config,
+            &material,
+            uv,
+            uv1,
+            vertex_color,
+            tbn,
+            lod_bias,
+            atlas,
+            sampler,
+            min_roughness,
+        );
+        bsdf.glass.ior = bsdf::get_ior(&material, self.channel);
+        bsdf.glass.outer_ior = self.media.outer_ior(materials, material_index, self.channel);
+        // let bsdf = bsdf::Lambertian { albedo: col };
+        // let bsdf = bsdf::Glass { albedo: col, ior: 1.5, roughness: 0.7 };
+
+        if bounce == 0 {
+            self.rng_state.use_blue_noise(self.blue_noise.zw());
+        }
+        self.bsdf_sample = bsdf.sample(-dir, norm, &mut self.rng_state);
+
+        let roughness = match self.bsdf_sample.lobe {
+            Lobe::DiffuseReflection => 1.0,
+            Lobe::SpecularTransmission => bsdf.glass.roughness,
+            _ => bsdf.pbr.roughness,
+        };
+
+        self.path_roughness = self.path_roughness.max(roughness);
+
+        if self.aov_pending && roughness > AOV_ROUGHNESS {
+            self.aov = Aov { albedo: bsdf.pbr.albedo, normal: norm.normalize() };
+            self.aov_pending = false;
+        }
+
+        self.used_nee = !furnace
+            && match self.bsdf_sample.lobe {
+                Lobe::DiffuseReflection => true,
+                Lobe::SpecularReflection => roughness > NEE_MIN_ROUGHNESS,
+                _ => false,
+            };
+        if self.used_nee {
+            self.light_sample = light::sample_direct_lighting(
+                config,
+                indices,
+                per_vertex,
+                materials,
+                lights,
+                punctual_lights,
+                sampler,
+                atlas,
+                bvh,
+                env,
+                occlusion,
+                // picked for the hit `trace` had before stepping through false interfaces
+                if steps == 0 { reservoir } else { None },
+                self.throughput,
+                &bsdf,
+                self.bsdf_sample.lobe,
+                hit,
+                norm,
+                dir,
+                &mut self.rng_state,
+            );
+            self.add_light_sample(config, occlusion);
+        }
+
+        if self.bsdf_sample.lobe == Lobe::SpecularTransmission {
+            self.media.cross(material_index, trace.backface);
+        }
+
+        self.cone.scatter(roughness);
+
+        self.throughput *= self.bsdf_sample.spectrum / self.bsdf_sample.pdf;
+        self.dir = self.bsdf_sample.direction;
+        self.ori = hit + self.dir * EPS;
+        self.next_bounce(config)
+    }
+
+    // Light sampled from the current vertex, unless `occlusion` waits for its shadow ray
+    fn add_light_sample(&mut self, config: &TracingConfig, occlusion: &mut impl light::Occlusion) {
+        let contribution = self.light_sample.contribution;
+        if !occlusion.defer(contribution) {
+            self.radiance.add(config, self.bounce, self.bounce > 0, contribution);
+        }
+    }
+
+    // Russian roulette past `min_bounces`, then whether bounces are left
+    fn next_bounce(&mut self, config: &TracingConfig) -> bool {
+        if self.bounce >= config.min_bounces
+            && !russian_roulette(&mut self.throughput, &mut self.rng_state)
+        {
+            return false;
+        }
+        self.bounce += 1;
+        self.bounce <= config.max_bounces
+    }
+}
+
+// The shading pass, the path starts from the camera ray's hit in `primary`
+fn trace_pixel(
+    id: UVec3,
+    config: &TracingConfig,
+    blue_noise: Vec4,
+    primary: &GBufferTexel,
+    reservoir: Option<Reservoir>,
+    indices: &[UVec4],
+    per_vertex: &[PerVertexData],
+    nodes_buffer: &[BVHNode],
+    parents_buffer: &[u32],
+    instances: &[InstanceData],
+    materials: &[MaterialData],
+    lights: &[LightPick],
+    punctual_lights: &[PunctualLight],
+    sampler: &Sampler,
+    atlas: &Image!(2D, type=f32, sampled),
+    env_map: &[Vec4],
+    env_marginal_cdf: &[f32],
+    env_conditional_cdf: &[f32],
+) -> (Radiance, Aov) {
+    let mut path = Path::new(id, config, blue_noise);
+    let primary = Trace::from_gbuffer(primary, indices);
+
+    let bvh = BVHReference { nodes: nodes_buffer, parents: parents_buffer, instances };
+    if config.render_mode() == RenderMode::AmbientOcclusion {
+        path.rng_state.use_blue_noise(path.blue_noise.zw());
+        return trace_ambient_occlusion(
+            &bvh,
+            indices,
+            per_vertex,
+            path.ori,
+            path.dir,
+            primary,
+            &mut path.rng_state,
+        );
+    }
+
+    let env = EnvReference::new(config, env_map, env_marginal_cdf, env_conditional_cdf);
+    let mut shadows = light::Shadows { bvh: &bvh, indices, per_vertex };
+    loop {
+        let (trace, reservoir) = if path.bounce == 0 {
+            (primary, reservoir)
+        } else {
+            (bvh.intersect_nearest(per_vertex, indices, path.ori, path.dir), None)
+        };
+        let more = path.shade(
+            trace,
+            config,
+            indices,
+            per_vertex,
+            materials,
+            lights,
+            punctual_lights,
+            sampler,
+            atlas,
+            &bvh,
+            &env,
+            &mut shadows,
+            reservoir,
+        );
+        if !more {
+            break;
         }
     }
 
-    (radiance.extend(1.0), rng_state.next_state())
+    (path.radiance, path.aov)
+}
+
+// Runs before `main_cs` on the same tile, bindings match it
+#[spirv(compute(threads(8, 8, 1)))]
+pub fn primary_cs(
+    #[spirv(global_invocation_id)] id: UVec3,
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] config: &TracingConfig,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] index_buffer: &[UVec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] per_vertex_buffer: &[PerVertexData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] nodes_buffer: &[BVHNode],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] materials: &[MaterialData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 15)] parents_buffer: &[u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 16)] instances: &[InstanceData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 17)] blue_noise: &[Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 20)] gbuffer: &mut [GBufferTexel],
+) {
+    if id.x >= config.tile.z || id.y >= config.tile.w {
+        return;
+    }
+    let id = (id.xy() + config.tile.xy()).extend(0);
+    let index = (id.y * config.width + id.x) as usize;
+    gbuffer[index] = trace_primary(
+        id,
+        config,
+        blue_noise[index],
+        index_buffer,
+        per_vertex_buffer,
+        nodes_buffer,
+        parents_buffer,
+        instances,
+        materials,
+    );
+}
+
+// ReSTIR passes, see `restir`. They run after `primary_cs` on every tile before `main_cs` runs
+// on any, the spatial pass reads reservoirs across tile borders. Bindings match `main_cs`.
+#[spirv(compute(threads(8, 8, 1)))]
+pub fn restir_initial_cs(
+    #[spirv(global_invocation_id)] id: UVec3,
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] config: &TracingConfig,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] index_buffer: &[UVec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] per_vertex_buffer: &[PerVertexData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] nodes_buffer: &[BVHNode],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] materials: &[MaterialData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] lights: &[LightPick],
+    #[spirv(descriptor_set = 0, binding = 7)] sampler: &Sampler,
+    #[spirv(descriptor_set = 0, binding = 8)] atlas: &Image!(2D, type=f32, sampled),
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 15)] parents_buffer: &[u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 16)] instances: &[InstanceData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 20)] gbuffer: &[GBufferTexel],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 26)] reservoirs: &mut [Reservoir],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 27)] spatial_reservoirs: &[Reservoir],
+) {
+    if id.x >= config.tile.z || id.y >= config.tile.w {
+        return;
+    }
+    let id = id.xy() + config.tile.xy();
+    let index = (id.y * config.width + id.x) as usize;
+    // what the pixel's last sample shaded with
+    let previous = if config.restir() == Restir::Spatiotemporal {
+        spatial_reservoirs[index]
+    } else {
+        reservoirs[index]
+    };
+    let bvh = BVHReference { nodes: nodes_buffer, parents: parents_buffer, instances };
+    reservoirs[index] = restir::initial(
+        id,
+        config,
+        &gbuffer[index],
+        &previous,
+        index_buffer,
+        per_vertex_buffer,
+        materials,
+        lights,
+        sampler,
+        atlas,
+        &bvh,
+    );
+}
+
+#[spirv(compute(threads(8, 8, 1)))]
+pub fn restir_spatial_cs(
+    #[spirv(global_invocation_id)] id: UVec3,
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] config: &TracingConfig,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 20)] gbuffer: &[GBufferTexel],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 26)] reservoirs: &[Reservoir],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 27)]
+    spatial_reservoirs: &mut [Reservoir],
+) {
+    if id.x >= config.tile.z || id.y >= config.tile.w {
+        return;
+    }
+    let id = id.xy() + config.tile.xy();
+    let index = (id.y * config.width + id.x) as usize;
+    spatial_reservoirs[index] = restir::spatial(id, config, gbuffer, reservoirs);
 }
 
 #[spirv(compute(threads(8, 8, 1)))]
 pub fn main_cs(
     #[spirv(global_invocation_id)] id: UVec3,
     #[spirv(uniform, descriptor_set = 0, binding = 0)] config: &TracingConfig,
-    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] rng: &mut [UVec2],
-    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] output: &mut [Vec4],
-    #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] index_buffer: &[UVec4],
-    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] per_vertex_buffer: &[PerVertexData],
-    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] nodes_buffer: &[BVHNode],
-    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] materials: &[MaterialData],
-    #[spirv(storage_buffer, descriptor_set = 0, binding = 7)] lights: &[LightPick],
-    #[spirv(descriptor_set = 0, binding = 8)] sampler: &Sampler,
-    #[spirv(descriptor_set = 0, binding = 9)] atlas: &Image!(2D, type=f32, sampled),
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] output: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] index_buffer: &[UVec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] per_vertex_buffer: &[PerVertexData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] nodes_buffer: &[BVHNode],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] materials: &[MaterialData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] lights: &[LightPick],
+    #[spirv(descriptor_set = 0, binding = 7)] sampler: &Sampler,
+    #[spirv(descriptor_set = 0, binding = 8)] atlas: &Image!(2D, type=f32, sampled),
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 9)] env_map: &[Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 10)] env_marginal_cdf: &[f32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 11)] env_conditional_cdf: &[f32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 12)] albedo_output: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 13)] normal_output: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 14)] indirect_output: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 15)] parents_buffer: &[u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 16)] instances: &[InstanceData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 17)] blue_noise: &[Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 18)] diagnostics: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 19)] punctual_lights: &[PunctualLight],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 20)] gbuffer: &[GBufferTexel],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 26)] reservoirs: &[Reservoir],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 27)] spatial_reservoirs: &[Reservoir],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 28)] variance_output: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 29)] block_mask: &mut [u32],
 ) {
+    let adaptive = config.adaptive_threshold > 0.0;
+    // the workgroups at the edges of a tile reach past it
+    if id.x >= config.tile.z || id.y >= config.tile.w {
+        // tiles end on block borders inside the image, so these are past its edge
+        if adaptive {
+            mark_converged(config, block_mask, id.xy() + config.tile.xy(), true);
+        }
+        return;
+    }
+    let id = (id.xy() + config.tile.xy()).extend(0);
     let index = (id.y * config.width + id.x) as usize;
-    let (pixel, state) = trace_pixel(
+    if adaptive {
+        let sum = output[index] + indirect_output[index].xyz().extend(0.0);
+        let converged = converged(config, sum, variance_output[index]);
+        mark_converged(config, block_mask, id.xy(), converged);
+        if converged {
+            return;
+        }
+    }
+    let reservoir = match config.restir() {
+        Restir::Off => None,
+        Restir::Temporal => Some(reservoirs[index]),
+        Restir::Spatiotemporal => Some(spatial_reservoirs[index]),
+    };
+    let (radiance, aov) = trace_pixel(
         id,
         config,
-        rng[index],
+        blue_noise[index],
+        &gbuffer[index],
+        reservoir,
         index_buffer,
         per_vertex_buffer,
         nodes_buffer,
+        parents_buffer,
+        instances,
+        materials,
+        lights,
+        punctual_lights,
+        sampler,
+        atlas,
+        env_map,
+        env_marginal_cdf,
+        env_conditional_cdf,
+    );
+
+    store_sample(
+        index,
+        config,
+        radiance,
+        aov,
+        output,
+        indirect_output,
+        albedo_output,
+        normal_output,
+        variance_output,
+        diagnostics,
+    );
+}
+
+// Whether the estimate of a pixel is within `adaptive_threshold` relative standard error, from
+// its sum of radiance with the sample count in `w` and its sum of squares
+fn converged(config: &TracingConfig, sum: Vec4, squares: Vec4) -> bool {
+    // the first sample starts the sums over, they are the last accumulation's before it
+    if config.sample_index < config.adaptive_min_samples.max(1) || sum.w <= 0.0 {
+        return false;
+    }
+    let count = sum.w;
+    let mean = sum.xyz() / count;
+    let variance = (squares.xyz() / count - mean * mean).max(Vec3::ZERO);
+    // of the mean, not of a single sample
+    let error = (shared::luminance(variance) / count).sqrt();
+    error < config.adaptive_threshold * shared::luminance(mean).max(ADAPTIVE_MIN_LUMINANCE)
+}
+
+// Sets or clears the bit of `pixel` in the mask of its block, see `shared::BLOCK_SIZE`. Every
+// pixel of a dispatched block writes its own bit, so the masks never need clearing.
+fn mark_converged(config: &TracingConfig, block_mask: &mut [u32], pixel: UVec2, converged: bool) {
+    let blocks_per_row = (config.width + BLOCK_SIZE - 1) / BLOCK_SIZE;
+    let block = (pixel.y / BLOCK_SIZE) * blocks_per_row + pixel.x / BLOCK_SIZE;
+    let local = (pixel.y % BLOCK_SIZE) * BLOCK_SIZE + pixel.x % BLOCK_SIZE;
+    let word = &mut block_mask[(block * BLOCK_WORDS + local / 32) as usize];
+    let bit = 1u32 << (local % 32);
+    unsafe {
+        if converged {
+            atomic_or::<_, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(word, bit);
+        } else {
+            atomic_and::<_, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(word, !bit);
+        }
+    }
+}
+
+// Adds the sample of pixel `index` to the sums
+fn store_sample(
+    index: usize,
+    config: &TracingConfig,
+    radiance: Radiance,
+    aov: Aov,
+    output: &mut [Vec4],
+    indirect_output: &mut [Vec4],
+    albedo_output: &mut [Vec4],
+    normal_output: &mut [Vec4],
+    variance_output: &mut [Vec4],
+    diagnostics: &mut [u32],
+) {
+    let sample = config.sample_index;
+    if radiance.nan_bounce != 0 {
+        // magenta sentinel, see `shared::DIAGNOSTICS_SIZE` for the counter layout
+        accumulate(&mut output[index], Vec4::new(1.0, 0.0, 1.0, 1.0), sample);
+        accumulate(&mut indirect_output[index], Vec4::W, sample);
+        unsafe {
+            atomic_i_increment::<_, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(
+                &mut diagnostics[0],
+            );
+            atomic_i_increment::<_, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(
+                &mut diagnostics[radiance.nan_bounce as usize],
+            );
+        }
+    } else {
+        // `output` holds the direct part
+        accumulate(&mut output[index], radiance.direct.extend(1.0), sample);
+        accumulate(&mut indirect_output[index], radiance.indirect.extend(1.0), sample);
+    }
+    // of the whole sample, the sentinel's squares don't count
+    let total =
+        if radiance.nan_bounce != 0 { Vec3::ZERO } else { radiance.direct + radiance.indirect };
+    accumulate(&mut variance_output[index], (total * total).extend(1.0), sample);
+    accumulate(&mut albedo_output[index], aov.albedo.extend(1.0), sample);
+    accumulate(&mut normal_output[index], aov.normal.extend(1.0), sample);
+}
+
+// Wavefront passes, see `wavefront`. They run over the paths of a tile rather than its pixels,
+// bindings 21 and up hold the paths and their queues.
+#[spirv(compute(threads(64, 1, 1)))]
+pub fn generate_cs(
+    #[spirv(global_invocation_id)] id: UVec3,
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] config: &TracingConfig,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 17)] blue_noise: &[Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 21)] paths: &mut [PathRecord],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 23)] queues: &mut [u32],
+) {
+    wavefront::generate(id.x, config, blue_noise, paths, queues);
+}
+
+#[spirv(compute(threads(64, 1, 1)))]
+pub fn intersect_cs(
+    #[spirv(global_invocation_id)] id: UVec3,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] index_buffer: &[UVec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] per_vertex_buffer: &[PerVertexData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] nodes_buffer: &[BVHNode],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] materials: &[MaterialData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 15)] parents_buffer: &[u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 16)] instances: &[InstanceData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 21)] paths: &[PathRecord],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 22)] hits: &mut [HitRecord],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 23)] queues: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 25)] counters: &mut [u32],
+) {
+    let bvh = BVHReference { nodes: nodes_buffer, parents: parents_buffer, instances };
+    wavefront::intersect(
+        id.x,
+        index_buffer,
+        per_vertex_buffer,
+        &bvh,
+        materials,
+        paths,
+        hits,
+        queues,
+        counters,
+    );
+}
+
+#[spirv(compute(threads(64, 1, 1)))]
+pub fn shade_cs(
+    #[spirv(global_invocation_id)] id: UVec3,
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] config: &TracingConfig,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] index_buffer: &[UVec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] per_vertex_buffer: &[PerVertexData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] nodes_buffer: &[BVHNode],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] materials: &[MaterialData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] lights: &[LightPick],
+    #[spirv(descriptor_set = 0, binding = 7)] sampler: &Sampler,
+    #[spirv(descriptor_set = 0, binding = 8)] atlas: &Image!(2D, type=f32, sampled),
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 9)] env_map: &[Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 10)] env_marginal_cdf: &[f32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 11)] env_conditional_cdf: &[f32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 15)] parents_buffer: &[u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 16)] instances: &[InstanceData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 19)] punctual_lights: &[PunctualLight],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 21)] paths: &mut [PathRecord],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 22)] hits: &[HitRecord],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 23)] queues: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 24)] shadow_rays: &mut [ShadowRay],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 25)] counters: &mut [u32],
+) {
+    let bvh = BVHReference { nodes: nodes_buffer, parents: parents_buffer, instances };
+    let env = EnvReference::new(config, env_map, env_marginal_cdf, env_conditional_cdf);
+    wavefront::shade(
+        id.x,
+        config,
+        index_buffer,
+        per_vertex_buffer,
         materials,
         lights,
+        punctual_lights,
         sampler,
         atlas,
+        &bvh,
+        &env,
+        paths,
+        hits,
+        queues,
+        shadow_rays,
+        counters,
     );
+}
+
+#[spirv(compute(threads(64, 1, 1)))]
+pub fn shadow_cs(
+    #[spirv(global_invocation_id)] id: UVec3,
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] config: &TracingConfig,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] index_buffer: &[UVec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] per_vertex_buffer: &[PerVertexData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] nodes_buffer: &[BVHNode],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 15)] parents_buffer: &[u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 16)] instances: &[InstanceData],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 21)] paths: &mut [PathRecord],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 24)] shadow_rays: &[ShadowRay],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 25)] counters: &[u32],
+) {
+    let bvh = BVHReference { nodes: nodes_buffer, parents: parents_buffer, instances };
+    wavefront::shadow(
+        id.x,
+        config,
+        index_buffer,
+        per_vertex_buffer,
+        &bvh,
+        paths,
+        shadow_rays,
+        counters,
+    );
+}
+
+// Ends the sample of every path of the tile, like the end of `main_cs`
+#[spirv(compute(threads(64, 1, 1)))]
+pub fn accumulate_cs(
+    #[spirv(global_invocation_id)] id: UVec3,
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] config: &TracingConfig,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] output: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 12)] albedo_output: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 13)] normal_output: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 14)] indirect_output: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 18)] diagnostics: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 21)] paths: &[PathRecord],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 28)] variance_output: &mut [Vec4],
+) {
+    if id.x >= wavefront::path_count(config) {
+        return;
+    }
+    let pixel = wavefront::pixel(config, id.x);
+    let (radiance, aov) = wavefront::finished(&paths[id.x as usize]);
+    store_sample(
+        (pixel.y * config.width + pixel.x) as usize,
+        config,
+        radiance,
+        aov,
+        output,
+        indirect_output,
+        albedo_output,
+        normal_output,
+        variance_output,
+        diagnostics,
+    );
+}
 
-    output[index] += pixel;
-    rng[index] = state;
+// Adds to the running sum, the first sample starts it over instead of the host clearing the
+// buffers on every reset
+fn accumulate(sum: &mut Vec4, value: Vec4, sample_index: u32) {
+    *sum = if sample_index == 0 { value } else { *sum + value };
 }
@@ -1,14 +1,21 @@
 #[allow(dead_code)]
-use spirv_std::num_traits::Float;
+use spirv_std::num_traits::{Float, FloatConst};
 use {
     crate::{
-        bsdf::{BSDFSample, Lobe, BSDF},
+        bsdf::{self, BSDFSample, Lobe, BSDF},
+        env::EnvReference,
         inter::{BVHReference, Trace},
         rng::RngState,
-        util,
+        skybox, texture, util,
+    },
+    shared::{
+        LightPick, MaterialData, PerVertexData, PunctualLight, PunctualLightKind, Reservoir,
+        Sampler, TracingConfig,
+    },
+    spirv_std::{
+        glam::{UVec4, Vec3, Vec4Swizzles},
+        Image,
     },
-    shared::{LightPick, MaterialData, PerVertexData},
-    spirv_std::glam::{UVec4, Vec3, Vec4Swizzles},
 };
 
 pub fn pdf(area: f32, len: f32, norm: Vec3, dir: Vec3) -> f32 {
@@ -23,21 +30,35 @@ fn reflect(i: Vec3, n: Vec3) -> Vec3 {
     i - 2.0 * n.dot(i) * n
 }
 
-pub fn pick_light(table: &[LightPick], rng_state: &mut RngState) -> (u32, f32, f32) {
+// Returns (instance, triangle, world space area, pick pdf)
+pub fn pick_light(table: &[LightPick], rng_state: &mut RngState) -> (u32, u32, f32, f32) {
     let rng = rng_state.gen_r2();
-    let entry = table[(rng.x * table.len() as f32) as usize];
+    // rng.x can round up to 1.0, which would index one past the end
+    let bin = ((rng.x * table.len() as f32) as usize).min(table.len() - 1);
+    let entry = table[bin];
     if rng.y < entry.ratio {
-        (entry.triangle_index_a, entry.triangle_area_a, entry.triangle_pick_pdf_a)
+        (
+            entry.instance_index_a,
+            entry.triangle_index_a,
+            entry.triangle_area_a,
+            entry.triangle_pick_pdf_a,
+        )
     } else {
-        (entry.triangle_index_b, entry.triangle_area_b, entry.triangle_pick_pdf_b)
+        (
+            entry.instance_index_b,
+            entry.triangle_index_b,
+            entry.triangle_area_b,
+            entry.triangle_pick_pdf_b,
+        )
     }
 }
 
 // https://www.cs.princeton.edu/~funk/tog02.pdf equation 1
-pub fn pick_triangle_point(a: Vec3, b: Vec3, c: Vec3, rng_state: &mut RngState) -> Vec3 {
+// Returns barycentric weights of a uniformly distributed point
+pub fn pick_triangle_point(rng_state: &mut RngState) -> Vec3 {
     let rng = rng_state.gen_r2();
     let r1_sqrt = rng.x.sqrt();
-    (1.0 - r1_sqrt) * a + (r1_sqrt * (1.0 - rng.y)) * b + (r1_sqrt * rng.y) * c
+    Vec3::new(1.0 - r1_sqrt, r1_sqrt * (1.0 - rng.y), r1_sqrt * rng.y)
 }
 
 pub fn calculate_light_pdf(
@@ -53,85 +74,476 @@ pub fn calculate_light_pdf(
     light_distance.powi(2) / (light_area * cos_theta)
 }
 
+// Shadow rays toward sampled lights. `Shadows` traces them on the spot, wavefront shading
+// queues them for a pass of their own and counts them as unoccluded until then.
+pub trait Occlusion {
+    fn visible(&mut self, origin: Vec3, direction: Vec3, max_t: f32) -> bool;
+
+    // Takes over the contribution of the light sample whose shadow ray is still pending,
+    // false when the path should add it right away
+    fn defer(&mut self, contribution: Vec3) -> bool;
+}
+
+pub struct Shadows<'a> {
+    pub bvh: &'a BVHReference<'a>,
+    pub indices: &'a [UVec4],
+    pub per_vertex: &'a [PerVertexData],
+}
+
+impl Occlusion for Shadows<'_> {
+    fn visible(&mut self, origin: Vec3, direction: Vec3, max_t: f32) -> bool {
+        !self.bvh.intersect_any(self.per_vertex, self.indices, origin, direction, max_t).hit
+    }
+
+    fn defer(&mut self, _contribution: Vec3) -> bool {
+        false
+    }
+}
+
 #[derive(Default, Copy, Clone)]
 pub struct LightSample {
     pub area: f32,
     pub normal: Vec3,
     pub pick_pdf: f32,
-    pub emission: Vec3,
-    pub triangle_idx: u32,
+    pub instance: u32,
+    pub triangle: UVec4,
     pub throughput: Vec3,
     pub contribution: Vec3,
 }
 
+fn sample_env_lighting(
+    occlusion: &mut impl Occlusion,
+    env: &EnvReference,
+    pick_pdf: f32,
+    throughput: Vec3,
+    surface_bsdf: &impl BSDF,
+    lobe: Lobe,
+    surface_point: Vec3,
+    surface_normal: Vec3,
+    ray_direction: Vec3,
+    rng_state: &mut RngState,
+) -> LightSample {
+    let (light_direction, env_pdf) = env.sample(rng_state.gen_r2());
+    let light_pdf = env_pdf * pick_pdf;
+
+    let mut direct = Vec3::ZERO;
+    if light_pdf > 0.0 {
+        let origin = surface_point + light_direction * util::EPS;
+        if occlusion.visible(origin, light_direction, f32::MAX) {
+            let bsdf_attenuation =
+                surface_bsdf.evaluate(-ray_direction, surface_normal, light_direction, lobe);
+            let bsdf_pdf = surface_bsdf.pdf(-ray_direction, surface_normal, light_direction, lobe);
+            if bsdf_pdf > 0.0 {
+                let weight = get_weight(light_pdf, bsdf_pdf);
+                direct = bsdf_attenuation * env.lookup(light_direction) * weight / light_pdf;
+            }
+        }
+    }
+
+    LightSample {
+        pick_pdf,
+        // never matches a triangle, BSDF samples that escape are weighted on miss instead
+        triangle: UVec4::MAX,
+        throughput,
+        contribution: throughput * direct,
+        ..Default::default()
+    }
+}
+
+// How often the sun is light sampled instead of emissive triangles. Only the procedural sky
+// has one, and it is dark at night.
+pub fn sun_pick_pdf(config: &TracingConfig, lights: &[LightPick]) -> f32 {
+    if config.has_env_map() || config.sun_angular_radius <= 0.0 || config.sun_direction.y <= 0.0 {
+        0.0
+    } else if lights[0].is_sentinel() && config.punctual_light_count == 0 {
+        1.0
+    } else {
+        0.5
+    }
+}
+
+// How often punctual lights are sampled instead of emissive triangles, once the environment
+// and the sun weren't picked
+fn punctual_pick_pdf(config: &TracingConfig, lights: &[LightPick]) -> f32 {
+    if config.punctual_light_count == 0 {
+        0.0
+    } else if lights[0].is_sentinel() {
+        1.0
+    } else {
+        0.5
+    }
+}
+
+// KHR_lights_punctual's recommended falloff, smoothly reaching zero at `range`
+fn range_attenuation(range: f32, distance: f32) -> f32 {
+    if range <= 0.0 {
+        return 1.0;
+    }
+    (1.0 - (distance / range).powi(4)).clamp(0.0, 1.0)
+}
+
+// Delta lights have no BSDF sampling counterpart, so their samples are not MIS weighted
+fn sample_punctual_lighting(
+    config: &TracingConfig,
+    occlusion: &mut impl Occlusion,
+    punctual_lights: &[PunctualLight],
+    pick_pdf: f32,
+    throughput: Vec3,
+    surface_bsdf: &impl BSDF,
+    lobe: Lobe,
+    surface_point: Vec3,
+    surface_normal: Vec3,
+    ray_direction: Vec3,
+    rng_state: &mut RngState,
+) -> LightSample {
+    let count = config.punctual_light_count;
+    let index = ((rng_state.gen_r1() * count as f32) as u32).min(count - 1);
+    let light = punctual_lights[index as usize];
+    let pick_pdf = pick_pdf / count as f32;
+
+    let (light_direction, light_distance, irradiance) = match light.kind() {
+        PunctualLightKind::Directional => {
+            (-light.direction.xyz().normalize(), f32::MAX, light.intensity())
+        }
+        kind => {
+            let to_light = light.position.xyz() - surface_point;
+            let distance = to_light.length();
+            let direction = to_light / distance;
+            let mut falloff = range_attenuation(light.range, distance) / (distance * distance);
+            if kind == PunctualLightKind::Spot {
+                let cos_angle = light.direction.xyz().normalize().dot(-direction);
+                let cone = (cos_angle - light.cos_outer_cone)
+                    / (light.cos_inner_cone - light.cos_outer_cone).max(util::EPS);
+                let cone = cone.clamp(0.0, 1.0);
+                falloff *= cone * cone;
+            }
+            (direction, distance, light.intensity() * falloff)
+        }
+    };
+
+    let mut direct = Vec3::ZERO;
+    if irradiance != Vec3::ZERO {
+        let origin = surface_point + light_direction * util::EPS;
+        if occlusion.visible(origin, light_direction, light_distance - util::EPS * 2.0) {
+            let bsdf_attenuation =
+                surface_bsdf.evaluate(-ray_direction, surface_normal, light_direction, lobe);
+            let transmittance = fog_transmittance(config, light_distance);
+            direct = bsdf_attenuation * irradiance * transmittance / pick_pdf;
+        }
+    }
+
+    LightSample {
+        pick_pdf,
+        // never matches a triangle, BSDF samples can't hit a delta light
+        triangle: UVec4::MAX,
+        throughput,
+        contribution: throughput * direct,
+        ..Default::default()
+    }
+}
+
+fn sample_sun_lighting(
+    config: &TracingConfig,
+    occlusion: &mut impl Occlusion,
+    pick_pdf: f32,
+    throughput: Vec3,
+    surface_bsdf: &impl BSDF,
+    lobe: Lobe,
+    surface_point: Vec3,
+    surface_normal: Vec3,
+    ray_direction: Vec3,
+    rng_state: &mut RngState,
+) -> LightSample {
+    // uniform over the cone of the disc
+    let solid_angle = skybox::sun_solid_angle(config);
+    let rng = rng_state.gen_r2();
+    let one_minus_cos = rng.x * solid_angle / (2.0 * f32::PI());
+    let cos_theta = 1.0 - one_minus_cos;
+    let sin_theta = (one_minus_cos * (2.0 - one_minus_cos)).max(0.0).sqrt();
+    let phi = 2.0 * f32::PI() * rng.y;
+    let (up, right, forward) = util::create_cartesian(config.sun_direction.xyz().normalize());
+    let light_direction =
+        (up * cos_theta + (right * phi.cos() + forward * phi.sin()) * sin_theta).normalize();
+    let light_pdf = pick_pdf / solid_angle;
+
+    let mut direct = Vec3::ZERO;
+    let origin = surface_point + light_direction * util::EPS;
+    if occlusion.visible(origin, light_direction, f32::MAX) {
+        let bsdf_attenuation =
+            surface_bsdf.evaluate(-ray_direction, surface_normal, light_direction, lobe);
+        let bsdf_pdf = surface_bsdf.pdf(-ray_direction, surface_normal, light_direction, lobe);
+        if bsdf_pdf > 0.0 {
+            let weight = get_weight(light_pdf, bsdf_pdf);
+            let sun = skybox::sun_disc(config, surface_point, light_direction);
+            direct = bsdf_attenuation * sun * weight / light_pdf;
+        }
+    }
+
+    LightSample {
+        pick_pdf,
+        // never matches a triangle, BSDF samples that escape are weighted on miss instead
+        triangle: UVec4::MAX,
+        throughput,
+        contribution: throughput * direct,
+        ..Default::default()
+    }
+}
+
+// A point on an emissive triangle, picked from the light table and uniformly over the triangle
+pub struct TriangleLight {
+    pub instance: u32,
+    pub triangle: UVec4,
+    // world space
+    pub area: f32,
+    pub pick_pdf: f32,
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub emission: Vec3,
+    pub double_sided: bool,
+}
+
+impl TriangleLight {
+    // The normal seen along `direction` toward the light, double-sided emitters face whichever
+    // side it comes from
+    pub fn facing(&self, direction: Vec3) -> Vec3 {
+        facing(self.normal, self.double_sided, direction)
+    }
+}
+
+pub fn facing(normal: Vec3, double_sided: bool, direction: Vec3) -> Vec3 {
+    if double_sided && normal.dot(direction) > 0.0 {
+        -normal
+    } else {
+        normal
+    }
+}
+
+// There has to be an emissive triangle, the first entry of `lights` isn't a sentinel
+pub fn sample_triangle_light(
+    config: &TracingConfig,
+    indices: &[UVec4],
+    per_vertex: &[PerVertexData],
+    materials: &[MaterialData],
+    lights: &[LightPick],
+    sampler: &Sampler,
+    atlas: &Image!(2D, type=f32, sampled),
+    bvh: &BVHReference,
+    rng_state: &mut RngState,
+) -> TriangleLight {
+    // Pick a light, get its surface properties
+    let (instance, light_index, area, pick_pdf) = pick_light(lights, rng_state);
+    let instance_data = bvh.instances[instance as usize];
+    let triangle = indices[light_index as usize];
+    let vert_a = instance_data.point_to_world(per_vertex[triangle.x as usize].vertex.xyz());
+    let vert_b = instance_data.point_to_world(per_vertex[triangle.y as usize].vertex.xyz());
+    let vert_c = instance_data.point_to_world(per_vertex[triangle.z as usize].vertex.xyz());
+    let norm_a = per_vertex[triangle.x as usize].normal.xyz();
+    let norm_b = per_vertex[triangle.y as usize].normal.xyz();
+    let norm_c = per_vertex[triangle.z as usize].normal.xyz();
+    let normal = instance_data.normal_to_world(norm_a + norm_b + norm_c); // lights can use flat shading, no need to pay for interpolation
+    let light_material =
+        materials[instance_data.material_index(triangle, materials.len()) as usize];
+
+    // Pick a point on the light
+    let bary = pick_triangle_point(rng_state);
+    let point = bary.x * vert_a + bary.y * vert_b + bary.z * vert_c;
+    let uv_a = per_vertex[triangle.x as usize].uv0;
+    let uv_b = per_vertex[triangle.y as usize].uv0;
+    let uv_c = per_vertex[triangle.z as usize].uv0;
+    let light_uv = bary.x * uv_a + bary.y * uv_b + bary.z * uv_c;
+    let emission =
+        bsdf::get_emission(config, &light_material, light_uv, texture::FINEST_LOD, atlas, sampler);
+
+    TriangleLight {
+        instance,
+        triangle,
+        area,
+        pick_pdf,
+        point,
+        normal,
+        emission,
+        double_sided: light_material.double_sided(),
+    }
+}
+
+// The point a ReSTIR reservoir picked for the camera hit, see `restir`. Its weight already
+// stands in for the pdf over all emissive triangles, so it isn't MIS weighted and BSDF samples
+// hitting an emitter add nothing.
+fn sample_reservoir_lighting(
+    config: &TracingConfig,
+    occlusion: &mut impl Occlusion,
+    reservoir: &Reservoir,
+    pick_pdf: f32,
+    throughput: Vec3,
+    surface_bsdf: &impl BSDF,
+    lobe: Lobe,
+    surface_point: Vec3,
+    surface_normal: Vec3,
+    ray_direction: Vec3,
+) -> LightSample {
+    let light_direction_unorm = reservoir.point.xyz() - surface_point;
+    let light_distance = light_direction_unorm.length();
+    let light_direction = light_direction_unorm / light_distance;
+    let normal = facing(reservoir.normal.xyz(), reservoir.double_sided(), light_direction);
+    let cos_light = normal.dot(-light_direction);
+
+    let mut direct = Vec3::ZERO;
+    if reservoir.weight > 0.0 && cos_light > 0.0 {
+        let origin = surface_point + light_direction * util::EPS;
+        if occlusion.visible(origin, light_direction, light_distance - util::EPS * 2.0) {
+            let bsdf_attenuation =
+                surface_bsdf.evaluate(-ray_direction, surface_normal, light_direction, lobe);
+            let transmittance = fog_transmittance(config, light_distance);
+            // from the area of the light to the solid angle at the surface
+            let geometry = cos_light / (light_distance * light_distance);
+            direct = bsdf_attenuation
+                * reservoir.emission.xyz()
+                * transmittance
+                * geometry
+                * reservoir.weight
+                / pick_pdf;
+        }
+    }
+
+    LightSample {
+        pick_pdf,
+        // never matches a triangle, the reservoir already covers what BSDF samples would hit
+        triangle: UVec4::MAX,
+        throughput,
+        contribution: throughput * direct,
+        ..Default::default()
+    }
+}
+
+// Light samples are evaluated and MIS weighted against `lobe`, the lobe the BSDF sample picked.
+// `reservoir` replaces the pick among emissive triangles at the camera hit when ReSTIR is on.
 pub fn sample_direct_lighting(
+    config: &TracingConfig,
     indices: &[UVec4],
     per_vertex: &[PerVertexData],
     materials: &[MaterialData],
     lights: &[LightPick],
+    punctual_lights: &[PunctualLight],
+    sampler: &Sampler,
+    atlas: &Image!(2D, type=f32, sampled),
     bvh: &BVHReference,
+    env: &EnvReference,
+    occlusion: &mut impl Occlusion,
+    reservoir: Option<Reservoir>,
     throughput: Vec3,
     surface_bsdf: &impl BSDF,
+    lobe: Lobe,
     surface_point: Vec3,
     surface_normal: Vec3,
     ray_direction: Vec3,
     rng_state: &mut RngState,
 ) -> LightSample {
+    // Choose between the environment and emissive triangles
+    let env_pick_pdf = if config.has_env_map() { config.env_pick_pdf } else { 0.0 };
+    if env_pick_pdf > 0.0 && rng_state.gen_r1() < env_pick_pdf {
+        let mut light_sample = sample_env_lighting(
+            occlusion,
+            env,
+            env_pick_pdf,
+            throughput,
+            surface_bsdf,
+            lobe,
+            surface_point,
+            surface_normal,
+            ray_direction,
+            rng_state,
+        );
+        light_sample.contribution *= fog_transmittance(config, f32::MAX);
+        return light_sample;
+    }
+
+    // Then between the sun of the procedural sky and emissive triangles
+    let sun_pick_pdf = sun_pick_pdf(config, lights);
+    if sun_pick_pdf > 0.0 && rng_state.gen_r1() < sun_pick_pdf {
+        let mut light_sample = sample_sun_lighting(
+            config,
+            occlusion,
+            sun_pick_pdf,
+            throughput,
+            surface_bsdf,
+            lobe,
+            surface_point,
+            surface_normal,
+            ray_direction,
+            rng_state,
+        );
+        light_sample.contribution *= fog_transmittance(config, f32::MAX);
+        return light_sample;
+    }
+
+    // Then between punctual lights and emissive triangles
+    let punctual_pick_pdf = punctual_pick_pdf(config, lights);
+    if punctual_pick_pdf > 0.0 && rng_state.gen_r1() < punctual_pick_pdf {
+        return sample_punctual_lighting(
+            config,
+            occlusion,
+            punctual_lights,
+            punctual_pick_pdf * (1.0 - env_pick_pdf) * (1.0 - sun_pick_pdf),
+            throughput,
+            surface_bsdf,
+            lobe,
+            surface_point,
+            surface_normal,
+            ray_direction,
+            rng_state,
+        );
+    }
+
     // If the first entry is a sentinel, there are no lights
     if lights[0].is_sentinel() {
         return LightSample::default();
     }
 
-    // Pick a light, get its surface properties
-    let (light_index, area, pick_pdf) = pick_light(&lights, rng_state);
-    let triangle = indices[light_index as usize];
-    let vert_a = per_vertex[triangle.x as usize].vertex.xyz();
-    let vert_b = per_vertex[triangle.y as usize].vertex.xyz();
-    let vert_c = per_vertex[triangle.z as usize].vertex.xyz();
-    let norm_a = per_vertex[triangle.x as usize].normal.xyz();
-    let norm_b = per_vertex[triangle.y as usize].normal.xyz();
-    let norm_c = per_vertex[triangle.z as usize].normal.xyz();
-    let normal = (norm_a + norm_b + norm_c) / 3.0; // lights can use flat shading, no need to pay for interpolation
-    let light_material = materials[triangle.w as usize];
-    let emission = light_material.emissive.xyz();
+    // at most one of the environment, the sun and the punctual lights can be picked
+    let branch_pdf = (1.0 - env_pick_pdf) * (1.0 - sun_pick_pdf) * (1.0 - punctual_pick_pdf);
+    if let Some(reservoir) = reservoir {
+        return sample_reservoir_lighting(
+            config,
+            occlusion,
+            &reservoir,
+            branch_pdf,
+            throughput,
+            surface_bsdf,
+            lobe,
+            surface_point,
+            surface_normal,
+            ray_direction,
+        );
+    }
 
-    // Pick a point on the light
-    let light_point = pick_triangle_point(vert_a, vert_b, vert_c, rng_state);
+    let light = sample_triangle_light(
+        config, indices, per_vertex, materials, lights, sampler, atlas, bvh, rng_state,
+    );
+    let TriangleLight { instance, triangle, area, point: light_point, emission, .. } = light;
+    let pick_pdf = light.pick_pdf * branch_pdf;
     let light_direction_unorm = light_point - surface_point;
     let light_distance = light_direction_unorm.length();
     let light_direction = light_direction_unorm / light_distance;
+    let normal = light.facing(light_direction);
 
     // Sample the light directly using MIS
     let mut direct = Vec3::ZERO;
-    let light_trace = bvh.intersect_any(
-        per_vertex,
-        indices,
-        surface_point + light_direction * util::EPS,
-        light_direction,
-        light_distance - util::EPS * 2.0,
-    );
-    if !light_trace.hit {
+    let origin = surface_point + light_direction * util::EPS;
+    if occlusion.visible(origin, light_direction, light_distance - util::EPS * 2.0) {
         // Calculate light pdf for this sample
         let light_pdf = calculate_light_pdf(area, light_distance, normal, light_direction);
         if light_pdf > 0.0 {
             // Calculate BSDF attenuation for this sample
-            let bsdf_attenuation = surface_bsdf.evaluate(
-                -ray_direction,
-                surface_normal,
-                light_direction,
-                Lobe::DiffuseReflection,
-            );
+            let bsdf_attenuation =
+                surface_bsdf.evaluate(-ray_direction, surface_normal, light_direction, lobe);
             // Calculate BSDF pdf for this sample
-            let bsdf_pdf = surface_bsdf.pdf(
-                -ray_direction,
-                surface_normal,
-                light_direction,
-                Lobe::DiffuseReflection,
-            );
+            let bsdf_pdf = surface_bsdf.pdf(-ray_direction, surface_normal, light_direction, lobe);
             if bsdf_pdf > 0.0 {
                 // MIS - add the weighted sample
                 let weight = get_weight(light_pdf, bsdf_pdf);
-                direct = (bsdf_attenuation * emission * weight / light_pdf) / pick_pdf;
+                let transmittance = fog_transmittance(config, light_distance);
+                direct =
+                    (bsdf_attenuation * emission * transmittance * weight / light_pdf) / pick_pdf;
             }
         }
     }
@@ -140,13 +552,23 @@ pub fn sample_direct_lighting(
         area,
         normal,
         pick_pdf,
-        emission,
-        triangle_idx: light_index,
+        instance,
+        triangle,
         throughput,
         contribution: throughput * direct,
     }
 }
 
+// Fraction of light surviving `distance` through the fog. Paths sampled by the BSDF get it
+// implicitly from free-flight sampling, so MIS weights stay as they are.
+pub fn fog_transmittance(config: &TracingConfig, distance: f32) -> f32 {
+    if config.sigma_t > 0.0 {
+        (-config.sigma_t * distance).exp()
+    } else {
+        1.0
+    }
+}
+
 pub fn get_weight(p1: f32, p2: f32) -> f32 {
     util::power_heuristic(p1, p2)
 }
@@ -155,9 +577,11 @@ pub fn calculate_bsdf_mis_contribution(
     trace: &Trace,
     bsdf_sample: &BSDFSample,
     light_sample: &LightSample,
+    emission: Vec3,
 ) -> Vec3 {
-    // If we haven't hit the same light as we sampled directly, no contribution
-    if trace.triangle_index != light_sample.triangle_idx {
+    // If we haven't hit the same light as we sampled directly, no contribution.
+    // Compare the triangles themselves, spatial splits can reference one from several slots.
+    if trace.instance != light_sample.instance || trace.triangle != light_sample.triangle {
         return Vec3::ZERO;
     }
 
@@ -171,8 +595,8 @@ pub fn calculate_bsdf_mis_contribution(
     if light_pdf > 0.0 {
         // MIS - add the weighted sample
         let weight = get_weight(bsdf_sample.pdf, light_pdf);
-        let direct = (bsdf_sample.spectrum * light_sample.emission * weight / bsdf_sample.pdf)
-            / light_sample.pick_pdf;
+        let direct =
+            (bsdf_sample.spectrum * emission * weight / bsdf_sample.pdf) / light_sample.pick_pdf;
         light_sample.throughput * direct
     } else {
         Vec3::ZERO
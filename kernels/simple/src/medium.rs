@@ -0,0 +1,80 @@
+use {
+    crate::{bsdf, vec::FixedVec},
+    shared::{MaterialData, MEDIUM_STACK_SIZE},
+};
+
+// Dielectrics the path is currently inside of, following "Simple Nested Dielectrics in Ray
+// Traced Images" (Schmidt, Budge). Overlapping volumes belong to the highest priority
+// material, the surfaces of lower priority ones inside it are false interfaces and skipped.
+pub struct MediumStack {
+    materials: FixedVec<u32, MEDIUM_STACK_SIZE>,
+}
+
+impl MediumStack {
+    pub fn new() -> Self {
+        Self { materials: FixedVec::new() }
+    }
+
+    // The entered materials and how many of them there are, for `shared::PathRecord`
+    pub fn entries(&self) -> ([u32; MEDIUM_STACK_SIZE], u32) {
+        (self.materials.data, self.materials.len)
+    }
+
+    pub fn from_entries(data: [u32; MEDIUM_STACK_SIZE], len: u32) -> Self {
+        Self { materials: FixedVec { data, len } }
+    }
+
+    // Highest priority among the entered materials other than `material`, later entries win ties
+    fn outer(&self, materials: &[MaterialData], material: u32) -> Option<u32> {
+        let mut outer = None;
+        let mut priority = 0;
+        let mut i = 0;
+        while i < self.materials.len {
+            let entry = self.materials[i];
+            let entry_priority = materials[entry as usize].priority;
+            if entry != material && (outer.is_none() || entry_priority >= priority) {
+                outer = Some(entry);
+                priority = entry_priority;
+            }
+            i += 1;
+        }
+        outer
+    }
+
+    // Whether the surface of `material` lies inside a higher priority medium
+    pub fn is_false_interface(&self, materials: &[MaterialData], material: u32) -> bool {
+        match self.outer(materials, material) {
+            Some(outer) => {
+                materials[material as usize].priority < materials[outer as usize].priority
+            }
+            None => false,
+        }
+    }
+
+    // IOR on the other side of a surface of `material`, the medium the path comes from when
+    // entering and the one it goes into when exiting
+    pub fn outer_ior(&self, materials: &[MaterialData], material: u32, channel: u32) -> f32 {
+        match self.outer(materials, material) {
+            Some(outer) => bsdf::get_ior(&materials[outer as usize], channel),
+            None => 1.0,
+        }
+    }
+
+    // Update the stack after passing through a surface of `material`
+    pub fn cross(&mut self, material: u32, exiting: bool) {
+        if !exiting {
+            let _ = self.materials.push(material);
+            return;
+        }
+
+        // remove the latest entry, paths starting inside a volume never entered it
+        let mut i = self.materials.len;
+        while i > 0 {
+            i -= 1;
+            if self.materials[i] == material {
+                self.materials.remove(i);
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,206 @@
+// ReSTIR DI for the camera hits, see `shared::Reservoir`. The initial pass resamples
+// `CANDIDATES` emissive triangle points per pixel and merges them with the pixel's reservoir of
+// the previous sample, the spatial pass then merges a few neighbours' reservoirs into it. The
+// target function is the unshadowed light reaching the hit without the BSDF, so reuse needs no
+// rays, and `merged` only counts the reservoirs that could have picked the sample. Shading
+// traces the shadow ray of the final pick, see `light::sample_direct_lighting`.
+use {
+    crate::{
+        inter::BVHReference,
+        light::{self, TriangleLight},
+        rng::RngState,
+        util,
+    },
+    shared::{
+        GBufferTexel, LightPick, MaterialData, PerVertexData, Reservoir, Sampler, TracingConfig,
+    },
+    spirv_std::{
+        glam::{IVec2, UVec2, UVec4, Vec3, Vec4Swizzles},
+        Image,
+    },
+};
+
+// emissive triangle points resampled per pixel and sample
+const CANDIDATES: u32 = 8;
+// The history stands for at most this many samples' candidates, so it keeps adapting and a
+// sample that was picked once isn't kept forever
+const MAX_HISTORY: f32 = 20.0 * CANDIDATES as f32;
+const NEIGHBOURS: u32 = 5;
+// in pixels
+const RADIUS: f32 = 30.0;
+// neighbours on another surface than the pixel's are skipped
+const MIN_NORMAL_COS: f32 = 0.9;
+const MAX_DEPTH_RATIO: f32 = 0.1;
+// Draws start this far into the pixel's sequence, away from the dimensions paths use
+const INITIAL_DIMENSION: u32 = 1 << 16;
+const SPATIAL_DIMENSION: u32 = 2 << 16;
+
+// The unshadowed luminance `reservoir`'s point sends toward `position`, per area of the light
+fn target(reservoir: &Reservoir, position: Vec3, normal: Vec3) -> f32 {
+    let to_light = reservoir.point.xyz() - position;
+    let distance_squared = to_light.length_squared();
+    if distance_squared <= 0.0 {
+        return 0.0;
+    }
+    let direction = to_light / distance_squared.sqrt();
+    let light_normal = light::facing(reservoir.normal.xyz(), reservoir.double_sided(), direction);
+    let cos_light = light_normal.dot(-direction);
+    if cos_light <= 0.0 {
+        return 0.0;
+    }
+    // either side, transmission sees lights behind the surface
+    let cos_surface = normal.dot(direction).abs();
+    shared::luminance(reservoir.emission.xyz()) * cos_surface * cos_light / distance_squared
+}
+
+// Weighted reservoir sampling: takes over `candidate`'s point with probability `weight` over
+// the weights so far
+fn stream(reservoir: &mut Reservoir, candidate: &Reservoir, weight: f32, count: f32, r: f32) {
+    reservoir.weight_sum += weight;
+    reservoir.count += count;
+    if weight > 0.0 && r * reservoir.weight_sum < weight {
+        reservoir.point = candidate.point;
+        reservoir.normal = candidate.normal;
+        reservoir.emission = candidate.emission;
+        reservoir.set_double_sided(candidate.double_sided());
+    }
+}
+
+// Streams in another reservoir, weighed by the target at the pixel's hit
+fn merge(reservoir: &mut Reservoir, other: &Reservoir, position: Vec3, normal: Vec3, r: f32) {
+    let weight = target(other, position, normal) * other.weight * other.count;
+    stream(reservoir, other, weight, other.count, r);
+}
+
+// The contribution weight over `count` candidates, zero when the point sends nothing
+fn finish(reservoir: &mut Reservoir, count: f32, position: Vec3, normal: Vec3) {
+    let target = target(reservoir, position, normal);
+    reservoir.weight =
+        if target > 0.0 && count > 0.0 { reservoir.weight_sum / (count * target) } else { 0.0 };
+}
+
+fn candidate(light: &TriangleLight) -> Reservoir {
+    let mut candidate = Reservoir::default();
+    candidate.point = light.point.extend(1.0);
+    candidate.normal = light.normal.extend(0.0);
+    candidate.emission = light.emission.extend(0.0);
+    candidate.set_double_sided(light.double_sided);
+    candidate
+}
+
+// The reservoir of the camera hit `texel` of `pixel`, merged with `previous` of the pixel's
+// last sample unless the accumulation just started
+pub fn initial(
+    pixel: UVec2,
+    config: &TracingConfig,
+    texel: &GBufferTexel,
+    previous: &Reservoir,
+    indices: &[UVec4],
+    per_vertex: &[PerVertexData],
+    materials: &[MaterialData],
+    lights: &[LightPick],
+    sampler: &Sampler,
+    atlas: &Image!(2D, type=f32, sampled),
+    bvh: &BVHReference,
+) -> Reservoir {
+    let mut reservoir = Reservoir::default();
+    // emitters aren't lit, the path ends on them
+    if !texel.hit()
+        || lights[0].is_sentinel()
+        || materials[texel.material_index as usize].emission() != Vec3::ZERO
+    {
+        return reservoir;
+    }
+    let (position, normal) = (texel.position.xyz(), texel.normal.xyz());
+    let mut rng_state = RngState::resume(pixel, config.sample_index, INITIAL_DIMENSION, None);
+
+    for _ in 0..CANDIDATES {
+        let light = light::sample_triangle_light(
+            config,
+            indices,
+            per_vertex,
+            materials,
+            lights,
+            sampler,
+            atlas,
+            bvh,
+            &mut rng_state,
+        );
+        let candidate = candidate(&light);
+        // pdf over the area of all emissive triangles
+        let pdf = light.pick_pdf / light.area;
+        let weight = if pdf > 0.0 { target(&candidate, position, normal) / pdf } else { 0.0 };
+        stream(&mut reservoir, &candidate, weight, 1.0, rng_state.gen_r1());
+    }
+    finish(&mut reservoir, CANDIDATES as f32, position, normal);
+    if config.sample_index == 0 {
+        return reservoir;
+    }
+
+    // the camera holds still during an accumulation, so the history is the same pixel's
+    let mut history = *previous;
+    history.count = history.count.min(MAX_HISTORY);
+    let mut merged = Reservoir::default();
+    merge(&mut merged, &reservoir, position, normal, rng_state.gen_r1());
+    merge(&mut merged, &history, position, normal, rng_state.gen_r1());
+    let count = merged.count;
+    finish(&mut merged, count, position, normal);
+    merged
+}
+
+// Merges the reservoirs of up to `NEIGHBOURS` pixels around `pixel` on the same surface into
+// its own. Only those whose hit the picked point could light count toward its weight.
+pub fn spatial(
+    pixel: UVec2,
+    config: &TracingConfig,
+    gbuffer: &[GBufferTexel],
+    reservoirs: &[Reservoir],
+) -> Reservoir {
+    let index = (pixel.y * config.width + pixel.x) as usize;
+    let texel = gbuffer[index];
+    let own = reservoirs[index];
+    if !texel.hit() || own.count <= 0.0 {
+        return own;
+    }
+    let (position, normal) = (texel.position.xyz(), texel.normal.xyz());
+    let mut rng_state = RngState::resume(pixel, config.sample_index, SPATIAL_DIMENSION, None);
+
+    let mut merged = Reservoir::default();
+    merge(&mut merged, &own, position, normal, rng_state.gen_r1());
+    // the pixels merged besides the pixel itself
+    let mut neighbours = [0usize; NEIGHBOURS as usize];
+    let mut found = 0;
+    for _ in 0..NEIGHBOURS {
+        let rng = rng_state.gen_r2();
+        let offset = (util::concentric_disk(rng.x, rng.y) * RADIUS).as_ivec2();
+        let size = IVec2::new(config.width as i32, config.height as i32);
+        let neighbour = (pixel.as_ivec2() + offset).clamp(IVec2::ZERO, size - 1).as_uvec2();
+        let neighbour_index = (neighbour.y * config.width + neighbour.x) as usize;
+        let other = gbuffer[neighbour_index];
+        if neighbour_index == index
+            || !other.hit()
+            || other.normal.xyz().dot(normal) < MIN_NORMAL_COS
+            || (other.position.w - texel.position.w).abs() > MAX_DEPTH_RATIO * texel.position.w
+        {
+            continue;
+        }
+        merge(&mut merged, &reservoirs[neighbour_index], position, normal, rng_state.gen_r1());
+        neighbours[found] = neighbour_index;
+        found += 1;
+    }
+
+    // Pixels that couldn't have picked the point don't count, with plain 1 / M weights lights
+    // facing away from some neighbours would come out darker
+    let mut count = 0.0;
+    if target(&merged, position, normal) > 0.0 {
+        count += own.count;
+    }
+    for i in 0..found {
+        let other = gbuffer[neighbours[i]];
+        if target(&merged, other.position.xyz(), other.normal.xyz()) > 0.0 {
+            count += reservoirs[neighbours[i]].count;
+        }
+    }
+    finish(&mut merged, count, position, normal);
+    merged
+}
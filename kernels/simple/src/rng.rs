@@ -1,4 +1,4 @@
-use spirv_std::glam::{UVec2, Vec2, Vec3};
+use spirv_std::glam::{UVec2, UVec4, Vec2, Vec3, Vec4};
 
 #[allow(dead_code)]
 #[cfg(target_arch = "spirv")]
@@ -16,73 +16,314 @@ pub fn pcg_hash(input: u32) -> u32 {
     (word >> 22u32) ^ word
 }
 
-// From loicvdbruh: https://www.shadertoy.com/view/NlGXzz. Square roots of primes.
-const LDS_MAX_DIMENSIONS: usize = 32;
-const LDS_PRIMES: [u32; LDS_MAX_DIMENSIONS] = [
-    0x6a09e667u32,
-    0xbb67ae84u32,
-    0x3c6ef372u32,
-    0xa54ff539u32,
-    0x510e527fu32,
-    0x9b05688au32,
-    0x1f83d9abu32,
-    0x5be0cd18u32,
-    0xcbbb9d5cu32,
-    0x629a2929u32,
-    0x91590159u32,
-    0x452fecd8u32,
-    0x67332667u32,
-    0x8eb44a86u32,
-    0xdb0c2e0bu32,
-    0x47b5481du32,
-    0xae5f9155u32,
-    0xcf6c85d1u32,
-    0x2f73477du32,
-    0x6d1826cau32,
-    0x8b43d455u32,
-    0xe360b595u32,
-    0x1c456002u32,
-    0x6f196330u32,
-    0xd94ebeafu32,
-    0x9cc4a611u32,
-    0x261dc1f2u32,
-    0x5815a7bdu32,
-    0x70b7ed67u32,
-    0xa1513c68u32,
-    0x44f93634u32,
-    0x720dcdfcu32,
+// Jarzynski and Olano, "Hash Functions for GPU Rendering": https://jcgt.org/published/0009/03/02/
+pub fn pcg4d(v: UVec4) -> UVec4 {
+    let mut v = UVec4::new(
+        v.x.wrapping_mul(1664525u32).wrapping_add(1013904223u32),
+        v.y.wrapping_mul(1664525u32).wrapping_add(1013904223u32),
+        v.z.wrapping_mul(1664525u32).wrapping_add(1013904223u32),
+        v.w.wrapping_mul(1664525u32).wrapping_add(1013904223u32),
+    );
+    v.x = v.x.wrapping_add(v.y.wrapping_mul(v.w));
+    v.y = v.y.wrapping_add(v.z.wrapping_mul(v.x));
+    v.z = v.z.wrapping_add(v.x.wrapping_mul(v.y));
+    v.w = v.w.wrapping_add(v.y.wrapping_mul(v.z));
+    v = v ^ (v >> 16u32);
+    v.x = v.x.wrapping_add(v.y.wrapping_mul(v.w));
+    v.y = v.y.wrapping_add(v.z.wrapping_mul(v.x));
+    v.z = v.z.wrapping_add(v.x.wrapping_mul(v.y));
+    v.w = v.w.wrapping_add(v.y.wrapping_mul(v.z));
+    v
+}
+
+// A 4D point set indexed by sample, every pixel and dimension gets its own decorrelated copy.
+// `RngState` draws one point per `gen_r*` call from `SEQUENCE`, picked at compile time.
+pub trait Sequence {
+    fn point(&self, pixel: UVec2, sample: u32, dimension: u32) -> UVec4;
+}
+
+// Plain hashing, white noise in every dimension
+#[cfg_attr(feature = "sobol", allow(dead_code))]
+pub struct Pcg4d;
+
+impl Sequence for Pcg4d {
+    fn point(&self, pixel: UVec2, sample: u32, dimension: u32) -> UVec4 {
+        pcg4d(UVec4::new(pixel.x, pixel.y, sample, dimension))
+    }
+}
+
+// First four Sobol dimensions (Joe-Kuo direction numbers)
+const SOBOL_DIRECTIONS: [[u32; 32]; 4] = [
+    [
+        0x80000000u32,
+        0x40000000u32,
+        0x20000000u32,
+        0x10000000u32,
+        0x08000000u32,
+        0x04000000u32,
+        0x02000000u32,
+        0x01000000u32,
+        0x00800000u32,
+        0x00400000u32,
+        0x00200000u32,
+        0x00100000u32,
+        0x00080000u32,
+        0x00040000u32,
+        0x00020000u32,
+        0x00010000u32,
+        0x00008000u32,
+        0x00004000u32,
+        0x00002000u32,
+        0x00001000u32,
+        0x00000800u32,
+        0x00000400u32,
+        0x00000200u32,
+        0x00000100u32,
+        0x00000080u32,
+        0x00000040u32,
+        0x00000020u32,
+        0x00000010u32,
+        0x00000008u32,
+        0x00000004u32,
+        0x00000002u32,
+        0x00000001u32,
+    ],
+    [
+        0x80000000u32,
+        0xc0000000u32,
+        0xa0000000u32,
+        0xf0000000u32,
+        0x88000000u32,
+        0xcc000000u32,
+        0xaa000000u32,
+        0xff000000u32,
+        0x80800000u32,
+        0xc0c00000u32,
+        0xa0a00000u32,
+        0xf0f00000u32,
+        0x88880000u32,
+        0xcccc0000u32,
+        0xaaaa0000u32,
+        0xffff0000u32,
+        0x80008000u32,
+        0xc000c000u32,
+        0xa000a000u32,
+        0xf000f000u32,
+        0x88008800u32,
+        0xcc00cc00u32,
+        0xaa00aa00u32,
+        0xff00ff00u32,
+        0x80808080u32,
+        0xc0c0c0c0u32,
+        0xa0a0a0a0u32,
+        0xf0f0f0f0u32,
+        0x88888888u32,
+        0xccccccccu32,
+        0xaaaaaaaau32,
+        0xffffffffu32,
+    ],
+    [
+        0x80000000u32,
+        0xc0000000u32,
+        0x60000000u32,
+        0x90000000u32,
+        0xe8000000u32,
+        0x5c000000u32,
+        0x8e000000u32,
+        0xc5000000u32,
+        0x68800000u32,
+        0x9cc00000u32,
+        0xee600000u32,
+        0x55900000u32,
+        0x80680000u32,
+        0xc09c0000u32,
+        0x60ee0000u32,
+        0x90550000u32,
+        0xe8808000u32,
+        0x5cc0c000u32,
+        0x8e606000u32,
+        0xc5909000u32,
+        0x6868e800u32,
+        0x9c9c5c00u32,
+        0xeeee8e00u32,
+        0x5555c500u32,
+        0x8000e880u32,
+        0xc0005cc0u32,
+        0x60008e60u32,
+        0x9000c590u32,
+        0xe8006868u32,
+        0x5c009c9cu32,
+        0x8e00eeeeu32,
+        0xc5005555u32,
+    ],
+    [
+        0x80000000u32,
+        0xc0000000u32,
+        0x20000000u32,
+        0x50000000u32,
+        0xf8000000u32,
+        0x74000000u32,
+        0xa2000000u32,
+        0x93000000u32,
+        0xd8800000u32,
+        0x25400000u32,
+        0x59e00000u32,
+        0xe6d00000u32,
+        0x78080000u32,
+        0xb40c0000u32,
+        0x82020000u32,
+        0xc3050000u32,
+        0x208f8000u32,
+        0x51474000u32,
+        0xfbea2000u32,
+        0x75d93000u32,
+        0xa0858800u32,
+        0x914e5400u32,
+        0xdbe79e00u32,
+        0x25db6d00u32,
+        0x58800080u32,
+        0xe54000c0u32,
+        0x79e00020u32,
+        0xb6d00050u32,
+        0x800800f8u32,
+        0xc00c0074u32,
+        0x200200a2u32,
+        0x50050093u32,
+    ],
 ];
 
-// http://extremelearning.com.au/unreasonable-effectiveness-of-quasirandom-sequences/
-pub fn lds(n: u32, dimension: usize, offset: u32) -> f32 {
-    const INV_U32_MAX_FLOAT: f32 = 1.0 / 4294967296.0;
-    (LDS_PRIMES[dimension].wrapping_mul(n.wrapping_add(offset))) as f32 * INV_U32_MAX_FLOAT
+fn sobol(mut index: u32, dimension: usize) -> u32 {
+    let mut result = 0;
+    let mut bit = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= SOBOL_DIRECTIONS[dimension][bit];
+        }
+        index >>= 1;
+        bit += 1;
+    }
+    result
+}
+
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47cu32);
+    x ^= x.wrapping_mul(0xb82f1e52u32);
+    x ^= x.wrapping_mul(0xc7afe638u32);
+    x ^= x.wrapping_mul(0x8d22f6e6u32);
+    x
 }
 
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+fn hash_combine(seed: u32, v: u32) -> u32 {
+    seed ^ v.wrapping_add(seed << 6).wrapping_add(seed >> 2)
+}
+
+// Burley, "Practical Hash-based Owen Scrambling": https://jcgt.org/published/0009/04/01/
+// Shuffling the index per (pixel, dimension) keeps dimensions decorrelated while every
+// dimension stays a stratified Owen-scrambled Sobol sequence over samples.
+#[cfg_attr(not(feature = "sobol"), allow(dead_code))]
+pub struct OwenSobol;
+
+impl Sequence for OwenSobol {
+    fn point(&self, pixel: UVec2, sample: u32, dimension: u32) -> UVec4 {
+        let seed = pcg4d(UVec4::new(pixel.x, pixel.y, dimension, 0)).x;
+        let index = nested_uniform_scramble(sample, seed);
+        UVec4::new(
+            nested_uniform_scramble(sobol(index, 0), hash_combine(seed, 0)),
+            nested_uniform_scramble(sobol(index, 1), hash_combine(seed, 1)),
+            nested_uniform_scramble(sobol(index, 2), hash_combine(seed, 2)),
+            nested_uniform_scramble(sobol(index, 3), hash_combine(seed, 3)),
+        )
+    }
+}
+
+#[cfg(not(feature = "sobol"))]
+const SEQUENCE: Pcg4d = Pcg4d;
+#[cfg(feature = "sobol")]
+const SEQUENCE: OwenSobol = OwenSobol;
+
+// top 24 bits, so the result is strictly below 1.0
+fn to_unit(x: u32) -> f32 {
+    (x >> 8) as f32 * (1.0 / 16777216.0)
+}
+
+// R2 sequence in 32-bit fixed point: http://extremelearning.com.au/unreasonable-effectiveness-of-quasirandom-sequences/
+const R2_ALPHA: UVec2 = UVec2::new(3242174889u32, 2447445413u32);
+
+// Heitz et al. screen-space blue noise: `texel` is the tiled blue-noise texture at this pixel,
+// every sample shifts it by the next R2 point (Cranley-Patterson rotation) so the pattern
+// stays blue per frame without repeating over time
+pub fn blue_noise(texel: Vec4, sample: u32) -> Vec4 {
+    let offset = Vec2::new(
+        to_unit(sample.wrapping_mul(R2_ALPHA.x)),
+        to_unit(sample.wrapping_mul(R2_ALPHA.y)),
+    );
+    (texel + offset.extend(offset.x).extend(offset.y)).fract()
+}
+
+// Every `gen_r*` call takes a fresh point of `SEQUENCE` for (pixel, sample, dimension),
+// so dimensions are decorrelated from each other, from neighbouring pixels and from other samples
 pub struct RngState {
-    state: UVec2,
-    dimension: usize,
+    pixel: UVec2,
+    sample: u32,
+    dimension: u32,
+    // replaces the next 2D draw, see `use_blue_noise`
+    blue: Vec2,
+    has_blue: bool,
 }
 
 impl RngState {
-    pub fn new(state: UVec2) -> Self {
-        Self { state, dimension: 0 }
+    pub fn new(pixel: UVec2, sample: u32) -> Self {
+        Self { pixel, sample, dimension: 0, blue: Vec2::ZERO, has_blue: false }
     }
 
-    pub fn next_state(&self) -> UVec2 {
-        UVec2::new(self.state.x + 1, self.state.y)
+    // Continues the draws of a path stored between wavefront passes, see `saved`
+    pub fn resume(pixel: UVec2, sample: u32, dimension: u32, blue: Option<Vec2>) -> Self {
+        let mut state = Self::new(pixel, sample);
+        state.dimension = dimension;
+        if let Some(blue) = blue {
+            state.use_blue_noise(blue);
+        }
+        state
     }
 
-    pub fn gen_r1(&mut self) -> f32 {
+    // The draw position and pending blue noise, pixel and sample are known from the path
+    pub fn saved(&self) -> (u32, Option<Vec2>) {
+        (self.dimension, if self.has_blue { Some(self.blue) } else { None })
+    }
+
+    // The next `gen_r2` or the `xy` of the next `gen_r3` returns `blue` instead of white noise
+    pub fn use_blue_noise(&mut self, blue: Vec2) {
+        self.blue = blue;
+        self.has_blue = true;
+    }
+
+    fn take_blue(&mut self, white: Vec2) -> Vec2 {
+        let r2 = if self.has_blue { self.blue } else { white };
+        self.has_blue = false;
+        r2
+    }
+
+    fn next(&mut self) -> UVec4 {
         self.dimension += 1;
-        lds(self.state.x, self.dimension, self.state.y)
+        SEQUENCE.point(self.pixel, self.sample, self.dimension)
+    }
+
+    pub fn gen_r1(&mut self) -> f32 {
+        to_unit(self.next().x)
     }
 
     pub fn gen_r2(&mut self) -> Vec2 {
-        Vec2::new(self.gen_r1(), self.gen_r1())
+        let v = self.next();
+        self.take_blue(Vec2::new(to_unit(v.x), to_unit(v.y)))
     }
 
     pub fn gen_r3(&mut self) -> Vec3 {
-        Vec3::new(self.gen_r1(), self.gen_r1(), self.gen_r1())
+        let v = self.next();
+        self.take_blue(Vec2::new(to_unit(v.x), to_unit(v.y))).extend(to_unit(v.z))
     }
 }
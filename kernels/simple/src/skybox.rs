@@ -1,7 +1,10 @@
 // https://github.com/pema99/rust-path-tracer/blob/master/kernels/src/skybox.rs
-use spirv_std::glam::{Vec2, Vec3, Vec4, Vec4Swizzles};
 #[allow(unused_imports)]
-use spirv_std::num_traits::Float;
+use spirv_std::num_traits::{Float, FloatConst};
+use {
+    shared::{SkyModel, TracingConfig},
+    spirv_std::glam::{Mat3, Vec2, Vec3, Vec4, Vec4Swizzles},
+};
 
 const RAY_SCATTER_COEFF: Vec3 = Vec3::new(58e-7, 135e-7, 331e-7);
 const RAY_EFFECTIVE_COEFF: Vec3 = RAY_SCATTER_COEFF; // Rayleight doesn't absorb light
@@ -41,7 +44,14 @@ fn scatter_depth_int(o: Vec3, d: Vec3, l: f32) -> Vec2 {
     densities_rm(o) * (l / 2.) + densities_rm(o + d * l) * (l / 2.)
 }
 
-fn scatter_in(origin: Vec3, direction: Vec3, depth: f32, steps: u32, sundir: Vec3) -> (Vec3, Vec3) {
+fn scatter_in(
+    origin: Vec3,
+    direction: Vec3,
+    depth: f32,
+    steps: u32,
+    sundir: Vec3,
+    mie_scale: f32,
+) -> (Vec3, Vec3) {
     let depth = depth / steps as f32;
 
     let mut i_r = Vec3::ZERO;
@@ -59,8 +69,9 @@ fn scatter_in(origin: Vec3, direction: Vec3, depth: f32, steps: u32, sundir: Vec
             total_depth_rm + scatter_depth_int(p, sundir, escape(p, sundir, ATMOSPHERE_RADIUS));
 
         // Calculate exponent part of both integrals
-        let a =
-            (-RAY_EFFECTIVE_COEFF * depth_rm_sum.x - MIE_EFFECTIVE_COEFF * depth_rm_sum.y).exp();
+        let a = (-RAY_EFFECTIVE_COEFF * depth_rm_sum.x
+            - MIE_EFFECTIVE_COEFF * mie_scale * depth_rm_sum.y)
+            .exp();
 
         i_r += a * d_rm.x;
         i_m += a * d_rm.y;
@@ -89,7 +100,8 @@ fn proc_sky(rd: Vec3) -> Vec3 {
     return (sun + col).clamp_length(0.0, 1.0);
 }
 
-pub fn scatter(sundir: Vec4, origin: Vec3, direction: Vec3) -> Vec3 {
+// `sundir.w` is the sun intensity, `mie_scale` scales the Mie density
+pub fn scatter(sundir: Vec4, mie_scale: f32, origin: Vec3, direction: Vec3) -> Vec3 {
     // return proc_sky(direction);
 
     let (i_r, i_m) = scatter_in(
@@ -98,6 +110,7 @@ pub fn scatter(sundir: Vec4, origin: Vec3, direction: Vec3) -> Vec3 {
         escape(origin, direction, ATMOSPHERE_RADIUS),
         12,
         sundir.xyz(),
+        mie_scale,
     );
 
     let mu = direction.dot(sundir.xyz());
@@ -106,8 +119,143 @@ pub fn scatter(sundir: Vec4, origin: Vec3, direction: Vec3) -> Vec3 {
         * (
             // 3/16pi = 0.597
             i_r * RAY_EFFECTIVE_COEFF * 0.0597
-                + i_m * MIE_SCATTER_COEFF * 0.0196 / (1.58 - 1.52 * mu).powf(1.5)
+                + i_m * MIE_SCATTER_COEFF * mie_scale * 0.0196 / (1.58 - 1.52 * mu).powf(1.5)
         );
 
     mask_nan(Vec3::new(res.x.sqrt(), res.y.sqrt(), res.z.sqrt())).powf(2.2)
 }
+
+// Turbidity is the optical depth of the whole atmosphere over that of clean air, so aerosols
+// (Mie) make up `turbidity - 1` of it
+fn mie_scale(turbidity: f32) -> f32 {
+    (turbidity - 1.0).max(0.0)
+}
+
+// Of the atmosphere between `origin` and space along `direction`
+fn transmittance(origin: Vec3, direction: Vec3, mie_scale: f32) -> Vec3 {
+    let depth_rm =
+        scatter_depth_int(origin, direction, escape(origin, direction, ATMOSPHERE_RADIUS));
+    (-RAY_EFFECTIVE_COEFF * depth_rm.x - MIE_EFFECTIVE_COEFF * mie_scale * depth_rm.y).exp()
+}
+
+// Perez et al. sky distribution, `coeffs` are its A to E parameters
+fn perez(cos_theta: f32, gamma: f32, cos_gamma: f32, coeffs: [f32; 5]) -> f32 {
+    (1.0 + coeffs[0] * (coeffs[1] / cos_theta).exp())
+        * (1.0 + coeffs[2] * (coeffs[3] * gamma).exp() + coeffs[4] * cos_gamma * cos_gamma)
+}
+
+// Brings the zenith luminance (kcd/m^2) of the Preetham fit in line with `scatter`
+const PREETHAM_SCALE: f32 = 0.0015;
+
+// CIE XYZ to linear sRGB (D65)
+const XYZ_TO_RGB: Mat3 = Mat3::from_cols_array(&[
+    3.2406, -0.9689, 0.0557, -1.5372, 1.8758, -0.2040, -0.4986, 0.0415, 1.0570,
+]);
+
+// "A Practical Analytic Model for Daylight" (Preetham, Shirley, Smits). The fit only covers
+// daylight, so the sun is kept above the horizon and turbidity within 1.7 to 10.
+pub fn preetham(sun: Vec3, turbidity: f32, direction: Vec3) -> Vec3 {
+    let t = turbidity.clamp(1.7, 10.0);
+    let cos_theta_s = sun.y.clamp(0.01, 1.0);
+    let theta_s = cos_theta_s.acos();
+    let cos_theta = direction.y.max(0.01);
+    let cos_gamma = direction.dot(sun).clamp(-1.0, 1.0);
+    let gamma = cos_gamma.acos();
+
+    // distribution coefficients of the luminance and the two chromaticities
+    let coeffs_y = [
+        0.1787 * t - 1.4630,
+        -0.3554 * t + 0.4275,
+        -0.0227 * t + 5.3251,
+        0.1206 * t - 2.5771,
+        -0.0670 * t + 0.3703,
+    ];
+    let coeffs_cx = [
+        -0.0193 * t - 0.2592,
+        -0.0665 * t + 0.0008,
+        -0.0004 * t + 0.2125,
+        -0.0641 * t - 0.8989,
+        -0.0033 * t + 0.0452,
+    ];
+    let coeffs_cy = [
+        -0.0167 * t - 0.2608,
+        -0.0950 * t + 0.0092,
+        -0.0079 * t + 0.2102,
+        -0.0441 * t - 1.6537,
+        -0.0109 * t + 0.0529,
+    ];
+
+    // zenith luminance and chromaticity
+    let chi = (4.0 / 9.0 - t / 120.0) * (f32::PI() - 2.0 * theta_s);
+    let zenith_y = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+    let theta = Vec4::new(theta_s * theta_s * theta_s, theta_s * theta_s, theta_s, 1.0);
+    let zenith_cx = t * t * theta.dot(Vec4::new(0.00166, -0.00375, 0.00209, 0.0))
+        + t * theta.dot(Vec4::new(-0.02903, 0.06377, -0.03202, 0.00394))
+        + theta.dot(Vec4::new(0.11693, -0.21196, 0.06052, 0.25886));
+    let zenith_cy = t * t * theta.dot(Vec4::new(0.00275, -0.00610, 0.00317, 0.0))
+        + t * theta.dot(Vec4::new(-0.04214, 0.08970, -0.04153, 0.00516))
+        + theta.dot(Vec4::new(0.15346, -0.26756, 0.06670, 0.26688));
+
+    let relative = |coeffs: [f32; 5]| {
+        perez(cos_theta, gamma, cos_gamma, coeffs) / perez(1.0, theta_s, cos_theta_s, coeffs)
+    };
+    let y = zenith_y * relative(coeffs_y);
+    let cx = zenith_cx * relative(coeffs_cx);
+    let cy = zenith_cy * relative(coeffs_cy);
+
+    let xyz = Vec3::new(cx / cy * y, y, (1.0 - cx - cy) / cy * y);
+    (XYZ_TO_RGB * xyz).max(Vec3::ZERO)
+}
+
+fn sky(config: &TracingConfig, origin: Vec3, sun: Vec3, direction: Vec3) -> Vec3 {
+    match config.sky_model() {
+        SkyModel::Scatter => scatter(
+            sun.extend(config.sun_intensity),
+            mie_scale(config.sky_turbidity),
+            origin,
+            direction,
+        ),
+        SkyModel::Preetham => {
+            preetham(sun, config.sky_turbidity, direction) * config.sun_intensity * PREETHAM_SCALE
+        }
+    }
+}
+
+// Of the sun disc at normal incidence, dimmed by the atmosphere in front of it
+fn sun_irradiance(config: &TracingConfig, origin: Vec3, sun: Vec3) -> Vec3 {
+    config.sun_intensity * transmittance(origin, sun, mie_scale(config.sky_turbidity))
+}
+
+pub fn sun_solid_angle(config: &TracingConfig) -> f32 {
+    // 2 pi (1 - cos(radius)), without the cancellation
+    let half_sin = (config.sun_angular_radius * 0.5).sin();
+    4.0 * f32::PI() * half_sin * half_sin
+}
+
+// The disc is a uniform emitter spreading the sun irradiance over its solid angle, whatever part
+// of it sets below the horizon is hidden
+pub fn sun_disc(config: &TracingConfig, origin: Vec3, direction: Vec3) -> Vec3 {
+    let sun = config.sun_direction.xyz().normalize();
+    let radius = config.sun_angular_radius;
+    if radius <= 0.0 || direction.y < 0.0 || direction.dot(sun) < radius.cos() {
+        return Vec3::ZERO;
+    }
+    sun_irradiance(config, origin, sun) / sun_solid_angle(config)
+}
+
+// Lambertian ground lit by the sun and, crudely, a uniform sky as bright as its zenith
+fn ground(config: &TracingConfig, origin: Vec3, sun: Vec3) -> Vec3 {
+    let sky = sky(config, origin, sun, Vec3::Y);
+    let sun = sun_irradiance(config, origin, sun) * sun.y.max(0.0);
+    config.ground_albedo * (sky + sun / f32::PI())
+}
+
+// Radiance of the procedural sky along `direction`, without the sun disc, see `sun_disc`
+pub fn radiance(config: &TracingConfig, origin: Vec3, direction: Vec3) -> Vec3 {
+    let sun = config.sun_direction.xyz().normalize();
+    if config.ground() && direction.y < 0.0 {
+        ground(config, origin, sun)
+    } else {
+        sky(config, origin, sun, direction)
+    }
+}
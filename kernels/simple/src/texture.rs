@@ -0,0 +1,147 @@
+#[allow(unused_imports)]
+use spirv_std::num_traits::Float;
+use {
+    shared::{MaterialData, Sampler, TextureSlot, TracingConfig, WrapMode, ATLAS_MIP_LEVELS},
+    spirv_std::{
+        glam::{Vec2, Vec3, Vec4, Vec4Swizzles},
+        Image,
+    },
+};
+
+// Lod bias that always selects the base level, for lookups without a ray cone
+pub const FINEST_LOD: f32 = f32::NEG_INFINITY;
+
+fn wrap(x: f32, mode: WrapMode) -> f32 {
+    match mode {
+        WrapMode::Repeat => x - x.floor(),
+        WrapMode::Clamp => x.clamp(0.0, 1.0),
+        WrapMode::Mirror => {
+            let t = x - 2.0 * (x * 0.5).floor();
+            if t > 1.0 {
+                2.0 - t
+            } else {
+                t
+            }
+        }
+    }
+}
+
+// Component `channel` of a texel, see `MaterialData::texture_channel`
+pub fn channel(texel: Vec4, channel: u32) -> f32 {
+    match channel {
+        0 => texel.x,
+        1 => texel.y,
+        2 => texel.z,
+        _ => texel.w,
+    }
+}
+
+fn srgb_channel_to_linear(x: f32) -> f32 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// Albedo and emissive textures are stored sRGB encoded, see `scene::load_texture`
+pub fn srgb_to_linear(color: Vec3) -> Vec3 {
+    Vec3::new(
+        srgb_channel_to_linear(color.x),
+        srgb_channel_to_linear(color.y),
+        srgb_channel_to_linear(color.z),
+    )
+}
+
+// Mips are stacked in a column to the right of the base level, see `atlas::pack_textures`
+fn mip_rect(rect: Vec4, level: u32) -> Vec4 {
+    if level == 0 {
+        return rect;
+    }
+    let scale = 1.0 / (1 << level) as f32;
+    Vec4::new(
+        rect.x + rect.z,
+        rect.y + rect.w * (1.0 - 2.0 * scale),
+        rect.z * scale,
+        rect.w * scale,
+    )
+}
+
+fn sample_level(
+    atlas: &Image!(2D, type=f32, sampled),
+    sampler: &Sampler,
+    rect: Vec4,
+    half_texel: Vec2,
+    uv: Vec2,
+) -> Vec4 {
+    // Stay half a texel inside the entry, so filtering never reads the neighboring texture
+    let min = rect.xy() + half_texel;
+    let max = (rect.xy() + rect.zw() - half_texel).max(min);
+    let scaled_uv = (rect.xy() + uv * rect.zw()).clamp(min, max);
+    atlas.sample_by_lod(*sampler, scaled_uv, 0.0)
+}
+
+// Samples the texture of `slot` with its UV transform and wrap modes.
+// `lod_bias` comes from `RayCone::lod_bias`, the texture resolution is added here.
+pub fn sample(
+    config: &TracingConfig,
+    atlas: &Image!(2D, type=f32, sampled),
+    sampler: &Sampler,
+    material: &MaterialData,
+    slot: TextureSlot,
+    uv: Vec2,
+    lod_bias: f32,
+) -> Vec4 {
+    // offset in xy and size in zw
+    let rect = material.texture_rect(slot);
+    let (wrap_u, wrap_v) = material.wrap_modes(slot);
+    let transform = material.uv_transform(slot);
+    let uv = transform.apply(uv);
+    let lod_bias = lod_bias + 0.5 * transform.area_scale().log2();
+    let uv = Vec2::new(wrap(uv.x, wrap_u), wrap(uv.y, wrap_v));
+    let atlas_size = Vec2::new(config.atlas_width as f32, config.atlas_height as f32);
+    let half_texel = 0.5 / atlas_size;
+
+    let texels = rect.zw() * atlas_size;
+    let max_lod = texels.min_element().max(1.0).log2().min(ATLAS_MIP_LEVELS as f32);
+    let lod = (lod_bias + 0.5 * (texels.x * texels.y).max(1.0).log2()).clamp(0.0, max_lod);
+
+    // Trilinear filtering between the two closest levels
+    let level = lod.floor();
+    let fine = sample_level(atlas, sampler, mip_rect(rect, level as u32), half_texel, uv);
+    if lod - level <= 0.0 {
+        return fine;
+    }
+    let coarse = sample_level(atlas, sampler, mip_rect(rect, level as u32 + 1), half_texel, uv);
+    fine.lerp(coarse, lod - level)
+}
+
+// http://advances.realtimerendering.com/s2019/Texture-Level-of-Detail-Ray-Cones.pdf
+#[derive(Copy, Clone)]
+pub struct RayCone {
+    pub width: f32,
+    pub spread: f32,
+}
+
+impl RayCone {
+    pub fn new(spread: f32) -> Self {
+        Self { width: 0.0, spread }
+    }
+
+    pub fn propagate(&mut self, distance: f32) {
+        self.width += self.spread * distance;
+    }
+
+    // Rough surfaces blur the reflected footprint, diffuse bounces pass roughness 1.0
+    pub fn scatter(&mut self, roughness: f32) {
+        self.spread += roughness * roughness;
+    }
+
+    // Texture independent part of the lod, `uv_area` and `world_area` are of the hit triangle
+    pub fn lod_bias(&self, uv_area: f32, world_area: f32, cos_theta: f32) -> f32 {
+        if uv_area <= 0.0 || world_area <= 0.0 {
+            return FINEST_LOD;
+        }
+        0.5 * (uv_area / world_area).log2() + (self.width / cos_theta.abs().max(0.01)).log2()
+    }
+}
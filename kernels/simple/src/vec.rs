@@ -10,9 +10,15 @@ impl<T: Sized + Default + Copy, const CAPACITY: usize> FixedVec<T, CAPACITY> {
         Self { data: [Default::default(); CAPACITY], len: 0 }
     }
 
-    pub fn push(&mut self, value: T) {
+    // Returns false and leaves the vec untouched when it is full
+    #[must_use]
+    pub fn push(&mut self, value: T) -> bool {
+        if self.len as usize >= CAPACITY {
+            return false;
+        }
         self.data[self.len as usize] = value;
         self.len += 1;
+        true
     }
 
     pub fn pop(&mut self) -> Option<T> {
@@ -24,6 +30,18 @@ impl<T: Sized + Default + Copy, const CAPACITY: usize> FixedVec<T, CAPACITY> {
         }
     }
 
+    // Shifts the following elements down to keep their order
+    pub fn remove(&mut self, index: u32) -> T {
+        let value = self.data[index as usize];
+        let mut i = index as usize;
+        while i + 1 < self.len as usize {
+            self.data[i] = self.data[i + 1];
+            i += 1;
+        }
+        self.len -= 1;
+        value
+    }
+
     pub fn is_empty(&self) -> bool {
         self.len == 0
     }
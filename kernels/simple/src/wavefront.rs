@@ -0,0 +1,316 @@
+// Wavefront tracing: instead of one thread running a path to the end, every pass runs one step
+// of all paths of a tile and hands them on through queues, see `shared::PathRecord`. The host
+// loops intersection and shading until no ray is left, shading is dispatched per `ShadeClass`
+// so neighbouring threads run the same material code.
+use {
+    crate::{
+        bsdf::{self, Lobe},
+        env::EnvReference,
+        inter::{BVHReference, Trace},
+        light::{self, Occlusion},
+        medium::MediumStack,
+        rng::RngState,
+        texture::RayCone,
+        Aov, Path, Radiance,
+    },
+    shared::{
+        HitRecord, LightPick, MaterialData, PathRecord, PerVertexData, PunctualLight, Sampler,
+        ShadeClass, ShadowRay, TracingConfig, COUNTER_RAYS, COUNTER_SHADE, COUNTER_SHADE_CLASS,
+        COUNTER_SHADOWS,
+    },
+    spirv_std::{
+        arch::atomic_i_increment,
+        glam::{UVec3, UVec4, Vec3, Vec3Swizzles, Vec4, Vec4Swizzles},
+        memory::{Scope, Semantics},
+        Image,
+    },
+};
+
+// Pixel of path `path`, paths run along the rows of the tile
+pub fn pixel(config: &TracingConfig, path: u32) -> UVec3 {
+    let width = config.tile.z;
+    UVec3::new(config.tile.x + path % width, config.tile.y + path / width, 0)
+}
+
+pub fn path_count(config: &TracingConfig) -> u32 {
+    config.tile.z * config.tile.w
+}
+
+// Claims the next entry of the queue counted by `counters[counter]`
+fn claim(counters: &mut [u32], counter: usize) -> u32 {
+    unsafe {
+        atomic_i_increment::<_, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(
+            &mut counters[counter],
+        )
+    }
+}
+
+// Appends `path` to queue `queue` of `queues`, each `capacity` long
+fn push(
+    queues: &mut [u32],
+    queue: u32,
+    capacity: u32,
+    counters: &mut [u32],
+    counter: usize,
+    path: u32,
+) {
+    let slot = claim(counters, counter);
+    queues[(queue * capacity + slot) as usize] = path;
+}
+
+// Keeps the shadow ray of a light sample for `shadow` instead of tracing it, the sample
+// counts as unoccluded until then
+#[derive(Default)]
+struct Deferred {
+    ray: ShadowRay,
+    pending: bool,
+}
+
+impl Occlusion for Deferred {
+    fn visible(&mut self, origin: Vec3, direction: Vec3, max_t: f32) -> bool {
+        self.ray.origin = origin.extend(max_t);
+        self.ray.direction = direction.extend(0.0);
+        self.pending = true;
+        true
+    }
+
+    fn defer(&mut self, contribution: Vec3) -> bool {
+        self.ray.contribution = contribution.extend(0.0);
+        true
+    }
+}
+
+impl Path {
+    fn load(record: &PathRecord, id: UVec3, config: &TracingConfig) -> Self {
+        let blue = if record.has_blue() { Some(record.rng_blue) } else { None };
+        Self {
+            ori: record.origin.xyz(),
+            dir: record.direction.xyz(),
+            bounce: record.bounce,
+            throughput: record.throughput.xyz(),
+            radiance: Radiance {
+                direct: record.direct.xyz(),
+                indirect: record.indirect.xyz(),
+                nan_bounce: record.nan_bounce,
+            },
+            bsdf_sample: bsdf::BSDFSample {
+                pdf: record.bsdf_spectrum.w,
+                lobe: Lobe::from_bits(record.bsdf_lobe),
+                spectrum: record.bsdf_spectrum.xyz(),
+                direction: record.bsdf_direction.xyz(),
+            },
+            light_sample: light::LightSample {
+                area: record.light_normal.w,
+                normal: record.light_normal.xyz(),
+                pick_pdf: record.light_throughput.w,
+                instance: record.light_instance,
+                triangle: record.light_triangle,
+                throughput: record.light_throughput.xyz(),
+                // already added, or waiting in the shadow queue
+                contribution: Vec3::ZERO,
+            },
+            used_nee: record.used_nee(),
+            aov: Aov { albedo: record.albedo.xyz(), normal: record.normal.xyz() },
+            aov_pending: record.aov_pending(),
+            path_roughness: record.throughput.w,
+            media: MediumStack::from_entries(record.media, record.media_len),
+            channel: record.channel,
+            cone: RayCone { width: record.origin.w, spread: record.direction.w },
+            rng_state: RngState::resume(id.xy(), config.sample_index, record.rng_dimension, blue),
+            blue_noise: record.blue_noise,
+        }
+    }
+
+    fn store(&self) -> PathRecord {
+        let (media, media_len) = self.media.entries();
+        let (rng_dimension, blue) = self.rng_state.saved();
+        // the flags are private to `shared`, so no struct literal
+        let mut record = PathRecord::default();
+        record.origin = self.ori.extend(self.cone.width);
+        record.direction = self.dir.extend(self.cone.spread);
+        record.throughput = self.throughput.extend(self.path_roughness);
+        record.direct = self.radiance.direct.extend(0.0);
+        record.indirect = self.radiance.indirect.extend(0.0);
+        record.albedo = self.aov.albedo.extend(0.0);
+        record.normal = self.aov.normal.extend(0.0);
+        record.blue_noise = self.blue_noise;
+        record.bsdf_spectrum = self.bsdf_sample.spectrum.extend(self.bsdf_sample.pdf);
+        record.bsdf_direction = self.bsdf_sample.direction.extend(0.0);
+        record.light_normal = self.light_sample.normal.extend(self.light_sample.area);
+        record.light_throughput = self.light_sample.throughput.extend(self.light_sample.pick_pdf);
+        record.light_triangle = self.light_sample.triangle;
+        record.media = media;
+        record.media_len = media_len;
+        record.rng_blue = blue.unwrap_or_default();
+        record.rng_dimension = rng_dimension;
+        record.bounce = self.bounce;
+        record.channel = self.channel;
+        record.nan_bounce = self.radiance.nan_bounce;
+        record.bsdf_lobe = self.bsdf_sample.lobe as u32;
+        record.light_instance = self.light_sample.instance;
+        record.set_used_nee(self.used_nee);
+        record.set_aov_pending(self.aov_pending);
+        record.set_has_blue(blue.is_some());
+        record
+    }
+}
+
+// Starts the path of every pixel of the tile on its camera ray and queues all of them for
+// intersection, the host sets the ray count
+pub fn generate(
+    path: u32,
+    config: &TracingConfig,
+    blue_noise: &[Vec4],
+    paths: &mut [PathRecord],
+    queues: &mut [u32],
+) {
+    if path >= path_count(config) {
+        return;
+    }
+    let id = pixel(config, path);
+    let index = (id.y * config.width + id.x) as usize;
+    paths[path as usize] = Path::new(id, config, blue_noise[index]).store();
+    queues[path as usize] = path;
+}
+
+// Traces the rays of the ray queue and sorts the paths into the shade queues
+pub fn intersect(
+    entry: u32,
+    indices: &[UVec4],
+    per_vertex: &[PerVertexData],
+    bvh: &BVHReference,
+    materials: &[MaterialData],
+    paths: &[PathRecord],
+    hits: &mut [HitRecord],
+    queues: &mut [u32],
+    counters: &mut [u32],
+) {
+    if entry >= counters[COUNTER_RAYS] {
+        return;
+    }
+    let capacity = paths.len() as u32;
+    let path = queues[entry as usize];
+    let record = &paths[path as usize];
+    let trace =
+        bvh.intersect_nearest(per_vertex, indices, record.origin.xyz(), record.direction.xyz());
+    hits[path as usize] = trace.record();
+
+    let class = if trace.hit {
+        let instance = bvh.instances[trace.instance as usize];
+        let material = materials[instance.material_index(trace.triangle, materials.len()) as usize];
+        if material.emission() != Vec3::ZERO {
+            ShadeClass::Emissive
+        } else if material.transmission > 0.0 {
+            ShadeClass::Transmissive
+        } else {
+            ShadeClass::Opaque
+        }
+    } else {
+        ShadeClass::Miss
+    };
+    let class = class as u32;
+    push(queues, 1 + class, capacity, counters, COUNTER_SHADE + class as usize, path);
+}
+
+// Shades the paths of the shade queue the host picked, queueing their next ray and the shadow
+// ray of their light sample
+pub fn shade(
+    entry: u32,
+    config: &TracingConfig,
+    indices: &[UVec4],
+    per_vertex: &[PerVertexData],
+    materials: &[MaterialData],
+    lights: &[LightPick],
+    punctual_lights: &[PunctualLight],
+    sampler: &Sampler,
+    atlas: &Image!(2D, type=f32, sampled),
+    bvh: &BVHReference,
+    env: &EnvReference,
+    paths: &mut [PathRecord],
+    hits: &[HitRecord],
+    queues: &mut [u32],
+    shadow_rays: &mut [ShadowRay],
+    counters: &mut [u32],
+) {
+    let class = counters[COUNTER_SHADE_CLASS];
+    if entry >= counters[COUNTER_SHADE + class as usize] {
+        return;
+    }
+    let capacity = paths.len() as u32;
+    let path_index = queues[((1 + class) * capacity + entry) as usize];
+    let id = pixel(config, path_index);
+    let mut path = Path::load(&paths[path_index as usize], id, config);
+    let trace = Trace::from_record(&hits[path_index as usize], indices);
+
+    let bounce = path.bounce;
+    let mut shadows = Deferred::default();
+    let more = path.shade(
+        trace,
+        config,
+        indices,
+        per_vertex,
+        materials,
+        lights,
+        punctual_lights,
+        sampler,
+        atlas,
+        bvh,
+        env,
+        &mut shadows,
+        // the camera hits aren't resampled, `restir` runs on the megakernel's G-buffer
+        None,
+    );
+    if shadows.pending {
+        let slot = claim(counters, COUNTER_SHADOWS);
+        shadows.ray.path = path_index;
+        shadows.ray.bounce = bounce;
+        shadow_rays[slot as usize] = shadows.ray;
+    }
+    if more {
+        push(queues, 0, capacity, counters, COUNTER_RAYS, path_index);
+    }
+    paths[path_index as usize] = path.store();
+}
+
+// Adds the contribution of every light sample whose shadow ray gets through
+pub fn shadow(
+    entry: u32,
+    config: &TracingConfig,
+    indices: &[UVec4],
+    per_vertex: &[PerVertexData],
+    bvh: &BVHReference,
+    paths: &mut [PathRecord],
+    shadow_rays: &[ShadowRay],
+    counters: &[u32],
+) {
+    if entry >= counters[COUNTER_SHADOWS] {
+        return;
+    }
+    let ray = shadow_rays[entry as usize];
+    let mut shadows = light::Shadows { bvh, indices, per_vertex };
+    if !shadows.visible(ray.origin.xyz(), ray.direction.xyz(), ray.origin.w) {
+        return;
+    }
+
+    // a path has at most one shadow ray per bounce, nothing else writes its radiance now
+    let record = &mut paths[ray.path as usize];
+    let mut radiance = Radiance {
+        direct: record.direct.xyz(),
+        indirect: record.indirect.xyz(),
+        nan_bounce: record.nan_bounce,
+    };
+    radiance.add(config, ray.bounce, ray.bounce > 0, ray.contribution.xyz());
+    record.direct = radiance.direct.extend(0.0);
+    record.indirect = radiance.indirect.extend(0.0);
+    record.nan_bounce = radiance.nan_bounce;
+}
+
+// What path `path` gathered, once no ray is left
+pub fn finished(path: &PathRecord) -> (Radiance, Aov) {
+    let radiance = Radiance {
+        direct: path.direct.xyz(),
+        indirect: path.indirect.xyz(),
+        nan_bounce: path.nan_bounce,
+    };
+    (radiance, Aov { albedo: path.albedo.xyz(), normal: path.normal.xyz() })
+}
@@ -2,19 +2,189 @@
 
 use {
     bytemuck::{Pod, Zeroable},
-    glam::{Vec3, Vec4, Vec4Swizzles},
+    glam::{Mat3, Mat4, UVec4, Vec3, Vec4, Vec4Swizzles},
     spirv_std::glam::Vec2,
 };
 
+// Upper bound for `TracingConfig::max_bounces`, paths longer than that contribute next to nothing
+pub const MAX_BOUNCES: u32 = 64;
+
+// Number of mips packed next to every atlas entry, see `atlas::pack_textures`
+pub const ATLAS_MIP_LEVELS: u32 = 8;
+
+// Capacity of the kernel's BVH traversal stack, `BVHBuilder` never builds trees deeper than that
+pub const BVH_STACK_SIZE: usize = 64;
+
+// Kernel-side counters read back by the host: `[0]` counts non-finite samples,
+// `[1 + bounce]` the bounce each of them first showed up at
+pub const DIAGNOSTICS_SIZE: usize = MAX_BOUNCES as usize + 2;
+
+// Adaptive sampling keeps a mask per `BLOCK_SIZE` square of pixels, one bit per pixel that
+// converged in `BLOCK_WORDS` words. The blocks are the kernel's workgroups, a block whose mask is
+// full needn't be dispatched.
+pub const BLOCK_SIZE: u32 = 8;
+pub const BLOCK_WORDS: u32 = BLOCK_SIZE * BLOCK_SIZE / 32;
+
+// Deepest nesting of dielectrics a path can be inside of, entering more is ignored
+pub const MEDIUM_STACK_SIZE: usize = 4;
+
+// Rec.709 luminance, used wherever light power or sampling densities are weighed so
+// triangles, the environment and the kernel all agree on what "bright" means
+pub fn luminance(color: Vec3) -> f32 {
+    color.dot(Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+// Camera to world rotation of `TracingConfig::cam_rot` (pitch, yaw, roll): roll around the view
+// axis, then pitch, then yaw around world up. The kernel and the fly camera must agree on it.
+pub fn camera_basis(rotation: Vec3) -> Mat3 {
+    Mat3::from_rotation_y(rotation.y)
+        * Mat3::from_rotation_x(rotation.x)
+        * Mat3::from_rotation_z(rotation.z)
+}
+
+// Procedural sky used when there is no environment map
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[repr(u32)]
+pub enum SkyModel {
+    // single scattering through a spherical atmosphere
+    Scatter,
+    // Preetham, Shirley and Smits analytic daylight fit
+    Preetham,
+}
+
+impl SkyModel {
+    pub fn from_bits(bits: u32) -> Self {
+        match bits {
+            1 => SkyModel::Preetham,
+            _ => SkyModel::Scatter,
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            SkyModel::Scatter => SkyModel::Preetham,
+            SkyModel::Preetham => SkyModel::Scatter,
+        }
+    }
+}
+
+// What the kernel renders, the debug modes ignore lights and surface appearance
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[repr(u32)]
+pub enum RenderMode {
+    PathTracing,
+    // white surfaces, darkened by geometry within `AO_RADIUS`
+    AmbientOcclusion,
+    // white diffuse surfaces under a white environment, converges to 1.0 unless the BSDF
+    // loses or creates energy
+    Furnace,
+}
+
+impl RenderMode {
+    pub fn from_bits(bits: u32) -> Self {
+        match bits {
+            1 => RenderMode::AmbientOcclusion,
+            2 => RenderMode::Furnace,
+            _ => RenderMode::PathTracing,
+        }
+    }
+}
+
+// Reach of the ambient occlusion rays in world units
+pub const AO_RADIUS: f32 = 1.0;
+
+// How the camera hits pick among emissive triangles, see `Reservoir`
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[repr(u32)]
+pub enum Restir {
+    // one light sample per hit, like every other vertex
+    Off,
+    // reservoirs carry on from the previous sample of the same pixel
+    Temporal,
+    // and are then combined with some of their neighbours' in a pass of its own
+    Spatiotemporal,
+}
+
+impl Restir {
+    pub fn from_bits(bits: u32) -> Self {
+        match bits {
+            1 => Restir::Temporal,
+            2 => Restir::Spatiotemporal,
+            _ => Restir::Off,
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            Restir::Off => Restir::Temporal,
+            Restir::Temporal => Restir::Spatiotemporal,
+            Restir::Spatiotemporal => Restir::Off,
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Pod, Zeroable)]
 pub struct TracingConfig {
     pub cam_pos: Vec4,
+    // pitch, yaw and roll in radians, applied as yaw * pitch * roll
     pub cam_rot: Vec4,
+    // not required to be normalized
+    pub sun_direction: Vec4,
     pub width: u32,
     pub height: u32,
+    // Russian roulette starts at this bounce
     pub min_bounces: u32,
     pub max_bounces: u32,
+    pub env_width: u32,
+    pub env_height: u32,
+    pub env_rotation: f32,
+    has_env_map: u32,
+    pub env_pick_pdf: f32,
+    pub sun_intensity: f32,
+    // atmospheric turbidity, 2.0 is a clear day and higher is hazier
+    pub sky_turbidity: f32,
+    // reflectance of the ground below the horizon of the procedural sky, with `ground` set
+    pub ground_albedo: f32,
+    // off, the sky carries on below the horizon as it always did
+    ground: u32,
+    // of the sun disc in radians, 0.0 leaves only the glow around it
+    pub sun_angular_radius: f32,
+    sky_model: u32,
+    // full vertical field of view in radians
+    pub vertical_fov: f32,
+    // lens diameter in world units, 0.0 is a pinhole camera
+    pub aperture: f32,
+    // distance along the view axis that is in perfect focus
+    pub focus_distance: f32,
+    // Max luminance of indirect path contributions, 0.0 disables it.
+    // Removes fireflies at the cost of energy loss (bias) in bright indirect light.
+    pub clamp_indirect: f32,
+    // Path regularization strength, 0.0 disables it. Past the first rough vertex, roughness is
+    // floored towards 0.3 * regularization, trading slight blur for fewer SDS fireflies.
+    pub regularization: f32,
+    // Homogeneous fog filling the whole scene, `sigma_t == 0.0` disables it. Extinction and
+    // scattering coefficients per world unit, `phase_g` is the Henyey-Greenstein asymmetry.
+    pub sigma_t: f32,
+    pub sigma_s: f32,
+    pub phase_g: f32,
+    // used to keep filtered lookups inside their atlas entry
+    pub atlas_width: u32,
+    pub atlas_height: u32,
+    // index of the sample being traced, seeds the kernel's per-pixel RNG
+    pub sample_index: u32,
+    // paint non-finite samples magenta and count them instead of silently dropping them
+    debug_nan: u32,
+    render_mode: u32,
+    // entries of the punctual light buffer, which holds a placeholder when there are none
+    pub punctual_light_count: u32,
+    restir: u32,
+    // Adaptive sampling, `adaptive_threshold == 0.0` disables it. Past `adaptive_min_samples`,
+    // pixels whose standard error is below this fraction of their estimate aren't traced.
+    pub adaptive_threshold: f32,
+    pub adaptive_min_samples: u32,
+    // origin and size in pixels of the part of the image a dispatch traces, set per dispatch
+    pub tile: UVec4,
 }
 
 impl TracingConfig {
@@ -24,10 +194,95 @@ impl TracingConfig {
             height: 720,
             cam_pos: Vec4::new(0.0, 1.0, -5.0, 0.0),
             cam_rot: Vec4::ZERO,
+            sun_direction: Vec4::new(0.5, 1.3, 1.0, 0.0),
             min_bounces: 3,
             max_bounces: 4,
+            env_width: 0,
+            env_height: 0,
+            env_rotation: 0.0,
+            has_env_map: 0,
+            env_pick_pdf: 0.0,
+            sun_intensity: 15.0,
+            sky_turbidity: 2.0,
+            ground_albedo: 0.3,
+            ground: 0,
+            // the real sun, 0.27 degrees
+            sun_angular_radius: 0.0047,
+            sky_model: SkyModel::Scatter as u32,
+            vertical_fov: core::f32::consts::FRAC_PI_2,
+            aperture: 0.0,
+            focus_distance: 5.0,
+            clamp_indirect: 0.0,
+            regularization: 0.0,
+            sigma_t: 0.0,
+            sigma_s: 0.0,
+            phase_g: 0.0,
+            atlas_width: 4096,
+            atlas_height: 4096,
+            sample_index: 0,
+            debug_nan: 0,
+            render_mode: RenderMode::PathTracing as u32,
+            punctual_light_count: 0,
+            restir: Restir::Off as u32,
+            adaptive_threshold: 0.0,
+            adaptive_min_samples: 16,
+            tile: UVec4::ZERO,
         }
     }
+
+    // Bounce 0 is the camera hit, so `max_bounces == 0` renders direct light and the sky only
+    pub fn clamp_bounces(&mut self) {
+        self.max_bounces = self.max_bounces.min(MAX_BOUNCES);
+        self.min_bounces = self.min_bounces.min(self.max_bounces);
+    }
+
+    pub fn has_env_map(&self) -> bool {
+        self.has_env_map != 0
+    }
+
+    pub fn set_has_env_map(&mut self, has_env_map: bool) {
+        self.has_env_map = if has_env_map { 1 } else { 0 };
+    }
+
+    pub fn sky_model(&self) -> SkyModel {
+        SkyModel::from_bits(self.sky_model)
+    }
+
+    pub fn set_sky_model(&mut self, sky_model: SkyModel) {
+        self.sky_model = sky_model as u32;
+    }
+
+    pub fn ground(&self) -> bool {
+        self.ground != 0
+    }
+
+    pub fn set_ground(&mut self, ground: bool) {
+        self.ground = if ground { 1 } else { 0 };
+    }
+
+    pub fn render_mode(&self) -> RenderMode {
+        RenderMode::from_bits(self.render_mode)
+    }
+
+    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
+        self.render_mode = render_mode as u32;
+    }
+
+    pub fn restir(&self) -> Restir {
+        Restir::from_bits(self.restir)
+    }
+
+    pub fn set_restir(&mut self, restir: Restir) {
+        self.restir = restir as u32;
+    }
+
+    pub fn debug_nan(&self) -> bool {
+        self.debug_nan != 0
+    }
+
+    pub fn set_debug_nan(&mut self, debug_nan: bool) {
+        self.debug_nan = if debug_nan { 1 } else { 0 };
+    }
 }
 
 #[repr(C)]
@@ -36,34 +291,227 @@ pub struct PerVertexData {
     pub vertex: Vec4,
     pub normal: Vec4,
     pub tangent: Vec4,
+    // linear RGBA, white when the mesh has no vertex colors
+    pub color: Vec4,
     pub uv0: Vec2,
     pub uv1: Vec2,
 }
 
+fn axis(v: Vec3, i: u32) -> f32 {
+    match i {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+fn abs(x: f32) -> f32 {
+    if x < 0.0 {
+        -x
+    } else {
+        x
+    }
+}
+
+// Top-left rule of rasterizers in the sheared space of `intersect_triangle`: of two triangles
+// sharing an edge and winding, which run it in opposite directions, exactly one owns it
+fn owns_edge(dx: f32, dy: f32, det: f32) -> bool {
+    // back faces run their edges the other way around
+    let (dx, dy) = if det < 0.0 { (-dx, -dy) } else { (dx, dy) };
+    dy > 0.0 || (dy == 0.0 && dx > 0.0)
+}
+
+// Watertight ray/triangle intersection, rays passing exactly between two triangles hit one of
+// them, never neither nor both. `backface` is set when the ray hits the clockwise side.
+// https://jcgt.org/published/0002/01/05/paper.pdf
+pub fn intersect_triangle(
+    ro: Vec3,
+    rd: Vec3,
+    a: Vec3,
+    b: Vec3,
+    c: Vec3,
+    out_t: &mut f32,
+    out_backface: &mut bool,
+) -> bool {
+    *out_t = 0.0;
+
+    // Permute so z is the dominant ray axis, swapping x and y keeps the winding
+    let (dx, dy, dz) = (abs(rd.x), abs(rd.y), abs(rd.z));
+    let kz = if dx > dy && dx > dz {
+        0
+    } else if dy > dz {
+        1
+    } else {
+        2
+    };
+    let mut kx = (kz + 1) % 3;
+    let mut ky = (kx + 1) % 3;
+    if axis(rd, kz) < 0.0 {
+        core::mem::swap(&mut kx, &mut ky);
+    }
+
+    // Shear so the ray points along +z
+    let sz = 1.0 / axis(rd, kz);
+    let sx = axis(rd, kx) * sz;
+    let sy = axis(rd, ky) * sz;
+
+    let (a, b, c) = (a - ro, b - ro, c - ro);
+    let ax = axis(a, kx) - sx * axis(a, kz);
+    let ay = axis(a, ky) - sy * axis(a, kz);
+    let bx = axis(b, kx) - sx * axis(b, kz);
+    let by = axis(b, ky) - sy * axis(b, kz);
+    let cx = axis(c, kx) - sx * axis(c, kz);
+    let cy = axis(c, ky) - sy * axis(c, kz);
+
+    // Scaled barycentrics from 2D edge functions, an edge exactly through the ray
+    // is 0.0 on both triangles sharing it, so one of them always reports the hit
+    let u = cx * by - cy * bx;
+    let v = ax * cy - ay * cx;
+    let w = bx * ay - by * ax;
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return false;
+    }
+
+    let det = u + v + w;
+    if det == 0.0 {
+        return false;
+    }
+    // A ray exactly through a shared edge or vertex zeroes an edge function on every triangle
+    // there, only the triangle owning the edge counts the hit so it isn't reported twice
+    if (u == 0.0 && !owns_edge(cx - bx, cy - by, det))
+        || (v == 0.0 && !owns_edge(ax - cx, ay - cy, det))
+        || (w == 0.0 && !owns_edge(bx - ax, by - ay, det))
+    {
+        return false;
+    }
+    *out_backface = det < 0.0;
+
+    let t = (u * axis(a, kz) + v * axis(b, kz) + w * axis(c, kz)) * sz / det;
+    if t < 0.0 {
+        return false;
+    }
+    *out_t = t;
+
+    true
+}
+
+// Reciprocal of the ray direction with zero components replaced by a tiny value of the same
+// sign, so slab distances stay finite instead of relying on inf/NaN propagation
+pub fn safe_inverse(rd: Vec3) -> Vec3 {
+    const TINY: f32 = 1e-20;
+    let fix = |x: f32| {
+        if abs(x) >= TINY {
+            x
+        } else if x.is_sign_negative() {
+            -TINY
+        } else {
+            TINY
+        }
+    };
+    Vec3::new(1.0 / fix(rd.x), 1.0 / fix(rd.y), 1.0 / fix(rd.z))
+}
+
+// Axis aligned box, the default one is empty and takes the shape of whatever it grows by
+#[derive(Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Default for Aabb {
+    fn default() -> Self {
+        Self::EMPTY
+    }
+}
+
+impl Aabb {
+    pub const EMPTY: Self = Self { min: Vec3::splat(f32::MAX), max: Vec3::splat(f32::MIN) };
+
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Self { min, max }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.cmpgt(self.max).any()
+    }
+
+    pub fn grow(&mut self, point: Vec3) {
+        self.min = self.min.min(point);
+        self.max = self.max.max(point);
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self { min: self.min.min(other.min), max: self.max.max(other.max) }
+    }
+
+    // Overlap of both boxes, `EMPTY` if there is none
+    pub fn intersection(self, other: Self) -> Self {
+        let aabb = Self { min: self.min.max(other.min), max: self.max.min(other.max) };
+        if aabb.is_empty() {
+            Self::EMPTY
+        } else {
+            aabb
+        }
+    }
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.min.cmple(other.min).all() && self.max.cmpge(other.max).all()
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let extent = self.max - self.min;
+        2.0 * (extent.x * extent.y + extent.y * extent.z + extent.z * extent.x)
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    // Slab test, returns where the ray enters the box if that happens before `t_max`. Rays
+    // starting inside enter at a negative distance. The slabs alone can't tell an inverted
+    // box from a proper one, so empty boxes are ruled out first.
+    pub fn intersect(&self, ro: Vec3, inv_rd: Vec3, t_max: f32) -> Option<f32> {
+        if self.is_empty() {
+            return None;
+        }
+        let t1 = (self.min - ro) * inv_rd;
+        let t2 = (self.max - ro) * inv_rd;
+        let tmin = t1.x.min(t2.x).max(t1.y.min(t2.y)).max(t1.z.min(t2.z));
+        let tmax = t1.x.max(t2.x).min(t1.y.max(t2.y)).min(t1.z.max(t2.z));
+        if tmax >= tmin && tmax > 0.0 && tmin < t_max {
+            Some(tmin)
+        } else {
+            None
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Pod, Zeroable)]
 pub struct BVHNode {
+    // The w components carry u32 bits, never do float math on the whole Vec4s
     aabb_min: Vec4, // w = triangle count
-    aabb_max: Vec4, // w = left_node if triangle_count is 0, first_triangle_index if triangle_count is 1
+    aabb_max: Vec4, // w = left_node if triangle_count is 0, first_triangle_index otherwise
 }
 
 impl Default for BVHNode {
     fn default() -> Self {
-        Self {
-            aabb_min: Vec4::new(f32::MAX, f32::MAX, f32::MAX, 0.0),
-            aabb_max: Vec4::new(f32::MIN, f32::MIN, f32::MIN, 0.0),
-        }
+        Self { aabb_min: Aabb::EMPTY.min.extend(0.0), aabb_max: Aabb::EMPTY.max.extend(0.0) }
     }
 }
 
 impl BVHNode {
     // Immutable access
     pub fn triangle_count(&self) -> u32 {
-        unsafe { core::mem::transmute(self.aabb_min.w) }
+        self.aabb_min.w.to_bits()
     }
 
     pub fn left_node_index(&self) -> u32 {
-        unsafe { core::mem::transmute(self.aabb_max.w) }
+        debug_assert!(!self.is_leaf(), "leaves have no children");
+        self.aabb_max.w.to_bits()
     }
 
     pub fn right_node_index(&self) -> u32 {
@@ -71,15 +519,12 @@ impl BVHNode {
     }
 
     pub fn first_triangle_index(&self) -> u32 {
-        unsafe { core::mem::transmute(self.aabb_max.w) }
+        debug_assert!(self.is_leaf(), "interior nodes have no triangles");
+        self.aabb_max.w.to_bits()
     }
 
-    pub fn aabb_min(&self) -> Vec3 {
-        self.aabb_min.xyz()
-    }
-
-    pub fn aabb_max(&self) -> Vec3 {
-        self.aabb_max.xyz()
+    pub fn aabb(&self) -> Aabb {
+        Aabb::new(self.aabb_min.xyz(), self.aabb_max.xyz())
     }
 
     pub fn is_leaf(&self) -> bool {
@@ -88,46 +533,202 @@ impl BVHNode {
 
     // Mutable access
     pub fn set_triangle_count(&mut self, triangle_count: u32) {
-        self.aabb_min.w = unsafe { core::mem::transmute(triangle_count) };
+        self.aabb_min.w = f32::from_bits(triangle_count);
     }
 
     pub fn set_left_node_index(&mut self, left_node_index: u32) {
-        self.aabb_max.w = unsafe { core::mem::transmute(left_node_index) };
+        self.aabb_max.w = f32::from_bits(left_node_index);
     }
 
     pub fn set_first_triangle_index(&mut self, first_triangle_index: u32) {
-        self.aabb_max.w = unsafe { core::mem::transmute(first_triangle_index) };
+        self.aabb_max.w = f32::from_bits(first_triangle_index);
     }
 
-    pub fn set_aabb_min(&mut self, aabb_min: Vec3) {
-        self.aabb_min.x = aabb_min.x;
-        self.aabb_min.y = aabb_min.y;
-        self.aabb_min.z = aabb_min.z;
+    // Leaves the triangle count and index in the w components alone
+    pub fn set_aabb(&mut self, aabb: Aabb) {
+        self.aabb_min = aabb.min.extend(self.aabb_min.w);
+        self.aabb_max = aabb.max.extend(self.aabb_max.w);
     }
+}
 
-    pub fn set_aabb_max(&mut self, aabb_max: Vec3) {
-        self.aabb_max.x = aabb_max.x;
-        self.aabb_max.y = aabb_max.y;
-        self.aabb_max.z = aabb_max.z;
+// A placed copy of a mesh. Its bottom-level BVH starts at `blas_root` in the nodes buffer and
+// is traversed in object space, so only the ray gets transformed. Backface tests happen there
+// too, mirroring transforms need no flipped winding. The winding of world space vertices does
+// flip with them, take normals from `normal_to_world` instead.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+pub struct InstanceData {
+    pub object_to_world: Mat4,
+    pub world_to_object: Mat4,
+    pub blas_root: u32,
+    // added to the material index in `.w` of every triangle of the mesh
+    pub material_offset: u32,
+    // -1.0 for mirroring transforms, tangent frames flip along with them
+    pub handedness: f32,
+    pub _padding: u32,
+}
+
+impl InstanceData {
+    pub fn new(object_to_world: Mat4, blas_root: u32, material_offset: u32) -> Self {
+        let handedness = if object_to_world.determinant() < 0.0 { -1.0 } else { 1.0 };
+        Self {
+            object_to_world,
+            world_to_object: object_to_world.inverse(),
+            blas_root,
+            material_offset,
+            handedness,
+            _padding: 0,
+        }
+    }
+
+    // The direction isn't renormalized, so hit distances along it are the same in both spaces
+    pub fn ray_to_object(&self, ro: Vec3, rd: Vec3) -> (Vec3, Vec3) {
+        (self.world_to_object.transform_point3(ro), self.world_to_object.transform_vector3(rd))
+    }
+
+    pub fn point_to_world(&self, point: Vec3) -> Vec3 {
+        self.object_to_world.transform_point3(point)
+    }
+
+    pub fn vector_to_world(&self, vector: Vec3) -> Vec3 {
+        self.object_to_world.transform_vector3(vector)
+    }
+
+    // Material of a triangle of the mesh. Scenes end with a default material, broken indices
+    // are clamped onto it rather than read past the buffer.
+    pub fn material_index(&self, triangle: UVec4, material_count: usize) -> u32 {
+        (triangle.w + self.material_offset).min(material_count.max(1) as u32 - 1)
+    }
+
+    // Normals go through the inverse transpose to stay perpendicular under non-uniform scale
+    pub fn normal_to_world(&self, normal: Vec3) -> Vec3 {
+        (Mat3::from_mat4(self.world_to_object).transpose() * normal).normalize()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Default)]
+#[repr(u32)]
+pub enum WrapMode {
+    #[default]
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+impl WrapMode {
+    pub fn from_bits(bits: u32) -> Self {
+        match bits {
+            1 => WrapMode::Clamp,
+            2 => WrapMode::Mirror,
+            _ => WrapMode::Repeat,
+        }
+    }
+}
+
+// A 2x3 transform of the UVs of one texture, like KHR_texture_transform. Applied before
+// wrapping, so repeating textures tile.
+#[repr(C)]
+#[derive(Copy, Clone, Pod, Zeroable)]
+pub struct UvTransform {
+    pub x_axis: Vec2,
+    pub y_axis: Vec2,
+    pub offset: Vec2,
+    pub _padding: Vec2,
+}
+
+impl Default for UvTransform {
+    fn default() -> Self {
+        Self::IDENTITY
     }
 }
 
+impl UvTransform {
+    pub const IDENTITY: Self =
+        Self { x_axis: Vec2::X, y_axis: Vec2::Y, offset: Vec2::ZERO, _padding: Vec2::ZERO };
+
+    pub fn apply(&self, uv: Vec2) -> Vec2 {
+        self.x_axis * uv.x + self.y_axis * uv.y + self.offset
+    }
+
+    // How much UV area grows, tiled textures need coarser mips
+    pub fn area_scale(&self) -> f32 {
+        self.x_axis.perp_dot(self.y_axis).abs()
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(u32)]
+pub enum TextureSlot {
+    Albedo,
+    Metallic,
+    Roughness,
+    Normals,
+    Emissive,
+    Occlusion,
+    ThinFilm,
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Pod, Zeroable, Default)]
 pub struct MaterialData {
-    // each Vec4 is either a color or an atlas location
+    // each Vec4 is either a color or an atlas location,
+    // except `emissive` which is a color with its strength in `w`
     pub emissive: Vec4,
     pub albedo: Vec4,
     pub roughness: Vec4,
     pub metallic: Vec4,
     pub normals: Vec4,
+    // atlas location, multiplied with `emissive` when `has_emissive_texture` is set
+    pub emissive_texture: Vec4,
+    pub occlusion: Vec4,
+    // KHR_materials_sheen color, with the sheen roughness in `w`
+    pub sheen: Vec4,
+    // KHR_materials_iridescence strength, film IOR and the film thickness range in nm,
+    // untextured films are as thick as the maximum
+    pub thin_film: Vec4,
+    // atlas location, its green channel picks the thickness when `has_thin_film_texture` is set
+    pub thin_film_texture: Vec4,
+    // one per `TextureSlot`
+    uv_transforms: [UvTransform; 7],
     has_albedo_texture: u32,
     has_metallic_texture: u32,
     has_roughness_texture: u32,
     has_normal_texture: u32,
+    // 0.0 is opaque, 1.0 is fully transmissive glass
+    pub transmission: f32,
+    pub ior: f32,
+    has_emissive_texture: u32,
+    // scales the tangent space xy of the normal map, 1.0 leaves it untouched
+    pub normal_strength: f32,
+    has_occlusion_texture: u32,
+    // glTF texCoord of the occlusion texture, 0 = uv0, 1 = uv1
+    occlusion_uv_set: u32,
+    // 4 bits per `TextureSlot`, u mode in the low 2 bits and v mode in the high 2 bits
+    wrap_modes: u32,
+    // KHR_materials_anisotropy, strength in [0, 1] and the counter-clockwise rotation
+    // of the highlight direction from the tangent in radians
+    pub anisotropy: f32,
+    pub anisotropy_rotation: f32,
+    // nested dielectrics, overlapping volumes belong to the higher priority material
+    pub priority: u32,
+    has_thin_film_texture: u32,
+    // dispersion of transmissive materials, lower is stronger and 0.0 disables it
+    pub abbe_number: f32,
+    // 2 bits per `TextureSlot`, the texel component the slot reads. glTF packs metallic
+    // into blue and roughness into green of one texture.
+    texture_channels: u32,
+    // set when a mesh using the material has vertex colors, they multiply the albedo
+    has_vertex_colors: u32,
+    // glTF doubleSided, backfaces shade and emit like frontfaces
+    double_sided: u32,
+    _padding: [u32; 1],
 }
 
 impl MaterialData {
+    pub fn emission(&self) -> Vec3 {
+        self.emissive.xyz() * self.emissive.w
+    }
+
     pub fn has_albedo_texture(&self) -> bool {
         self.has_albedo_texture != 0
     }
@@ -159,14 +760,105 @@ impl MaterialData {
     pub fn set_has_normal_texture(&mut self, has_normal_texture: bool) {
         self.has_normal_texture = if has_normal_texture { 1 } else { 0 };
     }
+
+    pub fn has_emissive_texture(&self) -> bool {
+        self.has_emissive_texture != 0
+    }
+
+    pub fn set_has_emissive_texture(&mut self, has_emissive_texture: bool) {
+        self.has_emissive_texture = if has_emissive_texture { 1 } else { 0 };
+    }
+
+    pub fn has_occlusion_texture(&self) -> bool {
+        self.has_occlusion_texture != 0
+    }
+
+    pub fn set_has_occlusion_texture(&mut self, has_occlusion_texture: bool) {
+        self.has_occlusion_texture = if has_occlusion_texture { 1 } else { 0 };
+    }
+
+    pub fn has_thin_film_texture(&self) -> bool {
+        self.has_thin_film_texture != 0
+    }
+
+    pub fn set_has_thin_film_texture(&mut self, has_thin_film_texture: bool) {
+        self.has_thin_film_texture = if has_thin_film_texture { 1 } else { 0 };
+    }
+
+    pub fn occlusion_uses_uv1(&self) -> bool {
+        self.occlusion_uv_set != 0
+    }
+
+    pub fn set_occlusion_uses_uv1(&mut self, occlusion_uses_uv1: bool) {
+        self.occlusion_uv_set = if occlusion_uses_uv1 { 1 } else { 0 };
+    }
+
+    pub fn wrap_modes(&self, slot: TextureSlot) -> (WrapMode, WrapMode) {
+        let bits = self.wrap_modes >> (slot as u32 * 4);
+        (WrapMode::from_bits(bits & 3), WrapMode::from_bits((bits >> 2) & 3))
+    }
+
+    pub fn set_wrap_modes(&mut self, slot: TextureSlot, u: WrapMode, v: WrapMode) {
+        let shift = slot as u32 * 4;
+        let bits = u as u32 | (v as u32) << 2;
+        self.wrap_modes = (self.wrap_modes & !(0xF << shift)) | bits << shift;
+    }
+
+    pub fn texture_channel(&self, slot: TextureSlot) -> u32 {
+        (self.texture_channels >> (slot as u32 * 2)) & 3
+    }
+
+    pub fn set_texture_channel(&mut self, slot: TextureSlot, channel: u32) {
+        let shift = slot as u32 * 2;
+        self.texture_channels = (self.texture_channels & !(3 << shift)) | (channel & 3) << shift;
+    }
+
+    // Atlas location of the texture in `slot`
+    pub fn texture_rect(&self, slot: TextureSlot) -> Vec4 {
+        match slot {
+            TextureSlot::Albedo => self.albedo,
+            TextureSlot::Metallic => self.metallic,
+            TextureSlot::Roughness => self.roughness,
+            TextureSlot::Normals => self.normals,
+            TextureSlot::Emissive => self.emissive_texture,
+            TextureSlot::Occlusion => self.occlusion,
+            TextureSlot::ThinFilm => self.thin_film_texture,
+        }
+    }
+
+    pub fn uv_transform(&self, slot: TextureSlot) -> UvTransform {
+        self.uv_transforms[slot as usize]
+    }
+
+    pub fn set_uv_transform(&mut self, slot: TextureSlot, transform: UvTransform) {
+        self.uv_transforms[slot as usize] = transform;
+    }
+
+    pub fn has_vertex_colors(&self) -> bool {
+        self.has_vertex_colors != 0
+    }
+
+    pub fn set_has_vertex_colors(&mut self, has_vertex_colors: bool) {
+        self.has_vertex_colors = if has_vertex_colors { 1 } else { 0 };
+    }
+
+    pub fn double_sided(&self) -> bool {
+        self.double_sided != 0
+    }
+
+    pub fn set_double_sided(&mut self, double_sided: bool) {
+        self.double_sided = if double_sided { 1 } else { 0 };
+    }
 }
 
 #[repr(C)]
 #[derive(Copy, Clone, Pod, Zeroable, Default)]
 pub struct LightPick {
+    pub instance_index_a: u32,
     pub triangle_index_a: u32,
     pub triangle_area_a: f32,
     pub triangle_pick_pdf_a: f32,
+    pub instance_index_b: u32,
     pub triangle_index_b: u32,
     pub triangle_area_b: f32,
     pub triangle_pick_pdf_b: f32,
@@ -180,6 +872,276 @@ impl LightPick {
     }
 }
 
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[repr(u32)]
+pub enum PunctualLightKind {
+    Point,
+    Spot,
+    Directional,
+}
+
+impl PunctualLightKind {
+    pub fn from_bits(bits: u32) -> Self {
+        match bits {
+            1 => PunctualLightKind::Spot,
+            2 => PunctualLightKind::Directional,
+            _ => PunctualLightKind::Point,
+        }
+    }
+}
+
+// KHR_lights_punctual light in world space. Delta lights can't be hit by rays, they are only
+// reached through light sampling.
+#[repr(C)]
+#[derive(Copy, Clone, Pod, Zeroable, Default)]
+pub struct PunctualLight {
+    // unused by directional lights
+    pub position: Vec4,
+    // the light shines along it, unused by point lights
+    pub direction: Vec4,
+    // linear color with the intensity in `w`, candela for point and spot lights and lux
+    // for directional ones
+    pub color: Vec4,
+    // distance where the light is cut off, 0.0 is infinite
+    pub range: f32,
+    // cosines of the spot angles, full intensity inside the inner cone and none outside
+    // the outer one
+    pub cos_inner_cone: f32,
+    pub cos_outer_cone: f32,
+    kind: u32,
+}
+
+impl PunctualLight {
+    pub fn kind(&self) -> PunctualLightKind {
+        PunctualLightKind::from_bits(self.kind)
+    }
+
+    pub fn set_kind(&mut self, kind: PunctualLightKind) {
+        self.kind = kind as u32;
+    }
+
+    pub fn intensity(&self) -> Vec3 {
+        self.color.xyz() * self.color.w
+    }
+}
+
+// What the camera ray of a pixel hit in the current sample, written by the primary ray pass
+// for the shading pass to start from. Position, normal, material and UV also guide denoisers.
+#[repr(C)]
+#[derive(Copy, Clone, Pod, Zeroable, Default)]
+pub struct GBufferTexel {
+    // world space, with the distance along the camera ray in `w`
+    pub position: Vec4,
+    // interpolated vertex normal in world space, before normal mapping
+    pub normal: Vec4,
+    pub uv: Vec2,
+    pub material_index: u32,
+    pub instance: u32,
+    // into the index buffer
+    pub triangle_index: u32,
+    hit: u32,
+    backface: u32,
+    _padding: u32,
+}
+
+impl GBufferTexel {
+    // false for camera rays that left the scene, the rest is zero then
+    pub fn hit(&self) -> bool {
+        self.hit != 0
+    }
+
+    pub fn set_hit(&mut self, hit: bool) {
+        self.hit = hit as u32;
+    }
+
+    pub fn backface(&self) -> bool {
+        self.backface != 0
+    }
+
+    pub fn set_backface(&mut self, backface: bool) {
+        self.backface = backface as u32;
+    }
+}
+
+// Wavefront tracing keeps every path of a tile in a `PathRecord` between passes. Passes hand
+// paths on through queues of path indices, appended to with atomic counters so every queue
+// stays compacted. One queue of extension rays to intersect and one per `ShadeClass` to shade,
+// each as long as there are paths, in that order, and one of `ShadowRay`s of its own.
+pub const WAVEFRONT_TILE_SIZE: u32 = 512;
+pub const MAX_WAVEFRONT_PATHS: usize = (WAVEFRONT_TILE_SIZE * WAVEFRONT_TILE_SIZE) as usize;
+pub const SHADE_CLASSES: usize = 4;
+pub const WAVEFRONT_QUEUES: usize = 1 + SHADE_CLASSES;
+
+// Slots of the wavefront counters. The host writes the shade class the next shading dispatch
+// drains into the first one, the others count the entries of their queue.
+pub const COUNTER_SHADE_CLASS: usize = 0;
+pub const COUNTER_RAYS: usize = 1;
+// followed by the other classes
+pub const COUNTER_SHADE: usize = 2;
+pub const COUNTER_SHADOWS: usize = COUNTER_SHADE + SHADE_CLASSES;
+pub const WAVEFRONT_COUNTERS: usize = COUNTER_SHADOWS + 1;
+
+// Paths are sorted by what they hit before shading, so a dispatch runs one kind of material
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[repr(u32)]
+pub enum ShadeClass {
+    Miss,
+    Emissive,
+    Opaque,
+    // glass, the only class that steps through media
+    Transmissive,
+}
+
+impl ShadeClass {
+    pub fn from_bits(bits: u32) -> Self {
+        match bits {
+            1 => ShadeClass::Emissive,
+            2 => ShadeClass::Opaque,
+            3 => ShadeClass::Transmissive,
+            _ => ShadeClass::Miss,
+        }
+    }
+}
+
+// A path between wavefront passes, everything the megakernel keeps in registers across bounces
+#[repr(C)]
+#[derive(Copy, Clone, Pod, Zeroable, Default)]
+pub struct PathRecord {
+    // of the ray to trace next, with the ray cone width and spread in `w`
+    pub origin: Vec4,
+    pub direction: Vec4,
+    // with the roughest lobe sampled so far in `w`
+    pub throughput: Vec4,
+    pub direct: Vec4,
+    pub indirect: Vec4,
+    pub albedo: Vec4,
+    pub normal: Vec4,
+    // of the pixel and sample, already shifted
+    pub blue_noise: Vec4,
+    // of the last BSDF sample, with its pdf in `w`
+    pub bsdf_spectrum: Vec4,
+    pub bsdf_direction: Vec4,
+    // of the last light sample, with the area and pick pdf in `w`
+    pub light_normal: Vec4,
+    pub light_throughput: Vec4,
+    pub light_triangle: UVec4,
+    // materials the path is inside of, the first `media_len` count
+    pub media: [u32; MEDIUM_STACK_SIZE],
+    // pending blue noise of the RNG, see `has_blue`
+    pub rng_blue: Vec2,
+    pub bounce: u32,
+    pub channel: u32,
+    // `1 + bounce` of the first non-finite contribution, 0 if there was none
+    pub nan_bounce: u32,
+    pub bsdf_lobe: u32,
+    pub light_instance: u32,
+    pub media_len: u32,
+    pub rng_dimension: u32,
+    used_nee: u32,
+    aov_pending: u32,
+    has_blue: u32,
+}
+
+impl PathRecord {
+    pub fn used_nee(&self) -> bool {
+        self.used_nee != 0
+    }
+
+    pub fn set_used_nee(&mut self, used_nee: bool) {
+        self.used_nee = if used_nee { 1 } else { 0 };
+    }
+
+    pub fn aov_pending(&self) -> bool {
+        self.aov_pending != 0
+    }
+
+    pub fn set_aov_pending(&mut self, aov_pending: bool) {
+        self.aov_pending = if aov_pending { 1 } else { 0 };
+    }
+
+    pub fn has_blue(&self) -> bool {
+        self.has_blue != 0
+    }
+
+    pub fn set_has_blue(&mut self, has_blue: bool) {
+        self.has_blue = if has_blue { 1 } else { 0 };
+    }
+}
+
+// What the ray of a wavefront path hit, the triangle itself is looked up again when shading
+#[repr(C)]
+#[derive(Copy, Clone, Pod, Zeroable, Default)]
+pub struct HitRecord {
+    pub distance: f32,
+    pub instance: u32,
+    // into the index buffer
+    pub triangle_index: u32,
+    hit: u32,
+    backface: u32,
+}
+
+impl HitRecord {
+    pub fn hit(&self) -> bool {
+        self.hit != 0
+    }
+
+    pub fn set_hit(&mut self, hit: bool) {
+        self.hit = if hit { 1 } else { 0 };
+    }
+
+    pub fn backface(&self) -> bool {
+        self.backface != 0
+    }
+
+    pub fn set_backface(&mut self, backface: bool) {
+        self.backface = if backface { 1 } else { 0 };
+    }
+}
+
+// A light sample waiting for its shadow ray, `contribution` reaches path `path` if it gets through
+#[repr(C)]
+#[derive(Copy, Clone, Pod, Zeroable, Default)]
+pub struct ShadowRay {
+    // with the distance the ray may travel in `w`
+    pub origin: Vec4,
+    pub direction: Vec4,
+    pub contribution: Vec4,
+    pub path: u32,
+    // of the vertex the light was sampled from
+    pub bounce: u32,
+    _padding: [u32; 2],
+}
+
+// ReSTIR DI keeps one reservoir per pixel: a point on an emissive triangle picked for the
+// camera hit out of `count` candidates by resampled importance sampling, with what it needs to
+// be weighed again at another hit. `weight` is the unbiased contribution weight, it stands in
+// for one over the pdf of `point` over the area of all emissive triangles.
+// https://benedikt-bitterli.me/restir/bitterli20restir.pdf
+#[repr(C)]
+#[derive(Copy, Clone, Pod, Zeroable, Default)]
+pub struct Reservoir {
+    pub point: Vec4,
+    // of the triangle, double-sided emitters face whichever side the hit is on
+    pub normal: Vec4,
+    pub emission: Vec4,
+    // of the resampling weights of all candidates so far
+    pub weight_sum: f32,
+    // candidates the reservoir stands for, not whole once the history is capped
+    pub count: f32,
+    pub weight: f32,
+    double_sided: u32,
+}
+
+impl Reservoir {
+    pub fn double_sided(&self) -> bool {
+        self.double_sided != 0
+    }
+
+    pub fn set_double_sided(&mut self, double_sided: bool) {
+        self.double_sided = if double_sided { 1 } else { 0 };
+    }
+}
+
 #[cfg(target_arch = "spirv")]
 pub mod polyfill {
     pub use spirv_std::{Image, Sampler};
@@ -208,13 +1170,14 @@ pub mod polyfill {
         }
 
         fn sample_raw(&self, coord: IVec2) -> Vec4 {
-            let x = coord.x as usize % self.width as usize;
-            let y = coord.y as usize % self.height as usize;
+            let x = coord.x.rem_euclid(self.width as i32) as usize;
+            let y = coord.y.rem_euclid(self.height as i32) as usize;
             self.buffer[y * self.width as usize + x]
         }
 
         pub fn sample_by_lod(&self, _sampler: Sampler, coord: Vec2, _lod: f32) -> Vec4 {
-            let scaled_uv = coord * Vec2::new(self.width as f32, self.height as f32);
+            // texel centers sit at half integers, like on the GPU
+            let scaled_uv = coord * Vec2::new(self.width as f32, self.height as f32) - 0.5;
             let frac_uv = scaled_uv.fract();
             let ceil_uv = scaled_uv.ceil().as_ivec2();
             let floor_uv = scaled_uv.floor().as_ivec2();
@@ -242,3 +1205,184 @@ pub mod polyfill {
 }
 
 pub use polyfill::{Image, Sampler};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hits(ro: Vec3, rd: Vec3, triangle: [Vec3; 3]) -> bool {
+        let (mut t, mut backface) = (0.0, false);
+        intersect_triangle(ro, rd, triangle[0], triangle[1], triangle[2], &mut t, &mut backface)
+    }
+
+    // Two triangles wound the same way around their shared edge `p`-`q`, every ray aimed at
+    // a point of the edge hits exactly one of them from either side
+    fn assert_one_hit(p: Vec3, q: Vec3, left: Vec3, right: Vec3, origins: &[Vec3]) {
+        let (first, second) = ([p, q, left], [q, p, right]);
+        for &ro in origins {
+            // the vertices belong to the rest of their fan as well
+            for step in 1..1000 {
+                let target = p.lerp(q, step as f32 / 1000.0);
+                let rd = (target - ro).normalize();
+                let count = hits(ro, rd, first) as u32 + hits(ro, rd, second) as u32;
+                assert_eq!(count, 1, "ray from {ro} toward {target}");
+            }
+        }
+    }
+
+    #[test]
+    fn edge_on_rays_hit_one_triangle() {
+        // exactly on the edge, both edge functions are zero
+        let (p, q) = (Vec3::new(-1.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let (left, right) = (Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        assert_one_hit(p, q, left, right, &[Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0)]);
+        // a skewed edge no float point lies on exactly, at glancing angles
+        let (p, q) = (Vec3::new(0.1, 0.2, 0.3), Vec3::new(0.7, -0.45, 1.9));
+        let (left, right) = (Vec3::new(1.3, 0.9, -0.2), Vec3::new(-0.6, -0.8, 1.1));
+        let origins = [
+            Vec3::new(3.0, 7.0, -2.0),
+            Vec3::new(-5.0, 0.01, 0.3),
+            Vec3::new(0.4, -0.1251, 1.1),
+            Vec3::new(100.0, -30.0, 55.0),
+        ];
+        assert_one_hit(p, q, left, right, &origins);
+    }
+
+    // Bit patterns that must come back unchanged, whatever float they read as
+    const BITS: [u32; 6] = [0, 1, 0x7f80_0001, 0x7fc0_0000, 0xffc0_0001, u32::MAX];
+
+    #[test]
+    fn node_indices_survive_as_float_bits() {
+        for bits in BITS {
+            let mut node = BVHNode::default();
+            node.set_triangle_count(bits);
+            assert_eq!(node.triangle_count(), bits);
+
+            let mut interior = BVHNode::default();
+            interior.set_left_node_index(bits);
+            interior.set_aabb(unit_box());
+            assert_eq!(interior.left_node_index(), bits);
+            assert_eq!(interior.triangle_count(), 0);
+
+            let mut leaf = BVHNode::default();
+            leaf.set_triangle_count(3);
+            leaf.set_first_triangle_index(bits);
+            leaf.set_aabb(unit_box());
+            assert_eq!(leaf.first_triangle_index(), bits);
+            assert_eq!(leaf.triangle_count(), 3);
+
+            // as uploaded
+            let copy = bytemuck::pod_read_unaligned::<BVHNode>(bytemuck::bytes_of(&leaf));
+            assert_eq!(copy.first_triangle_index(), bits);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "leaves have no children")]
+    fn leaves_have_no_left_node() {
+        let mut leaf = BVHNode::default();
+        leaf.set_triangle_count(1);
+        leaf.left_node_index();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "interior nodes have no triangles")]
+    fn interior_nodes_have_no_first_triangle() {
+        BVHNode::default().first_triangle_index();
+    }
+
+    fn unit_box() -> Aabb {
+        Aabb::new(Vec3::ZERO, Vec3::ONE)
+    }
+
+    #[test]
+    fn safe_inverse_keeps_the_sign_of_zeros() {
+        let inverse = safe_inverse(Vec3::new(0.0, -0.0, 2.0));
+        assert!(inverse.is_finite());
+        assert!(inverse.x > 0.0 && inverse.y < 0.0);
+        assert_eq!(inverse.z, 0.5);
+    }
+
+    #[test]
+    fn rays_parallel_to_faces() {
+        let aabb = unit_box();
+        for rd in [Vec3::X, Vec3::new(1.0, -0.0, 0.0), Vec3::new(1.0, 0.0, -0.0)] {
+            let inv_rd = safe_inverse(rd);
+            // inside the y and z slabs
+            assert_eq!(aabb.intersect(Vec3::new(-1.0, 0.5, 0.5), inv_rd, f32::MAX), Some(1.0));
+            // outside either of them
+            assert_eq!(aabb.intersect(Vec3::new(-1.0, 1.5, 0.5), inv_rd, f32::MAX), None);
+            assert_eq!(aabb.intersect(Vec3::new(-1.0, 0.5, -0.5), inv_rd, f32::MAX), None);
+            // past the box along the ray
+            assert_eq!(aabb.intersect(Vec3::new(2.0, 0.5, 0.5), inv_rd, f32::MAX), None);
+        }
+        let down = safe_inverse(-Vec3::Z);
+        assert_eq!(aabb.intersect(Vec3::new(0.25, 0.75, 3.0), down, f32::MAX), Some(2.0));
+        assert_eq!(aabb.intersect(Vec3::new(0.25, -0.75, 3.0), down, f32::MAX), None);
+    }
+
+    #[test]
+    fn degenerate_boxes_are_hit_across() {
+        // flat, along a line and a single point
+        let flat = Aabb::new(Vec3::new(0.0, 0.0, 0.5), Vec3::new(1.0, 1.0, 0.5));
+        let line = Aabb::new(Vec3::new(0.0, 1.0, 1.0), Vec3::new(2.0, 1.0, 1.0));
+        let point = Aabb::new(Vec3::ONE, Vec3::ONE);
+        assert!(!flat.is_empty() && !line.is_empty() && !point.is_empty());
+        assert_eq!(flat.surface_area(), 2.0);
+        assert_eq!(line.surface_area(), 0.0);
+
+        let up = safe_inverse(Vec3::Z);
+        assert_eq!(flat.intersect(Vec3::new(0.5, 0.5, -1.0), up, f32::MAX), Some(1.5));
+        assert_eq!(flat.intersect(Vec3::new(1.5, 0.5, -1.0), up, f32::MAX), None);
+        let diagonal = safe_inverse(Vec3::new(0.0, 1.0, 1.0));
+        assert_eq!(line.intersect(Vec3::new(1.0, 0.0, 0.0), diagonal, f32::MAX), Some(1.0));
+        assert_eq!(point.intersect(Vec3::ZERO, safe_inverse(Vec3::ONE), f32::MAX), Some(1.0));
+    }
+
+    #[test]
+    fn empty_boxes_are_never_hit() {
+        let inverted = Aabb::new(Vec3::ONE, Vec3::ZERO);
+        let inverted_once = Aabb::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(1.0, 0.0, 1.0));
+        for aabb in [Aabb::EMPTY, inverted, inverted_once] {
+            assert!(aabb.is_empty());
+            assert_eq!(aabb.surface_area(), 0.0);
+            // the slabs alone would take the inverted boxes for the unit box
+            assert_eq!(
+                aabb.intersect(Vec3::new(-1.0, 0.5, 0.5), safe_inverse(Vec3::X), f32::MAX),
+                None
+            );
+            assert_eq!(aabb.intersect(Vec3::splat(0.5), Vec3::ONE, f32::MAX), None);
+        }
+
+        let aabb = unit_box();
+        assert!(Aabb::EMPTY.union(aabb) == aabb);
+        assert!(aabb.intersection(Aabb::new(Vec3::splat(2.0), Vec3::splat(3.0))) == Aabb::EMPTY);
+        let mut grown = Aabb::EMPTY;
+        grown.grow(Vec3::ONE);
+        assert!(grown == Aabb::new(Vec3::ONE, Vec3::ONE));
+    }
+
+    #[test]
+    fn rays_starting_inside_enter_behind_their_origin() {
+        let aabb = unit_box();
+        let inv_rd = safe_inverse(Vec3::X);
+        assert_eq!(aabb.intersect(Vec3::splat(0.5), inv_rd, f32::MAX), Some(-0.5));
+        // still counts as a hit before any `t_max`, the ray leaves the box in front of it
+        assert_eq!(aabb.intersect(Vec3::splat(0.5), inv_rd, 1e-6), Some(-0.5));
+        // on the far face the box lies behind the ray
+        assert_eq!(aabb.intersect(Vec3::new(1.0, 0.5, 0.5), inv_rd, f32::MAX), None);
+    }
+
+    #[test]
+    fn hits_from_t_max_on_are_dropped() {
+        let aabb = unit_box();
+        let (ro, inv_rd) = (Vec3::new(-1.0, 0.5, 0.5), safe_inverse(Vec3::X));
+        assert_eq!(aabb.intersect(ro, inv_rd, 1.5), Some(1.0));
+        assert_eq!(aabb.intersect(ro, inv_rd, 1.0), None);
+        assert_eq!(aabb.intersect(ro, inv_rd, 0.5), None);
+        // a direction twice as long halves the distances
+        assert_eq!(aabb.intersect(ro, safe_inverse(Vec3::X * 2.0), 0.75), Some(0.5));
+    }
+}
@@ -0,0 +1,162 @@
+use {
+    glam::{Mat4, Quat, Vec3},
+    russimp::{
+        animation::{NodeAnim, QuatKey, VectorKey},
+        node::Node,
+        scene::Scene,
+    },
+};
+
+// assimp leaves it at 0 when the file doesn't say
+const DEFAULT_TICKS_PER_SECOND: f64 = 25.0;
+
+// Node transforms of the first animation in a scene file, sampled to move the instances the
+// nodes place. Cameras and lights stay where they are at the start.
+pub struct Animation {
+    // the scene root placed by `root` first, then in the order `World::from_scene` walks
+    // them, parents before their children
+    nodes: Vec<AnimatedNode>,
+    channels: Vec<Channel>,
+    // node of every instance, in the order they were placed
+    instance_nodes: Vec<usize>,
+    // in seconds, sampling wraps around after it
+    duration: f32,
+}
+
+struct AnimatedNode {
+    parent: Option<usize>,
+    local: Mat4,
+    channel: Option<usize>,
+}
+
+// Keys in seconds, an empty list keeps the node's own part of the transform
+struct Channel {
+    positions: Vec<(f32, Vec3)>,
+    rotations: Vec<(f32, Quat)>,
+    scales: Vec<(f32, Vec3)>,
+}
+
+impl Animation {
+    // None for static scenes, so they never sample anything. `root` places the root node.
+    pub fn new(scene: &Scene, root: Mat4) -> Option<Self> {
+        let animation = scene.animations.first()?;
+        let ticks_per_second = match animation.ticks_per_second {
+            ticks if ticks > 0.0 => ticks,
+            _ => DEFAULT_TICKS_PER_SECOND,
+        };
+
+        // `root` goes first, as the parent of the root node
+        let mut nodes = vec![AnimatedNode { parent: None, local: root, channel: None }];
+        let mut names = vec![None];
+        let mut instance_nodes = Vec::new();
+        walk(scene.root.as_ref()?, 0, &mut nodes, &mut names, &mut instance_nodes);
+
+        let mut channels = Vec::new();
+        for channel in &animation.channels {
+            if let Some(node) = names.iter().position(|name| name.as_ref() == Some(&channel.name)) {
+                nodes[node].channel = Some(channels.len());
+                channels.push(Channel::new(channel, ticks_per_second));
+            }
+        }
+        if channels.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            nodes,
+            channels,
+            instance_nodes,
+            duration: (animation.duration / ticks_per_second) as f32,
+        })
+    }
+
+    // Drops the instances `World::from_scene` doesn't place after all, `kept` has a flag for
+    // every instance the nodes place
+    pub fn retain_instances(&mut self, kept: &[bool]) {
+        let mut kept = kept.iter();
+        self.instance_nodes.retain(|_| *kept.next().unwrap());
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.duration
+    }
+
+    // World transform of every instance at `time` seconds, in the order they were placed
+    pub fn sample(&self, time: f32) -> Vec<Mat4> {
+        let time = if self.duration > 0.0 { time.rem_euclid(self.duration) } else { 0.0 };
+        let mut globals = Vec::<Mat4>::with_capacity(self.nodes.len());
+        for node in &self.nodes {
+            let local = match node.channel {
+                Some(channel) => self.channels[channel].sample(time, node.local),
+                None => node.local,
+            };
+            let global = match node.parent {
+                Some(parent) => globals[parent] * local,
+                None => local,
+            };
+            globals.push(global);
+        }
+        self.instance_nodes.iter().map(|&node| globals[node]).collect()
+    }
+}
+
+impl Channel {
+    fn new(channel: &NodeAnim, ticks_per_second: f64) -> Self {
+        let seconds = |time: f64| (time / ticks_per_second) as f32;
+        let vector =
+            |key: &VectorKey| (seconds(key.time), Vec3::new(key.value.x, key.value.y, key.value.z));
+        let quat = |key: &QuatKey| {
+            let value = Quat::from_xyzw(key.value.x, key.value.y, key.value.z, key.value.w);
+            (seconds(key.time), value.normalize())
+        };
+        Self {
+            positions: channel.position_keys.iter().map(vector).collect(),
+            rotations: channel.rotation_keys.iter().map(quat).collect(),
+            scales: channel.scaling_keys.iter().map(vector).collect(),
+        }
+    }
+
+    fn sample(&self, time: f32, rest: Mat4) -> Mat4 {
+        let (scale, rotation, translation) = rest.to_scale_rotation_translation();
+        Mat4::from_scale_rotation_translation(
+            interpolate(&self.scales, time, Vec3::lerp).unwrap_or(scale),
+            interpolate(&self.rotations, time, Quat::slerp).unwrap_or(rotation),
+            interpolate(&self.positions, time, Vec3::lerp).unwrap_or(translation),
+        )
+    }
+}
+
+// Linear between the keys around `time`, held before the first and after the last one
+fn interpolate<T: Copy>(keys: &[(f32, T)], time: f32, lerp: fn(T, T, f32) -> T) -> Option<T> {
+    let next = keys.partition_point(|&(key, _)| key <= time);
+    match (next.checked_sub(1).map(|previous| keys[previous]), keys.get(next)) {
+        (Some((start, a)), Some(&(end, b))) => Some(lerp(a, b, (time - start) / (end - start))),
+        (Some((_, a)), None) => Some(a),
+        (None, Some(&(_, b))) => Some(b),
+        (None, None) => None,
+    }
+}
+
+// Same order as `walk_node_graph` in `World::from_scene`, so instances match up
+fn walk(
+    node: &Node,
+    parent: usize,
+    nodes: &mut Vec<AnimatedNode>,
+    names: &mut Vec<Option<String>>,
+    instance_nodes: &mut Vec<usize>,
+) {
+    let index = nodes.len();
+    let t = &node.transformation;
+    let local = Mat4::from_cols_array_2d(&[
+        [t.a1, t.b1, t.c1, t.d1],
+        [t.a2, t.b2, t.c2, t.d2],
+        [t.a3, t.b3, t.c3, t.d3],
+        [t.a4, t.b4, t.c4, t.d4],
+    ]);
+    nodes.push(AnimatedNode { parent: Some(parent), local, channel: None });
+    names.push(Some(node.name.clone()));
+    instance_nodes.extend(node.meshes.iter().map(|_| index));
+    for child in node.children.borrow().iter() {
+        walk(child, index, nodes, names, instance_nodes);
+    }
+}
@@ -0,0 +1,274 @@
+use {
+    crate::scene::{Axis, Handedness, SceneOptions},
+    glam::Vec3,
+    shared::Restir,
+    std::{path::PathBuf, time::Duration},
+    wgpu::PresentMode,
+};
+
+pub const USAGE: &str = "\
+usage: racist [scene] [options]
+
+  scene                        glTF or other scene file, PBRTest.glb by default
+  --width <pixels>             window and trace width, 1400 by default
+  --height <pixels>            window and trace height, 1400 by default
+  --max-bounces <count>        longest path, 4 bounces by default
+  --camera <index>             start from another camera of the scene than the first one
+  --camera <x,y,z,yaw,pitch>   start from a position, yaw and pitch in degrees
+  --env <path>                 environment map, an equirectangular HDR image
+  --sensitivity <factor>       mouse look speed, 1 by default
+  --max-dispatch-ms <ms>       longest a single GPU dispatch should take, 50 by default
+  --present <mode>             fifo, mailbox or immediate, fifo waits for vsync and is the
+                               default, the others fall back to it where unsupported
+  --render-scale <factor>      trace resolution relative to the window, 0.25 to 2, 1 by default
+  --kernel <path>              trace with a SPIR-V kernel built from kernels/ instead of the
+                               built-in one, reloaded whenever the file changes
+  --wavefront                  trace bounce by bounce with paths queued between passes and
+                               shaded sorted by material, instead of a thread per path
+  --restir <mode>              off, temporal or spatial, resamples many emissive triangles for
+                               the camera hits, reusing the pixel's previous samples and with
+                               spatial also its neighbours', off by default
+  --adaptive <error>           stop tracing pixels whose relative standard error is below
+                               this, 0.02 for example, off by default
+  --adaptive-warmup <samples>  samples every pixel gets before --adaptive, 16 by default
+  --up <x|y|z>                 up axis of the scene file, z by default
+  --left-handed                the scene file is left-handed
+  --scale <factor>             scene units to meters
+  --bounce <instance>          animate an instance to exercise BVH refitting
+  --animate <samples>          render the scene's animation to numbered images
+  --bench <samples>            render a fixed number of samples without a window and report
+                               the throughput, the last line is key=value pairs for scripts
+  --target-samples <count>     stop tracing at this many samples until the next edit, the
+                               window title estimates the time left until then
+  --save-when-done             save the image once --target-samples is reached
+  --checkpoint <path>          where B and --checkpoint-every save the accumulation,
+                               racist.ckpt by default
+  --checkpoint-every <minutes> save the accumulation periodically
+  --resume <path>              continue the accumulation of a checkpoint of the same scene,
+                               camera and resolution
+  --headless                   render without a window, needs --samples and --output
+  --samples <count>            samples of a headless render
+  --output <path>              image a headless render is written to, by extension
+  --help                       print this";
+
+// Bounds of the trace resolution relative to the window's
+pub const MIN_RENDER_SCALE: f32 = 0.25;
+pub const MAX_RENDER_SCALE: f32 = 2.0;
+
+pub enum StartCamera {
+    // one of the scene's cameras
+    Index(usize),
+    // in radians
+    Pose { position: Vec3, yaw: f32, pitch: f32 },
+}
+
+pub struct Checkpoints {
+    pub path: PathBuf,
+    pub every: Option<Duration>,
+    pub resume: Option<PathBuf>,
+}
+
+pub struct Headless {
+    pub samples: usize,
+    pub output: PathBuf,
+}
+
+// The command line, defaults already filled in
+pub struct Args {
+    pub scene: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    pub max_bounces: Option<u32>,
+    pub camera: Option<StartCamera>,
+    pub env: Option<PathBuf>,
+    pub sensitivity: f64,
+    pub max_dispatch: Duration,
+    pub render_scale: f32,
+    pub present_mode: PresentMode,
+    pub kernel: Option<PathBuf>,
+    pub wavefront: bool,
+    pub restir: Restir,
+    // relative error, 0 traces every pixel of every sample
+    pub adaptive: f32,
+    pub adaptive_warmup: Option<u32>,
+    pub scene_options: SceneOptions,
+    pub bounce: Option<usize>,
+    pub animate: Option<usize>,
+    pub bench: Option<usize>,
+    pub target_samples: Option<usize>,
+    pub save_when_done: bool,
+    pub checkpoints: Checkpoints,
+    pub headless: Option<Headless>,
+    pub help: bool,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            scene: PathBuf::from("PBRTest.glb"),
+            width: 1400,
+            height: 1400,
+            max_bounces: None,
+            camera: None,
+            env: None,
+            sensitivity: 1.0,
+            max_dispatch: Duration::from_millis(50),
+            render_scale: 1.0,
+            present_mode: PresentMode::Fifo,
+            kernel: None,
+            wavefront: false,
+            restir: Restir::Off,
+            adaptive: 0.0,
+            adaptive_warmup: None,
+            scene_options: SceneOptions::default(),
+            bounce: None,
+            animate: None,
+            bench: None,
+            target_samples: None,
+            save_when_done: false,
+            checkpoints: Checkpoints {
+                path: PathBuf::from("racist.ckpt"),
+                every: None,
+                resume: None,
+            },
+            headless: None,
+            help: false,
+        }
+    }
+}
+
+impl Args {
+    // Errors describe the offending argument, print `USAGE` after them
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Self::default();
+        let (mut scene, mut headless, mut samples, mut output) = (None, false, None, None);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("`{arg}` expects a value"));
+            match arg.as_str() {
+                "--width" => parsed.width = pixels(&arg, &value()?)?,
+                "--height" => parsed.height = pixels(&arg, &value()?)?,
+                "--max-bounces" => parsed.max_bounces = Some(number(&arg, &value()?)?),
+                "--camera" => parsed.camera = Some(camera(&value()?)?),
+                "--env" => parsed.env = Some(PathBuf::from(value()?)),
+                "--sensitivity" => parsed.sensitivity = number(&arg, &value()?)?,
+                "--max-dispatch-ms" => {
+                    parsed.max_dispatch = Duration::from_millis(number(&arg, &value()?)?)
+                }
+                "--present" => {
+                    parsed.present_mode = match value()?.as_str() {
+                        "fifo" => PresentMode::Fifo,
+                        "mailbox" => PresentMode::Mailbox,
+                        "immediate" => PresentMode::Immediate,
+                        _ => return Err("`--present` expects fifo, mailbox or immediate".into()),
+                    }
+                }
+                "--render-scale" => parsed.render_scale = render_scale(&value()?)?,
+                "--kernel" => parsed.kernel = Some(PathBuf::from(value()?)),
+                "--wavefront" => parsed.wavefront = true,
+                "--restir" => {
+                    parsed.restir = match value()?.as_str() {
+                        "off" => Restir::Off,
+                        "temporal" => Restir::Temporal,
+                        "spatial" => Restir::Spatiotemporal,
+                        _ => return Err("`--restir` expects off, temporal or spatial".into()),
+                    }
+                }
+                "--adaptive" => parsed.adaptive = adaptive(&value()?)?,
+                "--adaptive-warmup" => parsed.adaptive_warmup = Some(number(&arg, &value()?)?),
+                "--up" => {
+                    parsed.scene_options.up_axis = match value()?.as_str() {
+                        "x" => Axis::X,
+                        "y" => Axis::Y,
+                        "z" => Axis::Z,
+                        _ => return Err("`--up` expects x, y or z".into()),
+                    }
+                }
+                "--left-handed" => parsed.scene_options.handedness = Handedness::Left,
+                "--scale" => parsed.scene_options.scale = number(&arg, &value()?)?,
+                "--bounce" => parsed.bounce = Some(number(&arg, &value()?)?),
+                "--animate" => parsed.animate = Some(number(&arg, &value()?)?),
+                "--bench" | "--benchmark" => parsed.bench = Some(number(&arg, &value()?)?),
+                "--target-samples" => parsed.target_samples = Some(number(&arg, &value()?)?),
+                "--save-when-done" => parsed.save_when_done = true,
+                "--checkpoint" => parsed.checkpoints.path = PathBuf::from(value()?),
+                "--checkpoint-every" => parsed.checkpoints.every = Some(minutes(&arg, &value()?)?),
+                "--resume" => parsed.checkpoints.resume = Some(PathBuf::from(value()?)),
+                "--headless" => headless = true,
+                "--samples" => samples = Some(number(&arg, &value()?)?),
+                "--output" => output = Some(PathBuf::from(value()?)),
+                "--help" | "-h" => parsed.help = true,
+                _ if arg.starts_with('-') => return Err(format!("unknown option `{arg}`")),
+                _ if scene.is_some() => return Err(format!("unexpected argument `{arg}`")),
+                _ => scene = Some(PathBuf::from(arg)),
+            }
+        }
+
+        if let Some(scene) = scene {
+            parsed.scene = scene;
+        }
+        if headless {
+            parsed.headless = Some(Headless {
+                samples: samples.ok_or("`--headless` expects `--samples <count>`")?,
+                output: output.ok_or("`--headless` expects `--output <path>`")?,
+            });
+        } else if samples.is_some() || output.is_some() {
+            return Err("`--samples` and `--output` only apply with `--headless`".into());
+        }
+        if parsed.save_when_done && parsed.target_samples.is_none() {
+            return Err("`--save-when-done` expects `--target-samples <count>`".into());
+        }
+        Ok(parsed)
+    }
+}
+
+fn number<T: std::str::FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("`{arg}` expects a number, not `{value}`"))
+}
+
+fn pixels(arg: &str, value: &str) -> Result<u32, String> {
+    match number(arg, value)? {
+        0 => Err(format!("`{arg}` expects at least one pixel")),
+        pixels => Ok(pixels),
+    }
+}
+
+fn minutes(arg: &str, value: &str) -> Result<Duration, String> {
+    match number::<f64>(arg, value)? {
+        minutes if minutes > 0.0 && minutes.is_finite() => {
+            Ok(Duration::from_secs_f64(minutes * 60.0))
+        }
+        _ => Err(format!("`{arg}` expects a positive number of minutes, not `{value}`")),
+    }
+}
+
+fn render_scale(value: &str) -> Result<f32, String> {
+    match number("--render-scale", value)? {
+        scale if (MIN_RENDER_SCALE..=MAX_RENDER_SCALE).contains(&scale) => Ok(scale),
+        _ => Err(format!(
+            "`--render-scale` expects {MIN_RENDER_SCALE} to {MAX_RENDER_SCALE}, not `{value}`"
+        )),
+    }
+}
+
+fn adaptive(value: &str) -> Result<f32, String> {
+    match number("--adaptive", value)? {
+        error if error >= 0.0 && f32::is_finite(error) => Ok(error),
+        _ => Err(format!("`--adaptive` expects a relative error of 0 or more, not `{value}`")),
+    }
+}
+
+// An index, or a position with yaw and pitch in degrees
+fn camera(value: &str) -> Result<StartCamera, String> {
+    if let Ok(index) = value.parse() {
+        return Ok(StartCamera::Index(index));
+    }
+    let pose: Result<Vec<f32>, _> = value.split(',').map(|part| part.trim().parse()).collect();
+    match pose.as_deref() {
+        Ok(&[x, y, z, yaw, pitch]) => Ok(StartCamera::Pose {
+            position: Vec3::new(x, y, z),
+            yaw: yaw.to_radians(),
+            pitch: pitch.to_radians(),
+        }),
+        _ => Err(format!("`--camera` expects an index or x,y,z,yaw,pitch, not `{value}`")),
+    }
+}
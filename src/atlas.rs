@@ -3,9 +3,15 @@ use std::{collections::VecDeque, num::NonZeroU32};
 use {
     fast_image_resize as fir,
     glam::Vec4,
-    image::{DynamicImage, GenericImage},
+    image::{DynamicImage, GenericImage, GenericImageView},
+    shared::ATLAS_MIP_LEVELS,
 };
 
+// Texels around every atlas entry repeating its outermost ones. The kernel already keeps
+// lookups half a texel inside each level, the gutter covers the filtering precision of the
+// hardware on top of that.
+const GUTTER: u32 = 2;
+
 #[derive(Clone, Copy)]
 pub struct PackingRect {
     pub x: u32,
@@ -18,22 +24,37 @@ impl PackingRect {
     pub fn to_uvst(&self, atlas_width: u32, atlas_height: u32) -> Vec4 {
         Vec4::new(
             self.x as f32 / atlas_width as f32,
-            self.y as f32 / atlas_width as f32,
+            self.y as f32 / atlas_height as f32,
             self.width as f32 / atlas_width as f32,
             self.height as f32 / atlas_height as f32,
         )
     }
 }
 
-pub fn pack_textures(
-    textures: &[DynamicImage],
-    atlas_width: u32,
-    atlas_height: u32,
-) -> (DynamicImage, Vec<Vec4>) {
-    let root = PackingRect { x: 0, y: 0, width: atlas_width, height: atlas_height };
+// Clamp-to-edge copy of `inner` into the rest of `leaf`
+fn fill_gutter(atlas: &mut DynamicImage, leaf: PackingRect, inner: PackingRect) {
+    let (inner_max_x, inner_max_y) = (inner.x + inner.width - 1, inner.y + inner.height - 1);
+    for y in leaf.y..leaf.y + leaf.height {
+        let inside_rows = (inner.y..=inner_max_y).contains(&y);
+        for x in leaf.x..leaf.x + leaf.width {
+            if inside_rows && (inner.x..=inner_max_x).contains(&x) {
+                continue;
+            }
+            let source = (x.clamp(inner.x, inner_max_x), y.clamp(inner.y, inner_max_y));
+            atlas.put_pixel(x, y, atlas.get_pixel(source.0, source.1));
+        }
+    }
+}
+
+// Smallest atlas tried, doubled until every texture fits or the device limit is reached
+const MIN_ATLAS_SIZE: u32 = 4096;
+
+// Quadtree leaves of a square atlas, at least `count` of them with the largest first
+fn leaves(atlas_size: u32, count: usize) -> Vec<PackingRect> {
+    let root = PackingRect { x: 0, y: 0, width: atlas_size, height: atlas_size };
     let mut queue = VecDeque::from([root]);
 
-    while queue.len() <= textures.len() {
+    while queue.len() <= count {
         let node = queue.pop_front().expect("Texture packing queue was empty.");
         let half_width = node.width / 2;
         let half_height = node.height / 2;
@@ -62,36 +83,119 @@ pub fn pack_textures(
 
     let mut leafs = queue.into_iter().collect::<Vec<_>>();
     leafs.sort_by(|a, b| b.width.cmp(&a.width));
-    leafs.truncate(textures.len());
+    leafs.truncate(count);
+    leafs
+}
+
+// Side of the smallest leaf holding the texture and its mip column without downscaling
+fn required_leaf_size(texture: &DynamicImage) -> u32 {
+    let width = (texture.width() * 3).div_ceil(2);
+    width.max(texture.height()) + 2 * GUTTER
+}
+
+// Smallest leaf with room for a texel of the base level and its mip column inside the gutter
+const MIN_LEAF_SIZE: u32 = 2 * GUTTER + 3;
+
+// Packs the textures into a square atlas of at least `MIN_ATLAS_SIZE` and at most
+// `max_size` texels. Larger textures get larger leaves, they are downscaled only when
+// even the largest atlas can't hold all of them at full resolution. Fails with the atlas
+// size that would be needed when there are too many textures to give each a usable leaf.
+pub fn pack_textures(
+    textures: &[DynamicImage],
+    max_size: u32,
+) -> Result<(DynamicImage, Vec<Vec4>), u32> {
+    // texture indices by required size, largest first, matching the order of the leaves
+    let mut order = (0..textures.len()).collect::<Vec<_>>();
+    order.sort_by_cached_key(|&i| std::cmp::Reverse(required_leaf_size(&textures[i])));
+
+    let fits = |atlas_size: u32| {
+        let leafs = leaves(atlas_size, textures.len());
+        order.iter().zip(&leafs).all(|(&i, leaf)| leaf.width >= required_leaf_size(&textures[i]))
+    };
+    let max_size = max_size.max(MIN_ATLAS_SIZE);
+    let usable = |atlas_size: u32| {
+        leaves(atlas_size, textures.len()).last().map_or(true, |leaf| leaf.width >= MIN_LEAF_SIZE)
+    };
+    if !usable(max_size) {
+        let mut needed = max_size;
+        while !usable(needed) && needed < 1 << 30 {
+            needed *= 2;
+        }
+        return Err(needed);
+    }
+    let mut atlas_size = MIN_ATLAS_SIZE;
+    while !fits(atlas_size) && atlas_size * 2 <= max_size {
+        atlas_size *= 2;
+    }
+    if !fits(atlas_size) {
+        println!(
+            "textures don't fit a {atlas_size}x{atlas_size} atlas, the largest are downscaled"
+        );
+    }
+    let (atlas_width, atlas_height) = (atlas_size, atlas_size);
+
+    let mut leafs = vec![PackingRect { x: 0, y: 0, width: 0, height: 0 }; textures.len()];
+    for (&i, leaf) in order.iter().zip(leaves(atlas_size, textures.len())) {
+        leafs[i] = leaf;
+    }
 
     let mut resizer = fir::Resizer::new(fir::ResizeAlg::Convolution(fir::FilterType::Lanczos3));
     let mut atlas = DynamicImage::new_rgba8(atlas_width, atlas_height);
+    let mut rects = Vec::with_capacity(leafs.len());
     for (i, leaf) in leafs.iter().enumerate() {
         let tex = &textures[i];
-        let width = NonZeroU32::new(tex.width()).unwrap();
-        let height = NonZeroU32::new(tex.height()).unwrap();
-
-        let desired_width = NonZeroU32::new(leaf.width).unwrap();
-        let desired_height = NonZeroU32::new(leaf.height).unwrap();
+        // empty textures fail to load with `SceneLoadError::TextureDecode`
+        let width = NonZeroU32::new(tex.width()).expect("texture has no texels");
+        let height = NonZeroU32::new(tex.height()).expect("texture has no texels");
         let fir_img_src =
             fir::Image::from_vec_u8(width, height, tex.to_rgba8().into_raw(), fir::PixelType::U8x4)
                 .unwrap();
-        let mut fir_img_dst = fir::Image::new(desired_width, desired_height, fir::PixelType::U8x4);
-        resizer.resize(&fir_img_src.view(), &mut fir_img_dst.view_mut()).unwrap();
-
-        let resized_tex = DynamicImage::ImageRgba8(
-            image::RgbaImage::from_raw(
-                desired_width.get(),
-                desired_height.get(),
-                fir_img_dst.into_vec(),
-            )
-            .unwrap(),
-        );
-        atlas.copy_from(&resized_tex.flipv(), leaf.x, leaf.y).unwrap();
-    }
 
-    atlas.save("atlas_debug.png").unwrap();
+        // The base level takes the left 2/3 of the leaf, mips are stacked in the column to
+        // its right: level k sits at (x + w, y + h * (1 - 2^(1 - k))) with size (w, h) / 2^k.
+        // The kernel derives mip rects from the base rect the same way.
+        let inner = PackingRect {
+            x: leaf.x + GUTTER,
+            y: leaf.y + GUTTER,
+            width: leaf.width.saturating_sub(2 * GUTTER).max(1),
+            height: leaf.height.saturating_sub(2 * GUTTER).max(1),
+        };
+        let base = PackingRect { width: (inner.width * 2 / 3).max(1), ..inner };
+        for level in 0..=ATLAS_MIP_LEVELS {
+            let rect = if level == 0 {
+                base
+            } else {
+                PackingRect {
+                    x: base.x + base.width,
+                    y: base.y + base.height - (base.height >> (level - 1)),
+                    width: base.width >> level,
+                    height: base.height >> level,
+                }
+            };
+            let (Some(desired_width), Some(desired_height)) =
+                (NonZeroU32::new(rect.width), NonZeroU32::new(rect.height))
+            else {
+                break;
+            };
+
+            let mut fir_img_dst =
+                fir::Image::new(desired_width, desired_height, fir::PixelType::U8x4);
+            resizer.resize(&fir_img_src.view(), &mut fir_img_dst.view_mut()).unwrap();
+
+            let resized_tex = DynamicImage::ImageRgba8(
+                image::RgbaImage::from_raw(
+                    desired_width.get(),
+                    desired_height.get(),
+                    fir_img_dst.into_vec(),
+                )
+                .unwrap(),
+            );
+            atlas.copy_from(&resized_tex.flipv(), rect.x, rect.y).unwrap();
+        }
+        fill_gutter(&mut atlas, *leaf, inner);
+        rects.push(base);
+    }
 
-    let sts = leafs.iter().map(|x| x.to_uvst(atlas_width, atlas_height)).collect::<Vec<_>>();
-    (atlas, sts)
+    let sts = rects.iter().map(|x| x.to_uvst(atlas_width, atlas_height)).collect::<Vec<_>>();
+    Ok((atlas, sts))
 }
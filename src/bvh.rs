@@ -1,324 +1,1242 @@
 use {
-    glam::{UVec4, Vec3, Vec4, Vec4Swizzles},
-    shared::BVHNode,
+    glam::{BVec3, Mat4, UVec4, Vec3, Vec4, Vec4Swizzles},
+    shared::{
+        intersect_triangle, safe_inverse, Aabb, BVHNode, InstanceData, PerVertexData,
+        BVH_STACK_SIZE,
+    },
+    std::mem,
 };
 
-trait BHVNodeExt {
-    fn area(&self) -> f32;
-    fn enc(&mut self, point: Vec3);
-    fn enc_node(&mut self, node: BVHNode);
+use super::{compute::FW, gpu::GpuBuffer};
+
+#[derive(Clone)]
+pub struct BVH {
+    pub nodes: Vec<BVHNode>,
+    // parent of every node, `u32::MAX` for the root
+    pub parents: Vec<u32>,
+    // longest root to leaf path, counting both ends
+    pub depth: usize,
+    // `sah_cost` right after the build, refits are measured against it
+    pub build_cost: f32,
 }
 
-impl BHVNodeExt for BVHNode {
-    fn area(&self) -> f32 {
-        let extent = self.aabb_max() - self.aabb_min();
-        extent.x * extent.y + extent.y * extent.z + extent.z * extent.x
+impl BVH {
+    // Expected cost of tracing a ray that hits the root, every node it enters costs one box
+    // test, every leaf one test per triangle
+    pub fn sah_cost(&self) -> f32 {
+        let Some(root) = self.nodes.first() else {
+            return 0.0;
+        };
+        let cost = self
+            .nodes
+            .iter()
+            .map(|node| node.aabb().surface_area() * node.triangle_count().max(1) as f32)
+            .sum::<f32>();
+        cost / root.aabb().surface_area()
     }
 
-    fn enc(&mut self, point: Vec3) {
-        self.set_aabb_min(self.aabb_min().min(point));
-        self.set_aabb_max(self.aabb_max().max(point));
+    // Recomputes every box from moved vertices while keeping the tree as it was built.
+    // Returns how many times worse `sah_cost` got since then, callers should rebuild once
+    // that grows too large. A refit can't redo the clipping of spatial splits, so their leaves
+    // grow back to whole triangles and such a BVH can start well above 1.0 without any motion.
+    pub fn refit(&mut self, vertices: &[Vec4], indices: &[UVec4]) -> f32 {
+        if self.nodes.is_empty() {
+            return 1.0;
+        }
+        // children always come after their parent, so walking backwards updates them first
+        for node_idx in (0..self.nodes.len()).rev() {
+            let node = self.nodes[node_idx];
+            let mut aabb = Aabb::EMPTY;
+            if node.is_leaf() {
+                let first = node.first_triangle_index() as usize;
+                for triangle in &indices[first..first + node.triangle_count() as usize] {
+                    aabb.grow(vertices[triangle.x as usize].xyz());
+                    aabb.grow(vertices[triangle.y as usize].xyz());
+                    aabb.grow(vertices[triangle.z as usize].xyz());
+                }
+            } else {
+                let left_idx = node.left_node_index() as usize;
+                aabb = self.nodes[left_idx].aabb().union(self.nodes[left_idx + 1].aabb());
+            }
+            self.nodes[node_idx].set_aabb(aabb);
+        }
+
+        self.sah_cost() / self.build_cost
     }
 
-    fn enc_node(&mut self, node: BVHNode) {
-        if node.aabb_min().x == f32::MAX {
-            return;
+    // For BVHs built over a slice of the index buffer, makes leaves point into the whole buffer
+    pub fn offset_triangles(&mut self, triangle_offset: u32) {
+        for node in &mut self.nodes {
+            relocate(node, 0, triangle_offset);
+        }
+    }
+
+    // Checks the tree against the index buffer it was built over: children come after their
+    // parent and lie inside it, every reference belongs to exactly one leaf and touches that
+    // leaf's box (spatial splits clip triangles to their leaves, so it may stick out), and every
+    // vertex index is in bounds. Only an empty index buffer may have no nodes.
+    pub fn validate(&self, vertices: &[Vec4], indices: &[UVec4]) -> Result<(), String> {
+        if self.nodes.is_empty() {
+            return match indices.len() {
+                0 => Ok(()),
+                n => Err(format!("BVH has no nodes for {n} triangles")),
+            };
+        }
+
+        let mut visits = vec![0; self.nodes.len()];
+        let mut owners = vec![None; indices.len()];
+        for (node_idx, node) in self.nodes.iter().enumerate() {
+            if !node.is_leaf() {
+                let left_idx = node.left_node_index() as usize;
+                if left_idx <= node_idx || left_idx + 1 >= self.nodes.len() {
+                    return Err(format!(
+                        "node {node_idx} has children {left_idx} and {}, out of order or bounds",
+                        left_idx + 1
+                    ));
+                }
+                for child_idx in [left_idx, left_idx + 1] {
+                    visits[child_idx] += 1;
+                    if !node.aabb().contains(self.nodes[child_idx].aabb()) {
+                        return Err(format!(
+                            "node {child_idx} sticks out of its parent {node_idx}"
+                        ));
+                    }
+                }
+                continue;
+            }
+
+            let first = node.first_triangle_index() as usize;
+            let count = node.triangle_count() as usize;
+            if first + count > indices.len() {
+                return Err(format!(
+                    "leaf {node_idx} covers triangles {first}..{}, but there are only {}",
+                    first + count,
+                    indices.len()
+                ));
+            }
+            for (triangle_idx, triangle) in indices.iter().enumerate().skip(first).take(count) {
+                if let Some(owner) = owners[triangle_idx].replace(node_idx) {
+                    return Err(format!(
+                        "triangle {triangle_idx} belongs to both leaves {owner} and {node_idx}"
+                    ));
+                }
+                if triangle.truncate().max_element() as usize >= vertices.len() {
+                    return Err(format!(
+                        "triangle {triangle_idx} {triangle} indexes past {} vertices",
+                        vertices.len()
+                    ));
+                }
+                let mut aabb = Aabb::EMPTY;
+                aabb.grow(vertices[triangle.x as usize].xyz());
+                aabb.grow(vertices[triangle.y as usize].xyz());
+                aabb.grow(vertices[triangle.z as usize].xyz());
+                if aabb.intersection(node.aabb()).is_empty() {
+                    return Err(format!(
+                        "triangle {triangle_idx} lies outside its leaf {node_idx}"
+                    ));
+                }
+            }
+        }
+
+        if let Some(node_idx) = visits.iter().skip(1).position(|&visits| visits != 1) {
+            return Err(format!(
+                "node {} is reachable from {} parents",
+                node_idx + 1,
+                visits[node_idx + 1]
+            ));
+        }
+        if let Some(triangle_idx) = owners.iter().position(Option::is_none) {
+            return Err(format!("triangle {triangle_idx} belongs to no leaf"));
         }
-        self.set_aabb_min(self.aabb_min().min(node.aabb_min()));
-        self.set_aabb_max(self.aabb_max().max(node.aabb_max()));
+        Ok(())
+    }
+
+    // Cache entry for a BVH along with `indices`, the index buffer as the build left it.
+    // `geometry_hash` and `triangle_count` describe the input of the build and have to match
+    // again for `deserialize` to accept the entry.
+    pub fn serialize(
+        &self,
+        indices: &[UVec4],
+        geometry_hash: u64,
+        triangle_count: usize,
+    ) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(bytemuck::cast_slice(&self.nodes));
+        payload.extend_from_slice(bytemuck::cast_slice(indices));
+
+        let mut bytes = Vec::with_capacity(CACHE_HEADER_SIZE + payload.len());
+        let counts = [triangle_count, indices.len(), self.nodes.len(), self.depth];
+        for word in [CACHE_MAGIC, CACHE_VERSION].into_iter().chain(counts.map(|n| n as u32)) {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes.extend_from_slice(&geometry_hash.to_le_bytes());
+        bytes.extend_from_slice(&fnv1a(FNV_OFFSET, &payload).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        bytes
+    }
+
+    // Reads an entry written by `serialize` from the front of `bytes` and advances past it.
+    // Anything unexpected, from a stale hash to a truncated file, gives `None`.
+    pub fn deserialize(
+        bytes: &mut &[u8],
+        geometry_hash: u64,
+        triangle_count: usize,
+    ) -> Option<(Self, Vec<UVec4>)> {
+        let header = bytes.get(..CACHE_HEADER_SIZE)?;
+        let word = |i: usize| u32::from_le_bytes(header[i * 4..i * 4 + 4].try_into().unwrap());
+        let hash = |i: usize| u64::from_le_bytes(header[i * 4..i * 4 + 8].try_into().unwrap());
+        let [magic, version, triangles, references, nodes, depth] = [0, 1, 2, 3, 4, 5].map(word);
+        if magic != CACHE_MAGIC
+            || version != CACHE_VERSION
+            || triangles as usize != triangle_count
+            || hash(6) != geometry_hash
+            || (nodes == 0) != (references == 0)
+        {
+            return None;
+        }
+
+        let nodes_size = nodes as usize * mem::size_of::<BVHNode>();
+        let indices_size = references as usize * mem::size_of::<UVec4>();
+        let payload =
+            bytes.get(CACHE_HEADER_SIZE..CACHE_HEADER_SIZE + nodes_size + indices_size)?;
+        if fnv1a(FNV_OFFSET, payload) != hash(8) {
+            return None;
+        }
+        *bytes = &bytes[CACHE_HEADER_SIZE + payload.len()..];
+
+        // the payload may not be aligned for either type
+        let (nodes, indices) = payload.split_at(nodes_size);
+        let nodes = nodes
+            .chunks_exact(mem::size_of::<BVHNode>())
+            .map(bytemuck::pod_read_unaligned)
+            .collect::<Vec<BVHNode>>();
+        let indices = indices
+            .chunks_exact(mem::size_of::<UVec4>())
+            .map(bytemuck::pod_read_unaligned)
+            .collect::<Vec<UVec4>>();
+
+        // a matching hash doesn't make the tree sound, it could have been written that way
+        let leaves_in_bounds = nodes.iter().filter(|node| node.is_leaf()).all(|node| {
+            node.first_triangle_index() as usize + node.triangle_count() as usize <= indices.len()
+        });
+        if !leaves_in_bounds {
+            return None;
+        }
+        let mut bvh =
+            BVH { parents: parents(&nodes)?, nodes, depth: depth as usize, build_cost: 0.0 };
+        bvh.build_cost = bvh.sah_cost();
+        Some((bvh, indices))
     }
 }
 
-use {
-    super::compute::FW,
-    gpgpu::{BufOps, GpuBuffer},
-};
+// "BVHC", followed by the version, triangle, reference and node counts, the depth, the
+// geometry hash and a hash of the payload
+const CACHE_MAGIC: u32 = u32::from_le_bytes(*b"BVHC");
+// bump whenever the layout or the builder's output changes
+const CACHE_VERSION: u32 = 2;
+const CACHE_HEADER_SIZE: usize = 6 * 4 + 2 * 8;
 
-pub struct BVH {
-    pub nodes: Vec<BVHNode>,
+// https://en.wikipedia.org/wiki/Fowler%E2%80%93Noll%E2%80%93Vo_hash_function
+// Unlike `DefaultHasher` it stays the same across Rust releases, which matters on disk
+pub(crate) const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+pub(crate) fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
 }
 
-impl BVH {
-    pub fn into_gpu<'fw>(self) -> GpuBVH<'fw> {
-        let nodes_buffer = GpuBuffer::from_slice(&FW, &self.nodes);
-        GpuBVH { nodes: nodes_buffer }
+// Identifies the triangles a BVH is built over, for `BVH::serialize`
+pub fn geometry_hash(vertices: &[Vec4], indices: &[UVec4]) -> u64 {
+    let hash = fnv1a(FNV_OFFSET, bytemuck::cast_slice(vertices));
+    fnv1a(hash, bytemuck::cast_slice(indices))
+}
+
+// Parent of every node, None unless every child comes after its only parent and lies inside
+// `nodes`, which is all traversal and `BVH::refit` need to terminate
+fn parents(nodes: &[BVHNode]) -> Option<Vec<u32>> {
+    let mut parents = vec![u32::MAX; nodes.len()];
+    for (node_idx, node) in nodes.iter().enumerate().filter(|(_, node)| !node.is_leaf()) {
+        let left_idx = node.left_node_index() as usize;
+        if left_idx <= node_idx
+            || left_idx + 1 >= nodes.len()
+            || parents[left_idx..=left_idx + 1] != [u32::MAX; 2]
+        {
+            return None;
+        }
+        parents[left_idx] = node_idx as u32;
+        parents[left_idx + 1] = node_idx as u32;
+    }
+    Some(parents)
+}
+
+// Moves a node `node_offset` further into the nodes buffer, or its triangles `triangle_offset`
+// further into the index buffer if it's a leaf
+fn relocate(node: &mut BVHNode, node_offset: u32, triangle_offset: u32) {
+    if node.is_leaf() {
+        node.set_first_triangle_index(node.first_triangle_index() + triangle_offset);
+    } else {
+        node.set_left_node_index(node.left_node_index() + node_offset);
+    }
+}
+
+// Nearest hit of `TLAS::intersect`
+#[derive(Clone, Copy, Debug)]
+pub struct Hit {
+    // into `TLAS::instances`
+    pub instance: usize,
+    // into the index buffer
+    pub triangle_index: u32,
+    pub triangle: UVec4,
+    // along the world space direction, which isn't renormalized in object space
+    pub distance: f32,
+    pub backface: bool,
+}
+
+// A bottom-level BVH per mesh under a top-level BVH over the instances placing them. The top
+// level goes through `BVHBuilder` too, every instance as a triangle spanning the diagonal of
+// its world space box, which has exactly the bounds and centroid of that box.
+#[derive(Clone)]
+pub struct TLAS {
+    // leaves point into `instances`
+    pub bvh: BVH,
+    // one per mesh, leaves point into the shared index buffer
+    pub blas: Vec<BVH>,
+    pub instances: Vec<InstanceData>,
+    // mesh placed by every instance
+    pub meshes: Vec<usize>,
+    // index of every instance into the ones passed to `new`
+    pub order: Vec<usize>,
+    corners: Vec<Vec4>,
+    boxes: Vec<UVec4>,
+}
+
+impl TLAS {
+    // `instances` pair a mesh with its placement, `blas_root` is filled in here
+    pub fn new(blas: Vec<BVH>, instances: Vec<(usize, InstanceData)>) -> Self {
+        let mut corners = Vec::with_capacity(instances.len() * 3);
+        for (mesh, instance) in &instances {
+            corners.extend(Self::corners(&blas[*mesh], instance));
+        }
+        let mut boxes = (0..instances.len() as u32)
+            .map(|i| UVec4::new(i * 3, i * 3 + 1, i * 3 + 2, i))
+            .collect::<Vec<_>>();
+        let bvh = BVHBuilder::new(&corners, &mut boxes).build();
+
+        // store instances in leaf order, so leaves can index them directly
+        let mut blas_root = bvh.nodes.len() as u32;
+        let blas_roots = blas
+            .iter()
+            .map(|blas| {
+                let root = blas_root;
+                blas_root += blas.nodes.len() as u32;
+                root
+            })
+            .collect::<Vec<_>>();
+        let order = boxes.iter().map(|reference| reference.w as usize).collect();
+        let (meshes, instances): (Vec<_>, Vec<_>) = boxes
+            .iter()
+            .map(|reference| {
+                let (mesh, mut instance) = instances[reference.w as usize];
+                instance.blas_root = blas_roots[mesh];
+                (mesh, instance)
+            })
+            .unzip();
+        let corners = boxes
+            .iter()
+            .flat_map(|reference| [reference.x, reference.y, reference.z])
+            .map(|corner| corners[corner as usize])
+            .collect();
+        let boxes = (0..instances.len() as u32)
+            .map(|i| UVec4::new(i * 3, i * 3 + 1, i * 3 + 2, i))
+            .collect();
+
+        Self { bvh, blas, instances, meshes, order, corners, boxes }
+    }
+
+    // World space box of an instance as min, max and center
+    fn corners(blas: &BVH, instance: &InstanceData) -> [Vec4; 3] {
+        let root = blas.nodes[0].aabb();
+        let mut aabb = Aabb::EMPTY;
+        for corner in 0..8 {
+            let mask = BVec3::new(corner & 1 != 0, corner & 2 != 0, corner & 4 != 0);
+            aabb.grow(instance.point_to_world(Vec3::select(mask, root.max, root.min)));
+        }
+        [aabb.min.extend(1.0), aabb.max.extend(1.0), aabb.centroid().extend(1.0)]
+    }
+
+    // Moves an instance and refits the top level over it, see `BVH::refit` for the result
+    pub fn place(&mut self, instance: usize, object_to_world: Mat4) -> f32 {
+        self.place_all([(instance, object_to_world)])
+    }
+
+    // Moves several instances with a single refit
+    pub fn place_all(&mut self, placements: impl IntoIterator<Item = (usize, Mat4)>) -> f32 {
+        for (instance, object_to_world) in placements {
+            let old = self.instances[instance];
+            let new = InstanceData::new(object_to_world, old.blas_root, old.material_offset);
+            self.instances[instance] = new;
+            let corners = Self::corners(&self.blas[self.meshes[instance]], &new);
+            self.corners[instance * 3..instance * 3 + 3].copy_from_slice(&corners);
+        }
+        self.bvh.refit(&self.corners, &self.boxes)
+    }
+
+    // Top level first, then every bottom level at its `blas_root`
+    fn flatten(&self) -> (Vec<BVHNode>, Vec<u32>) {
+        let mut nodes = self.bvh.nodes.clone();
+        let mut parents = self.bvh.parents.clone();
+        for blas in &self.blas {
+            let offset = nodes.len() as u32;
+            nodes.extend(blas.nodes.iter().map(|&node| {
+                let mut node = node;
+                relocate(&mut node, offset, 0);
+                node
+            }));
+            parents.extend(
+                blas.parents.iter().map(|&parent| parent.checked_add(offset).unwrap_or(u32::MAX)),
+            );
+        }
+        (nodes, parents)
+    }
+
+    // Nodes of the top level and every bottom level, as uploaded
+    pub fn node_count(&self) -> usize {
+        self.bvh.nodes.len() + self.blas.iter().map(|blas| blas.nodes.len()).sum::<usize>()
+    }
+
+    // The kernel's `BVHReference::intersect_nearest` on the CPU, for picking. `indices` and
+    // `per_vertex` are the buffers of the `World` the BVH was built for.
+    pub fn intersect(
+        &self,
+        indices: &[UVec4],
+        per_vertex: &[PerVertexData],
+        ro: Vec3,
+        rd: Vec3,
+    ) -> Option<Hit> {
+        let mut nearest = None;
+        traverse(&self.bvh.nodes, ro, rd, &mut nearest, |node, nearest| {
+            for instance in
+                node.first_triangle_index()..node.first_triangle_index() + node.triangle_count()
+            {
+                let data = &self.instances[instance as usize];
+                let (ro, rd) = data.ray_to_object(ro, rd);
+                let blas = &self.blas[self.meshes[instance as usize]];
+                traverse(&blas.nodes, ro, rd, nearest, |node, nearest| {
+                    for triangle_index in node.first_triangle_index()
+                        ..node.first_triangle_index() + node.triangle_count()
+                    {
+                        let triangle = indices[triangle_index as usize];
+                        let [a, b, c] = [triangle.x, triangle.y, triangle.z]
+                            .map(|i| per_vertex[i as usize].vertex.xyz());
+                        let (mut distance, mut backface) = (0.0, false);
+                        if intersect_triangle(ro, rd, a, b, c, &mut distance, &mut backface)
+                            && distance > 0.001
+                            && nearest.map_or(true, |hit: Hit| distance < hit.distance)
+                        {
+                            let instance = instance as usize;
+                            *nearest = Some(Hit {
+                                instance,
+                                triangle_index,
+                                triangle,
+                                distance,
+                                backface,
+                            });
+                        }
+                    }
+                });
+            }
+        });
+        nearest
+    }
+
+    pub fn upload<'fw>(&self) -> GpuBVH<'fw> {
+        let (nodes, parents) = self.flatten();
+        GpuBVH {
+            nodes: GpuBuffer::from_slice(&FW, &nodes),
+            parents: GpuBuffer::from_slice(&FW, &parents),
+            instances: GpuBuffer::from_slice(&FW, &self.instances),
+        }
+    }
+}
+
+// Calls `leaf` for the leaves under `nodes[0]` the ray enters before the nearest hit so far
+fn traverse(
+    nodes: &[BVHNode],
+    ro: Vec3,
+    rd: Vec3,
+    nearest: &mut Option<Hit>,
+    mut leaf: impl FnMut(&BVHNode, &mut Option<Hit>),
+) {
+    if nodes.is_empty() {
+        return;
+    }
+    let inv_rd = safe_inverse(rd);
+    let mut stack = vec![0];
+    while let Some(index) = stack.pop() {
+        let node = &nodes[index];
+        let max_distance = nearest.map_or(f32::MAX, |hit| hit.distance);
+        if node.aabb().intersect(ro, inv_rd, max_distance).is_none() {
+            continue;
+        }
+        if node.is_leaf() {
+            leaf(node, nearest);
+        } else {
+            stack.extend([node.right_node_index() as usize, node.left_node_index() as usize]);
+        }
     }
 }
 
 pub struct GpuBVH<'fw> {
     pub nodes: GpuBuffer<'fw, BVHNode>,
+    pub parents: GpuBuffer<'fw, u32>,
+    pub instances: GpuBuffer<'fw, InstanceData>,
+}
+
+impl GpuBVH<'_> {
+    // Uploads boxes and instances after refits, the topology and so the parents stay the same
+    pub fn update(&mut self, tlas: &TLAS) {
+        let (nodes, _) = tlas.flatten();
+        let _ = self.nodes.write(&nodes);
+        let _ = self.instances.write(&tlas.instances);
+    }
 }
 
 // https://github.com/pema99/rust-path-tracer/blob/master/src/bvh.rs
 pub struct BVHBuilder<'a> {
-    sah_samples: usize,
+    bins: usize,
+    alpha: Option<f32>,
     vertices: &'a [Vec4],
-    indices: &'a mut [UVec4],
-    centroids: Vec<Vec3>,
-    nodes: Vec<BVHNode>,
+    indices: &'a mut Vec<UVec4>,
 }
 
+// subtrees with at least this many triangles split their children on separate threads
+const PARALLEL_THRESHOLD: usize = 4096;
+
 impl<'a> BVHBuilder<'a> {
-    pub fn new(vertices: &'a [Vec4], indices: &'a mut [UVec4]) -> Self {
-        let centroids = indices
-            .iter()
-            .map(|ind| {
-                let v0 = vertices[ind.x as usize].xyz();
-                let v1 = vertices[ind.y as usize].xyz();
-                let v2 = vertices[ind.z as usize].xyz();
-                (v0 + v1 + v2) / 3.0
-            })
-            .collect::<Vec<_>>();
-        let nodes = vec![BVHNode::default(); indices.len() * 2 - 1];
+    pub fn new(vertices: &'a [Vec4], indices: &'a mut Vec<UVec4>) -> Self {
+        Self { bins: 32, alpha: None, vertices, indices }
+    }
 
-        Self { sah_samples: 128, vertices, indices, centroids, nodes }
+    pub fn bins(mut self, bins: usize) -> Self {
+        self.bins = bins.max(2);
+        self
     }
 
-    pub fn sah_samples(mut self, sah_samples: usize) -> Self {
-        self.sah_samples = sah_samples;
+    // Also consider splitting triangles between children (SBVH) wherever the best object
+    // split's children overlap by more than `alpha` of the root's area. Triangles can then be
+    // referenced by several leaves, so `build` grows the index buffer with duplicates.
+    // https://www.nvidia.com/docs/IO/77714/sbvh.pdf
+    pub fn spatial_splits(mut self, alpha: f32) -> Self {
+        self.alpha = Some(alpha);
         self
     }
 
-    fn update_node_aabb(&mut self, node_idx: usize) {
-        let node = &mut self.nodes[node_idx];
-        let mut aabb_min = Vec3::splat(f32::INFINITY);
-        let mut aabb_max = Vec3::splat(f32::NEG_INFINITY);
+    // Without any triangles the BVH has no nodes at all, there is nothing to traverse
+    pub fn build(&mut self) -> BVH {
+        if self.indices.is_empty() {
+            return BVH { nodes: Vec::new(), parents: Vec::new(), depth: 0, build_cost: 0.0 };
+        }
+        let mut binning = Binning { bins: self.bins, min_overlap: 0.0, vertices: self.vertices };
+
+        let mut root = BVHNode::default();
+        root.set_first_triangle_index(0);
+        root.set_triangle_count(self.indices.len() as u32);
+        binning.fit(&mut root, self.indices);
 
-        for i in 0..node.triangle_count() {
-            let triangle_index = (node.first_triangle_index() + i) as usize;
-            let index = self.indices[triangle_index];
-            let v0 = self.vertices[index.x as usize].xyz();
-            let v1 = self.vertices[index.y as usize].xyz();
-            let v2 = self.vertices[index.z as usize].xyz();
+        let mut nodes = Vec::with_capacity(self.indices.len() * 2 - 1);
+        nodes.push(root);
+        let depth = if let Some(alpha) = self.alpha {
+            binning.min_overlap = alpha * root.aabb().surface_area();
+            let references = self
+                .indices
+                .iter()
+                .map(|&triangle| Reference { triangle, aabb: binning.bounds(triangle) })
+                .collect();
+            let mut indices = Vec::with_capacity(self.indices.len());
+            let depth =
+                binning.subdivide_spatial(&mut root, 1, references, &mut nodes, &mut indices);
+            *self.indices = indices;
+            depth
+        } else {
+            let mut centroids = self
+                .indices
+                .iter()
+                .map(|&triangle| {
+                    let [v0, v1, v2] = binning.triangle(triangle);
+                    (v0 + v1 + v2) / 3.0
+                })
+                .collect::<Vec<_>>();
+            binning.subdivide(&mut root, 1, self.indices, &mut centroids, &mut nodes)
+        };
+        nodes[0] = root;
 
-            aabb_min = aabb_min.min(v0.min(v1).min(v2));
-            aabb_max = aabb_max.max(v0.max(v1).max(v2));
+        // Lay the triangles out in node order, so leaves stored next to each other also read
+        // neighbouring triangles and every leaf starts where the previous one ends
+        let mut indices = Vec::with_capacity(self.indices.len());
+        for node in nodes.iter_mut().filter(|node| node.is_leaf()) {
+            let first = node.first_triangle_index() as usize;
+            node.set_first_triangle_index(indices.len() as u32);
+            indices.extend_from_slice(&self.indices[first..first + node.triangle_count() as usize]);
         }
+        *self.indices = indices;
+
+        debug_assert!(depth <= BVH_STACK_SIZE, "BVH is deeper than the traversal stack");
+        let parents = parents(&nodes).expect("the builder lays out children after parents");
+        let mut bvh = BVH { parents, nodes, depth, build_cost: 0.0 };
+        bvh.build_cost = bvh.sah_cost();
+        bvh
+    }
+}
 
-        node.set_aabb_min(aabb_min);
-        node.set_aabb_max(aabb_max);
+// A triangle in a spatial split build, `aabb` covers only the part inside its node
+#[derive(Clone, Copy)]
+struct Reference {
+    triangle: UVec4,
+    aabb: Aabb,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Bin {
+    aabb: Aabb,
+    // triangles starting and ending in this bin, always the same for object splits
+    entries: u32,
+    exits: u32,
+}
+
+struct Split {
+    axis: usize,
+    position: f32,
+    cost: f32,
+    left: Aabb,
+    right: Aabb,
+}
+
+impl Split {
+    fn none() -> Self {
+        Self { axis: 0, position: 0.0, cost: f32::INFINITY, left: Aabb::EMPTY, right: Aabb::EMPTY }
     }
+}
 
-    fn calculate_surface_area_heuristic(&self, node: &BVHNode, axis: usize, split: f32) -> f32 {
-        let mut left_box = BVHNode::default();
-        let mut right_box = BVHNode::default();
-        let mut left_tri_count = 0;
-        let mut right_tri_count = 0;
+// Binned SAH over the triangles of a node. Object splits work on the slices covering exactly
+// that node, so disjoint subtrees can be built on different threads.
+struct Binning<'a> {
+    bins: usize,
+    // smallest overlap of object split children worth trying a spatial split for
+    min_overlap: f32,
+    vertices: &'a [Vec4],
+}
 
-        for i in 0..node.triangle_count() {
-            let triangle_index = (node.left_node_index() + i) as usize;
-            let index = self.indices[triangle_index];
-            let v0 = self.vertices[index.x as usize].xyz();
-            let v1 = self.vertices[index.y as usize].xyz();
-            let v2 = self.vertices[index.z as usize].xyz();
-            let centroid = self.centroids[triangle_index];
+impl Binning<'_> {
+    fn triangle(&self, index: UVec4) -> [Vec3; 3] {
+        [
+            self.vertices[index.x as usize].xyz(),
+            self.vertices[index.y as usize].xyz(),
+            self.vertices[index.z as usize].xyz(),
+        ]
+    }
 
-            if centroid[axis] < split {
-                left_box.enc(v0);
-                left_box.enc(v1);
-                left_box.enc(v2);
-                left_tri_count += 1;
-            } else {
-                right_box.enc(v0);
-                right_box.enc(v1);
-                right_box.enc(v2);
-                right_tri_count += 1;
-            }
+    fn bounds(&self, index: UVec4) -> Aabb {
+        let mut aabb = Aabb::EMPTY;
+        for vertex in self.triangle(index) {
+            aabb.grow(vertex);
         }
+        aabb
+    }
 
-        let result =
-            left_box.area() * left_tri_count as f32 + right_box.area() * right_tri_count as f32;
-        if result > 0.0 {
-            result
-        } else {
-            f32::INFINITY
-        }
+    fn fit(&self, node: &mut BVHNode, indices: &[UVec4]) {
+        node.set_aabb(
+            indices.iter().fold(Aabb::EMPTY, |aabb, &index| aabb.union(self.bounds(index))),
+        );
     }
 
-    #[allow(dead_code)]
-    fn find_best_split(&self, node: &BVHNode) -> (usize, f32, f32) {
-        // calculate the best split (SAH)
-        let mut best_axis = 0;
-        let mut best_split = 0.0;
-        let mut best_cost = f32::INFINITY;
-        for axis in 0..3 {
-            // find bounds of centroids in this node
-            let mut bounds_min = f32::INFINITY;
-            let mut bounds_max = f32::NEG_INFINITY;
-            for i in 0..node.triangle_count() {
-                let triangle_index = (node.first_triangle_index() + i) as usize;
-                let centroid = self.centroids[triangle_index];
-                bounds_min = bounds_min.min(centroid[axis]);
-                bounds_max = bounds_max.max(centroid[axis]);
+    // Cheapest plane between the bins, `lo` and `scale` map bin boundaries back to positions
+    fn sweep(&self, bins: &[Bin], axis: usize, lo: f32, scale: f32, best: &mut Split) {
+        // sweep from the right to get every plane's right side, then from the left
+        // evaluating SAH as the left side grows
+        let mut right_boxes = vec![Aabb::EMPTY; self.bins - 1];
+        let mut right_counts = vec![0; self.bins - 1];
+        let mut right_box = Aabb::EMPTY;
+        let mut right_sum = 0;
+        for i in (1..self.bins).rev() {
+            right_sum += bins[i].exits;
+            right_box = right_box.union(bins[i].aabb);
+            right_counts[i - 1] = right_sum;
+            right_boxes[i - 1] = right_box;
+        }
+
+        let mut left_box = Aabb::EMPTY;
+        let mut left_sum = 0;
+        for i in 0..self.bins - 1 {
+            left_sum += bins[i].entries;
+            left_box = left_box.union(bins[i].aabb);
+            if left_sum == 0 || right_counts[i] == 0 {
+                continue;
             }
+            let cost = left_sum as f32 * left_box.surface_area()
+                + right_counts[i] as f32 * right_boxes[i].surface_area();
+            if cost < best.cost {
+                *best = Split {
+                    axis,
+                    position: lo + scale * (i + 1) as f32,
+                    cost,
+                    left: left_box,
+                    right: right_boxes[i],
+                };
+            }
+        }
+    }
 
+    // `item` gives the centroid and bounds of the i-th of `count` triangles
+    fn find_object_split(&self, count: usize, item: impl Fn(usize) -> (Vec3, Aabb)) -> Split {
+        // bin by centroid bounds, the triangle bounds can be much wider than where they split
+        let mut bounds_min = Vec3::splat(f32::INFINITY);
+        let mut bounds_max = Vec3::splat(f32::NEG_INFINITY);
+        for i in 0..count {
+            let (centroid, _) = item(i);
+            bounds_min = bounds_min.min(centroid);
+            bounds_max = bounds_max.max(centroid);
+        }
+
+        let mut best = Split::none();
+        let mut bins = vec![Bin::default(); self.bins];
+        for axis in 0..3 {
             // skip if completely flat
-            if bounds_min == bounds_max {
+            if bounds_min[axis] == bounds_max[axis] {
                 continue;
             }
 
-            // check splits uniformly along the axis
-            let scale = (bounds_max - bounds_min) / self.sah_samples as f32;
-            for i in 1..self.sah_samples {
-                let candidate = bounds_min + scale * i as f32;
-                let cost = self.calculate_surface_area_heuristic(node, axis, candidate);
-                if cost < best_cost {
-                    best_axis = axis;
-                    best_split = candidate;
-                    best_cost = cost;
-                }
+            bins.fill(Bin::default());
+            let scale = self.bins as f32 / (bounds_max[axis] - bounds_min[axis]);
+            for i in 0..count {
+                let (centroid, aabb) = item(i);
+                let bin_index =
+                    (((centroid[axis] - bounds_min[axis]) * scale) as usize).min(self.bins - 1);
+                let bin = &mut bins[bin_index];
+                bin.aabb = bin.aabb.union(aabb);
+                bin.entries += 1;
+                bin.exits += 1;
             }
+            self.sweep(&bins, axis, bounds_min[axis], 1.0 / scale, &mut best);
         }
 
-        (best_axis, best_split, best_cost)
+        best
     }
 
-    fn find_best_split_segmented(&self, node: &BVHNode) -> (usize, f32, f32) {
-        // calculate the best split (SAH)
-        let mut best_axis = 0;
-        let mut best_split = 0.0;
-        let mut best_cost = f32::INFINITY;
+    // Bins are slabs of the node itself, triangles are chopped into every slab they cross
+    fn find_spatial_split(&self, node: &BVHNode, references: &[Reference]) -> Split {
+        let mut best = Split::none();
+        let mut bins = vec![Bin::default(); self.bins];
         for axis in 0..3 {
-            // find bounds of centroids in this node
-            let mut bounds_min = f32::INFINITY;
-            let mut bounds_max = f32::NEG_INFINITY;
-            for i in 0..node.triangle_count() {
-                let triangle_index = (node.first_triangle_index() + i) as usize;
-                let centroid = self.centroids[triangle_index];
-                bounds_min = bounds_min.min(centroid[axis]);
-                bounds_max = bounds_max.max(centroid[axis]);
+            let (lo, hi) = (node.aabb().min[axis], node.aabb().max[axis]);
+            if lo == hi {
+                continue;
             }
 
-            // skip if completely flat
-            if bounds_min == bounds_max {
-                continue;
+            bins.fill(Bin::default());
+            let scale = self.bins as f32 / (hi - lo);
+            let bin_index = |x: f32| (((x - lo) * scale) as usize).min(self.bins - 1);
+            for reference in references {
+                let first = bin_index(reference.aabb.min[axis]);
+                let last = bin_index(reference.aabb.max[axis]);
+                let mut rest = *reference;
+                for (i, bin) in bins.iter_mut().enumerate().take(last).skip(first) {
+                    let (left, right) =
+                        self.split_reference(&rest, axis, lo + (i + 1) as f32 / scale);
+                    bin.aabb = bin.aabb.union(left.aabb);
+                    rest = right;
+                }
+                bins[last].aabb = bins[last].aabb.union(rest.aabb);
+                bins[first].entries += 1;
+                bins[last].exits += 1;
             }
+            self.sweep(&bins, axis, lo, 1.0 / scale, &mut best);
+        }
+
+        best
+    }
 
-            // build segments and fill them with triangles
-            #[derive(Default, Clone, Copy)]
-            struct Segment {
-                aabb: BVHNode,
-                triangle_count: u32,
+    // Bounds of the triangle parts on either side of the plane, within the reference bounds
+    fn split_reference(
+        &self,
+        reference: &Reference,
+        axis: usize,
+        position: f32,
+    ) -> (Reference, Reference) {
+        let mut left = Aabb::EMPTY;
+        let mut right = Aabb::EMPTY;
+        let [v0, v1, v2] = self.triangle(reference.triangle);
+        for (a, b) in [(v0, v1), (v1, v2), (v2, v0)] {
+            if a[axis] <= position {
+                left.grow(a);
             }
-            let mut segments = vec![Segment::default(); self.sah_samples];
-            let scale = self.sah_samples as f32 / (bounds_max - bounds_min);
-            for i in 0..node.triangle_count() {
-                let triangle_index = (node.first_triangle_index() + i) as usize;
-                let index = self.indices[triangle_index];
-                let v0 = self.vertices[index.x as usize].xyz();
-                let v1 = self.vertices[index.y as usize].xyz();
-                let v2 = self.vertices[index.z as usize].xyz();
-                let segment_index = (((self.centroids[triangle_index][axis] - bounds_min) * scale)
-                    as usize)
-                    .min(self.sah_samples - 1);
-                segments[segment_index].aabb.enc(v0);
-                segments[segment_index].aabb.enc(v1);
-                segments[segment_index].aabb.enc(v2);
-                segments[segment_index].triangle_count += 1;
+            if a[axis] >= position {
+                right.grow(a);
             }
+            if (a[axis] < position && position < b[axis])
+                || (b[axis] < position && position < a[axis])
+            {
+                let mut edge = a.lerp(b, (position - a[axis]) / (b[axis] - a[axis]));
+                edge[axis] = position;
+                left.grow(edge);
+                right.grow(edge);
+            }
+        }
+
+        (
+            Reference { triangle: reference.triangle, aabb: left.intersection(reference.aabb) },
+            Reference { triangle: reference.triangle, aabb: right.intersection(reference.aabb) },
+        )
+    }
+
+    // Moves subtrees built into their own arrays behind `nodes`, fixing up child indices
+    // and, for spatial splits, where their triangles start in the index buffer
+    fn append(
+        nodes: &mut Vec<BVHNode>,
+        root: &mut BVHNode,
+        subtree: Vec<BVHNode>,
+        triangle_offset: u32,
+    ) {
+        let offset = nodes.len() as u32;
+        relocate(root, offset, triangle_offset);
+        nodes.extend(subtree.into_iter().map(|mut node| {
+            relocate(&mut node, offset, triangle_offset);
+            node
+        }));
+    }
+
+    // Splits `node` while SAH says it pays off, appending the children pair and then their
+    // descendants to `nodes`. Returns the depth of the deepest leaf below `node`.
+    fn subdivide(
+        &self,
+        node: &mut BVHNode,
+        depth: usize,
+        indices: &mut [UVec4],
+        centroids: &mut [Vec3],
+        nodes: &mut Vec<BVHNode>,
+    ) -> usize {
+        // traversal needs one stack slot per level, so deeper nodes have to stay leaves
+        if depth >= BVH_STACK_SIZE {
+            return depth;
+        }
+
+        // if the parent node is cheaper, don't split
+        let split =
+            self.find_object_split(indices.len(), |i| (centroids[i], self.bounds(indices[i])));
+        let parent_cost = node.aabb().surface_area() * node.triangle_count() as f32;
+        if parent_cost <= split.cost {
+            return depth;
+        }
 
-            // gather what we need for SAH from each plane between the segments
-            // this is area, tri_count of left and right segment for each possible split.
-            // the sum and box are used to calculate these efficiently with a sweep.
-            let mut left_box = BVHNode::default();
-            let mut left_sum = 0;
-            let mut left_areas = vec![0.0; self.sah_samples - 1];
-            let mut left_tri_counts = vec![0; self.sah_samples - 1];
-            let mut right_box = BVHNode::default();
-            let mut right_sum = 0;
-            let mut right_areas = vec![0.0; self.sah_samples - 1];
-            let mut right_tri_counts = vec![0; self.sah_samples - 1];
-            for i in 0..self.sah_samples - 1 {
-                left_sum += segments[i].triangle_count;
-                left_tri_counts[i] = left_sum;
-                left_box.enc_node(segments[i].aabb);
-                left_areas[i] = left_box.area();
-                right_sum += segments[self.sah_samples - 1 - i].triangle_count;
-                right_tri_counts[self.sah_samples - 2 - i] = right_sum;
-                right_box.enc_node(segments[self.sah_samples - 1 - i].aabb);
-                right_areas[self.sah_samples - 2 - i] = right_box.area();
+        // partition the triangles
+        let (mut a, mut b) = (0, indices.len());
+        while a < b {
+            if centroids[a][split.axis] < split.position {
+                a += 1;
+            } else {
+                b -= 1;
+                indices.swap(a, b);
+                centroids.swap(a, b);
             }
+        }
+
+        // if either side is empty (no split), then we're done
+        if a == 0 || a == indices.len() {
+            return depth;
+        }
+
+        // create children
+        let triangle_count = indices.len();
+        let first_triangle_idx = node.first_triangle_index();
+        let (left_indices, right_indices) = indices.split_at_mut(a);
+        let (left_centroids, right_centroids) = centroids.split_at_mut(a);
+        let mut left = BVHNode::default();
+        left.set_first_triangle_index(first_triangle_idx);
+        left.set_triangle_count(a as u32);
+        self.fit(&mut left, left_indices);
+        let mut right = BVHNode::default();
+        right.set_first_triangle_index(first_triangle_idx + a as u32);
+        right.set_triangle_count(right_indices.len() as u32);
+        self.fit(&mut right, right_indices);
+
+        let left_idx = nodes.len();
+        node.set_left_node_index(left_idx as u32);
+        node.set_triangle_count(0);
+        nodes.extend([left, right]);
+
+        let depth = if triangle_count >= PARALLEL_THRESHOLD {
+            // build both sides into their own arrays, then move them behind the children
+            let subtree = |node: &mut BVHNode, indices: &mut [UVec4], centroids: &mut [Vec3]| {
+                let mut nodes = Vec::new();
+                let depth = self.subdivide(node, depth + 1, indices, centroids, &mut nodes);
+                (nodes, depth)
+            };
+            let ((left_nodes, left_depth), (right_nodes, right_depth)) = rayon::join(
+                || subtree(&mut left, left_indices, left_centroids),
+                || subtree(&mut right, right_indices, right_centroids),
+            );
+            // leaves already know where their triangles are
+            Self::append(nodes, &mut left, left_nodes, 0);
+            Self::append(nodes, &mut right, right_nodes, 0);
+            left_depth.max(right_depth)
+        } else {
+            let left_depth =
+                self.subdivide(&mut left, depth + 1, left_indices, left_centroids, nodes);
+            let right_depth =
+                self.subdivide(&mut right, depth + 1, right_indices, right_centroids, nodes);
+            left_depth.max(right_depth)
+        };
+        nodes[left_idx] = left;
+        nodes[left_idx + 1] = right;
+
+        depth
+    }
+
+    // Same as `subdivide`, but a triangle straddling the split can go to both children,
+    // so every node owns its references and leaves write them out to `indices`
+    fn subdivide_spatial(
+        &self,
+        node: &mut BVHNode,
+        depth: usize,
+        references: Vec<Reference>,
+        nodes: &mut Vec<BVHNode>,
+        indices: &mut Vec<UVec4>,
+    ) -> usize {
+        let leaf = |node: &mut BVHNode, indices: &mut Vec<UVec4>, references: Vec<Reference>| {
+            node.set_first_triangle_index(indices.len() as u32);
+            node.set_triangle_count(references.len() as u32);
+            indices.extend(references.iter().map(|reference| reference.triangle));
+            depth
+        };
+
+        // traversal needs one stack slot per level, so deeper nodes have to stay leaves
+        if depth >= BVH_STACK_SIZE || references.len() < 2 {
+            return leaf(node, indices, references);
+        }
+
+        let object = self.find_object_split(references.len(), |i| {
+            let aabb = references[i].aabb;
+            (aabb.centroid(), aabb)
+        });
 
-            // evaluate SAH for each split, pick the best
-            let scale = (bounds_max - bounds_min) / self.sah_samples as f32;
-            for i in 0..self.sah_samples - 1 {
-                let cost = left_tri_counts[i] as f32 * left_areas[i]
-                    + right_tri_counts[i] as f32 * right_areas[i];
-                if cost < best_cost {
-                    best_axis = axis;
-                    best_split = bounds_min + scale * (i + 1) as f32;
-                    best_cost = cost;
+        // only bother chopping triangles where the object split leaves children overlapping
+        let overlap = object.left.intersection(object.right);
+        let spatial = if !overlap.is_empty() && overlap.surface_area() > self.min_overlap {
+            self.find_spatial_split(node, &references)
+        } else {
+            Split::none()
+        };
+
+        // if the parent node is cheaper, don't split
+        let parent_cost = node.aabb().surface_area() * references.len() as f32;
+        if parent_cost <= object.cost.min(spatial.cost) {
+            return leaf(node, indices, references);
+        }
+
+        let mut left_refs = Vec::with_capacity(references.len());
+        let mut right_refs = Vec::with_capacity(references.len());
+        if object.cost <= spatial.cost {
+            for reference in references {
+                if reference.aabb.centroid()[object.axis] < object.position {
+                    left_refs.push(reference);
+                } else {
+                    right_refs.push(reference);
                 }
             }
+        } else {
+            let Split { axis, position, mut left, mut right, .. } = spatial;
+            let mut left_count = references.iter().filter(|r| r.aabb.min[axis] < position).count();
+            let mut right_count = references.iter().filter(|r| r.aabb.max[axis] > position).count();
+            for reference in references {
+                if reference.aabb.max[axis] <= position {
+                    left_refs.push(reference);
+                } else if reference.aabb.min[axis] >= position {
+                    right_refs.push(reference);
+                } else {
+                    // keep the triangle whole on one side when that's cheaper than splitting
+                    let split_cost = left.surface_area() * left_count as f32
+                        + right.surface_area() * right_count as f32;
+                    let grown_left = left.union(reference.aabb);
+                    let grown_right = right.union(reference.aabb);
+                    let left_cost = grown_left.surface_area() * left_count as f32
+                        + right.surface_area() * (right_count - 1) as f32;
+                    let right_cost = left.surface_area() * (left_count - 1) as f32
+                        + grown_right.surface_area() * right_count as f32;
+                    if left_cost < split_cost && left_cost <= right_cost {
+                        left = grown_left;
+                        right_count -= 1;
+                        left_refs.push(reference);
+                    } else if right_cost < split_cost {
+                        right = grown_right;
+                        left_count -= 1;
+                        right_refs.push(reference);
+                    } else {
+                        // clipping can round a side away, but never the whole triangle
+                        let (l, r) = self.split_reference(&reference, axis, position);
+                        let (has_l, has_r) = (!l.aabb.is_empty(), !r.aabb.is_empty());
+                        if has_l || !has_r {
+                            left_refs.push(if has_l { l } else { reference });
+                        }
+                        if has_r {
+                            right_refs.push(r);
+                        }
+                    }
+                }
+            }
+        }
+
+        // if either side is empty (no split), then we're done
+        if left_refs.is_empty() || right_refs.is_empty() {
+            left_refs.append(&mut right_refs);
+            return leaf(node, indices, left_refs);
         }
 
-        (best_axis, best_split, best_cost)
+        // create children, their triangles are only known once they turn into leaves
+        let fit = |refs: &[Reference]| {
+            let mut node = BVHNode::default();
+            node.set_aabb(
+                refs.iter().fold(Aabb::EMPTY, |aabb, reference| aabb.union(reference.aabb)),
+            );
+            node
+        };
+        let reference_count = left_refs.len() + right_refs.len();
+        let mut left = fit(&left_refs);
+        let mut right = fit(&right_refs);
+
+        let left_idx = nodes.len();
+        node.set_left_node_index(left_idx as u32);
+        node.set_triangle_count(0);
+        nodes.extend([left, right]);
+
+        let depth = if reference_count >= PARALLEL_THRESHOLD {
+            let subtree = |node: &mut BVHNode, references: Vec<Reference>| {
+                let (mut nodes, mut indices) = (Vec::new(), Vec::new());
+                let depth =
+                    self.subdivide_spatial(node, depth + 1, references, &mut nodes, &mut indices);
+                (nodes, indices, depth)
+            };
+            let ((left_nodes, left_indices, left_depth), (right_nodes, right_indices, right_depth)) =
+                rayon::join(|| subtree(&mut left, left_refs), || subtree(&mut right, right_refs));
+            for (root, subtree, subtree_indices) in
+                [(&mut left, left_nodes, left_indices), (&mut right, right_nodes, right_indices)]
+            {
+                Self::append(nodes, root, subtree, indices.len() as u32);
+                indices.extend(subtree_indices);
+            }
+            left_depth.max(right_depth)
+        } else {
+            let left_depth =
+                self.subdivide_spatial(&mut left, depth + 1, left_refs, nodes, indices);
+            let right_depth =
+                self.subdivide_spatial(&mut right, depth + 1, right_refs, nodes, indices);
+            left_depth.max(right_depth)
+        };
+        nodes[left_idx] = left;
+        nodes[left_idx + 1] = right;
+
+        depth
     }
+}
 
-    pub fn build(&mut self) -> BVH {
-        let mut node_count = 1;
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        rand::{rngs::StdRng, Rng, SeedableRng},
+        std::time::Instant,
+    };
 
-        let root = &mut self.nodes[0];
-        root.set_first_triangle_index(0);
-        root.set_triangle_count(self.indices.len() as u32);
-        self.update_node_aabb(0);
+    // Small triangles scattered over the unit cube
+    fn soup(count: u32, rng: &mut StdRng) -> (Vec<Vec4>, Vec<UVec4>) {
+        let mut vertices = Vec::with_capacity(count as usize * 3);
+        for _ in 0..count {
+            let center = Vec3::new(rng.gen(), rng.gen(), rng.gen());
+            for _ in 0..3 {
+                let offset = Vec3::new(rng.gen(), rng.gen(), rng.gen()) - 0.5;
+                vertices.push((center + offset * 0.1).extend(1.0));
+            }
+        }
+        let indices = (0..count).map(|i| UVec4::new(i * 3, i * 3 + 1, i * 3 + 2, 0)).collect();
+        (vertices, indices)
+    }
 
-        let mut stack = vec![0];
-        while !stack.is_empty() {
-            // get the next root node
-            let node_idx = stack.pop().expect("BVH build stack is empty.");
-            let node = &self.nodes[node_idx];
+    fn place(bvh: BVH) -> TLAS {
+        TLAS::new(vec![bvh], vec![(0, InstanceData::new(Mat4::IDENTITY, 0, 0))])
+    }
 
-            // calculate the best split (SAH)
-            let (best_axis, best_split, best_cost) = self.find_best_split_segmented(node);
+    fn build(
+        vertices: &[Vec4],
+        indices: &[UVec4],
+        bins: usize,
+        alpha: Option<f32>,
+    ) -> (BVH, Vec<UVec4>) {
+        let mut built = indices.to_vec();
+        let mut builder = BVHBuilder::new(vertices, &mut built).bins(bins);
+        if let Some(alpha) = alpha {
+            builder = builder.spatial_splits(alpha);
+        }
+        let bvh = builder.build();
+        (bvh, built)
+    }
 
-            // if the parent node is cheaper, don't split
-            let parent_cost = node.area() * node.triangle_count() as f32;
-            if parent_cost <= best_cost {
-                continue;
+    #[test]
+    fn builds_find_the_same_hits_as_a_single_leaf() {
+        let mut rng = StdRng::seed_from_u64(3);
+        // enough triangles for the top of the tree to be built on several threads
+        let (vertices, indices) = soup(PARALLEL_THRESHOLD as u32 * 2, &mut rng);
+        let per_vertex = vertices
+            .iter()
+            .map(|&vertex| PerVertexData { vertex, ..Default::default() })
+            .collect::<Vec<_>>();
+
+        // tests every ray against every triangle
+        let mut leaf = BVHNode::default();
+        leaf.set_triangle_count(indices.len() as u32);
+        leaf.set_first_triangle_index(0);
+        leaf.set_aabb(vertices.iter().fold(Aabb::EMPTY, |mut aabb, vertex| {
+            aabb.grow(vertex.xyz());
+            aabb
+        }));
+        let reference =
+            place(BVH { nodes: vec![leaf], parents: vec![u32::MAX], depth: 1, build_cost: 1.0 });
+
+        let builds = [(32, None), (2, None), (32, Some(1e-5))].map(|(bins, alpha)| {
+            let (bvh, built) = build(&vertices, &indices, bins, alpha);
+            assert_eq!(bvh.validate(&vertices, &built), Ok(()));
+            (place(bvh), built)
+        });
+
+        let mut hits = 0;
+        for _ in 0..1024 {
+            // from around the cube, towards a point inside it
+            let ro = Vec3::new(rng.gen(), rng.gen(), rng.gen()) * 3.0 - 1.0;
+            let rd = Vec3::new(rng.gen(), rng.gen(), rng.gen()) - ro;
+            let nearest = |tlas: &TLAS, indices: &[UVec4]| {
+                let hit = tlas.intersect(indices, &per_vertex, ro, rd);
+                hit.map(|hit| (hit.triangle, hit.distance))
+            };
+            let expected = nearest(&reference, &indices);
+            hits += expected.is_some() as usize;
+            for (tlas, built) in &builds {
+                assert_eq!(nearest(tlas, built), expected, "ray from {ro} along {rd}");
             }
+        }
+        assert!(hits > 256, "only {hits} rays hit anything");
+    }
 
-            // partition the triangles
-            let mut a = node.first_triangle_index();
-            let mut b = a + node.triangle_count() - 1;
-            while a <= b {
-                let centroid = self.centroids[a as usize][best_axis];
-                if centroid < best_split {
-                    a += 1;
+    // Not run by default, `cargo test --release build_time -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn build_time() {
+        let (vertices, indices) = soup(1 << 20, &mut StdRng::seed_from_u64(5));
+        let single = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        for (name, alpha) in [("binned", None), ("spatial splits", Some(1e-5))] {
+            for threads in [1, rayon::current_num_threads()] {
+                let start = Instant::now();
+                let (bvh, built) = if threads == 1 {
+                    single.install(|| build(&vertices, &indices, 32, alpha))
                 } else {
-                    self.indices.swap(a as usize, b as usize);
-                    self.centroids.swap(a as usize, b as usize);
-                    b -= 1;
-                }
+                    build(&vertices, &indices, 32, alpha)
+                };
+                println!(
+                    "{name} on {threads} threads: {:?} for {} triangles, {} references, {} \
+                     nodes, depth {}, SAH cost {}",
+                    start.elapsed(),
+                    indices.len(),
+                    built.len(),
+                    bvh.nodes.len(),
+                    bvh.depth,
+                    bvh.build_cost
+                );
             }
+        }
+    }
 
-            // if either side is empty (no split), then we're done
-            let left_count = a - node.first_triangle_index();
-            if left_count == 0 || left_count == node.triangle_count() {
-                continue;
-            }
+    // Triangles zigzagging along x, enough of them for a few levels of nodes
+    fn strip(count: u32) -> (Vec<Vec4>, Vec<UVec4>) {
+        let vertices = (0..count + 2).map(|i| Vec4::new(i as f32, (i % 2) as f32, 0.0, 1.0));
+        let indices = (0..count).map(|i| UVec4::new(i, i + 1, i + 2, 0));
+        (vertices.collect(), indices.collect())
+    }
+
+    // A cache entry for `strip(64)` along with its key and the BVH it holds
+    fn entry() -> (Vec<u8>, u64, BVH, Vec<UVec4>) {
+        let (vertices, mut indices) = strip(64);
+        let hash = geometry_hash(&vertices, &indices);
+        let bvh = BVHBuilder::new(&vertices, &mut indices).build();
+        (bvh.serialize(&indices, hash, 64), hash, bvh, indices)
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let (bytes, hash, bvh, indices) = entry();
+        let mut rest = bytes.as_slice();
+        let (cached, cached_indices) = BVH::deserialize(&mut rest, hash, 64).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(cached_indices, indices);
+        assert_eq!(
+            bytemuck::cast_slice::<_, u8>(&cached.nodes),
+            bytemuck::cast_slice::<_, u8>(&bvh.nodes)
+        );
+        assert_eq!(cached.parents, bvh.parents);
+        assert_eq!(cached.depth, bvh.depth);
+    }
+
+    #[test]
+    fn stale_cache_is_rejected() {
+        let (bytes, hash, ..) = entry();
+        assert!(BVH::deserialize(&mut bytes.as_slice(), hash ^ 1, 64).is_none());
+        assert!(BVH::deserialize(&mut bytes.as_slice(), hash, 63).is_none());
+    }
+
+    #[test]
+    fn truncated_cache_is_rejected() {
+        let (bytes, hash, ..) = entry();
+        for len in 0..bytes.len() {
+            assert!(BVH::deserialize(&mut &bytes[..len], hash, 64).is_none(), "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn corrupt_payload_is_rejected() {
+        let (bytes, hash, ..) = entry();
+        for i in CACHE_HEADER_SIZE..bytes.len() {
+            let mut bytes = bytes.clone();
+            bytes[i] ^= 0x10;
+            assert!(BVH::deserialize(&mut bytes.as_slice(), hash, 64).is_none(), "byte {i}");
+        }
+    }
+
+    #[test]
+    fn children_out_of_bounds_are_rejected() {
+        let (_, hash, bvh, indices) = entry();
+        assert!(!bvh.nodes[0].is_leaf());
+        // Children before their parent, past the end or shared with another node. The payload
+        // hash matches, only the tree itself is broken.
+        let shared = bvh.nodes.iter().skip(1).find(|node| !node.is_leaf()).unwrap();
+        let shared = shared.left_node_index();
+        for left_idx in [0, bvh.nodes.len() as u32 - 1, u32::MAX, shared] {
+            let mut broken = bvh.clone();
+            broken.nodes[0].set_left_node_index(left_idx);
+            let bytes = broken.serialize(&indices, hash, 64);
+            assert!(BVH::deserialize(&mut bytes.as_slice(), hash, 64).is_none(), "{left_idx}");
+        }
+
+        let mut broken = bvh.clone();
+        let leaf = broken.nodes.iter_mut().find(|node| node.is_leaf()).unwrap();
+        leaf.set_first_triangle_index(indices.len() as u32);
+        let bytes = broken.serialize(&indices, hash, 64);
+        assert!(BVH::deserialize(&mut bytes.as_slice(), hash, 64).is_none());
+    }
+
+    #[test]
+    fn no_triangles_build_no_nodes() {
+        let mut indices = Vec::new();
+        let mut bvh = BVHBuilder::new(&[], &mut indices).spatial_splits(1e-5).build();
+        assert!(bvh.nodes.is_empty());
+        assert_eq!(bvh.validate(&[], &indices), Ok(()));
+        assert_eq!(bvh.refit(&[], &indices), 1.0);
 
-            // create children
-            let prev_triangle_idx = node.first_triangle_index();
-            let prev_triangle_count = node.triangle_count();
-            let left_idx = node_count;
-            let right_idx = node_count + 1;
-            node_count += 2;
-            self.nodes[node_idx].set_left_node_index(left_idx as u32);
-            self.nodes[node_idx].set_triangle_count(0);
-            self.nodes[left_idx].set_first_triangle_index(prev_triangle_idx);
-            self.nodes[left_idx].set_triangle_count(left_count);
-            self.nodes[right_idx].set_first_triangle_index(a);
-            self.nodes[right_idx].set_triangle_count(prev_triangle_count - left_count);
-            self.update_node_aabb(left_idx);
-            self.update_node_aabb(right_idx);
-
-            // push children onto the stack
-            stack.push(right_idx);
-            stack.push(left_idx);
-        }
-
-        self.nodes.truncate(node_count);
-        BVH { nodes: self.nodes.clone() }
+        let bytes = bvh.serialize(&indices, 0, 0);
+        let (cached, cached_indices) = BVH::deserialize(&mut bytes.as_slice(), 0, 0).unwrap();
+        assert!(cached.nodes.is_empty() && cached_indices.is_empty());
     }
 }
@@ -0,0 +1,189 @@
+use {
+    crate::bvh,
+    glam::{UVec4, Vec4},
+    shared::TracingConfig,
+    std::{
+        ffi::OsString,
+        fs::File,
+        io::{self, BufReader, BufWriter, Read, Write},
+        path::Path,
+    },
+};
+
+const MAGIC: &[u8; 8] = b"RACISTCK";
+const VERSION: u32 = 3;
+// magic, version, resolution, sample count and hash
+const HEADER_SIZE: u64 = 36;
+
+// The sums of an accumulation, written so another run can continue it. The file is a header
+// with the version, resolution, sample count and `hash`, then the sums as little-endian f32s.
+pub struct Checkpoint {
+    pub width: u32,
+    pub height: u32,
+    pub samples: usize,
+    // of the scene and the settings, see `Checkpoint::hash`
+    pub hash: u64,
+    // direct, indirect, albedo, normal and the squares adaptive sampling estimates the error
+    // from, `width` by `height` each
+    pub sums: [Vec<Vec4>; 5],
+}
+
+impl Checkpoint {
+    // Of the scene path and every setting but the ones set per sample and dispatch
+    pub fn hash(scene: &Path, config: &TracingConfig) -> u64 {
+        let scene = scene.canonicalize().unwrap_or_else(|_| scene.to_path_buf());
+        let mut config = *config;
+        config.sample_index = 0;
+        config.tile = UVec4::ZERO;
+        let hash = bvh::fnv1a(bvh::FNV_OFFSET, scene.to_string_lossy().as_bytes());
+        bvh::fnv1a(hash, bytemuck::bytes_of(&config))
+    }
+
+    // Why this can't continue the accumulation of `config` for `scene`
+    pub fn mismatch(&self, scene: &Path, config: &TracingConfig) -> Option<String> {
+        if (self.width, self.height) != (config.width, config.height) {
+            return Some(format!(
+                "it is {}x{} and the render {}x{}",
+                self.width, self.height, config.width, config.height
+            ));
+        }
+        (self.hash != Self::hash(scene, config))
+            .then(|| "it was rendered from another scene or with other settings".to_string())
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        // moved over the previous checkpoint once complete, a crash while writing keeps it
+        let mut temporary = OsString::from(path);
+        temporary.push(".tmp");
+        let mut out = BufWriter::new(File::create(&temporary)?);
+        out.write_all(MAGIC)?;
+        for value in [VERSION, self.width, self.height] {
+            out.write_all(&value.to_le_bytes())?;
+        }
+        out.write_all(&(self.samples as u64).to_le_bytes())?;
+        out.write_all(&self.hash.to_le_bytes())?;
+        for value in self.sums.iter().flatten().flat_map(|sum| sum.to_array()) {
+            out.write_all(&value.to_le_bytes())?;
+        }
+        out.flush()?;
+        drop(out);
+        std::fs::rename(&temporary, path)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let length = file.metadata()?.len();
+        let mut input = BufReader::new(file);
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if magic != *MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a checkpoint"));
+        }
+        if read_u32(&mut input)? != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "written by another version"));
+        }
+        let (width, height) = (read_u32(&mut input)?, read_u32(&mut input)?);
+        let samples = read_u64(&mut input)? as usize;
+        let hash = read_u64(&mut input)?;
+        // checked before allocating, a damaged header could ask for any size
+        let size = (width as u64 * height as u64).checked_mul(16 * 5);
+        if size.and_then(|size| size.checked_add(HEADER_SIZE)) != Some(length) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{length} bytes don't hold {width}x{height} sums"),
+            ));
+        }
+
+        let mut bytes = vec![0; width as usize * height as usize * 16];
+        let mut read_sums = || -> io::Result<Vec<Vec4>> {
+            input.read_exact(&mut bytes)?;
+            let sums = bytes.chunks_exact(16).map(|pixel| {
+                let float =
+                    |i: usize| f32::from_le_bytes(pixel[i * 4..i * 4 + 4].try_into().unwrap());
+                Vec4::new(float(0), float(1), float(2), float(3))
+            });
+            Ok(sums.collect())
+        };
+        let sums = [read_sums()?, read_sums()?, read_sums()?, read_sums()?, read_sums()?];
+        Ok(Self { width, height, samples, hash, sums })
+    }
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("racist-{}-{name}", std::process::id()))
+    }
+
+    fn checkpoint() -> Checkpoint {
+        let sums = [0.0, 1.0, 2.0, 3.0, 4.0]
+            .map(|plane| (0..6).map(|i| Vec4::splat(plane * 10.0 + i as f32)).collect());
+        Checkpoint { width: 3, height: 2, samples: 7, hash: 0x1234_5678_9abc_def0, sums }
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = temporary("round-trip.ck");
+        let saved = checkpoint();
+        saved.save(&path).unwrap();
+        let loaded = Checkpoint::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((loaded.width, loaded.height, loaded.samples), (3, 2, 7));
+        assert_eq!(loaded.hash, saved.hash);
+        assert_eq!(loaded.sums, saved.sums);
+    }
+
+    #[test]
+    fn wrong_sizes_are_rejected() {
+        let path = temporary("sizes.ck");
+        checkpoint().save(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len() as u64, HEADER_SIZE + 6 * 16 * 5);
+
+        let rejected = |bytes: &[u8]| {
+            std::fs::write(&path, bytes).unwrap();
+            Checkpoint::load(&path).err().map(|err| err.kind())
+        };
+        assert_eq!(rejected(&bytes[..bytes.len() - 1]), Some(io::ErrorKind::InvalidData));
+        assert_eq!(rejected(&[&bytes[..], &[0]].concat()), Some(io::ErrorKind::InvalidData));
+        // a header claiming a huge image fails without allocating for it
+        let mut huge = bytes.clone();
+        huge[12..20].copy_from_slice(&[0xff; 8]);
+        assert_eq!(rejected(&huge), Some(io::ErrorKind::InvalidData));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn hash_covers_the_settings_but_not_the_sample() {
+        let scene = Path::new("scene.glb");
+        let config = TracingConfig::soft();
+        let hash = Checkpoint::hash(scene, &config);
+
+        let mut sample = config;
+        sample.sample_index = 8;
+        sample.tile = UVec4::new(0, 0, 32, 32);
+        assert_eq!(Checkpoint::hash(scene, &sample), hash);
+
+        let mut bounces = config;
+        bounces.max_bounces += 1;
+        assert_ne!(Checkpoint::hash(scene, &bounces), hash);
+        let mut camera = config;
+        camera.cam_pos.x += 1.0;
+        assert_ne!(Checkpoint::hash(scene, &camera), hash);
+        assert_ne!(Checkpoint::hash(Path::new("other.glb"), &config), hash);
+    }
+}
@@ -1,17 +1,29 @@
 use {
-    crate::block_on,
-    glam::{UVec2, Vec2, Vec4},
-    gpgpu::{
-        BufOps, DescriptorSet, GpuBuffer, GpuBufferUsage, GpuUniformBuffer, Kernel, Program,
-        Sampler, SamplerFilterMode, SamplerWrapMode, Shader,
+    crate::{
+        checkpoint::Checkpoint,
+        gpu::{
+            self, BufferError, DescriptorSet, FrameworkCell, GpuBuffer, GpuBufferUsage,
+            GpuUniformBuffer, Kernel, Program, Sampler, Shader,
+        },
+        output::Tonemap,
     },
+    glam::{UVec4, Vec2, Vec4, Vec4Swizzles},
     image::{io::Reader, RgbaImage},
-    rand::Rng,
-    std::{io::Cursor, thread::JoinHandle},
+    naga::{
+        valid::{Capabilities, ValidationFlags},
+        ShaderStage,
+    },
+    std::{
+        fs,
+        io::Cursor,
+        path::{Path, PathBuf},
+        sync::Arc,
+        thread::JoinHandle,
+        time::{Duration, Instant, SystemTime},
+    },
     wgpu::{
-        util, util::DeviceExt, Backends, Color, CommandEncoderDescriptor, CompositeAlphaMode,
-        DeviceDescriptor, Instance, InstanceDescriptor, LoadOp, PowerPreference, PresentMode,
-        RenderPassColorAttachment, RenderPassDescriptor, RequestAdapterOptions, StoreOp,
+        util, util::DeviceExt, AddressMode, Color, CommandEncoderDescriptor, CompositeAlphaMode,
+        FilterMode, LoadOp, PresentMode, RenderPassColorAttachment, RenderPassDescriptor, StoreOp,
         SurfaceConfiguration, SurfaceTargetUnsafe, TextureUsages, TextureViewDescriptor,
     },
     winit::{
@@ -21,8 +33,10 @@ use {
     },
 };
 
+// The window presents from it too, see `Wgpu::init`
+pub static FW: FrameworkCell = FrameworkCell::new();
+
 lazy_static::lazy_static! {
-    pub static ref FW: gpgpu::Framework = gpgpu::Framework::default();
     pub static ref BLUE_TEXTURE: RgbaImage = {
         Reader::new(Cursor::new(BLUE_NOISE)).with_guessed_format().unwrap().decode().unwrap().into_rgba8()
     };
@@ -30,63 +44,117 @@ lazy_static::lazy_static! {
 
 #[allow(non_upper_case_globals)]
 mod shaders {
+    pub const primary_cs: &str = "primary_cs";
+    pub const restir_initial_cs: &str = "restir_initial_cs";
+    pub const restir_spatial_cs: &str = "restir_spatial_cs";
     pub const main_cs: &str = "main_cs";
+    pub const generate_cs: &str = "generate_cs";
+    pub const intersect_cs: &str = "intersect_cs";
+    pub const shade_cs: &str = "shade_cs";
+    pub const shadow_cs: &str = "shadow_cs";
+    pub const accumulate_cs: &str = "accumulate_cs";
+}
+
+// The buffers `Tracing` accumulates into, the window binds them instead of reading them back.
+// Clones share the buffers.
+#[derive(Clone)]
+pub struct Sums {
+    width: u32,
+    height: u32,
+    direct: Arc<wgpu::Buffer>,
+    indirect: Arc<wgpu::Buffer>,
+    albedo: Arc<wgpu::Buffer>,
+    normal: Arc<wgpu::Buffer>,
 }
 
 struct RenderPipeline {
     pipeline: wgpu::RenderPipeline,
-    bind_group: wgpu::BindGroup,
+    bind_group_layout: wgpu::BindGroupLayout,
     uniform_buffer: wgpu::Buffer,
-    render_buffer: wgpu::Buffer,
+    // the sums `bind_group` binds, `Tracing` recreates them with the resolution or the scene
+    bound: Option<(Sums, wgpu::BindGroup)>,
 }
 
 impl RenderPipeline {
-    fn prepare(&self, que: &wgpu::Queue, frame: &[f32], width: u32, height: u32) {
-        que.write_buffer(&self.render_buffer, 0, bytemuck::cast_slice(frame));
-        que.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[width, height]));
+    fn prepare(
+        &mut self,
+        sums: &Sums,
+        samples: usize,
+        surface: (u32, u32),
+        post: Post,
+        srgb: bool,
+    ) {
+        let current =
+            self.bound.as_ref().is_some_and(|(bound, _)| Arc::ptr_eq(&bound.direct, &sums.direct));
+        if !current {
+            let buffers: [&wgpu::Buffer; 5] =
+                [&self.uniform_buffer, &sums.direct, &sums.indirect, &sums.albedo, &sums.normal];
+            let entries: Vec<_> = (0..)
+                .zip(buffers)
+                .map(|(binding, buffer)| wgpu::BindGroupEntry {
+                    binding,
+                    resource: buffer.as_entire_binding(),
+                })
+                .collect();
+            let bind_group = FW.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &self.bind_group_layout,
+                entries: &entries,
+            });
+            self.bound = Some((sums.clone(), bind_group));
+        }
+        let uniforms = [
+            sums.width,
+            sums.height,
+            post.view as u32,
+            post.tonemap as u32,
+            surface.0,
+            surface.1,
+            post.exposure.to_bits(),
+            // the shader encodes unless the surface does
+            if srgb { 0 } else { 1 },
+            samples as u32,
+        ];
+        FW.queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&uniforms));
     }
 
     fn paint<'rpass>(&'rpass self, rpass: &mut wgpu::RenderPass<'rpass>) {
+        let Some((_, bind_group)) = &self.bound else { return };
         rpass.set_pipeline(&self.pipeline);
-        rpass.set_bind_group(0, &self.bind_group, &[]);
+        rpass.set_bind_group(0, bind_group, &[]);
         rpass.draw(0..6, 0..1);
     }
 
-    fn new(
-        dev: &wgpu::Device,
-        format: wgpu::TextureFormat,
-        width: u32,
-        height: u32,
-    ) -> RenderPipeline {
+    fn new(dev: &wgpu::Device, format: wgpu::TextureFormat) -> RenderPipeline {
         let shader = dev.create_shader_module(wgpu::ShaderModuleDescriptor {
             label: None,
             source: wgpu::ShaderSource::Wgsl(include_str!("k/post.wgsl").into()),
         });
 
+        let uniforms = wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        // direct, indirect, albedo and normal sums, in the order of `Sums`
+        let sums = (1..5).map(|binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        });
         let bind_group_layout = dev.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: None,
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-            ],
+            entries: &[uniforms].into_iter().chain(sums).collect::<Vec<_>>(),
         });
 
         let pipeline_layout = dev.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
@@ -116,47 +184,31 @@ impl RenderPipeline {
 
         let uniform_buffer = dev.create_buffer_init(&util::BufferInitDescriptor {
             label: None,
-            contents: bytemuck::cast_slice(&[0.0, 0.0, 0.0]),
+            contents: bytemuck::cast_slice(&[0u32; 9]),
             usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
         });
 
-        let render_buffer = dev.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
-            size: (width * height * 3 * 4) as u64,
-            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
-            mapped_at_creation: false,
-        });
-
-        let bind_group = dev.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout: &bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry { binding: 0, resource: uniform_buffer.as_entire_binding() },
-                wgpu::BindGroupEntry { binding: 1, resource: render_buffer.as_entire_binding() },
-            ],
-        });
-
-        RenderPipeline { pipeline, bind_group, uniform_buffer, render_buffer }
+        RenderPipeline { pipeline, bind_group_layout, uniform_buffer, bound: None }
     }
 }
 
+// Presents on `FW`'s device, the image never leaves the GPU
 pub struct Wgpu<'a> {
-    dev: wgpu::Device,
-    que: wgpu::Queue,
     surface: wgpu::Surface<'a>,
     format: wgpu::TextureFormat,
+    surface_config: SurfaceConfiguration,
+    // the surface supports them, `Fifo` always
+    present_modes: Vec<PresentMode>,
+    // the window is minimized, a zero-sized surface can't be configured
+    paused: bool,
 
     pipeline: RenderPipeline,
     compute_handle: Option<JoinHandle<()>>,
 }
 
 impl<'a> Wgpu<'a> {
-    pub fn init(window: &Window) -> Self {
-        let instance = Instance::new(InstanceDescriptor {
-            dx12_shader_compiler: util::dx12_shader_compiler_from_env().unwrap_or_default(),
-            backends: Backends::PRIMARY,
-            ..Default::default()
-        });
+    pub fn init(window: &Window, present_mode: PresentMode) -> Self {
+        let instance = gpu::instance();
         let surface = unsafe {
             instance.create_surface_unsafe(SurfaceTargetUnsafe::RawHandle {
                 raw_display_handle: window.display_handle().unwrap().as_raw(),
@@ -164,43 +216,91 @@ impl<'a> Wgpu<'a> {
             })
         }
         .unwrap();
-        let adapter = block_on(instance.request_adapter(&RequestAdapterOptions {
-            power_preference: PowerPreference::HighPerformance,
-            compatible_surface: Some(&surface),
-            force_fallback_adapter: false,
-        }))
-        .expect("Failed to creator wgpu adapter.");
-
-        let (dev, que) =
-            block_on(adapter.request_device(&DeviceDescriptor { ..Default::default() }, None))
-                .expect("Failed to creator wgpu device.");
+        // the device is picked for the surface, so this comes before the scene is loaded
+        if let Err(err) = FW.init(instance, Some(&surface)) {
+            eprintln!("Failed to create a GPU device: {err}.");
+            std::process::exit(1);
+        }
 
         let size = window.inner_size();
-        let format = surface.get_capabilities(&adapter).formats[0];
-        let config = SurfaceConfiguration {
+        let capabilities = surface.get_capabilities(&FW.adapter);
+        let (format, present_modes) = (capabilities.formats[0], capabilities.present_modes);
+        let present_mode =
+            if present_modes.contains(&present_mode) { present_mode } else { PresentMode::Fifo };
+        let surface_config = SurfaceConfiguration {
             usage: TextureUsages::RENDER_ATTACHMENT,
             format,
             width: size.width,
             height: size.height,
             desired_maximum_frame_latency: 2,
-            present_mode: PresentMode::Fifo,
+            present_mode,
             alpha_mode: CompositeAlphaMode::Auto,
             view_formats: vec![],
         };
-        surface.configure(&dev, &config);
+        surface.configure(&FW.device, &surface_config);
 
-        let size = window.inner_size();
-        let pipeline = RenderPipeline::new(&dev, format, size.width, size.height);
-        Wgpu { dev, que, surface, format, pipeline, compute_handle: None }
+        let pipeline = RenderPipeline::new(&FW.device, format);
+        Wgpu {
+            surface,
+            format,
+            surface_config,
+            present_modes,
+            paused: false,
+            pipeline,
+            compute_handle: None,
+        }
     }
 
-    pub fn redraw(&self, buf: &[f32], width: u32, height: u32) {
-        let Ok(frame) = self.surface.get_current_texture() else { return };
+    // Follows the window, a traced image of another resolution gets letterboxed
+    pub fn resize(&mut self, size: PhysicalSize<u32>) {
+        self.paused = size.width == 0 || size.height == 0;
+        if self.paused {
+            return;
+        }
+        self.surface_config.width = size.width;
+        self.surface_config.height = size.height;
+        self.surface.configure(&FW.device, &self.surface_config);
+    }
 
-        self.pipeline.prepare(&self.que, &buf, width, height);
+    // Falls back to `Fifo` when the surface can't present in `mode`, returns the one in use
+    pub fn set_present_mode(&mut self, mode: PresentMode) -> PresentMode {
+        let mode = if self.present_modes.contains(&mode) { mode } else { PresentMode::Fifo };
+        self.surface_config.present_mode = mode;
+        if !self.paused {
+            self.surface.configure(&FW.device, &self.surface_config);
+        }
+        mode
+    }
+
+    // `sums` are at the trace resolution rather than the window's, None shows black. The
+    // shader divides them by their own count in `w`, the density view by `samples`.
+    pub fn redraw(&mut self, sums: Option<&Sums>, samples: usize, post: Post) {
+        if self.paused {
+            return;
+        }
+        let frame = match self.surface.get_current_texture() {
+            Ok(frame) => frame,
+            // reconfigure and skip the frame, the next one gets a fresh texture
+            Err(wgpu::SurfaceError::Outdated | wgpu::SurfaceError::Lost) => {
+                self.surface.configure(&FW.device, &self.surface_config);
+                return;
+            }
+            // the compositor didn't hand out a texture in time, the next frame tries again
+            Err(wgpu::SurfaceError::Timeout) => return,
+            Err(wgpu::SurfaceError::OutOfMemory) => {
+                eprintln!("Out of memory for the window surface.");
+                std::process::exit(1);
+            }
+        };
+
+        let surface = (self.surface_config.width, self.surface_config.height);
+        let srgb = self.format.is_srgb();
+        if let Some(sums) = sums {
+            self.pipeline.prepare(sums, samples, surface, post, srgb);
+        }
 
         let mut command_encoder =
-            self.dev.create_command_encoder(&CommandEncoderDescriptor::default());
+            FW.device.create_command_encoder(&CommandEncoderDescriptor::default());
         let view = &frame.texture.create_view(&TextureViewDescriptor::default());
 
         {
@@ -215,20 +315,22 @@ impl<'a> Wgpu<'a> {
                 })],
                 ..Default::default()
             });
-            self.pipeline.paint(&mut pass);
+            if sums.is_some() {
+                self.pipeline.paint(&mut pass);
+            }
         }
-        self.que.submit(Some(command_encoder.finish()));
+        FW.queue.submit(Some(command_encoder.finish()));
 
         frame.present();
     }
 
-    pub fn start_render(&mut self, size: PhysicalSize<u32>, continue_previous: bool) {
+    pub fn start_render(&mut self, _size: PhysicalSize<u32>, continue_previous: bool) {
         if self.compute_handle.is_some() {
             self.stop_render();
         }
 
         if !continue_previous {
-            self.pipeline = RenderPipeline::new(&self.dev, self.format, size.width, size.height);
+            self.pipeline = RenderPipeline::new(&FW.device, self.format);
         }
     }
 
@@ -243,104 +345,825 @@ impl<'a> Wgpu<'a> {
 
 use {
     crate::scene::{GpuWorld, World},
-    shared::TracingConfig,
+    shared::{
+        GBufferTexel, HitRecord, PathRecord, RenderMode, Reservoir, Restir, ShadowRay,
+        TracingConfig, BLOCK_SIZE, BLOCK_WORDS, COUNTER_RAYS, COUNTER_SHADE, COUNTER_SHADOWS,
+        DIAGNOSTICS_SIZE, MAX_WAVEFRONT_PATHS, SHADE_CLASSES, WAVEFRONT_COUNTERS, WAVEFRONT_QUEUES,
+        WAVEFRONT_TILE_SIZE,
+    },
 };
 
 const KERNEL: &[u8] = include_bytes!("k.gen/simple");
+// resources `Buffers::new` binds, all in the first descriptor set
+const KERNEL_BINDINGS: u32 = 30;
+// all but the config, the sampler and the atlas
+pub const KERNEL_STORAGE_BUFFERS: u32 = KERNEL_BINDINGS - 3;
+// between checks of a `KernelFile` for changes
+const KERNEL_POLL: Duration = Duration::from_millis(500);
 const BLUE_NOISE: &[u8] = include_bytes!("k/blue.png");
 
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum View {
+    #[default]
+    Color,
+    Albedo,
+    Normal,
+    Direct,
+    Indirect,
+    // samples per pixel relative to the most, shows where adaptive sampling spends them
+    Density,
+}
+
+impl View {
+    pub fn next(self) -> Self {
+        match self {
+            View::Color => View::Direct,
+            View::Direct => View::Indirect,
+            View::Indirect => View::Albedo,
+            View::Albedo => View::Normal,
+            View::Normal => View::Density,
+            View::Density => View::Color,
+        }
+    }
+}
+
+// Settings of the post pass, they only change what is shown and keep the accumulation
+#[derive(Debug, Clone, Copy)]
+pub struct Post {
+    pub view: View,
+    // in stops, applied before tonemapping radiance
+    pub exposure: f32,
+    pub tonemap: Tonemap,
+}
+
+impl Default for Post {
+    fn default() -> Self {
+        Self { view: View::default(), exposure: 0.0, tonemap: Tonemap::AcesFitted }
+    }
+}
+
 pub struct Tracing {
-    pub frame: Vec<f32>,
     pub config: TracingConfig,
     pub samples: usize,
+    // Longest a single dispatch should take, the tile size adapts to it. Operating systems reset
+    // GPUs that stay busy for too long, 2 s on Windows.
+    pub max_dispatch: Duration,
+    // the render loop stops tracing once there are this many samples
+    pub target_samples: Option<usize>,
+    // wall clock of the last sample's dispatches, timestamp queries are an optional feature
+    pub sample_time: Duration,
+    // a restart first traces a quick sample with few bounces, which the next one replaces
+    pub fast_preview: bool,
+    // the sums hold only such a preview
+    previewing: bool,
+    // edge length in pixels of the tiles a sample is dispatched in
+    tile_size: u32,
+    // traced with instead of the built-in kernel, reloaded when it changes on disk
+    pub kernel: Option<KernelFile>,
+    // trace with the wavefront passes instead of one thread per path, see `Wavefront`
+    pub wavefront: bool,
+    // created for the resolution, scene and kernel of the last `trace_gpu`, the kernel
+    // accumulates into its buffers across frames
+    buffers: Option<Buffers>,
+    // `sums` reads back into these, the color one holds `direct + indirect`, and `view`
+    // averages them into `frame`
+    frame: Vec<f32>,
+    readback: Vec<Vec4>,
+    indirect_readback: Vec<Vec4>,
 }
 
-impl Tracing {
-    pub fn frame(width: u32, height: u32) -> Vec<f32> {
-        vec![0.0; width as usize * height as usize * 3]
+struct Buffers {
+    size: (u32, u32),
+    // `GpuWorld::generation` the kernel is bound to
+    generation: u64,
+    // `KernelFile::generation` it was created from, 0 for the built-in one
+    kernel_generation: u64,
+    config: GpuUniformBuffer<'static, TracingConfig>,
+    // the kernel writes direct light to `output`
+    output: GpuBuffer<'static, Vec4>,
+    indirect: GpuBuffer<'static, Vec4>,
+    albedo: GpuBuffer<'static, Vec4>,
+    normal: GpuBuffer<'static, Vec4>,
+    // `BLUE_TEXTURE` tiled over the image
+    blue_noise: GpuBuffer<'static, Vec4>,
+    diagnostics: GpuBuffer<'static, u32>,
+    // what the camera rays of the current sample hit, `primary` writes it and `kernel` shades
+    // from it
+    gbuffer: GpuBuffer<'static, GBufferTexel>,
+    // the paths of a tile and their queues for the wavefront passes, a single entry each
+    // without them
+    paths: GpuBuffer<'static, PathRecord>,
+    hits: GpuBuffer<'static, HitRecord>,
+    queues: GpuBuffer<'static, u32>,
+    shadow_rays: GpuBuffer<'static, ShadowRay>,
+    counters: GpuBuffer<'static, u32>,
+    // ReSTIR reservoirs of every pixel, after temporal and after spatial reuse, a single entry
+    // each with ReSTIR off
+    reservoirs: GpuBuffer<'static, Reservoir>,
+    spatial_reservoirs: GpuBuffer<'static, Reservoir>,
+    // sums of the squared color for adaptive sampling, and per `BLOCK_SIZE` block of pixels
+    // which of them converged, see `shared::BLOCK_WORDS`
+    variance: GpuBuffer<'static, Vec4>,
+    block_mask: GpuBuffer<'static, u32>,
+    primary: Kernel<'static>,
+    kernel: Kernel<'static>,
+    wavefront: Option<Wavefront>,
+    resampling: Option<Resampling>,
+}
+
+// The ReSTIR passes between `primary` and `kernel`, see kernels/simple/src/restir.rs
+struct Resampling {
+    initial: Kernel<'static>,
+    spatial: Kernel<'static>,
+}
+
+// The passes of wavefront tracing, see kernels/simple/src/wavefront.rs
+struct Wavefront {
+    generate: Kernel<'static>,
+    intersect: Kernel<'static>,
+    shade: Kernel<'static>,
+    shadow: Kernel<'static>,
+    accumulate: Kernel<'static>,
+}
+
+impl Buffers {
+    fn new(
+        config: &TracingConfig,
+        world: &GpuWorld<'_>,
+        spirv: &[u8],
+        kernel_generation: u64,
+        wavefront: bool,
+    ) -> Self {
+        let (width, height) = (config.width, config.height);
+        let pixels = || GpuBuffer::with_capacity(&FW, width as u64 * height as u64);
+        let config_buf = GpuUniformBuffer::from_slice(&FW, &[*config]);
+        let (output, indirect, albedo, normal) = (pixels(), pixels(), pixels(), pixels());
+        let blue_noise = Self::blue_noise(width, height);
+        let diagnostics = GpuBuffer::from_slice(&FW, &[0u32; DIAGNOSTICS_SIZE]);
+        let gbuffer = GpuBuffer::with_capacity(&FW, width as u64 * height as u64);
+        // no wavefront tile has more pixels
+        let capacity = if wavefront {
+            (width as u64 * height as u64).min(MAX_WAVEFRONT_PATHS as u64)
+        } else {
+            1
+        };
+        let paths = GpuBuffer::with_capacity(&FW, capacity);
+        let hits = GpuBuffer::with_capacity(&FW, capacity);
+        let queues = GpuBuffer::with_capacity(&FW, capacity * WAVEFRONT_QUEUES as u64);
+        let shadow_rays = GpuBuffer::with_capacity(&FW, capacity);
+        let counters = GpuBuffer::from_slice(&FW, &[0u32; WAVEFRONT_COUNTERS]);
+        let restir = config.restir() != Restir::Off;
+        let reservoir_count = if restir { width as u64 * height as u64 } else { 1 };
+        let reservoirs = GpuBuffer::with_capacity(&FW, reservoir_count);
+        let spatial_reservoirs = GpuBuffer::with_capacity(&FW, reservoir_count);
+        let variance = pixels();
+        let blocks = width.div_ceil(BLOCK_SIZE) as u64 * height.div_ceil(BLOCK_SIZE) as u64;
+        let block_mask =
+            GpuBuffer::from_slice(&FW, &vec![0u32; (blocks * BLOCK_WORDS as u64) as usize]);
+
+        let shader = Shader::from_spirv_bytes(&FW, spirv, Some("compute"));
+        let sampler = Sampler::new(&FW, AddressMode::ClampToEdge, FilterMode::Linear);
+        // bindings are numbered in the order they are added, matching the parameters of
+        // `main_cs` in kernels/simple with the wavefront passes' between its G-buffer and its
+        // reservoirs, `KERNEL_BINDINGS` of them. Every pass uses some of them under the same
+        // numbers.
+        let bindings = || {
+            DescriptorSet::default()
+                .bind_uniform_buffer(&config_buf)
+                .bind_buffer(&output, GpuBufferUsage::ReadWrite)
+                .bind_buffer(&world.indices, GpuBufferUsage::ReadOnly)
+                .bind_buffer(&world.per_vertex, GpuBufferUsage::ReadOnly)
+                .bind_buffer(&world.bvh.nodes, GpuBufferUsage::ReadOnly)
+                .bind_buffer(&world.materials, GpuBufferUsage::ReadOnly)
+                .bind_buffer(&world.lights, GpuBufferUsage::ReadOnly)
+                .bind_sampler(&sampler)
+                .bind_const_image(&world.atlas)
+                .bind_buffer(&world.env_map, GpuBufferUsage::ReadOnly)
+                .bind_buffer(&world.env_marginal_cdf, GpuBufferUsage::ReadOnly)
+                .bind_buffer(&world.env_conditional_cdf, GpuBufferUsage::ReadOnly)
+                .bind_buffer(&albedo, GpuBufferUsage::ReadWrite)
+                .bind_buffer(&normal, GpuBufferUsage::ReadWrite)
+                .bind_buffer(&indirect, GpuBufferUsage::ReadWrite)
+                .bind_buffer(&world.bvh.parents, GpuBufferUsage::ReadOnly)
+                .bind_buffer(&world.bvh.instances, GpuBufferUsage::ReadOnly)
+                .bind_buffer(&blue_noise, GpuBufferUsage::ReadOnly)
+                .bind_buffer(&diagnostics, GpuBufferUsage::ReadWrite)
+                .bind_buffer(&world.punctual_lights, GpuBufferUsage::ReadOnly)
+                .bind_buffer(&gbuffer, GpuBufferUsage::ReadWrite)
+                .bind_buffer(&paths, GpuBufferUsage::ReadWrite)
+                .bind_buffer(&hits, GpuBufferUsage::ReadWrite)
+                .bind_buffer(&queues, GpuBufferUsage::ReadWrite)
+                .bind_buffer(&shadow_rays, GpuBufferUsage::ReadWrite)
+                .bind_buffer(&counters, GpuBufferUsage::ReadWrite)
+                .bind_buffer(&reservoirs, GpuBufferUsage::ReadWrite)
+                .bind_buffer(&spatial_reservoirs, GpuBufferUsage::ReadWrite)
+                .bind_buffer(&variance, GpuBufferUsage::ReadWrite)
+                .bind_buffer(&block_mask, GpuBufferUsage::ReadWrite)
+        };
+        let program = |entry: &str| Program::new(&shader, entry).add_descriptor_set(bindings());
+        let primary = Kernel::new(&FW, program(shaders::primary_cs));
+        let kernel = Kernel::new(&FW, program(shaders::main_cs));
+        let wavefront = wavefront.then(|| Wavefront {
+            generate: Kernel::new(&FW, program(shaders::generate_cs)),
+            intersect: Kernel::new(&FW, program(shaders::intersect_cs)),
+            shade: Kernel::new(&FW, program(shaders::shade_cs)),
+            shadow: Kernel::new(&FW, program(shaders::shadow_cs)),
+            accumulate: Kernel::new(&FW, program(shaders::accumulate_cs)),
+        });
+        let resampling = restir.then(|| Resampling {
+            initial: Kernel::new(&FW, program(shaders::restir_initial_cs)),
+            spatial: Kernel::new(&FW, program(shaders::restir_spatial_cs)),
+        });
+
+        Self {
+            size: (width, height),
+            generation: world.generation,
+            kernel_generation,
+            config: config_buf,
+            output,
+            indirect,
+            albedo,
+            normal,
+            blue_noise,
+            diagnostics,
+            gbuffer,
+            paths,
+            hits,
+            queues,
+            shadow_rays,
+            counters,
+            reservoirs,
+            spatial_reservoirs,
+            variance,
+            block_mask,
+            primary,
+            kernel,
+            wavefront,
+            resampling,
+        }
+    }
+
+    // in the order of `Checkpoint::sums`
+    fn sums(&self) -> [&GpuBuffer<'static, Vec4>; 5] {
+        [&self.output, &self.indirect, &self.albedo, &self.normal, &self.variance]
     }
 
+    // Whether every pixel of `tile` converged when it was last traced, see `main_cs`. `mask`
+    // is `block_mask` read back.
+    fn converged(&self, mask: &[u32], tile: UVec4) -> bool {
+        let blocks_per_row = self.size.0.div_ceil(BLOCK_SIZE);
+        let (first, last) = (tile.xy() / BLOCK_SIZE, (tile.xy() + tile.zw() - 1) / BLOCK_SIZE);
+        (first.y..=last.y).all(|y| {
+            (first.x..=last.x).all(|x| {
+                let block = ((y * blocks_per_row + x) * BLOCK_WORDS) as usize;
+                mask[block..block + BLOCK_WORDS as usize].iter().all(|&word| word == u32::MAX)
+            })
+        })
+    }
+
+    // all four channels, the kernel rotates them per sample
+    fn blue_noise(width: u32, height: u32) -> GpuBuffer<'static, Vec4> {
+        let (tile_width, tile_height) = BLUE_TEXTURE.dimensions();
+        let mut texels = Vec::with_capacity(width as usize * height as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let [r, g, b, a] = BLUE_TEXTURE.get_pixel(x % tile_width, y % tile_height).0;
+                texels.push(Vec4::new(r as f32, g as f32, b as f32, a as f32) / 255.0);
+            }
+        }
+        GpuBuffer::from_slice(&FW, &texels)
+    }
+}
+
+impl Tracing {
     pub fn new(config: TracingConfig) -> Self {
-        Self { frame: Self::frame(config.width, config.height), config, samples: 0 }
+        Self {
+            config,
+            samples: 0,
+            max_dispatch: Duration::from_millis(50),
+            target_samples: None,
+            sample_time: Duration::ZERO,
+            fast_preview: false,
+            previewing: false,
+            tile_size: 256,
+            kernel: None,
+            wavefront: false,
+            buffers: None,
+            frame: Vec::new(),
+            readback: Vec::new(),
+            indirect_readback: Vec::new(),
+        }
+    }
+
+    // The next sample starts the accumulation over, the kernel overwrites the sums then
+    pub fn reset(&mut self) {
+        self.samples = 0;
+        self.previewing = false;
+    }
+
+    // What the window shows, the buffers themselves rather than a copy. None before the first
+    // sample.
+    pub fn display(&self) -> Option<Sums> {
+        let buffers = self.buffers.as_ref().filter(|_| self.samples > 0)?;
+        let (width, height) = buffers.size;
+        Some(Sums {
+            width,
+            height,
+            direct: buffers.output.raw().clone(),
+            indirect: buffers.indirect.raw().clone(),
+            albedo: buffers.albedo.raw().clone(),
+            normal: buffers.normal.raw().clone(),
+        })
+    }
+
+    // The sums of the samples so far with their count in `w`, the color view adds up direct and
+    // indirect light. Reads back from the GPU, for picking and saving, the window binds the
+    // buffers instead, see `display`.
+    pub fn sums(&mut self, view: View) -> Result<&[Vec4], BufferError> {
+        let pixels = (self.config.width * self.config.height) as usize;
+        self.readback.resize(pixels, Vec4::ZERO);
+        let Some(buffers) = self.buffers.as_ref().filter(|_| self.samples > 0) else {
+            self.readback.fill(Vec4::ZERO);
+            return Ok(&self.readback);
+        };
+
+        let buffer = match view {
+            View::Color | View::Direct | View::Density => &buffers.output,
+            View::Indirect => &buffers.indirect,
+            View::Albedo => &buffers.albedo,
+            View::Normal => &buffers.normal,
+        };
+        buffer.read_blocking(&mut self.readback)?;
+        if view == View::Color {
+            self.indirect_readback.resize(pixels, Vec4::ZERO);
+            buffers.indirect.read_blocking(&mut self.indirect_readback)?;
+            // both count the same samples
+            for (sum, indirect) in self.readback.iter_mut().zip(&self.indirect_readback) {
+                *sum += indirect.truncate().extend(0.0);
+            }
+        }
+        Ok(&self.readback)
+    }
+
+    // Whether the accumulation reached `target_samples`, a preview doesn't count
+    pub fn done(&self) -> bool {
+        self.target_samples.is_some_and(|target| self.samples >= target && !self.previewing)
+    }
+
+    // The sums so far, for another run to continue from. None before the first sample.
+    pub fn checkpoint(&self, hash: u64) -> Result<Option<Checkpoint>, BufferError> {
+        let Some(buffers) = self.buffers.as_ref().filter(|_| self.samples > 0 && !self.previewing)
+        else {
+            return Ok(None);
+        };
+        let pixels = (self.config.width * self.config.height) as usize;
+        let mut sums = [(); 5].map(|_| vec![Vec4::ZERO; pixels]);
+        for (sums, buffer) in sums.iter_mut().zip(buffers.sums()) {
+            buffer.read_blocking(sums)?;
+        }
+        let (width, height) = (self.config.width, self.config.height);
+        Ok(Some(Checkpoint { width, height, samples: self.samples, hash, sums }))
+    }
+
+    // Continues the accumulation of `checkpoint`, which has to be at the current resolution
+    pub fn restore(
+        &mut self,
+        checkpoint: &Checkpoint,
+        world: &GpuWorld<'_>,
+    ) -> Result<(), BufferError> {
+        self.bind(world);
+        let Some(buffers) = &self.buffers else { return Ok(()) };
+        for (sums, buffer) in checkpoint.sums.iter().zip(buffers.sums()) {
+            buffer.write(sums)?;
+        }
+        self.samples = checkpoint.samples;
+        self.previewing = false;
+        Ok(())
+    }
+
+    // Picks up a change of the `kernel` file, returns whether the next sample traces with
+    // another kernel. The accumulation starts over with it.
+    pub fn reload_kernel(&mut self) -> bool {
+        self.kernel.as_mut().is_some_and(KernelFile::reload)
+    }
+
+    // Creates the buffers and the kernel for the resolution, the scene, the kernel, whether to
+    // trace wavefront and whether to keep reservoirs, the accumulation starts over when they are
+    // recreated
+    fn bind(&mut self, world: &GpuWorld<'_>) {
+        let TracingConfig { width, height, .. } = self.config;
+        let (spirv, kernel_generation) = match &self.kernel {
+            Some(kernel) => (&kernel.spirv[..], kernel.generation),
+            None => (KERNEL, 0),
+        };
+        let stale = self.buffers.as_ref().map_or(true, |buffers| {
+            buffers.size != (width, height)
+                || buffers.generation != world.generation
+                || buffers.kernel_generation != kernel_generation
+                || buffers.wavefront.is_some() != self.wavefront
+                || buffers.resampling.is_some() != (self.config.restir() != Restir::Off)
+        });
+        if stale {
+            // dropped first, the old kernel keeps the previous scene's buffers alive
+            self.buffers = None;
+            let buffers =
+                Buffers::new(&self.config, world, spirv, kernel_generation, self.wavefront);
+            self.buffers = Some(buffers);
+            self.samples = 0;
+        }
+    }
+
+    // Averages the samples so far into linear RGB, top row first. Adaptive sampling leaves
+    // pixels at different counts.
+    pub fn view(&mut self, view: View) -> Result<&[f32], BufferError> {
+        let pixels = (self.config.width * self.config.height) as usize;
+        self.frame.resize(pixels * 3, 0.0);
+        self.sums(view)?;
+        for (rgb, sum) in self.frame.chunks_exact_mut(3).zip(&self.readback) {
+            rgb.copy_from_slice(&(sum.truncate() / sum.w.max(1.0)).to_array());
+        }
+        Ok(&self.frame)
     }
 }
 
-struct PathTracing<'fw>(Kernel<'fw>);
+// A kernel built outside the viewer, like `KERNEL` by kernels/src/main.rs. Modules that don't
+// validate are reported and the previous one stays in use, so a broken build doesn't end the run.
+pub struct KernelFile {
+    path: PathBuf,
+    spirv: Vec<u8>,
+    // of the file when `spirv` was read
+    modified: Option<SystemTime>,
+    checked: Instant,
+    // bumped by every reload, starts at 1 to differ from the built-in kernel
+    generation: u64,
+}
 
-impl<'fw> PathTracing<'fw> {
-    fn new(
-        config_buf: &GpuUniformBuffer<'fw, TracingConfig>,
-        rng_buffer: &GpuBuffer<'fw, UVec2>,
-        output_buf: &GpuBuffer<'fw, Vec4>,
-        world: &GpuWorld<'fw>,
-    ) -> Self {
-        let shader = Shader::from_spirv_bytes(&FW, KERNEL, Some("compute"));
-        let sampler = Sampler::new(&FW, SamplerWrapMode::ClampToEdge, SamplerFilterMode::Linear);
-        let bindings = DescriptorSet::default()
-            .bind_uniform_buffer(config_buf)
-            .bind_buffer(rng_buffer, GpuBufferUsage::ReadWrite)
-            .bind_buffer(output_buf, GpuBufferUsage::ReadWrite)
-            .bind_buffer(&world.indices, GpuBufferUsage::ReadOnly)
-            .bind_buffer(&world.per_vertex, GpuBufferUsage::ReadOnly)
-            .bind_buffer(&world.bvh.nodes, GpuBufferUsage::ReadOnly)
-            .bind_buffer(&world.materials, GpuBufferUsage::ReadOnly)
-            .bind_buffer(&world.lights, GpuBufferUsage::ReadOnly)
-            .bind_sampler(&sampler)
-            .bind_const_image(&world.atlas);
-        Self(Kernel::new(&FW, Program::new(&shader, "main_cs").add_descriptor_set(bindings)))
+impl KernelFile {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let modified = modified(path);
+        Ok(Self {
+            path: path.to_path_buf(),
+            spirv: read_kernel(path)?,
+            modified,
+            checked: Instant::now(),
+            generation: 1,
+        })
+    }
+
+    // Rereads the file if it changed since the last check a while ago, returns whether it did
+    // and the new module is valid
+    fn reload(&mut self) -> bool {
+        if self.checked.elapsed() < KERNEL_POLL {
+            return false;
+        }
+        self.checked = Instant::now();
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return false;
+        }
+        // a file that is still being written fails and is read again once it changes next
+        self.modified = modified;
+        match read_kernel(&self.path) {
+            Ok(spirv) => {
+                println!("Reloaded the kernel from {}", self.path.display());
+                self.spirv = spirv;
+                self.generation += 1;
+                true
+            }
+            Err(err) => {
+                eprintln!("{err}, keeping the previous kernel");
+                false
+            }
+        }
     }
 }
 
-pub fn trace_gpu<'a>(mut state: &'a mut Tracing, world: &GpuWorld<'_>) -> &'a [f32] {
-    let TracingConfig { width, height, .. } = state.config;
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
 
-    let pixel_count = (width * height) as usize;
+// Reads a SPIR-V module and validates it the way wgpu would, since wgpu panics on modules
+// that don't validate. It has to have all passes and bind nothing `Buffers::new` doesn't.
+fn read_kernel(path: &Path) -> Result<Vec<u8>, String> {
+    let failed = |reason: String| format!("Failed to load the kernel {}: {reason}", path.display());
+    let spirv = fs::read(path).map_err(|err| failed(err.to_string()))?;
+    let module = naga::front::spv::parse_u8_slice(&spirv, &Default::default())
+        .map_err(|err| failed(crate::error_chain(&err)))?;
+    naga::valid::Validator::new(ValidationFlags::all(), Capabilities::all())
+        .validate(&module)
+        .map_err(|err| failed(crate::error_chain(&err)))?;
 
-    let mut rng = rand::thread_rng();
-    let mut blue: Vec<UVec2> = vec![UVec2::ZERO; pixel_count];
-    let mut uniform: Vec<UVec2> = vec![UVec2::ZERO; pixel_count];
-    for y in 0..height {
-        for x in 0..width {
-            let idx = (y * width + x) as usize;
-            let pixel = BLUE_TEXTURE.get_pixel(x % BLUE_TEXTURE.width(), y % BLUE_TEXTURE.height())
-                [0] as f32
-                / 255.0;
-            blue[idx].x = 0;
-            blue[idx].y = (pixel * 4294967295.0) as u32;
-            uniform[idx].x = Rng::gen(&mut rng);
+    let entries = [
+        shaders::primary_cs,
+        shaders::restir_initial_cs,
+        shaders::restir_spatial_cs,
+        shaders::main_cs,
+        shaders::generate_cs,
+        shaders::intersect_cs,
+        shaders::shade_cs,
+        shaders::shadow_cs,
+        shaders::accumulate_cs,
+    ];
+    for name in entries {
+        let entry = module.entry_points.iter().find(|entry| entry.name == name);
+        if !entry.is_some_and(|entry| entry.stage == ShaderStage::Compute) {
+            return Err(failed(format!("no `{name}` compute entry point")));
         }
     }
+    let unbound = module.global_variables.iter().find_map(|(_, global)| {
+        global
+            .binding
+            .as_ref()
+            .filter(|binding| binding.group != 0 || binding.binding >= KERNEL_BINDINGS)
+    });
+    if let Some(binding) = unbound {
+        return Err(failed(format!(
+            "binding {} of set {} isn't provided by the viewer",
+            binding.binding, binding.group
+        )));
+    }
+    Ok(spirv)
+}
 
-    // state.frame.fill(0.0);
+// Bounces of the fast preview, enough for direct light and a first indirect one
+const PREVIEW_BOUNCES: u32 = 1;
+// bounds of the adaptive tile size, multiples of the kernel's 8x8 workgroups
+const MIN_TILE_SIZE: u32 = 32;
+const MAX_TILE_SIZE: u32 = 4096;
 
-    let samples = state.samples as f32;
-    // let samples = 0.0;
-    let raw_buf = state
-        .frame
-        .chunks(3)
-        .map(|c| Vec4::new(c[0], c[1], c[2], 1.0) * samples)
-        .collect::<Vec<_>>();
+// Adds one sample per pixel. Only the config is uploaded per call, the buffers and the kernel
+// are recreated when the resolution or the scene changes.
+pub fn trace_gpu(state: &mut Tracing, world: &GpuWorld<'_>) {
+    let TracingConfig { width, height, .. } = state.config;
+    state.bind(world);
+    let Some(buffers) = &state.buffers else { return };
 
-    let config_buf = GpuUniformBuffer::from_slice(&FW, &[state.config]);
-    let rng_buf = GpuBuffer::from_slice(&FW, &uniform);
-    let output_buf = GpuBuffer::from_slice(&FW, &raw_buf);
-    let rt = PathTracing::new(&config_buf, &rng_buf, &output_buf, world);
+    // the sample after a preview starts the sums over
+    if state.previewing {
+        state.samples = 0;
+    }
+    let mut config = state.config;
+    let preview = state.fast_preview
+        && !state.previewing
+        && state.samples == 0
+        && config.max_bounces > PREVIEW_BOUNCES;
+    state.previewing = preview;
+    if preview {
+        config.max_bounces = PREVIEW_BOUNCES;
+        config.min_bounces = config.min_bounces.min(PREVIEW_BOUNCES);
+    }
+
+    // the kernel hashes (pixel, sample, dimension), so every sample gets fresh decorrelated numbers.
+    // Kept out of `state.config`, which is compared against the UI config to detect changes.
+    config.sample_index = state.samples as u32;
+    if config.debug_nan() {
+        let _ = buffers.diagnostics.write(&[0u32; DIAGNOSTICS_SIZE]);
+    }
 
-    let mut image_buf_raw: Vec<Vec4> = vec![Vec4::ZERO; pixel_count];
-    let mut image_buf: Vec<f32> = vec![0.0; pixel_count * 3];
+    // ambient occlusion only needs the camera hit, the megakernel traces it either way
+    let ambient_occlusion = config.render_mode() == RenderMode::AmbientOcclusion;
+    let wavefront = buffers.wavefront.as_ref().filter(|_| !ambient_occlusion);
+    // ReSTIR resamples the megakernel's camera hits only
+    let resampling =
+        buffers.resampling.as_ref().filter(|_| wavefront.is_none() && !ambient_occlusion);
+    if resampling.is_none() {
+        config.set_restir(Restir::Off);
+    }
+    let spatial = config.restir() == Restir::Spatiotemporal;
+    // Adaptive sampling skips converged pixels in the megakernel, and the tiles all of whose
+    // pixels were converged on the last sample
+    if wavefront.is_some() {
+        config.adaptive_threshold = 0.0;
+    }
+    let mut mask = Vec::new();
+    if config.adaptive_threshold > 0.0 && config.sample_index > config.adaptive_min_samples {
+        let blocks = width.div_ceil(BLOCK_SIZE) * height.div_ceil(BLOCK_SIZE);
+        mask.resize((blocks * BLOCK_WORDS) as usize, 0);
+        let _ = buffers.block_mask.read_blocking(&mut mask);
+    }
+    let converged = |tile: UVec4| !mask.is_empty() && buffers.converged(&mask, tile);
 
-    rt.0.enqueue(width.div_ceil(8), height.div_ceil(8), 1);
-    FW.poll_blocking();
+    // every tile is a submission of its own and waited for, so none of them runs long enough to
+    // lose the device. Wavefront tiles are as large as there are paths, every pass only runs
+    // one bounce of them.
+    let tile_size = if wavefront.is_some() { WAVEFRONT_TILE_SIZE } else { state.tile_size };
+    let tiles: Vec<UVec4> = (0..height)
+        .step_by(tile_size as usize)
+        .flat_map(|y| {
+            (0..width)
+                .step_by(tile_size as usize)
+                .map(move |x| UVec4::new(x, y, tile_size.min(width - x), tile_size.min(height - y)))
+        })
+        .collect();
+    let (mut slowest, mut total) = (Duration::ZERO, Duration::ZERO);
+    let mut dispatch = |tile: UVec4, enqueue: &dyn Fn(u32, u32)| {
+        config.tile = tile;
+        let _ = buffers.config.write(&[config]);
+        let start = Instant::now();
+        enqueue(tile.z.div_ceil(8), tile.w.div_ceil(8));
+        FW.poll_blocking();
+        let elapsed = start.elapsed();
+        slowest = slowest.max(elapsed);
+        total += elapsed;
+    };
 
+    // The spatial pass reads the reservoirs of neighbouring tiles, so every tile gets its
+    // G-buffer and reservoirs before any is shaded. Submissions run in order, every pass waits
+    // for the previous one's writes.
+    if let Some(resampling) = resampling {
+        for &tile in &tiles {
+            dispatch(tile, &|x, y| {
+                buffers.primary.enqueue(x, y, 1);
+                resampling.initial.enqueue(x, y, 1);
+            });
+        }
+    }
+    for &tile in tiles.iter().filter(|&&tile| !converged(tile)) {
+        if let Some(wavefront) = wavefront {
+            dispatch(tile, &|_, _| wavefront.trace(buffers, tile.z * tile.w));
+        } else {
+            dispatch(tile, &|x, y| {
+                match resampling {
+                    Some(resampling) if spatial => resampling.spatial.enqueue(x, y, 1),
+                    Some(_) => {}
+                    None => buffers.primary.enqueue(x, y, 1),
+                }
+                buffers.kernel.enqueue(x, y, 1);
+            });
+        }
+    }
     state.samples += 1;
+    state.sample_time = total;
+
+    // previews are cheaper than the samples the tiles are sized for
+    if !preview && wavefront.is_none() {
+        if slowest > state.max_dispatch {
+            state.tile_size = (tile_size / 2).max(MIN_TILE_SIZE);
+        } else if slowest * 4 < state.max_dispatch && tile_size < width.max(height) {
+            state.tile_size = (tile_size * 2).min(MAX_TILE_SIZE);
+        }
+    }
+
+    if config.debug_nan() {
+        let mut diagnostics = [0u32; DIAGNOSTICS_SIZE];
+        let _ = buffers.diagnostics.read_blocking(&mut diagnostics[..]);
+        if diagnostics[0] > 0 {
+            let bounces = diagnostics[1..]
+                .iter()
+                .enumerate()
+                .filter(|(_, &count)| count > 0)
+                .map(|(bounce, count)| format!("bounce {bounce}: {count}"))
+                .collect::<Vec<_>>();
+            println!("NaN samples: {} ({})", diagnostics[0], bounces.join(", "));
+        }
+    }
+}
+
+// threads per workgroup of the wavefront passes
+const WAVEFRONT_WORKGROUP: u32 = 64;
 
-    let _ = output_buf.read_blocking(&mut image_buf_raw[..]);
-    for (i, col) in image_buf_raw.iter().enumerate() {
-        image_buf[i * 3] = col.x / (samples + 1.0);
-        image_buf[i * 3 + 1] = col.y / (samples + 1.0);
-        image_buf[i * 3 + 2] = col.z / (samples + 1.0);
+impl Wavefront {
+    // Runs the first `paths` paths of the tile in `buffers.config` to the end. The counters are
+    // read back after intersecting and after shading, they size the next dispatches.
+    fn trace(&self, buffers: &Buffers, paths: u32) {
+        let workgroups = |count: u32| count.div_ceil(WAVEFRONT_WORKGROUP);
+        let mut counters = [0u32; WAVEFRONT_COUNTERS];
+        counters[COUNTER_RAYS] = paths;
+        let _ = buffers.counters.write(&counters);
+        self.generate.enqueue(workgroups(paths), 1, 1);
+
+        let mut rays = paths;
+        while rays > 0 {
+            self.intersect.enqueue(workgroups(rays), 1, 1);
+            let _ = buffers.counters.read_blocking(&mut counters[..]);
+            // shading queues the next rays from the start of the ray queue
+            counters[COUNTER_RAYS] = 0;
+            let _ = buffers.counters.write(&counters);
+            for class in 0..SHADE_CLASSES {
+                let count = counters[COUNTER_SHADE + class];
+                if count > 0 {
+                    // the first slot only, the others count what shading appends
+                    let _ = buffers.counters.write(&[class as u32]);
+                    self.shade.enqueue(workgroups(count), 1, 1);
+                }
+            }
+
+            let _ = buffers.counters.read_blocking(&mut counters[..]);
+            rays = counters[COUNTER_RAYS];
+            if counters[COUNTER_SHADOWS] > 0 {
+                self.shadow.enqueue(workgroups(counters[COUNTER_SHADOWS]), 1, 1);
+            }
+            // the shade and shadow queues fill up from the start again
+            counters[COUNTER_SHADE..].fill(0);
+            let _ = buffers.counters.write(&counters);
+        }
+        self.accumulate.enqueue(workgroups(paths), 1, 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::scene::{Axis, FormatHint, Handedness, SceneOptions, World},
+    };
+
+    // A triangle facing the default camera, it covers the middle of the image
+    const TRIANGLE: &[u8] = b"v -2 -1 0\nv 2 -1 0\nv 0 3 0\nf 1 2 3\n";
+
+    // Whether there is a device to trace on, the tests that need one pass without it
+    fn gpu() -> bool {
+        let available = FW.init(gpu::instance(), None).is_ok();
+        if !available {
+            eprintln!("no GPU adapter, skipped");
+        }
+        available
     }
 
-    state.frame.copy_from_slice(image_buf.as_slice());
-    &state.frame
+    fn triangle() -> (GpuWorld<'static>, TracingConfig) {
+        // world space as is
+        let options = SceneOptions { up_axis: Axis::Y, handedness: Handedness::Left, scale: 1.0 };
+        let world = World::from_bytes(TRIANGLE, FormatHint::Obj, options).unwrap();
+        let mut config = TracingConfig::soft();
+        (config.width, config.height) = (32, 32);
+        world.configure(&mut config);
+        (world.upload().unwrap(), config)
+    }
+
+    fn pixel(frame: &[f32], x: u32, y: u32) -> [f32; 3] {
+        let i = (y * 32 + x) as usize * 3;
+        [frame[i], frame[i + 1], frame[i + 2]]
+    }
+
+    #[test]
+    fn triangle_is_lit() {
+        if !gpu() {
+            return;
+        }
+        let (world, config) = triangle();
+        let mut state = Tracing::new(config);
+        for _ in 0..4 {
+            trace_gpu(&mut state, &world);
+        }
+        assert_eq!(state.samples, 4);
+
+        let color = pixel(state.view(View::Color).unwrap(), 16, 16);
+        assert!(color.iter().any(|&c| c > 0.0), "{color:?}");
+        assert!(color.iter().all(|c| c.is_finite()), "{color:?}");
+        // misses have no normal, so these tell the triangle from the sky
+        let normals = state.view(View::Normal).unwrap();
+        assert_ne!(pixel(normals, 16, 16), [0.0; 3]);
+        assert_eq!(pixel(normals, 0, 0), [0.0; 3]);
+    }
+
+    #[test]
+    fn resumed_accumulation_matches_an_uninterrupted_one() {
+        if !gpu() {
+            return;
+        }
+        let (world, config) = triangle();
+        let mut straight = Tracing::new(config);
+        for _ in 0..16 {
+            trace_gpu(&mut straight, &world);
+        }
+
+        let mut first = Tracing::new(config);
+        for _ in 0..8 {
+            trace_gpu(&mut first, &world);
+        }
+        let path = std::env::temp_dir().join(format!("racist-{}-resume.ck", std::process::id()));
+        first.checkpoint(0).unwrap().unwrap().save(&path).unwrap();
+        let checkpoint = Checkpoint::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut resumed = Tracing::new(config);
+        resumed.restore(&checkpoint, &world).unwrap();
+        for _ in 0..8 {
+            trace_gpu(&mut resumed, &world);
+        }
+
+        assert_eq!(resumed.samples, 16);
+        for view in [View::Color, View::Albedo, View::Normal] {
+            let expected = straight.view(view).unwrap().to_vec();
+            for (a, b) in resumed.view(view).unwrap().iter().zip(&expected) {
+                assert!((a - b).abs() <= 1e-4 * b.abs().max(1.0), "{view:?}: {a} != {b}");
+            }
+        }
+    }
+
+    #[test]
+    fn display_binds_the_accumulated_buffers() {
+        if !gpu() {
+            return;
+        }
+        let (world, config) = triangle();
+        let mut state = Tracing::new(config);
+        assert!(state.display().is_none());
+        trace_gpu(&mut state, &world);
+        let sums = state.display().unwrap();
+        assert_eq!((sums.width, sums.height), (32, 32));
+        // the kernel's own buffers, not copies
+        let buffers = state.buffers.as_ref().unwrap();
+        assert!(Arc::ptr_eq(&sums.direct, buffers.output.raw()));
+        assert!(Arc::ptr_eq(&sums.indirect, buffers.indirect.raw()));
+    }
 }
@@ -0,0 +1,493 @@
+use {
+    crate::{block_on, compute::KERNEL_STORAGE_BUFFERS},
+    bytemuck::Pod,
+    std::{
+        fmt,
+        marker::PhantomData,
+        mem,
+        ops::Deref,
+        sync::{mpsc, Arc, OnceLock},
+    },
+    wgpu::{
+        util::{self, DeviceExt},
+        Adapter, AddressMode, Backends, BindGroup, BindGroupDescriptor, BindGroupEntry,
+        BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType,
+        BufferBindingType, BufferUsages, CommandEncoderDescriptor, ComputePassDescriptor,
+        ComputePipeline, ComputePipelineDescriptor, DeviceDescriptor, DeviceLostReason, Extent3d,
+        Features, FilterMode, ImageCopyTexture, ImageDataLayout, Instance, InstanceDescriptor,
+        Limits, Maintain, MapMode, Origin3d, PipelineLayoutDescriptor, PowerPreference,
+        RequestAdapterOptions, SamplerBindingType, SamplerDescriptor, ShaderModuleDescriptor,
+        ShaderSource, ShaderStages, Surface, TextureAspect, TextureDescriptor, TextureDimension,
+        TextureFormat, TextureSampleType, TextureUsages, TextureViewDescriptor,
+        TextureViewDimension,
+    },
+};
+
+// The one device of the viewer, the kernels trace on it and the window presents from it, so
+// the display binds the sums instead of reading them back. Buffers, images and kernels borrow
+// it, see `compute::FW`.
+pub struct Framework {
+    // the window creates its surface from them, see `compute::Wgpu`
+    pub instance: Instance,
+    pub adapter: Adapter,
+    pub device: wgpu::Device,
+    pub queue: wgpu::Queue,
+}
+
+// Backends the viewer runs on, the window's surface has to come from the framework's instance
+pub fn instance() -> Instance {
+    Instance::new(InstanceDescriptor {
+        dx12_shader_compiler: util::dx12_shader_compiler_from_env().unwrap_or_default(),
+        backends: Backends::PRIMARY,
+        ..Default::default()
+    })
+}
+
+// What the kernels need past the defaults, scene buffers and the atlas as large as the adapter
+// allows
+fn required_limits(adapter: &Limits) -> Limits {
+    Limits {
+        max_storage_buffers_per_shader_stage: KERNEL_STORAGE_BUFFERS,
+        max_storage_buffer_binding_size: adapter.max_storage_buffer_binding_size,
+        max_buffer_size: adapter.max_buffer_size,
+        max_texture_dimension_2d: adapter.max_texture_dimension_2d,
+        ..Limits::default()
+    }
+}
+
+impl Framework {
+    // With a window, only adapters that can present to `surface` qualify, the high performance
+    // one of a hybrid laptop may not be able to
+    pub fn new(instance: Instance, surface: Option<&Surface>) -> Result<Self, String> {
+        let adapter = block_on(instance.request_adapter(&RequestAdapterOptions {
+            power_preference: PowerPreference::HighPerformance,
+            compatible_surface: surface,
+            force_fallback_adapter: false,
+        }))
+        .ok_or(match surface {
+            Some(_) => "no GPU adapter can present to the window",
+            None => "no GPU adapter",
+        })?;
+        let descriptor = DeviceDescriptor {
+            label: None,
+            required_features: Features::empty(),
+            required_limits: required_limits(&adapter.limits()),
+        };
+        let (device, queue) =
+            block_on(adapter.request_device(&descriptor, None)).map_err(|err| err.to_string())?;
+
+        // the default handler panics, which would take the viewer down over a bad frame
+        device.on_uncaptured_error(Box::new(|err| eprintln!("GPU error: {err}")));
+        // The scene, the sums and the window all live on it, so there is nothing left to
+        // recover with
+        device.set_device_lost_callback(|reason, message| {
+            // dropping the device reports it too, that happens on exit
+            if !matches!(reason, DeviceLostReason::Dropped) {
+                eprintln!("The GPU device was lost ({reason:?}), exiting: {message}");
+                std::process::exit(1);
+            }
+        });
+        Ok(Self { instance, adapter, device, queue })
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.device.limits()
+    }
+
+    // Waits for every submission so far
+    pub fn poll_blocking(&self) {
+        self.device.poll(Maintain::Wait);
+    }
+}
+
+// `compute::FW`, created by `init` or else on first use without a window
+pub struct FrameworkCell(OnceLock<Framework>);
+
+impl FrameworkCell {
+    pub const fn new() -> Self {
+        Self(OnceLock::new())
+    }
+
+    // Creates the framework for the window's `surface`, or without one. Has to come before
+    // anything else uses it when there is a window.
+    pub fn init(
+        &self,
+        instance: Instance,
+        surface: Option<&Surface>,
+    ) -> Result<&Framework, String> {
+        if let Some(framework) = self.0.get() {
+            return match surface {
+                Some(_) => Err("the GPU device was created before the window".to_string()),
+                None => Ok(framework),
+            };
+        }
+        let framework = Framework::new(instance, surface)?;
+        Ok(self.0.get_or_init(|| framework))
+    }
+
+    // Without creating it
+    pub fn get(&self) -> Option<&Framework> {
+        self.0.get()
+    }
+}
+
+impl Deref for FrameworkCell {
+    type Target = Framework;
+
+    // Panics without a usable adapter
+    fn deref(&self) -> &Framework {
+        self.0.get_or_init(|| {
+            Framework::new(instance(), None)
+                .unwrap_or_else(|err| panic!("Failed to create a GPU device: {err}"))
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum BufferError {
+    // more elements than the buffer holds
+    OutOfBounds,
+    Map,
+}
+
+impl fmt::Display for BufferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BufferError::OutOfBounds => write!(f, "more elements than the GPU buffer holds"),
+            BufferError::Map => write!(f, "failed to map the GPU buffer"),
+        }
+    }
+}
+
+// `len` elements of `T` the kernels read and write, shared so the display can bind it too
+pub struct GpuBuffer<'fw, T> {
+    fw: &'fw Framework,
+    buffer: Arc<wgpu::Buffer>,
+    len: u64,
+    _element: PhantomData<T>,
+}
+
+impl<'fw, T: Pod> GpuBuffer<'fw, T> {
+    const USAGE: BufferUsages =
+        BufferUsages::STORAGE.union(BufferUsages::COPY_SRC).union(BufferUsages::COPY_DST);
+
+    // Zeroed, wgpu clears new buffers
+    pub fn with_capacity(fw: &'fw Framework, len: u64) -> Self {
+        let buffer = fw.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: len * mem::size_of::<T>() as u64,
+            usage: Self::USAGE,
+            mapped_at_creation: false,
+        });
+        Self { fw, buffer: Arc::new(buffer), len, _element: PhantomData }
+    }
+
+    pub fn from_slice(fw: &'fw Framework, data: &[T]) -> Self {
+        Self::init(fw, data, Self::USAGE)
+    }
+
+    fn init(fw: &'fw Framework, data: &[T], usage: BufferUsages) -> Self {
+        let contents = bytemuck::cast_slice(data);
+        let buffer = fw.device.create_buffer_init(&util::BufferInitDescriptor {
+            label: None,
+            contents,
+            usage,
+        });
+        Self { fw, buffer: Arc::new(buffer), len: data.len() as u64, _element: PhantomData }
+    }
+
+    pub fn raw(&self) -> &Arc<wgpu::Buffer> {
+        &self.buffer
+    }
+
+    // Overwrites the first `data.len()` elements once the queue gets to it
+    pub fn write(&self, data: &[T]) -> Result<(), BufferError> {
+        if data.len() as u64 > self.len {
+            return Err(BufferError::OutOfBounds);
+        }
+        self.fw.queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(data));
+        Ok(())
+    }
+
+    // Copies the first `out.len()` elements back, after everything submitted so far
+    pub fn read_blocking(&self, out: &mut [T]) -> Result<(), BufferError> {
+        if out.len() as u64 > self.len {
+            return Err(BufferError::OutOfBounds);
+        }
+        let size = mem::size_of_val(out) as u64;
+        if size == 0 {
+            return Ok(());
+        }
+        let device = &self.fw.device;
+        let staging = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor::default());
+        encoder.copy_buffer_to_buffer(&self.buffer, 0, &staging, 0, size);
+        self.fw.queue.submit(Some(encoder.finish()));
+
+        let slice = staging.slice(..);
+        let (sender, mapped) = mpsc::channel();
+        slice.map_async(MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        device.poll(Maintain::Wait);
+        match mapped.recv() {
+            Ok(Ok(())) => {}
+            _ => return Err(BufferError::Map),
+        }
+        bytemuck::cast_slice_mut(out).copy_from_slice(&slice.get_mapped_range());
+        staging.unmap();
+        Ok(())
+    }
+}
+
+// Uniforms of the kernels, small and rewritten per dispatch
+pub struct GpuUniformBuffer<'fw, T>(GpuBuffer<'fw, T>);
+
+impl<'fw, T: Pod> GpuUniformBuffer<'fw, T> {
+    pub fn from_slice(fw: &'fw Framework, data: &[T]) -> Self {
+        Self(GpuBuffer::init(fw, data, BufferUsages::UNIFORM | BufferUsages::COPY_DST))
+    }
+
+    pub fn write(&self, data: &[T]) -> Result<(), BufferError> {
+        self.0.write(data)
+    }
+}
+
+// RGBA8 texels the kernels sample as floats in [0, 1], sRGB ones are decoded by the kernel
+pub struct GpuConstImage<'fw> {
+    _fw: PhantomData<&'fw Framework>,
+    _texture: wgpu::Texture,
+    view: wgpu::TextureView,
+}
+
+impl<'fw> GpuConstImage<'fw> {
+    pub fn from_bytes(fw: &'fw Framework, bytes: &[u8], width: u32, height: u32) -> Self {
+        let size = Extent3d { width, height, depth_or_array_layers: 1 };
+        let texture = fw.device.create_texture(&TextureDescriptor {
+            label: None,
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Rgba8Unorm,
+            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        fw.queue.write_texture(
+            ImageCopyTexture {
+                texture: &texture,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+                aspect: TextureAspect::All,
+            },
+            bytes,
+            ImageDataLayout { offset: 0, bytes_per_row: Some(width * 4), rows_per_image: None },
+            size,
+        );
+        let view = texture.create_view(&TextureViewDescriptor::default());
+        Self { _fw: PhantomData, _texture: texture, view }
+    }
+}
+
+pub struct Sampler(wgpu::Sampler);
+
+impl Sampler {
+    pub fn new(fw: &Framework, address_mode: AddressMode, filter: FilterMode) -> Self {
+        Self(fw.device.create_sampler(&SamplerDescriptor {
+            address_mode_u: address_mode,
+            address_mode_v: address_mode,
+            address_mode_w: address_mode,
+            mag_filter: filter,
+            min_filter: filter,
+            ..Default::default()
+        }))
+    }
+}
+
+pub struct Shader(wgpu::ShaderModule);
+
+impl Shader {
+    pub fn from_spirv_bytes(fw: &Framework, bytes: &[u8], label: Option<&str>) -> Self {
+        let source = ShaderSource::SpirV(util::make_spirv_raw(bytes));
+        Self(fw.device.create_shader_module(ShaderModuleDescriptor { label, source }))
+    }
+}
+
+#[derive(Copy, Clone)]
+pub enum GpuBufferUsage {
+    ReadOnly,
+    ReadWrite,
+}
+
+// Resources of a bind group, numbered in the order they are added
+#[derive(Default)]
+pub struct DescriptorSet<'res> {
+    bindings: Vec<(BindingType, BindingResource<'res>)>,
+}
+
+impl<'res> DescriptorSet<'res> {
+    fn bind(mut self, ty: BindingType, resource: BindingResource<'res>) -> Self {
+        self.bindings.push((ty, resource));
+        self
+    }
+
+    pub fn bind_uniform_buffer<T: Pod>(self, buffer: &'res GpuUniformBuffer<T>) -> Self {
+        let ty = BindingType::Buffer {
+            ty: BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        };
+        self.bind(ty, buffer.0.buffer.as_entire_binding())
+    }
+
+    pub fn bind_buffer<T: Pod>(self, buffer: &'res GpuBuffer<T>, usage: GpuBufferUsage) -> Self {
+        let read_only = matches!(usage, GpuBufferUsage::ReadOnly);
+        let ty = BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        };
+        self.bind(ty, buffer.buffer.as_entire_binding())
+    }
+
+    pub fn bind_sampler(self, sampler: &'res Sampler) -> Self {
+        let ty = BindingType::Sampler(SamplerBindingType::Filtering);
+        self.bind(ty, BindingResource::Sampler(&sampler.0))
+    }
+
+    pub fn bind_const_image(self, image: &'res GpuConstImage) -> Self {
+        let ty = BindingType::Texture {
+            sample_type: TextureSampleType::Float { filterable: true },
+            view_dimension: TextureViewDimension::D2,
+            multisampled: false,
+        };
+        self.bind(ty, BindingResource::TextureView(&image.view))
+    }
+}
+
+// An entry point of a shader with the descriptor sets it is dispatched with, in set order
+pub struct Program<'res> {
+    shader: &'res Shader,
+    entry: String,
+    sets: Vec<DescriptorSet<'res>>,
+}
+
+impl<'res> Program<'res> {
+    pub fn new(shader: &'res Shader, entry: &str) -> Self {
+        Self { shader, entry: entry.to_string(), sets: Vec::new() }
+    }
+
+    pub fn add_descriptor_set(mut self, set: DescriptorSet<'res>) -> Self {
+        self.sets.push(set);
+        self
+    }
+}
+
+// A compute pipeline bound to its resources, the bind groups keep them alive
+pub struct Kernel<'fw> {
+    fw: &'fw Framework,
+    pipeline: ComputePipeline,
+    bind_groups: Vec<BindGroup>,
+}
+
+impl<'fw> Kernel<'fw> {
+    pub fn new(fw: &'fw Framework, program: Program) -> Self {
+        let device = &fw.device;
+        let layouts: Vec<_> = program
+            .sets
+            .iter()
+            .map(|set| {
+                let entries: Vec<_> = (0..)
+                    .zip(&set.bindings)
+                    .map(|(binding, &(ty, _))| BindGroupLayoutEntry {
+                        binding,
+                        visibility: ShaderStages::COMPUTE,
+                        ty,
+                        count: None,
+                    })
+                    .collect();
+                device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                    label: None,
+                    entries: &entries,
+                })
+            })
+            .collect();
+        let layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &layouts.iter().collect::<Vec<_>>(),
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: Some(program.entry.as_str()),
+            layout: Some(&layout),
+            module: &program.shader.0,
+            entry_point: &program.entry,
+        });
+        let bind_groups = program
+            .sets
+            .iter()
+            .zip(&layouts)
+            .map(|(set, layout)| {
+                let entries: Vec<_> = (0..)
+                    .zip(&set.bindings)
+                    .map(|(binding, (_, resource))| BindGroupEntry {
+                        binding,
+                        resource: resource.clone(),
+                    })
+                    .collect();
+                device.create_bind_group(&BindGroupDescriptor {
+                    label: None,
+                    layout,
+                    entries: &entries,
+                })
+            })
+            .collect();
+        Self { fw, pipeline, bind_groups }
+    }
+
+    // Submits a dispatch of `x` by `y` by `z` workgroups, it runs after everything submitted
+    // before
+    pub fn enqueue(&self, x: u32, y: u32, z: u32) {
+        let mut encoder =
+            self.fw.device.create_command_encoder(&CommandEncoderDescriptor::default());
+        {
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+            pass.set_pipeline(&self.pipeline);
+            for (index, bind_group) in (0..).zip(&self.bind_groups) {
+                pass.set_bind_group(index, bind_group, &[]);
+            }
+            pass.dispatch_workgroups(x, y, z);
+        }
+        self.fw.queue.submit(Some(encoder.finish()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::compute::FW};
+
+    #[test]
+    fn buffers_read_back_what_was_written() {
+        if FW.init(instance(), None).is_err() {
+            eprintln!("no GPU adapter, skipped");
+            return;
+        }
+        let buffer = GpuBuffer::from_slice(&FW, &[1u32, 2, 3, 4]);
+        buffer.write(&[5, 6]).unwrap();
+        let mut out = [0; 4];
+        buffer.read_blocking(&mut out).unwrap();
+        assert_eq!(out, [5, 6, 3, 4]);
+
+        assert!(matches!(buffer.write(&[0; 5]), Err(BufferError::OutOfBounds)));
+        assert!(matches!(buffer.read_blocking(&mut [0; 5]), Err(BufferError::OutOfBounds)));
+        let zeroed = GpuBuffer::<u32>::with_capacity(&FW, 3);
+        let mut out = [1; 3];
+        zeroed.read_blocking(&mut out).unwrap();
+        assert_eq!(out, [0; 3]);
+    }
+}
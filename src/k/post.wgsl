@@ -6,13 +6,35 @@ struct VertexOut {
 struct Uniforms {
     width: u32,
     height: u32,
+    // 0 = color, 1 = albedo, 2 = normal, 3 = direct, 4 = indirect, 5 = sample density, see
+    // `compute::View`
+    view: u32,
+    // 0 = none, 1 = Reinhard, 2 = ACES, see `output::Tonemap`
+    tonemap: u32,
+    // the window, the traced image is scaled to fit it and centered
+    surface_width: u32,
+    surface_height: u32,
+    // in stops
+    exposure: f32,
+    // 1 when the surface format isn't sRGB and the shader has to encode
+    encode_srgb: u32,
+    // The buffers hold sums with their count in alpha, which adaptive sampling keeps below
+    // this where pixels converged
+    samples: u32,
 };
 
 @group(0) @binding(0)
 var<uniform> uniforms: Uniforms;
 
+// what the kernel accumulates into, bound as is, see `compute::Sums`
 @group(0) @binding(1)
-var<storage> render_buffer: array<f32>;
+var<storage> direct: array<vec4<f32>>;
+@group(0) @binding(2)
+var<storage> indirect: array<vec4<f32>>;
+@group(0) @binding(3)
+var<storage> albedo: array<vec4<f32>>;
+@group(0) @binding(4)
+var<storage> normal: array<vec4<f32>>;
 
 var<private> v_positions: array<vec2<f32>, 6> = array<vec2<f32>, 6>(
     vec2<f32>(-1.0, -1.0),
@@ -31,25 +53,114 @@ fn vs_main(@builtin(vertex_index) v_idx: u32) -> VertexOut {
     return out;
 }
 
-fn aces_narkowicz(x: vec3<f32>) -> vec3<f32> {
-  var a = 2.51;
-  var b = 0.03;
-  var c = 2.43;
-  var d = 0.59;
-  var e = 0.14;
-  return clamp((x * (a * x + b)) / (x * (c * x + d) + e), vec3<f32>(0.0), vec3<f32>(1.0));
+// Same fit as `output::aces_fitted`, so the window matches saved images
+fn aces_fitted(color: vec3<f32>) -> vec3<f32> {
+    // sRGB => XYZ => D65_2_D60 => AP1 => RRT_SAT
+    let to_rrt = transpose(mat3x3<f32>(
+        vec3<f32>(0.59719, 0.35458, 0.04823),
+        vec3<f32>(0.07600, 0.90834, 0.01566),
+        vec3<f32>(0.02840, 0.13383, 0.83777),
+    ));
+    // ODT_SAT => XYZ => D60_2_D65 => sRGB
+    let from_odt = transpose(mat3x3<f32>(
+        vec3<f32>(1.60475, -0.53108, -0.07367),
+        vec3<f32>(-0.10208, 1.10813, -0.00605),
+        vec3<f32>(-0.00327, -0.07276, 1.07602),
+    ));
+
+    let v = to_rrt * color;
+    let a = v * (v + 0.0245786) - 0.000090537;
+    let b = v * (0.983729 * v + 0.4329510) + 0.238081;
+    return from_odt * (a / b);
+}
+
+fn tonemap(color: vec3<f32>) -> vec3<f32> {
+    var exposed = color * exp2(uniforms.exposure);
+    if uniforms.tonemap == 1u {
+        exposed = exposed / (vec3<f32>(1.0) + exposed);
+    } else if uniforms.tonemap == 2u {
+        exposed = aces_fitted(exposed);
+    }
+    return clamp(exposed, vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+fn linear_to_srgb(x: vec3<f32>) -> vec3<f32> {
+    return select(1.055 * pow(x, vec3<f32>(1.0 / 2.4)) - 0.055, x * 12.92, x <= vec3<f32>(0.0031308));
+}
+
+fn encode(color: vec3<f32>) -> vec4<f32> {
+    if uniforms.encode_srgb == 1u {
+        return vec4<f32>(linear_to_srgb(clamp(color, vec3<f32>(0.0), vec3<f32>(1.0))), 1.0);
+    }
+    return vec4<f32>(color, 1.0);
+}
+
+// Sum of the view at a pixel, the color view adds up direct and indirect light, which count
+// the same samples
+fn view_sum(index: u32) -> vec4<f32> {
+    if uniforms.view == 0u {
+        return direct[index] + vec4<f32>(indirect[index].rgb, 0.0);
+    }
+    if uniforms.view == 1u {
+        return albedo[index];
+    }
+    if uniforms.view == 2u {
+        return normal[index];
+    }
+    if uniforms.view == 4u {
+        return indirect[index];
+    }
+    return direct[index];
+}
+
+// Average of a pixel, or its share of the samples for the density view, clamped to the image
+// at its edges
+fn texel(pixel: vec2<i32>) -> vec4<f32> {
+    let last = vec2<i32>(i32(uniforms.width), i32(uniforms.height)) - 1;
+    let clamped = vec2<u32>(clamp(pixel, vec2<i32>(0), last));
+    let sum = view_sum(clamped.y * uniforms.width + clamped.x);
+    if uniforms.view == 5u {
+        return vec4<f32>(sum.a / f32(max(uniforms.samples, 1u)));
+    }
+    return sum / max(sum.a, 1.0);
+}
+
+// Blue through green to red as `t` goes from 0 to 1
+fn heatmap(t: f32) -> vec3<f32> {
+    let x = clamp(t, 0.0, 1.0);
+    return clamp(vec3<f32>(2.0 * x - 1.0, 1.0 - abs(2.0 * x - 1.0), 1.0 - 2.0 * x), vec3<f32>(0.0), vec3<f32>(1.0));
 }
 
 @fragment
 fn fs_main(in: VertexOut) -> @location(0) vec4<f32> {
-    var uv = in.uv;
-    uv.y = 1.0 - uv.y;
-    var puv: vec2<u32> = vec2<u32>(uv * vec2<f32>(f32(uniforms.width), f32(uniforms.height)));
-    var idx: u32 = (puv.y*u32(uniforms.width)+puv.x);
-    var color: vec4<f32> = vec4<f32>(0.0, 0.0, 0.0, 0.0);
-    color.r = render_buffer[idx*3u+0u];
-    color.g = render_buffer[idx*3u+1u];
-    color.b = render_buffer[idx*3u+2u];
-
-    return vec4<f32>(aces_narkowicz(color.rgb), 1.0);
+    // letterbox, the largest scale that fits the whole image keeps its aspect ratio
+    var image_size = vec2<f32>(f32(uniforms.width), f32(uniforms.height));
+    var surface_size = vec2<f32>(f32(uniforms.surface_width), f32(uniforms.surface_height));
+    var scale = min(surface_size.x / image_size.x, surface_size.y / image_size.y);
+    // whole pixels, so an image at the window's resolution maps onto it one to one
+    var offset = floor((surface_size - image_size * scale) * 0.5);
+    // position in image pixels, top row first like the render buffer
+    var pixel = (in.position.xy - offset) / scale;
+    if any(pixel < vec2<f32>(0.0)) || any(pixel >= image_size) {
+        return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+    }
+    // bilinear between the nearest pixel centers, the render scale makes the image smaller or
+    // larger than the window
+    var corner = pixel - 0.5;
+    var base = vec2<i32>(floor(corner));
+    var t = corner - floor(corner);
+    var top = mix(texel(base), texel(base + vec2<i32>(1, 0)), t.x);
+    var bottom = mix(texel(base + vec2<i32>(0, 1)), texel(base + vec2<i32>(1, 1)), t.x);
+    var color: vec4<f32> = mix(top, bottom, t.y);
+
+    if uniforms.view == 1u {
+        return encode(color.rgb);
+    }
+    if uniforms.view == 2u {
+        return encode(color.rgb * 0.5 + 0.5);
+    }
+    if uniforms.view == 5u {
+        return encode(heatmap(color.r));
+    }
+    return encode(tonemap(color.rgb));
 }
\ No newline at end of file
@@ -1,115 +1,271 @@
 use {
+    crate::bvh::TLAS,
     glam::{UVec4, Vec3, Vec4, Vec4Swizzles},
     rand::Rng,
-    shared::{LightPick, MaterialData},
+    shared::{luminance, LightPick},
+    std::{collections::HashSet, f32::consts::PI, ops::Range},
 };
 
+// Zero rather than NaN for degenerate triangles, unlike Heron's formula
 fn triangle_area(a: Vec3, b: Vec3, c: Vec3) -> f32 {
-    let side_a = b - a;
-    let side_b = c - b;
-    let side_c = a - c;
-    let s = (side_a.length() + side_b.length() + side_c.length()) / 2.0;
-    (s * (s - side_a.length()) * (s - side_b.length()) * (s - side_c.length())).sqrt()
+    0.5 * (b - a).cross(c - a).length()
 }
 
-pub fn compute_emissive_mask(indices: &[UVec4], material_datas: &[MaterialData]) -> Vec<bool> {
-    let mut emissive_mask = vec![false; indices.len()];
-    for i in 0..indices.len() {
-        if material_datas[indices[i].w as usize].emissive.xyz() != Vec3::ZERO {
-            emissive_mask[i] = true;
-        }
-    }
-    emissive_mask
+// Perceived power of an emitter, `emission` already includes the emissive strength
+fn triangle_power(emission: Vec3, area: f32) -> f32 {
+    luminance(emission) * area
 }
 
-// NOTE: `mask` indicates which triangles are valid for picking
-pub fn build_light_pick_table(
+// An emissive triangle as placed by one instance, `area` is in world space
+pub struct Emitter {
+    pub instance: u32,
+    pub triangle: u32,
+    pub area: f32,
+    pub power: f32,
+}
+
+// `emissions` holds the average emitted radiance of each material, `meshes` the references of
+// each mesh in `indices`. Spatial splits can reference a triangle more than once, only the
+// first reference counts as a light. Degenerate triangles and broken emissions are skipped.
+pub fn collect_emitters(
     vertices: &[Vec4],
     indices: &[UVec4],
-    mask: &[bool],
-    material_datas: &[MaterialData],
-) -> Vec<LightPick> {
-    // Calculate areas and probabilities of picking each triangle
-    let mut triangle_areas = vec![0.0; indices.len()];
-    let mut triangle_powers = vec![0.0; indices.len()];
-    let mut total_power = 0.0;
-    let mut total_tris = 0;
-    for i in 0..indices.len() {
-        if !mask[i] {
-            continue;
+    meshes: &[Range<usize>],
+    tlas: &TLAS,
+    emissions: &[Vec3],
+) -> Vec<Emitter> {
+    let mut emitters = Vec::new();
+    let mut seen = HashSet::new();
+    for (instance_index, instance) in tlas.instances.iter().enumerate() {
+        seen.clear();
+        for i in meshes[tlas.meshes[instance_index]].clone() {
+            let triangle = indices[i];
+            let emission = emissions[instance.material_index(triangle, emissions.len()) as usize];
+            if emission == Vec3::ZERO || !seen.insert(triangle) {
+                continue;
+            }
+            let a = instance.point_to_world(vertices[triangle.x as usize].xyz());
+            let b = instance.point_to_world(vertices[triangle.y as usize].xyz());
+            let c = instance.point_to_world(vertices[triangle.z as usize].xyz());
+            let area = triangle_area(a, b, c);
+            let power = triangle_power(emission, area);
+            // a NaN or infinite power would poison the total and with it every probability
+            if !(power.is_finite() && power > 0.0) {
+                continue;
+            }
+            emitters.push(Emitter {
+                instance: instance_index as u32,
+                triangle: i as u32,
+                area,
+                power,
+            });
         }
-        total_tris += 1;
+    }
+    emitters
+}
+
+pub fn total_emissive_power(emitters: &[Emitter]) -> f32 {
+    emitters.iter().map(|emitter| emitter.power).sum()
+}
 
-        let triangle = indices[i];
-        let a = vertices[triangle.x as usize].xyz();
-        let b = vertices[triangle.y as usize].xyz();
-        let c = vertices[triangle.z as usize].xyz();
+// Returns (marginal cdf over rows, conditional cdf per row, power) of an equirectangular map.
+// Texels are weighted by sin(theta) since rows near the poles cover less solid angle.
+pub fn build_env_cdf(width: u32, height: u32, texels: &[Vec4]) -> (Vec<f32>, Vec<f32>, f32) {
+    let (width, height) = (width as usize, height as usize);
+    let mut marginal = vec![0.0; height];
+    let mut conditional = vec![0.0; width * height];
 
-        let triangle_area = triangle_area(a, b, c);
-        triangle_areas[i] = triangle_area;
+    let mut total = 0.0;
+    for y in 0..height {
+        let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+        let row = &mut conditional[y * width..(y + 1) * width];
 
-        let triangle_power =
-            material_datas[triangle.w as usize].emissive.xyz().dot(Vec3::ONE) * triangle_area;
-        triangle_powers[i] = triangle_power;
-        total_power += triangle_power;
+        let mut sum = 0.0;
+        for x in 0..width {
+            sum += luminance(texels[y * width + x].xyz()) * sin_theta;
+            row[x] = sum;
+        }
+        for (x, cdf) in row.iter_mut().enumerate() {
+            // black rows are never picked by the marginal, keep them well-formed anyway
+            *cdf = if sum > 0.0 { *cdf / sum } else { (x + 1) as f32 / width as f32 };
+        }
+
+        total += sum;
+        marginal[y] = total;
+    }
+    for (y, cdf) in marginal.iter_mut().enumerate() {
+        *cdf = if total > 0.0 { *cdf / total } else { (y + 1) as f32 / height as f32 };
     }
-    if total_tris == 0 {
+
+    // Integral of luminance over the sphere
+    let power = total * 2.0 * PI * PI / (width * height) as f32;
+    (marginal, conditional, power)
+}
+
+pub fn build_light_pick_table(emitters: &[Emitter]) -> Vec<LightPick> {
+    // Probabilities of picking each emitter
+    let total_power = total_emissive_power(emitters);
+    let probabilities =
+        emitters.iter().map(|emitter| emitter.power / total_power).collect::<Vec<f32>>();
+
+    // Vose's alias method: every bin is picked with probability 1 / n, then keeps its own
+    // emitter with probability `ratio` or falls through to its alias.
+    // https://www.keithschwarz.com/darts-dice-coins/
+    let lights = (0..emitters.len()).filter(|&i| probabilities[i] > 0.0).collect::<Vec<_>>();
+    let num_bins = lights.len();
+    if num_bins == 0 {
         // If there are 0 entries, put in a stupid sentinel value
         return vec![LightPick { ratio: -1.0, ..Default::default() }];
     }
-    let mut triangle_probabilities = vec![0.0; indices.len()];
-    for i in 0..indices.len() {
-        triangle_probabilities[i] = triangle_powers[i] / total_power;
+
+    // probabilities scaled so the average bin holds exactly 1.0
+    let mut scaled =
+        lights.iter().map(|&i| probabilities[i] as f64 * num_bins as f64).collect::<Vec<_>>();
+    let mut aliases = (0..num_bins).collect::<Vec<_>>();
+    let mut ratios = vec![1.0; num_bins];
+
+    let (mut small, mut large): (Vec<_>, Vec<_>) = (0..num_bins).partition(|&i| scaled[i] < 1.0);
+    while let (Some(&less), Some(&more)) = (small.last(), large.last()) {
+        small.pop();
+        ratios[less] = scaled[less];
+        aliases[less] = more;
+        scaled[more] -= 1.0 - scaled[less];
+        if scaled[more] < 1.0 {
+            large.pop();
+            small.push(more);
+        }
+    }
+    // leftovers are 1.0 up to rounding error
+    for i in small.into_iter().chain(large) {
+        ratios[i] = 1.0;
+    }
+
+    (0..num_bins)
+        .map(|bin| {
+            let (a, b) = (lights[bin], lights[aliases[bin]]);
+            LightPick {
+                instance_index_a: emitters[a].instance,
+                triangle_index_a: emitters[a].triangle,
+                triangle_area_a: emitters[a].area,
+                // true probability of picking the emitter through any bin
+                triangle_pick_pdf_a: probabilities[a],
+                instance_index_b: emitters[b].instance,
+                triangle_index_b: emitters[b].triangle,
+                triangle_area_b: emitters[b].area,
+                triangle_pick_pdf_b: probabilities[b],
+                ratio: ratios[bin] as f32,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::bvh::BVHBuilder,
+        glam::{Mat4, Vec2},
+        rand::{rngs::StdRng, Rng, SeedableRng},
+        shared::InstanceData,
+    };
+
+    // the bin and ratio logic of `pick_light` in kernels/simple/src/light.rs, returns the
+    // picked triangle and the pdf stored for it
+    fn pick(table: &[LightPick], rng: Vec2) -> (u32, f32) {
+        let bin = ((rng.x * table.len() as f32) as usize).min(table.len() - 1);
+        let entry = table[bin];
+        if rng.y < entry.ratio {
+            (entry.triangle_index_a, entry.triangle_pick_pdf_a)
+        } else {
+            (entry.triangle_index_b, entry.triangle_pick_pdf_b)
+        }
     }
-    let average_probability = triangle_probabilities.iter().sum::<f32>() / total_tris as f32;
-    // Build histogram bins. Each entry contains 2 discrete outcomes.
-    #[derive(Debug)]
-    struct TriangleBin {
-        index_a: usize,
-        probability_a: f32,
-        index_b: usize,
-        probability_b: f32,
+
+    fn emitters(powers: &[f32]) -> Vec<Emitter> {
+        let emitter = |(i, &power)| Emitter { instance: 0, triangle: i as u32, area: 1.0, power };
+        powers.iter().enumerate().map(emitter).collect()
     }
-    let mut bins = triangle_probabilities
-        .iter()
-        .enumerate()
-        .map(|x| TriangleBin { index_a: x.0, probability_a: *x.1, index_b: 0, probability_b: 0.0 })
-        .filter(|x| x.probability_a != 0.0)
-        .collect::<Vec<_>>();
-    bins.sort_by(|a, b| {
-        a.probability_a.partial_cmp(&b.probability_a).unwrap_or(std::cmp::Ordering::Equal)
-    });
-
-    // Robin hood - take from the most probable and give to the least probable
-    let num_bins = bins.len();
-    let mut most_probable = num_bins - 1;
-    for i in 0..num_bins {
-        let needed = average_probability - bins[i].probability_a;
-        if needed <= 0.0 {
-            break;
+
+    const POWERS: [f32; 7] = [1.0, 0.0, 5.0, 0.25, 2.0, 40.0, 0.5];
+
+    #[test]
+    fn picks_follow_power() {
+        let emitters = emitters(&POWERS);
+        let table = build_light_pick_table(&emitters);
+        let total = total_emissive_power(&emitters);
+        let mut rng = StdRng::seed_from_u64(7);
+        let draws = 4_000_000;
+        let mut counts = vec![0u32; POWERS.len()];
+        for _ in 0..draws {
+            let (triangle, _) = pick(&table, Vec2::new(rng.gen(), rng.gen()));
+            counts[triangle as usize] += 1;
+        }
+        for (count, power) in counts.iter().zip(POWERS) {
+            let expected = power / total;
+            let frequency = *count as f32 / draws as f32;
+            // a few standard deviations of the binomial
+            let tolerance = 5.0 * (expected * (1.0 - expected) / draws as f32).sqrt() + 1e-6;
+            assert!((frequency - expected).abs() <= tolerance, "{frequency} vs {expected}");
         }
+    }
 
-        bins[i].index_b = bins[most_probable].index_a;
-        bins[i].probability_b = needed;
-        bins[most_probable].probability_a -= needed;
-        if bins[most_probable].probability_a <= average_probability {
-            most_probable -= 1;
+    #[test]
+    fn stored_pdfs_are_the_selection_probabilities() {
+        let emitters = emitters(&POWERS);
+        let table = build_light_pick_table(&emitters);
+        let bin = 1.0 / table.len() as f64;
+        let mut probabilities = vec![0.0f64; POWERS.len()];
+        for entry in &table {
+            let ratio = entry.ratio.clamp(0.0, 1.0) as f64;
+            probabilities[entry.triangle_index_a as usize] += bin * ratio;
+            probabilities[entry.triangle_index_b as usize] += bin * (1.0 - ratio);
         }
+        for entry in &table {
+            for (triangle, pdf) in [
+                (entry.triangle_index_a, entry.triangle_pick_pdf_a),
+                (entry.triangle_index_b, entry.triangle_pick_pdf_b),
+            ] {
+                let probability = probabilities[triangle as usize];
+                assert!((pdf as f64 - probability).abs() < 1e-6, "{pdf} vs {probability}");
+            }
+        }
+        assert_eq!(probabilities[1], 0.0);
     }
 
-    // Build the table
-    let table = bins
-        .iter()
-        .map(|x| LightPick {
-            triangle_index_a: x.index_a as u32,
-            triangle_index_b: x.index_b as u32,
-            triangle_pick_pdf_a: triangle_probabilities[x.index_a],
-            triangle_area_a: triangle_areas[x.index_a],
-            triangle_area_b: triangle_areas[x.index_b],
-            triangle_pick_pdf_b: triangle_probabilities[x.index_b],
-            ratio: x.probability_a / (x.probability_a + x.probability_b),
-        })
-        .collect::<Vec<_>>();
+    #[test]
+    fn rng_of_one_picks_the_last_bin() {
+        let table = build_light_pick_table(&emitters(&POWERS));
+        let last = table[table.len() - 1];
+        assert_eq!(pick(&table, Vec2::new(1.0, 0.0)).0, last.triangle_index_a);
+        assert_eq!(pick(&table, Vec2::new(1.0, 1.0)).0, last.triangle_index_b);
+    }
+
+    #[test]
+    fn degenerate_emitters_are_skipped() {
+        let vertices = [
+            Vec4::new(0.0, 0.0, 0.0, 1.0),
+            Vec4::new(1.0, 0.0, 0.0, 1.0),
+            Vec4::new(0.0, 1.0, 0.0, 1.0),
+            // on the line through the first two
+            Vec4::new(2.0, 0.0, 0.0, 1.0),
+        ];
+        // a lit triangle, a collinear one and one with a broken material
+        let mut indices =
+            vec![UVec4::new(0, 1, 2, 0), UVec4::new(0, 1, 3, 0), UVec4::new(1, 3, 2, 1)];
+        let bvh = BVHBuilder::new(&vertices, &mut indices).build();
+        let meshes = [0..indices.len()];
+        let tlas = TLAS::new(vec![bvh], vec![(0, InstanceData::new(Mat4::IDENTITY, 0, 0))]);
+        let emissions = [Vec3::ONE, Vec3::NAN];
 
-    table
+        let emitters = collect_emitters(&vertices, &indices, &meshes, &tlas, &emissions);
+        assert_eq!(emitters.len(), 1);
+        let lit = &emitters[0];
+        assert_eq!(indices[lit.triangle as usize], UVec4::new(0, 1, 2, 0));
+        assert_eq!(lit.area, 0.5);
+        assert!(total_emissive_power(&emitters).is_finite());
+        for entry in build_light_pick_table(&emitters) {
+            assert_eq!((entry.triangle_index_a, entry.triangle_pick_pdf_a), (lit.triangle, 1.0));
+            assert_eq!((entry.triangle_index_b, entry.triangle_pick_pdf_b), (lit.triangle, 1.0));
+        }
+    }
 }
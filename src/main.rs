@@ -1,29 +1,54 @@
 #![feature(build_hasher_simple_hash_one)]
 #![feature(sync_unsafe_cell)]
 
+mod animation;
+mod args;
 mod atlas;
 mod block;
 mod bvh;
+mod checkpoint;
 mod compute;
+mod gpu;
 mod light;
+mod output;
 mod scene;
+#[cfg(feature = "watch")]
+mod watch;
 
 pub(crate) use block::block_on;
 use {
-    crate::{compute::Tracing, scene::World},
+    crate::{
+        args::{
+            Args, Checkpoints, Headless, StartCamera, MAX_RENDER_SCALE, MIN_RENDER_SCALE, USAGE,
+        },
+        bvh::{Hit, TLAS},
+        checkpoint::Checkpoint,
+        compute::{KernelFile, Post, Sums, Tracing, View},
+        output::{Snapshot, Tonemap},
+        scene::{EnvMap, GpuWorld, SceneLoadError, SceneOptions, World},
+    },
     compute::Wgpu,
-    glam::{Mat3, Vec3},
-    parking_lot::Mutex,
-    shared::TracingConfig,
-    std::{sync::Arc, thread, time::Instant},
+    glam::{Mat4, UVec2, Vec2, Vec3, Vec4},
+    parking_lot::{Condvar, Mutex},
+    shared::{camera_basis, MaterialData, RenderMode, TracingConfig},
+    std::{
+        collections::HashSet,
+        error::Error,
+        f32::consts::{FRAC_PI_2, PI, TAU},
+        path::{Path, PathBuf},
+        sync::Arc,
+        thread,
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    },
+    wgpu::PresentMode,
     winit::{
         application::ApplicationHandler,
-        dpi::{LogicalPosition, PhysicalPosition, PhysicalSize},
-        event::WindowEvent,
-        event_loop::{ActiveEventLoop, EventLoop},
+        dpi::{PhysicalPosition, PhysicalSize},
+        event::{DeviceEvent, DeviceId, ElementState, MouseButton, WindowEvent},
+        event_loop::{ActiveEventLoop, ControlFlow, EventLoop, EventLoopProxy},
         keyboard::{Key, KeyCode, PhysicalKey},
         raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle},
-        window::{Window, WindowAttributes, WindowId},
+        window::{CursorGrabMode, Window, WindowAttributes, WindowId},
     },
 };
 
@@ -31,20 +56,118 @@ struct App<'a> {
     window: &'a Window,
     req: Request,
     config: Arc<Mutex<TracingConfig>>,
+    post: Arc<Mutex<Post>>,
+    // dropped onto the window and not picked up by the render thread yet
+    scene: Arc<Mutex<Option<PathBuf>>>,
+    material: Arc<Mutex<MaterialEdit>>,
+    // window size the present thread hasn't resized the surface to yet
+    resized: Arc<Mutex<Option<PhysicalSize<u32>>>>,
+    // nothing would be shown, tracing waits
+    minimized: Arc<Mutex<bool>>,
+    // requested and not taken by the present thread yet, and the last one requested
+    present: Arc<Mutex<Option<PresentMode>>>,
+    present_mode: PresentMode,
+    // requested and not taken by the render thread yet
+    snapshot: Arc<Mutex<bool>>,
+    // requested and not taken by the render thread yet
+    checkpoint: Arc<Mutex<bool>>,
+    // the render thread stops tracing and keeps presenting the last frame
+    paused: Arc<Mutex<bool>>,
+    // requested and not taken by the render thread yet
+    restart: Arc<Mutex<bool>>,
+    // trace pixel clicked and not inspected by the render thread yet
+    pick: Arc<Mutex<Option<UVec2>>>,
+    // last position within the window
+    cursor: PhysicalPosition<f64>,
+    // the title shows `status` and then `progress`
+    status: String,
+    progress: Option<Progress>,
+    // the title estimates the time until this many samples
+    target_samples: Option<usize>,
+    // raw mouse motion turns the camera while the cursor is grabbed
+    captured: bool,
+    // scales `LOOK_SPEED`
+    sensitivity: f64,
+    // trace resolution relative to the window's, the post shader scales the image to fit
+    render_scale: f32,
+    // pressed and not released yet
+    held: HashSet<KeyCode>,
+    // time of the last camera step and whether it moved
+    moved: Instant,
+    moving: bool,
 }
 
 impl<'a> App<'a> {
-    pub fn new(window: &'a Window) -> Self {
-        let PhysicalSize { width, height } = window.inner_size();
+    // radians per count of raw mouse motion
+    const LOOK_SPEED: f64 = 0.0035;
+    // units per second, `Shift` moves 5x as fast and `Alt` 0.2x
+    const MOVE_SPEED: f32 = 3.0;
+    // between camera steps while a movement key is held
+    const MOVE_TICK: Duration = Duration::from_millis(8);
+
+    pub fn new(window: &'a Window, mut config: TracingConfig, args: &Args) -> Self {
+        (config.width, config.height) = render_size(window.inner_size(), args.render_scale);
         Self {
             window,
             req: Request { close: false },
-            config: Arc::new(Mutex::new(TracingConfig { width, height, ..TracingConfig::soft() })),
+            config: Arc::new(Mutex::new(config)),
+            post: Arc::new(Mutex::new(Post::default())),
+            scene: Arc::new(Mutex::new(None)),
+            material: Arc::new(Mutex::new(MaterialEdit::default())),
+            resized: Arc::new(Mutex::new(None)),
+            minimized: Arc::new(Mutex::new(false)),
+            present: Arc::new(Mutex::new(None)),
+            present_mode: args.present_mode,
+            snapshot: Arc::new(Mutex::new(false)),
+            checkpoint: Arc::new(Mutex::new(false)),
+            paused: Arc::new(Mutex::new(false)),
+            restart: Arc::new(Mutex::new(false)),
+            pick: Arc::new(Mutex::new(None)),
+            cursor: PhysicalPosition::new(0.0, 0.0),
+            status: String::from("racist"),
+            progress: None,
+            target_samples: args.target_samples,
+            captured: false,
+            sensitivity: args.sensitivity,
+            render_scale: args.render_scale,
+            held: HashSet::new(),
+            moved: Instant::now(),
+            moving: false,
         }
     }
 
     pub fn redraw_frame(&mut self) {}
 
+    fn update_title(&self) {
+        let title = match self.progress {
+            Some(Progress { samples, paused: true, .. }) => {
+                format!("{} - paused at {samples} samples", self.status)
+            }
+            Some(Progress { samples, done: true, elapsed, .. }) => {
+                format!("{} - done, {samples} samples in {}", self.status, clock(elapsed))
+            }
+            Some(Progress { samples, traced, elapsed, sample_time, .. }) => {
+                let seconds = elapsed.as_secs_f64();
+                let rate = if seconds > 0.0 { traced as f64 / seconds } else { 0.0 };
+                let mut title = format!(
+                    "{} - {samples} samples, {rate:.1} samples/s, {:.1} ms/sample, {}",
+                    self.status,
+                    sample_time.as_secs_f64() * 1000.0,
+                    clock(elapsed)
+                );
+                if let Some(target) = self.target_samples.filter(|&target| target > samples) {
+                    if rate > 0.0 {
+                        let left = Duration::from_secs_f64((target - samples) as f64 / rate);
+                        title += &format!(", {} to {target}", clock(left));
+                    }
+                }
+                title
+            }
+            None => self.status.clone(),
+        };
+        self.window.set_title(&title);
+    }
+
     pub fn start_render(&mut self, continue_previous: bool) {
         // self.wgpu.start_render(self.window.inner_size(), continue_previous)
     }
@@ -52,35 +175,282 @@ impl<'a> App<'a> {
     pub fn handle_mouse(&mut self, delta: (f64, f64)) {
         let mut config = self.config.lock();
 
-        config.cam_rot.x += delta.1 as f32 * 0.005;
-        config.cam_rot.y += delta.0 as f32 * 0.005;
+        let speed = Self::LOOK_SPEED * self.sensitivity;
+        // straight up or down would flip the view over
+        let max_pitch = FRAC_PI_2 - 0.001;
+        config.cam_rot.x =
+            (config.cam_rot.x + (delta.1 * speed) as f32).clamp(-max_pitch, max_pitch);
+        config.cam_rot.y = (config.cam_rot.y + (delta.0 * speed) as f32 + PI).rem_euclid(TAU) - PI;
     }
 
-    pub fn handle_input(&mut self, key: PhysicalKey, ctrl: Key) {
-        let mut config = self.config.lock();
+    fn set_captured(&mut self, captured: bool) {
+        if captured == self.captured {
+            return;
+        }
+        let grab = if captured {
+            // not every platform can lock the cursor in place, confining it is the fallback
+            self.window
+                .set_cursor_grab(CursorGrabMode::Locked)
+                .or_else(|_| self.window.set_cursor_grab(CursorGrabMode::Confined))
+        } else {
+            self.window.set_cursor_grab(CursorGrabMode::None)
+        };
+        // raw motion arrives either way, the cursor just may leave the window
+        if let Err(err) = grab {
+            eprintln!("Failed to grab the cursor: {err}");
+        }
+        self.window.set_cursor_visible(!captured);
+        self.captured = captured;
+    }
 
-        let mut forward = Vec3::new(0.0, 0.0, 1.0);
-        let mut right = Vec3::new(1.0, 0.0, 0.0);
-        let euler_mat =
-            Mat3::from_rotation_y(config.cam_rot.y) * Mat3::from_rotation_x(config.cam_rot.x);
-        forward = euler_mat * forward;
-        right = euler_mat * right;
-        let speed = 0.1;
+    // Trace pixel under the cursor, with the letterboxing of the post shader. None over the
+    // bars around the image.
+    fn cursor_pixel(&self) -> Option<UVec2> {
+        let config = self.config.lock();
+        let size = self.window.inner_size();
+        let image = Vec2::new(config.width as f32, config.height as f32);
+        let surface = Vec2::new(size.width as f32, size.height as f32);
+        let scale = (surface.x / image.x).min(surface.y / image.y);
+        let offset = ((surface - image * scale) * 0.5).floor();
+        let cursor = Vec2::new(self.cursor.x as f32, self.cursor.y as f32);
+        let pixel = (cursor - offset) / scale;
+        (pixel.cmpge(Vec2::ZERO).all() && pixel.cmplt(image).all()).then(|| pixel.as_uvec2())
+    }
 
-        if matches!(key, PhysicalKey::Code(KeyCode::KeyW)) {
-            config.cam_pos += forward.extend(0.0) * speed;
+    // Moves the camera by the held keys for the time since the last call, returns whether it
+    // moved. `WASD` move level with the ground whatever the pitch, `X`/`Z` straight up and down.
+    fn step_movement(&mut self) -> bool {
+        let now = Instant::now();
+        // the first step after standing still only starts the clock, long gaps are stalls
+        let dt = if self.moving {
+            now.duration_since(self.moved).min(Duration::from_millis(100)).as_secs_f32()
+        } else {
+            0.0
+        };
+        self.moved = now;
+
+        let held = |key: KeyCode| self.held.contains(&key);
+        let axis = |positive, negative| held(positive) as i32 as f32 - held(negative) as i32 as f32;
+        let direction = Vec3::new(
+            axis(KeyCode::KeyD, KeyCode::KeyA),
+            axis(KeyCode::KeyX, KeyCode::KeyZ),
+            axis(KeyCode::KeyW, KeyCode::KeyS),
+        );
+        let moving = direction != Vec3::ZERO;
+        let mut config = self.config.lock();
+        if self.moving && !moving {
+            println!("position: {:?}", config.cam_pos);
         }
-        if matches!(key, PhysicalKey::Code(KeyCode::KeyS)) {
-            config.cam_pos -= forward.extend(0.0) * speed;
+        self.moving = moving;
+        if !moving {
+            return false;
         }
-        if matches!(key, PhysicalKey::Code(KeyCode::KeyD)) {
-            config.cam_pos += right.extend(0.0) * speed;
+
+        let mut speed = Self::MOVE_SPEED;
+        if held(KeyCode::ShiftLeft) || held(KeyCode::ShiftRight) {
+            speed *= 5.0;
         }
-        if matches!(key, PhysicalKey::Code(KeyCode::KeyA)) {
-            config.cam_pos -= right.extend(0.0) * speed;
+        if held(KeyCode::AltLeft) || held(KeyCode::AltRight) {
+            speed *= 0.2;
         }
+        // diagonals are no faster
+        let heading = camera_basis(Vec3::new(0.0, config.cam_rot.y, 0.0));
+        config.cam_pos += (heading * direction.normalize() * speed * dt).extend(0.0);
+        true
+    }
+
+    pub fn handle_input(&mut self, key: PhysicalKey, ctrl: Key) {
+        let mut config = self.config.lock();
 
-        println!("position: {:?}", config.cam_pos);
+        let sun_step = 0.05;
+        let sun = match key {
+            PhysicalKey::Code(KeyCode::ArrowLeft) => Some((-sun_step, 0.0)),
+            PhysicalKey::Code(KeyCode::ArrowRight) => Some((sun_step, 0.0)),
+            PhysicalKey::Code(KeyCode::ArrowUp) => Some((0.0, sun_step)),
+            PhysicalKey::Code(KeyCode::ArrowDown) => Some((0.0, -sun_step)),
+            _ => None,
+        };
+        if let Some((azimuth, elevation)) = sun {
+            let dir = config.sun_direction.truncate().normalize();
+            let azimuth = dir.z.atan2(dir.x) + azimuth;
+            let elevation = (dir.y.asin() + elevation).clamp(-FRAC_PI_2, FRAC_PI_2);
+            config.sun_direction = Vec3::new(
+                elevation.cos() * azimuth.cos(),
+                elevation.sin(),
+                elevation.cos() * azimuth.sin(),
+            )
+            .extend(0.0);
+            println!("sun direction: {:?}", config.sun_direction);
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::PageUp)) {
+            config.max_bounces += 1;
+            config.clamp_bounces();
+            println!("bounces: {}..{}", config.min_bounces, config.max_bounces);
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::PageDown)) {
+            config.max_bounces = config.max_bounces.saturating_sub(1);
+            config.clamp_bounces();
+            println!("bounces: {}..{}", config.min_bounces, config.max_bounces);
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::BracketRight)) {
+            config.focus_distance *= 1.1;
+            println!("focus distance: {}", config.focus_distance);
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::BracketLeft)) {
+            config.focus_distance /= 1.1;
+            println!("focus distance: {}", config.focus_distance);
+        }
+        // one f-stop doubles or halves the aperture area
+        if matches!(key, PhysicalKey::Code(KeyCode::Equal)) {
+            config.aperture = (config.aperture * 2.0f32.sqrt()).max(0.01);
+            println!("aperture: {}", config.aperture);
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::Minus)) {
+            config.aperture /= 2.0f32.sqrt();
+            if config.aperture < 0.01 {
+                config.aperture = 0.0;
+            }
+            println!("aperture: {}", config.aperture);
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::KeyC)) {
+            let presets = [0.0, 16.0, 4.0, 1.0];
+            let current = presets.iter().position(|&p| p == config.clamp_indirect).unwrap_or(0);
+            config.clamp_indirect = presets[(current + 1) % presets.len()];
+            println!("indirect clamp: {} (0 is off)", config.clamp_indirect);
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::KeyU)) {
+            let presets = [0.0, 0.5, 1.0];
+            let current = presets.iter().position(|&p| p == config.regularization).unwrap_or(0);
+            config.regularization = presets[(current + 1) % presets.len()];
+            println!("path regularization: {} (0 is off)", config.regularization);
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::KeyF)) {
+            // white fog, scattering everything it extincts
+            let presets = [0.0, 0.02, 0.1];
+            let current = presets.iter().position(|&p| p == config.sigma_t).unwrap_or(0);
+            config.sigma_t = presets[(current + 1) % presets.len()];
+            config.sigma_s = config.sigma_t;
+            println!("fog density: {} (0 is off)", config.sigma_t);
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::KeyT)) {
+            config.sky_turbidity = (config.sky_turbidity + 0.5).min(10.0);
+            println!("sky turbidity: {}", config.sky_turbidity);
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::KeyG)) {
+            config.sky_turbidity = (config.sky_turbidity - 0.5).max(1.0);
+            println!("sky turbidity: {}", config.sky_turbidity);
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::KeyK)) {
+            let sky_model = config.sky_model().next();
+            config.set_sky_model(sky_model);
+            println!("sky model: {sky_model:?}");
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::KeyH)) {
+            let ground = !config.ground();
+            config.set_ground(ground);
+            println!("ground: {ground}");
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::KeyJ)) {
+            let restir = config.restir().next();
+            config.set_restir(restir);
+            println!("ReSTIR: {restir:?}");
+        }
+        let render_mode = match key {
+            PhysicalKey::Code(KeyCode::Digit1) => Some(RenderMode::PathTracing),
+            PhysicalKey::Code(KeyCode::Digit2) => Some(RenderMode::AmbientOcclusion),
+            PhysicalKey::Code(KeyCode::Digit3) => Some(RenderMode::Furnace),
+            _ => None,
+        };
+        if let Some(render_mode) = render_mode {
+            config.set_render_mode(render_mode);
+            println!("render mode: {render_mode:?}");
+        }
+        // halves or doubles the traced pixels, the buffers are recreated at the new resolution
+        let render_scale = match key {
+            PhysicalKey::Code(KeyCode::Digit9) => Some(self.render_scale / 2.0f32.sqrt()),
+            PhysicalKey::Code(KeyCode::Digit0) => Some(self.render_scale * 2.0f32.sqrt()),
+            _ => None,
+        };
+        if let Some(render_scale) = render_scale {
+            self.render_scale = render_scale.clamp(MIN_RENDER_SCALE, MAX_RENDER_SCALE);
+            let size = self.window.inner_size();
+            if size.width > 0 && size.height > 0 {
+                (config.width, config.height) = render_size(size, self.render_scale);
+            }
+            println!("render scale: {:.2} ({}x{})", self.render_scale, config.width, config.height);
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::KeyN)) {
+            let debug_nan = !config.debug_nan();
+            config.set_debug_nan(debug_nan);
+            println!("NaN debug: {debug_nan}");
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::KeyV)) {
+            let mut post = self.post.lock();
+            post.view = post.view.next();
+            println!("view: {:?}", post.view);
+        }
+        // display only, the accumulation goes on
+        if matches!(key, PhysicalKey::Code(KeyCode::KeyE)) {
+            let mut post = self.post.lock();
+            post.exposure += 0.5;
+            println!("exposure: {:+} EV", post.exposure);
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::KeyQ)) {
+            let mut post = self.post.lock();
+            post.exposure -= 0.5;
+            println!("exposure: {:+} EV", post.exposure);
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::KeyP)) {
+            *self.snapshot.lock() = true;
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::KeyB)) {
+            *self.checkpoint.lock() = true;
+        }
+        // only changes how often the image is shown, tracing runs on its own thread
+        if matches!(key, PhysicalKey::Code(KeyCode::KeyI)) {
+            self.present_mode = match self.present_mode {
+                PresentMode::Fifo => PresentMode::Mailbox,
+                PresentMode::Mailbox => PresentMode::Immediate,
+                _ => PresentMode::Fifo,
+            };
+            *self.present.lock() = Some(self.present_mode);
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::Space)) {
+            let mut paused = self.paused.lock();
+            *paused = !*paused;
+            println!("paused: {}", *paused);
+        }
+        // restarts once tracing isn't paused
+        if matches!(key, PhysicalKey::Code(KeyCode::KeyR)) {
+            *self.restart.lock() = true;
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::KeyO)) {
+            let mut post = self.post.lock();
+            post.tonemap = post.tonemap.next();
+            println!("tonemap: {:?}", post.tonemap);
+        }
+        // `M` selects the next material, numpad `+`/`-` change its roughness and `*`/`/` its
+        // metallic factor
+        let mut material = self.material.lock();
+        match key {
+            PhysicalKey::Code(KeyCode::KeyM) => {
+                material.selected += 1;
+                material.select = true;
+            }
+            PhysicalKey::Code(KeyCode::NumpadAdd) => material.roughness += MaterialEdit::STEP,
+            PhysicalKey::Code(KeyCode::NumpadSubtract) => material.roughness -= MaterialEdit::STEP,
+            PhysicalKey::Code(KeyCode::NumpadMultiply) => material.metallic += MaterialEdit::STEP,
+            PhysicalKey::Code(KeyCode::NumpadDivide) => material.metallic -= MaterialEdit::STEP,
+            _ => {}
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::Period)) {
+            config.sun_intensity *= 1.25;
+            println!("sun intensity: {}", config.sun_intensity);
+        }
+        if matches!(key, PhysicalKey::Code(KeyCode::Comma)) {
+            config.sun_intensity /= 1.25;
+            println!("sun intensity: {}", config.sun_intensity);
+        }
     }
 }
 
@@ -88,7 +458,38 @@ struct Request {
     close: bool,
 }
 
-impl ApplicationHandler for App<'_> {
+// Sent by the render thread once a dropped scene replaced the rendered one or failed to load
+// and once a second with its progress, and by snapshot threads once the PNG at the path
+// is written
+enum SceneEvent {
+    Loaded(PathBuf),
+    Failed(PathBuf),
+    Saved(PathBuf),
+    Progress(Progress),
+}
+
+// Sums for the present thread to bind, a newer frame replaces one it didn't get to yet
+struct Frame {
+    sums: Option<Sums>,
+    samples: usize,
+    post: Post,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Progress {
+    samples: usize,
+    // in this run, a resumed accumulation starts with more
+    traced: usize,
+    paused: bool,
+    // reached `--target-samples`
+    done: bool,
+    // spent on the samples so far, paused time left out
+    elapsed: Duration,
+    // dispatches of the last sample
+    sample_time: Duration,
+}
+
+impl ApplicationHandler<SceneEvent> for App<'_> {
     fn resumed(&mut self, _event_loop: &ActiveEventLoop) {
         self.start_render(false);
     }
@@ -101,24 +502,58 @@ impl ApplicationHandler for App<'_> {
     ) {
         match event {
             WindowEvent::RedrawRequested => self.redraw_frame(),
-            WindowEvent::CursorMoved { position: PhysicalPosition { x, y }, .. } => {
-                // Set mouse position to center of screen
-                let size = self.window.inner_size();
-                let center =
-                    LogicalPosition::new(size.width as f32 / 2.0, size.height as f32 / 2.0);
-                let _ = self.window.set_cursor_position(center);
-
-                let sensitivity = 0.7;
-                let delta =
-                    ((x - center.x as f64) * sensitivity, (y - center.y as f64) * sensitivity);
-
-                self.handle_mouse(delta);
+            // held to look around, `Tab` toggles the capture and `Escape` ends it
+            WindowEvent::MouseInput { state, button: MouseButton::Right, .. } => {
+                self.set_captured(state == ElementState::Pressed);
+            }
+            // the render thread prints what the pixel under the cursor hit
+            WindowEvent::MouseInput {
+                state: ElementState::Pressed,
+                button: MouseButton::Left,
+                ..
+            } if !self.captured => {
+                if let Some(pixel) = self.cursor_pixel() {
+                    *self.pick.lock() = Some(pixel);
+                }
+            }
+            WindowEvent::CursorMoved { position, .. } => self.cursor = position,
+            // neither a drag nor held keys go on in the background, their releases are missed
+            WindowEvent::Focused(false) => {
+                self.set_captured(false);
+                self.held.clear();
             }
             WindowEvent::KeyboardInput { event, .. } => {
+                if let PhysicalKey::Code(key) = event.physical_key {
+                    if event.state.is_pressed() {
+                        self.held.insert(key);
+                    } else {
+                        self.held.remove(&key);
+                    }
+                }
                 if event.state.is_pressed() {
+                    match event.physical_key {
+                        PhysicalKey::Code(KeyCode::Tab) => self.set_captured(!self.captured),
+                        PhysicalKey::Code(KeyCode::Escape) => self.set_captured(false),
+                        _ => {}
+                    }
                     self.handle_input(event.physical_key, event.logical_key);
                 }
             }
+            WindowEvent::DroppedFile(path) => {
+                self.status = format!("racist - loading {}", path.display());
+                self.update_title();
+                *self.scene.lock() = Some(path);
+            }
+            WindowEvent::Resized(size) => {
+                // minimizing reports a zero size, tracing pauses and keeps the last resolution
+                let minimized = size.width == 0 || size.height == 0;
+                if !minimized {
+                    let mut config = self.config.lock();
+                    (config.width, config.height) = render_size(size, self.render_scale);
+                }
+                *self.minimized.lock() = minimized;
+                *self.resized.lock() = Some(size);
+            }
             WindowEvent::CloseRequested => {
                 self.req.close = true;
             }
@@ -126,39 +561,830 @@ impl ApplicationHandler for App<'_> {
         }
     }
 
+    fn device_event(
+        &mut self,
+        _event_loop: &ActiveEventLoop,
+        _device_id: DeviceId,
+        event: DeviceEvent,
+    ) {
+        if let DeviceEvent::MouseMotion { delta } = event {
+            if self.captured {
+                self.handle_mouse(delta);
+            }
+        }
+    }
+
+    fn user_event(&mut self, _event_loop: &ActiveEventLoop, event: SceneEvent) {
+        match event {
+            SceneEvent::Loaded(path) => self.status = format!("racist - {}", path.display()),
+            SceneEvent::Failed(path) => {
+                self.status = format!("racist - failed to load {}", path.display())
+            }
+            SceneEvent::Saved(path) => self.status = format!("racist - saved {}", path.display()),
+            SceneEvent::Progress(progress) => self.progress = Some(progress),
+        }
+        self.update_title();
+    }
+
     fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
         if self.req.close {
             event_loop.exit();
         }
+        // woken up for the next step as long as the camera moves
+        if self.step_movement() {
+            event_loop.set_control_flow(ControlFlow::WaitUntil(Instant::now() + Self::MOVE_TICK));
+        } else {
+            event_loop.set_control_flow(ControlFlow::Wait);
+        }
+    }
+}
+
+// Keyboard edits of one material, the render thread applies them without reuploading the scene
+#[derive(Default)]
+struct MaterialEdit {
+    selected: usize,
+    // changes since the render thread last applied them
+    select: bool,
+    roughness: f32,
+    metallic: f32,
+}
+
+impl MaterialEdit {
+    const STEP: f32 = 0.05;
+
+    // Returns whether the selected material changed
+    fn apply(&mut self, world: &mut GpuWorld) -> bool {
+        let select = std::mem::take(&mut self.select);
+        let roughness_delta = std::mem::take(&mut self.roughness);
+        let metallic_delta = std::mem::take(&mut self.metallic);
+        if !select && roughness_delta == 0.0 && metallic_delta == 0.0 {
+            return false;
+        }
+
+        // indices of a previous scene wrap around
+        self.selected %= world.material_data.len();
+        let material = &mut world.material_data[self.selected];
+        let textured = (material.has_roughness_texture(), material.has_metallic_texture());
+        // textured slots keep their atlas rect in the factor
+        let factor = |factor: &mut Vec4, textured: bool, delta: f32| {
+            if textured {
+                return "textured".to_string();
+            }
+            *factor = Vec4::splat((factor.x + delta).clamp(0.0, 1.0));
+            format!("{:.2}", factor.x)
+        };
+        let roughness = factor(&mut material.roughness, textured.0, roughness_delta);
+        let metallic = factor(&mut material.metallic, textured.1, metallic_delta);
+        println!("material {}: roughness {roughness}, metallic {metallic}", self.selected);
+
+        world.update_materials(&world.material_data);
+        roughness_delta != 0.0 || metallic_delta != 0.0
+    }
+}
+
+// Bounces one instance of the scene, refitting the top level BVH over it every frame
+struct Bounce {
+    tlas: TLAS,
+    instance: usize,
+    rest: Mat4,
+    start: Instant,
+    degraded: bool,
+}
+
+impl Bounce {
+    const HEIGHT: f32 = 1.0;
+    // refits worse than this are reported, the BVH should be rebuilt by then
+    const MAX_DEGRADATION: f32 = 2.0;
+
+    // None when the scene has no such instance
+    fn new(world: &World, instance: usize) -> Option<Self> {
+        let rest = world.bvh.instances.get(instance)?;
+        Some(Self {
+            tlas: world.bvh.clone(),
+            instance,
+            rest: rest.object_to_world,
+            start: Instant::now(),
+            degraded: false,
+        })
+    }
+
+    fn step(&mut self, world: &mut GpuWorld) {
+        let height = (self.start.elapsed().as_secs_f32() * PI).sin().abs() * Self::HEIGHT;
+        let object_to_world = Mat4::from_translation(Vec3::Y * height) * self.rest;
+
+        let degradation = self.tlas.place(self.instance, object_to_world);
+        if degradation > Self::MAX_DEGRADATION && !self.degraded {
+            println!("BVH quality degraded {degradation:.2}x since the build, time to rebuild");
+        }
+        self.degraded = degradation > Self::MAX_DEGRADATION;
+        world.bvh.update(&self.tlas);
+    }
+}
+
+// Trace resolution for a window of `size`, at least a pixel each way
+fn render_size(size: PhysicalSize<u32>, render_scale: f32) -> (u32, u32) {
+    let scaled = |pixels: u32| ((pixels as f32 * render_scale).round() as u32).max(1);
+    (scaled(size.width), scaled(size.height))
+}
+
+// h:mm:ss, or m:ss under an hour
+fn clock(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{minutes}:{seconds:02}")
+    }
+}
+
+// Bad command line, reported with the usage instead of a panic
+fn usage_error(err: &str) -> ! {
+    eprintln!("{err}\n\n{USAGE}");
+    std::process::exit(2);
+}
+
+// `err` followed by its sources
+fn error_chain(err: &dyn Error) -> String {
+    let mut chain = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        chain += &format!(": {err}");
+        source = err.source();
+    }
+    chain
+}
+
+fn load_world(
+    path: &str,
+    env_map: Option<EnvMap>,
+    options: SceneOptions,
+) -> Result<World, SceneLoadError> {
+    // a line per phase and every tenth of it
+    let mut last = None;
+    let world = World::from_path_with_progress(path, options, |phase, progress| {
+        let step = (progress * 10.0) as u32;
+        if last != Some((phase, step)) {
+            last = Some((phase, step));
+            println!("Loading {path}: {phase:?} {}%", step * 10);
+        }
+    })?;
+    Ok(match env_map {
+        Some(env_map) => world.with_env_map(env_map),
+        None => world,
+    })
+}
+
+// Replaces the rendered scene with the one at `path`, keeping the current one on failure
+fn reload(
+    path: PathBuf,
+    env_map: Option<EnvMap>,
+    options: SceneOptions,
+    config: &Mutex<TracingConfig>,
+    world: &mut GpuWorld,
+    cpu_world: &mut World,
+    proxy: &EventLoopProxy<SceneEvent>,
+) -> bool {
+    println!("Loading {}", path.display());
+    let loaded = match load_world(&path.to_string_lossy(), env_map, options) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("Failed to load {}: {}", path.display(), error_chain(&err));
+            let _ = proxy.send_event(SceneEvent::Failed(path));
+            return false;
+        }
+    };
+    let gpu = match loaded.upload() {
+        Ok(gpu) => gpu,
+        Err(err) => {
+            eprintln!("Failed to upload {}: {}", path.display(), error_chain(&err));
+            let _ = proxy.send_event(SceneEvent::Failed(path));
+            return false;
+        }
+    };
+    {
+        let mut config = config.lock();
+        loaded.configure(&mut config);
+        if let Some(camera) = loaded.cameras.first() {
+            camera.configure(&mut config);
+        }
+    }
+    *world = gpu;
+    *cpu_world = loaded;
+    let _ = proxy.send_event(SceneEvent::Loaded(path));
+    true
+}
+
+// Swaps in a reload of the rendered scene, the camera stays where it is
+#[cfg(feature = "watch")]
+fn hot_reload(
+    loaded: World,
+    config: &Mutex<TracingConfig>,
+    world: &mut GpuWorld,
+    cpu_world: &mut World,
+) {
+    match loaded.upload() {
+        Ok(gpu) => {
+            loaded.configure(&mut config.lock());
+            *world = gpu;
+            *cpu_world = loaded;
+        }
+        Err(err) => eprintln!("Failed to upload the reloaded scene: {}", error_chain(&err)),
+    }
+}
+
+// Prints what the camera ray through the center of `pixel` hits and the pixel's average so far.
+// `world` is the scene `tlas` and `materials` belong to.
+fn inspect(
+    pixel: UVec2,
+    state: &mut Tracing,
+    world: &World,
+    tlas: &TLAS,
+    materials: &[MaterialData],
+) {
+    let config = state.config;
+    if pixel.x >= config.width || pixel.y >= config.height {
+        return;
+    }
+    // the kernel's `camera_ray`, without the jitter and the lens
+    let half_height = (config.vertical_fov * 0.5).tan();
+    let aspect = config.width as f32 / config.height as f32;
+    let suv = pixel.as_vec2() + 0.5;
+    let uv = Vec2::new(suv.x / config.width as f32, 1.0 - suv.y / config.height as f32) * 2.0 - 1.0;
+    let uv = uv * Vec2::new(aspect, 1.0) * half_height;
+    let ro = config.cam_pos.truncate();
+    let rd = camera_basis(config.cam_rot.truncate()) * Vec3::new(uv.x, uv.y, 1.0).normalize();
+
+    let sum = match state.sums(View::Color) {
+        Ok(sums) => sums[(pixel.y * config.width + pixel.x) as usize],
+        Err(err) => {
+            eprintln!("Failed to read pixel {}, {} back: {err}", pixel.x, pixel.y);
+            return;
+        }
+    };
+    // adaptive sampling may have stopped tracing the pixel earlier
+    let radiance = sum.truncate() / sum.w.max(1.0);
+    println!("pixel {}, {}: radiance {radiance:.3?} after {} samples", pixel.x, pixel.y, sum.w);
+    let Some(Hit { instance, triangle_index, triangle, distance, backface }) =
+        tlas.intersect(&world.index_buffer, &world.per_vertex_buffer, ro, rd)
+    else {
+        println!("  no hit, the environment");
+        return;
+    };
+
+    let instance = &tlas.instances[instance];
+    let vertices =
+        [triangle.x, triangle.y, triangle.z].map(|i| world.per_vertex_buffer[i as usize]);
+    // barycentrics of the hit on the world space triangle
+    let [a, b, c] = vertices.map(|vertex| instance.point_to_world(vertex.vertex.truncate()));
+    let (ab, ac, ap) = (b - a, c - a, ro + rd * distance - a);
+    let (d00, d01, d11) = (ab.dot(ab), ab.dot(ac), ac.dot(ac));
+    let (d20, d21) = (ap.dot(ab), ap.dot(ac));
+    let denominator = (d00 * d11 - d01 * d01).max(f32::MIN_POSITIVE);
+    let v = (d11 * d20 - d01 * d21) / denominator;
+    let w = (d00 * d21 - d01 * d20) / denominator;
+    let uv = vertices[0].uv0 * (1.0 - v - w) + vertices[1].uv0 * v + vertices[2].uv0 * w;
+    let side = if backface { "back" } else { "front" };
+    println!("  triangle {triangle_index}, {side} face at distance {distance:.4}, uv {uv:.4?}");
+
+    let index = instance.material_index(triangle, materials.len());
+    let Some(material) = materials.get(index as usize) else {
+        return;
+    };
+    // textured slots hold their atlas rect instead of a value
+    let value = |value: String, textured: bool| if textured { "textured".into() } else { value };
+    let albedo =
+        value(format!("{:.3?}", material.albedo.truncate()), material.has_albedo_texture());
+    let roughness = value(format!("{:.2}", material.roughness.x), material.has_roughness_texture());
+    let metallic = value(format!("{:.2}", material.metallic.x), material.has_metallic_texture());
+    let emissive = material.emissive.truncate() * material.emissive.w;
+    let textured = if material.has_emissive_texture() { " textured" } else { "" };
+    println!("  material {index}: albedo {albedo}, roughness {roughness}, metallic {metallic}");
+    println!(
+        "  emissive {emissive:.3?}{textured}, transmission {:.2}, ior {:.3}",
+        material.transmission, material.ior
+    );
+}
+
+// Saves the accumulated average on another thread, so the render loop doesn't hitch
+fn save_snapshot(state: &mut Tracing, post: Post, proxy: EventLoopProxy<SceneEvent>) {
+    let TracingConfig { width, height, cam_pos, cam_rot, .. } = state.config;
+    // a stale or blank image saved as the render would go unnoticed
+    let frame = match state.view(View::Color) {
+        Ok(frame) => frame.to_vec(),
+        Err(err) => {
+            eprintln!("Failed to read the image back, nothing saved: {err}");
+            return;
+        }
+    };
+    let snapshot = Snapshot {
+        frame,
+        width,
+        height,
+        samples: state.samples,
+        camera_position: cam_pos.truncate(),
+        camera_rotation: cam_rot.truncate(),
+        exposure: post.exposure,
+        tonemap: post.tonemap,
+    };
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let stem = format!("render-{}", time.as_millis());
+    thread::spawn(move || match snapshot.save(&stem) {
+        Ok(path) => {
+            println!("Saved {} after {} samples", path.display(), snapshot.samples);
+            let _ = proxy.send_event(SceneEvent::Saved(path));
+        }
+        Err(err) => eprintln!("Failed to save {stem}: {err}"),
+    });
+}
+
+// Writes the accumulation so far on another thread, `--resume` continues it
+fn save_checkpoint(state: &Tracing, scene: &Path, path: &Path) {
+    let checkpoint = match state.checkpoint(Checkpoint::hash(scene, &state.config)) {
+        Ok(Some(checkpoint)) => checkpoint,
+        Ok(None) => return,
+        // the previous checkpoint stays, rather than one of zeros
+        Err(err) => {
+            eprintln!("Failed to read the sums back for {}: {err}", path.display());
+            return;
+        }
+    };
+    let path = path.to_path_buf();
+    thread::spawn(move || match checkpoint.save(&path) {
+        Ok(()) => println!("Saved {} samples to {}", checkpoint.samples, path.display()),
+        Err(err) => eprintln!("Failed to write the checkpoint {}: {err}", path.display()),
+    });
+}
+
+// Continues the accumulation of a checkpoint, exits when it belongs to another render
+fn resume(state: &mut Tracing, path: &Path, scene: &Path, world: &GpuWorld<'_>) {
+    let checkpoint = match Checkpoint::load(path) {
+        Ok(checkpoint) => checkpoint,
+        Err(err) => {
+            eprintln!("Failed to read the checkpoint {}: {err}", path.display());
+            std::process::exit(1);
+        }
+    };
+    if let Some(mismatch) = checkpoint.mismatch(scene, &state.config) {
+        eprintln!("Can't resume from {}, {mismatch}.", path.display());
+        std::process::exit(1);
+    }
+    if let Err(err) = state.restore(&checkpoint, world) {
+        eprintln!("Failed to resume from {}: {err}", path.display());
+        std::process::exit(1);
+    }
+    println!("Resumed {} samples from {}", checkpoint.samples, path.display());
+}
+
+// Renders one loop of the animation as `frame_0000.png` and on into the working directory
+fn render_animation(mut world: World, samples: usize, mut state: Tracing) {
+    const FRAMES_PER_SECOND: f32 = 24.0;
+
+    let Some(duration) = world.animation.as_ref().map(|animation| animation.duration()) else {
+        eprintln!("The scene has no animation.");
+        std::process::exit(1);
+    };
+    let mut gpu = match world.upload() {
+        Ok(gpu) => gpu,
+        Err(err) => {
+            eprintln!("Failed to upload the scene: {}", error_chain(&err));
+            std::process::exit(1);
+        }
+    };
+    // the last frame is left out, it would repeat the first one
+    let frames = ((duration * FRAMES_PER_SECOND).round() as usize).max(1);
+    for frame in 0..frames {
+        let time = frame as f32 / FRAMES_PER_SECOND;
+        world.sample_animation(time);
+        gpu.bvh.update(&world.bvh);
+        state.reset();
+        for _ in 0..samples {
+            compute::trace_gpu(&mut state, &gpu);
+        }
+
+        let path = format!("frame_{frame:04}.png");
+        let TracingConfig { width, height, .. } = state.config;
+        let frame = match state.view(View::Color) {
+            Ok(frame) => frame,
+            Err(err) => {
+                eprintln!("Failed to read {path} back: {err}");
+                std::process::exit(1);
+            }
+        };
+        let image = output::to_rgb8(frame, width, height, 0.0, Tonemap::None);
+        match image.save(&path) {
+            Ok(()) => println!("{path}: {time:.3}s"),
+            Err(err) => eprintln!("Failed to write {path}: {err}"),
+        }
+    }
+}
+
+// Renders `samples` samples into `output` and exits, nonzero when anything fails
+fn render_headless(
+    world: World,
+    samples: usize,
+    output: &Path,
+    mut state: Tracing,
+    scene: &Path,
+    checkpoints: &Checkpoints,
+) {
+    const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+    let gpu = upload_headless(&world);
+    if let Some(path) = &checkpoints.resume {
+        resume(&mut state, path, scene, &gpu);
+    }
+    let resumed = state.samples;
+    let start = Instant::now();
+    let (mut reported, mut reported_samples) = (start, resumed);
+    let mut checkpointed = start;
+    while state.samples < samples {
+        compute::trace_gpu(&mut state, &gpu);
+        let sample = state.samples;
+        if reported.elapsed() >= PROGRESS_INTERVAL {
+            let rate = (sample - reported_samples) as f64 / reported.elapsed().as_secs_f64();
+            println!("{sample}/{samples} samples, {rate:.2} samples/sec");
+            (reported, reported_samples) = (Instant::now(), sample);
+        }
+        if checkpoints.every.is_some_and(|every| checkpointed.elapsed() >= every) {
+            save_checkpoint(&state, scene, &checkpoints.path);
+            checkpointed = Instant::now();
+        }
+    }
+
+    let (config, post) = (state.config, Post::default());
+    let frame = match state.view(View::Color) {
+        Ok(frame) => frame.to_vec(),
+        Err(err) => {
+            eprintln!("Failed to read the image back: {err}");
+            std::process::exit(1);
+        }
+    };
+    let snapshot = Snapshot {
+        frame,
+        width: config.width,
+        height: config.height,
+        samples: state.samples,
+        camera_position: config.cam_pos.truncate(),
+        camera_rotation: config.cam_rot.truncate(),
+        exposure: post.exposure,
+        tonemap: post.tonemap,
+    };
+    if let Err(err) = snapshot.save_as(output) {
+        eprintln!("Failed to write {}: {err}", output.display());
+        std::process::exit(1);
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "Saved {} after {} samples in {elapsed:?}, {:.2} samples/sec",
+        output.display(),
+        state.samples,
+        (state.samples - resumed) as f64 / elapsed.as_secs_f64()
+    );
+}
+
+// Renders `samples` samples without a window and prints how long they took, the last line is
+// `key=value` pairs for scripts that track the throughput
+fn render_bench(world: World, samples: usize, mut state: Tracing) {
+    let gpu = upload_headless(&world);
+    let mut sample_times = Vec::with_capacity(samples);
+    let start = Instant::now();
+    for _ in 0..samples {
+        compute::trace_gpu(&mut state, &gpu);
+        sample_times.push(state.sample_time);
+    }
+    let elapsed = start.elapsed();
+    let rate = samples as f64 / elapsed.as_secs_f64();
+    let TracingConfig { width, height, .. } = state.config;
+    println!("{samples} samples at {width}x{height} in {elapsed:?}, {rate:.2} samples/sec");
+    println!(
+        "bench samples={samples} width={width} height={height} seconds={:.3} \
+         samples_per_sec={rate:.3} sample_ms_mean={:.3} sample_ms_min={:.3} sample_ms_max={:.3}",
+        elapsed.as_secs_f64(),
+        sample_times.iter().sum::<Duration>().as_secs_f64() * 1000.0 / samples.max(1) as f64,
+        sample_times.iter().min().copied().unwrap_or_default().as_secs_f64() * 1000.0,
+        sample_times.iter().max().copied().unwrap_or_default().as_secs_f64() * 1000.0,
+    );
+}
+
+// The tracer of every mode, set up as the command line asks
+fn tracing(config: TracingConfig, args: &Args) -> Tracing {
+    let mut state = Tracing::new(config);
+    state.max_dispatch = args.max_dispatch;
+    state.wavefront = args.wavefront;
+    state
+}
+
+// The scene on the compute device, exits when the scene doesn't fit on it
+fn upload_headless(world: &World) -> GpuWorld<'static> {
+    match world.upload() {
+        Ok(gpu) => gpu,
+        Err(err) => {
+            eprintln!("Failed to upload the scene: {}", error_chain(&err));
+            std::process::exit(1);
+        }
     }
 }
 
 fn main() {
-    let event_loop = EventLoop::<()>::with_user_event().build().unwrap();
-    let (width, height) = (1400, 1400);
-    let window = event_loop
-        .create_window(
-            WindowAttributes::default()
-                .with_title("racist")
-                .with_inner_size(PhysicalSize { width, height }),
-        )
-        .unwrap();
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) if args.help => {
+            println!("{USAGE}");
+            return;
+        }
+        Ok(args) => args,
+        Err(err) => usage_error(&err),
+    };
+    let (width, height, options) = (args.width, args.height, args.scene_options);
+
+    // kept around for the scenes dropped onto the window later
+    let env_map = args.env.as_ref().map(|path| match EnvMap::from_path(&path.to_string_lossy()) {
+        Some(env_map) => env_map,
+        None => {
+            eprintln!("Failed to load the environment map {}.", path.display());
+            std::process::exit(1);
+        }
+    });
+    // The device is picked for the window's surface, and the scene's atlas is sized for the
+    // device, so both come first
+    let windowed = args.animate.is_none() && args.headless.is_none() && args.bench.is_none();
+    let display = windowed.then(|| {
+        let event_loop = EventLoop::<SceneEvent>::with_user_event().build().unwrap();
+        let window = event_loop
+            .create_window(
+                WindowAttributes::default()
+                    .with_title("racist")
+                    .with_inner_size(PhysicalSize { width, height }),
+            )
+            .unwrap();
+        let wgpu = Wgpu::init(&window, args.present_mode);
+        (event_loop, window, wgpu)
+    });
+    if !windowed {
+        if let Err(err) = compute::FW.init(gpu::instance(), None) {
+            eprintln!("Failed to create a GPU device: {err}.");
+            std::process::exit(1);
+        }
+    }
+    let world = match load_world(&args.scene.to_string_lossy(), env_map.clone(), options) {
+        Ok(world) => world,
+        Err(err) => {
+            eprintln!("Failed to load the scene: {}", error_chain(&err));
+            std::process::exit(1);
+        }
+    };
+    let mut config = TracingConfig::soft();
+    config.width = width;
+    config.height = height;
+    if let Some(max_bounces) = args.max_bounces {
+        config.max_bounces = max_bounces;
+    }
+    config.clamp_bounces();
+    config.set_restir(args.restir);
+    config.adaptive_threshold = args.adaptive;
+    if let Some(samples) = args.adaptive_warmup {
+        config.adaptive_min_samples = samples;
+    }
+    world.configure(&mut config);
+    match args.camera {
+        Some(StartCamera::Index(index)) => match world.cameras.get(index) {
+            Some(camera) => camera.configure(&mut config),
+            None => usage_error(&format!("the scene has no camera {index}")),
+        },
+        Some(StartCamera::Pose { position, yaw, pitch }) => {
+            config.cam_pos = position.extend(0.0);
+            config.cam_rot = Vec4::new(pitch, yaw, 0.0, 0.0);
+        }
+        None => {
+            if let Some(camera) = world.cameras.first() {
+                camera.configure(&mut config);
+            }
+        }
+    }
+    let mut bounce = args.bounce.map(|instance| match Bounce::new(&world, instance) {
+        Some(bounce) => bounce,
+        None => usage_error(&format!("the scene has no instance {instance}")),
+    });
+    if let Some(samples) = args.animate {
+        render_animation(world, samples, tracing(config, &args));
+        return;
+    }
+    if let Some(Headless { samples, output }) = &args.headless {
+        let state = tracing(config, &args);
+        render_headless(world, *samples, output, state, &args.scene, &args.checkpoints);
+        return;
+    }
+    // run `--bench` against kernels built with and without `stackless` to compare traversals,
+    // or with and without `--wavefront`
+    if let Some(samples) = args.bench {
+        render_bench(world, samples, tracing(config, &args));
+        return;
+    }
 
-    let mut app = App::new(&window);
-    let wgpu = Wgpu::init(app.window);
+    let Some((event_loop, window, mut wgpu)) = display else {
+        unreachable!("the modes without a window returned above")
+    };
+    let mut app = App::new(&window, config, &args);
 
-    let world = World::from_path("PBRTest.glb").unwrap().into_gpu();
+    // kept next to the upload, picking traces on the CPU
+    let mut cpu_world = world;
+    let mut world = match cpu_world.upload() {
+        Ok(world) => world,
+        Err(err) => {
+            eprintln!("Failed to upload the scene: {}", error_chain(&err));
+            std::process::exit(1);
+        }
+    };
 
     let config = app.config.clone();
-    let mut state = Tracing::new(*config.lock());
+    let post = app.post.clone();
+    let scene = app.scene.clone();
+    let material = app.material.clone();
+    let resized = app.resized.clone();
+    let minimized = app.minimized.clone();
+    let present = app.present.clone();
+    let snapshot = app.snapshot.clone();
+    let checkpoint = app.checkpoint.clone();
+    let paused = app.paused.clone();
+    let restart = app.restart.clone();
+    let pick = app.pick.clone();
+    let proxy = event_loop.create_proxy();
+    #[cfg(feature = "watch")]
+    let mut watcher = match watch::SceneWatcher::new(&args.scene, env_map.clone(), options) {
+        Ok(watcher) => Some(watcher),
+        Err(err) => {
+            eprintln!("Failed to watch the scene file: {err}");
+            None
+        }
+    };
+    let mut state = tracing(*config.lock(), &args);
+    state.target_samples = args.target_samples;
+    if let Some(path) = &args.kernel {
+        match KernelFile::load(path) {
+            Ok(kernel) => state.kernel = Some(kernel),
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1);
+            }
+        }
+    }
+    let save_when_done = args.save_when_done;
+    // checkpoints name the scene they were rendered from, dropped scenes replace it
+    let (mut scene_path, checkpoints) = (args.scene.clone(), args.checkpoints);
+    if let Some(path) = &checkpoints.resume {
+        resume(&mut state, path, &scene_path, &world);
+    }
+    let mut checkpointed = Instant::now();
+
+    // something shows up right after moving, before the first full sample
+    state.fast_preview = true;
+    // a panic on the render thread, like wgpu's over an invalid pass, would leave a
+    // frozen window behind
+    let report = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        report(info);
+        eprintln!("Rendering failed, exiting.");
+        std::process::exit(1);
+    }));
+    // the window title shows the progress, once a second keeps the rates readable
+    const TITLE_INTERVAL: Duration = Duration::from_secs(1);
+    let mut reported = (Instant::now(), None);
+    // spent on the current accumulation, counted up to `counted`, and the samples it started
+    // with, a resumed one doesn't start from zero
+    let (mut rendering, mut counted) = (Duration::ZERO, Instant::now());
+    let mut resumed = state.samples;
+    let frames = Arc::new((Mutex::new(None::<Frame>), Condvar::new()));
+
+    // presenting waits for the surface, for vsync with `Fifo`, tracing goes on meanwhile
+    let latest = frames.clone();
+    thread::spawn(move || loop {
+        if let Some(size) = resized.lock().take() {
+            wgpu.resize(size);
+        }
+        if let Some(mode) = present.lock().take() {
+            println!("present mode: {:?}", wgpu.set_present_mode(mode));
+        }
+        let (frame, fresh) = &*latest;
+        let mut next = frame.lock();
+        if next.is_none() {
+            // wakes up now and then for resizes and present mode changes
+            fresh.wait_for(&mut next, Duration::from_millis(50));
+        }
+        let Some(Frame { sums, samples, post }) = next.take() else {
+            continue;
+        };
+        drop(next);
+        wgpu.redraw(sums.as_ref(), samples, post);
+    });
+
     thread::spawn(move || loop {
-        let update = *config.clone().lock();
-        if update.cam_rot != state.config.cam_rot || update.cam_pos != state.config.cam_pos {
-            state.samples = 0;
-            state.frame.fill(0.0);
+        // nothing would be shown
+        if *minimized.lock() {
+            thread::sleep(Duration::from_millis(50));
+            counted = Instant::now();
+            continue;
+        }
+        // edits, reloads and restarts wait for the render to resume
+        let is_paused = *paused.lock();
+        // the sample that reached the target
+        let mut finished = false;
+        if !is_paused {
+            // rendering stops while the dropped scene loads
+            let dropped = scene.lock().take();
+            if let Some(path) = dropped {
+                if reload(
+                    path.clone(),
+                    env_map.clone(),
+                    options,
+                    &config,
+                    &mut world,
+                    &mut cpu_world,
+                    &proxy,
+                ) {
+                    // instance indices belong to the previous scene
+                    bounce = None;
+                    state.reset();
+                    scene_path = path.clone();
+                    #[cfg(feature = "watch")]
+                    if let Some(Err(err)) = watcher.as_mut().map(|watcher| watcher.set_path(&path))
+                    {
+                        eprintln!("Failed to watch {}: {err}", path.display());
+                    }
+                }
+            }
+            #[cfg(feature = "watch")]
+            if let Some(loaded) = watcher.as_ref().and_then(|watcher| watcher.take()) {
+                hot_reload(loaded, &config, &mut world, &mut cpu_world);
+                bounce = None;
+                state.reset();
+            }
+            let update = *config.clone().lock();
+            if bytemuck::bytes_of(&update) != bytemuck::bytes_of(&state.config) {
+                state.reset();
+            }
+            state.config = update;
+            if material.lock().apply(&mut world) {
+                state.reset();
+            }
+            if let Some(bounce) = &mut bounce {
+                bounce.step(&mut world);
+                state.reset();
+            }
+            if std::mem::take(&mut *restart.lock()) || state.reload_kernel() {
+                state.reset();
+            }
+            // a finished image keeps being presented until an edit restarts it
+            if !state.done() {
+                compute::trace_gpu(&mut state, &world);
+                finished = state.done();
+            }
+        }
+        let done = state.done();
+        let now = Instant::now();
+        if !is_paused && (finished || !done) {
+            // a restarted accumulation counts from its first sample
+            if state.samples == 1 {
+                (rendering, resumed) = (Duration::ZERO, 0);
+            }
+            rendering += now - counted;
+        }
+        counted = now;
+        let progress = Progress {
+            samples: state.samples,
+            traced: state.samples.saturating_sub(resumed),
+            paused: is_paused,
+            done,
+            elapsed: rendering,
+            sample_time: state.sample_time,
+        };
+        let immediate = is_paused || done;
+        if reported.1 != Some(progress) && (immediate || reported.0.elapsed() >= TITLE_INTERVAL) {
+            let _ = proxy.send_event(SceneEvent::Progress(progress));
+            reported = (Instant::now(), Some(progress));
+        }
+        let picked = pick.lock().take();
+        if let Some(pixel) = picked {
+            // a bouncing instance moved since the scene was loaded
+            let tlas = bounce.as_ref().map_or(&cpu_world.bvh, |bounce| &bounce.tlas);
+            inspect(pixel, &mut state, &cpu_world, tlas, &world.material_data);
+        }
+        let post = *post.lock();
+        if std::mem::take(&mut *snapshot.lock()) || (finished && save_when_done) {
+            save_snapshot(&mut state, post, proxy.clone());
+        }
+        let due = checkpoints.every.is_some_and(|every| checkpointed.elapsed() >= every);
+        if std::mem::take(&mut *checkpoint.lock()) || (due && !is_paused && !done) {
+            save_checkpoint(&state, &scene_path, &checkpoints.path);
+            checkpointed = Instant::now();
+        }
+        // the present thread binds the buffers, so every sample can be shown without a copy
+        let (frame, fresh) = &*frames;
+        *frame.lock() = Some(Frame { sums: state.display(), samples: state.samples, post });
+        fresh.notify_one();
+        // still presented for exposure and resizes, just not as often
+        if is_paused || done {
+            thread::sleep(Duration::from_millis(50));
         }
-        state.config = update;
-        wgpu.redraw(&compute::trace_gpu(&mut state, &world), width, height);
     });
 
     event_loop.run_app(&mut app).unwrap();
@@ -0,0 +1,299 @@
+use {
+    glam::{Mat3, Vec3},
+    image::RgbImage,
+    std::{
+        fs::File,
+        io::{self, BufWriter, Write},
+        path::{Path, PathBuf},
+    },
+};
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum Tonemap {
+    #[default]
+    None,
+    Reinhard,
+    AcesFitted,
+}
+
+impl Tonemap {
+    pub fn next(self) -> Self {
+        match self {
+            Tonemap::None => Tonemap::Reinhard,
+            Tonemap::Reinhard => Tonemap::AcesFitted,
+            Tonemap::AcesFitted => Tonemap::None,
+        }
+    }
+
+    pub fn apply(self, color: Vec3) -> Vec3 {
+        match self {
+            Tonemap::None => color,
+            Tonemap::Reinhard => color / (Vec3::ONE + color),
+            Tonemap::AcesFitted => aces_fitted(color),
+        }
+    }
+}
+
+// https://github.com/TheRealMJP/BakingLab/blob/master/BakingLab/ACES.hlsl
+fn aces_fitted(color: Vec3) -> Vec3 {
+    // sRGB => XYZ => D65_2_D60 => AP1 => RRT_SAT
+    let input = Mat3::from_cols_array_2d(&[
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777],
+    ])
+    .transpose();
+    // ODT_SAT => XYZ => D60_2_D65 => sRGB
+    let output = Mat3::from_cols_array_2d(&[
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602],
+    ])
+    .transpose();
+
+    let v = input * color;
+    let a = v * (v + 0.0245786) - 0.000090537;
+    let b = v * (0.983729 * v + 0.4329510) + 0.238081;
+    output * (a / b)
+}
+
+pub fn srgb_to_linear(x: f32) -> f32 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(x: f32) -> f32 {
+    if x <= 0.0031308 {
+        x * 12.92
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// `exposure` is in stops, so `0.0` together with `Tonemap::None` is a plain clamp
+pub fn to_rgb8(
+    frame: &[f32],
+    width: u32,
+    height: u32,
+    exposure: f32,
+    tonemap: Tonemap,
+) -> RgbImage {
+    let scale = exposure.exp2();
+    let mut image = RgbImage::new(width, height);
+    for (pixel, rgb) in image.pixels_mut().zip(frame.chunks_exact(3)) {
+        let color = tonemap.apply(Vec3::from_slice(rgb) * scale);
+        let color = color.clamp(Vec3::ZERO, Vec3::ONE);
+        pixel.0 = [color.x, color.y, color.z].map(|c| (linear_to_srgb(c) * 255.0 + 0.5) as u8);
+    }
+    image
+}
+
+// http://www.pauldebevec.com/Research/HDR/PFM/
+// `frame` is linear RGB, top row first, as produced by `Tracing::view`
+pub fn write_pfm(path: impl AsRef<Path>, frame: &[f32], width: u32, height: u32) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    // negative scale means little-endian samples
+    write!(out, "PF\n{width} {height}\n-1.0\n")?;
+    // PFM stores scanlines bottom to top
+    for row in frame.chunks(width as usize * 3).take(height as usize).rev() {
+        for value in row {
+            out.write_all(&value.to_le_bytes())?;
+        }
+    }
+    out.flush()
+}
+
+/// Extra per-pixel data written next to the color channels,
+/// e.g. `Layer { name: "N", channels: &["X", "Y", "Z"], data }`.
+#[cfg(feature = "exr")]
+pub struct Layer<'a> {
+    pub name: &'a str,
+    pub channels: &'a [&'a str],
+    pub data: &'a [f32],
+}
+
+#[cfg(feature = "exr")]
+pub fn write_exr(
+    path: impl AsRef<Path>,
+    frame: &[f32],
+    width: u32,
+    height: u32,
+    layers: &[Layer],
+) -> exr::error::Result<()> {
+    use exr::prelude::*;
+
+    let pixels = width as usize * height as usize;
+    let channel = |name: &str, data: &[f32], stride: usize, offset: usize| {
+        let samples = data.iter().skip(offset).step_by(stride).take(pixels).copied().collect();
+        AnyChannel::new(name, FlatSamples::F32(samples))
+    };
+
+    let mut channels = SmallVec::new();
+    for (i, name) in ["R", "G", "B"].iter().enumerate() {
+        channels.push(channel(*name, frame, 3, i));
+    }
+    for layer in layers {
+        let stride = layer.channels.len();
+        for (i, name) in layer.channels.iter().enumerate() {
+            channels.push(channel(&format!("{}.{name}", layer.name), layer.data, stride, i));
+        }
+    }
+
+    Image::from_channels((width as usize, height as usize), AnyChannels::sort(channels))
+        .write()
+        .to_file(path)
+}
+
+// An accumulated frame and what it was rendered with, see `save`
+pub struct Snapshot {
+    // linear RGB averaged over `samples`, top row first
+    pub frame: Vec<f32>,
+    pub width: u32,
+    pub height: u32,
+    pub samples: usize,
+    pub camera_position: Vec3,
+    pub camera_rotation: Vec3,
+    // as shown in the window, only applied to the PNG
+    pub exposure: f32,
+    pub tonemap: Tonemap,
+}
+
+impl Snapshot {
+    // Writes `<stem>.png` as the window shows it, the linear frame as `<stem>.exr` (`.pfm`
+    // without the `exr` feature) and the settings as `<stem>.json`. Returns the PNG path.
+    pub fn save(&self, stem: &str) -> io::Result<PathBuf> {
+        let png = PathBuf::from(format!("{stem}.png"));
+        self.save_as(&png)?;
+        let linear = if cfg!(feature = "exr") { "exr" } else { "pfm" };
+        self.save_as(format!("{stem}.{linear}"))?;
+
+        let vector = |v: Vec3| format!("[{}, {}, {}]", v.x, v.y, v.z);
+        let json = format!(
+            "{{\n  \"width\": {},\n  \"height\": {},\n  \"samples\": {},\n  \
+             \"camera_position\": {},\n  \"camera_rotation\": {},\n  \"exposure\": {},\n  \
+             \"tonemap\": \"{:?}\"\n}}\n",
+            self.width,
+            self.height,
+            self.samples,
+            vector(self.camera_position),
+            vector(self.camera_rotation),
+            self.exposure,
+            self.tonemap,
+        );
+        std::fs::write(format!("{stem}.json"), json)?;
+        Ok(png)
+    }
+
+    // The format follows the extension, `.exr` and `.pfm` keep the linear frame and anything
+    // else is written as the window shows it
+    pub fn save_as(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        match path.extension().and_then(|extension| extension.to_str()) {
+            #[cfg(feature = "exr")]
+            Some("exr") => write_exr(path, &self.frame, self.width, self.height, &[])
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string())),
+            #[cfg(not(feature = "exr"))]
+            Some("exr") => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "writing EXR needs the `exr` feature",
+            )),
+            Some("pfm") => write_pfm(path, &self.frame, self.width, self.height),
+            _ => to_rgb8(&self.frame, self.width, self.height, self.exposure, self.tonemap)
+                .save(path)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("racist-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn pfm_is_bottom_up_little_endian() {
+        let path = temporary("rows.pfm");
+        // 2x2, top row first
+        let frame = [[1.0, 2.0, 3.0], [4.0, 5.0, 6.0], [7.0, 8.0, 9.0], [10.0, 11.0, 12.0]];
+        write_pfm(&path, &frame.concat(), 2, 2).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let header = b"PF\n2 2\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+        let values: Vec<f32> = bytes[header.len()..]
+            .chunks_exact(4)
+            .map(|value| f32::from_le_bytes(value.try_into().unwrap()))
+            .collect();
+        let bottom_up = [frame[2], frame[3], frame[0], frame[1]];
+        assert_eq!(values, bottom_up.concat());
+    }
+
+    #[cfg(feature = "exr")]
+    #[test]
+    fn exr_round_trip() {
+        let path = temporary("round-trip.exr");
+        let (width, height) = (3, 2);
+        let frame: Vec<f32> = (0..width * height * 3).map(|i| i as f32 * 0.37 - 1.0).collect();
+        let normal: Vec<f32> = (0..width * height * 3).map(|i| (i as f32).sin()).collect();
+        let layer = Layer { name: "N", channels: &["X", "Y", "Z"], data: &normal };
+        write_exr(&path, &frame, width, height, &[layer]).unwrap();
+        let image = exr::prelude::read_all_flat_layers_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let channels = &image.layer_data[0].channel_data.list;
+        let expected = [("R", &frame, 0), ("G", &frame, 1), ("B", &frame, 2)].into_iter().chain([
+            ("N.X", &normal, 0),
+            ("N.Y", &normal, 1),
+            ("N.Z", &normal, 2),
+        ]);
+        for (name, data, offset) in expected {
+            let channel = channels.iter().find(|channel| channel.name.to_string() == name);
+            let Some(exr::prelude::FlatSamples::F32(samples)) =
+                channel.map(|channel| &channel.sample_data)
+            else {
+                panic!("no f32 channel {name}");
+            };
+            let written = data.iter().skip(offset).step_by(3);
+            assert_eq!(samples.len(), (width * height) as usize);
+            for (read, written) in samples.iter().zip(written) {
+                assert!((read - written).abs() <= f32::EPSILON, "{name}: {read} != {written}");
+            }
+        }
+    }
+
+    // `ACESFitted` of BakingLab's ACES.hlsl, Stephen Hill's fit of the ACES RRT and sRGB ODT,
+    // evaluated in f64 from the constants there. Its final saturate is the clamp of `to_rgb8`.
+    // https://github.com/TheRealMJP/BakingLab/blob/master/BakingLab/ACES.hlsl
+    #[test]
+    fn aces_fitted_matches_the_reference() {
+        for (input, output) in [
+            (Vec3::splat(0.0), Vec3::splat(0.0)),
+            (Vec3::splat(0.18), Vec3::splat(0.10559)),
+            (Vec3::splat(1.0), Vec3::new(0.61912, 0.61912, 0.61911)),
+            (Vec3::splat(10.0), Vec3::new(0.97382, 0.97382, 0.97381)),
+            (Vec3::splat(1000.0), Vec3::splat(1.0)),
+            (Vec3::new(0.5, 0.2, 0.05), Vec3::new(0.36450, 0.12841, 0.02258)),
+            (Vec3::new(4.0, 1.0, 0.25), Vec3::new(0.99489, 0.66338, 0.31668)),
+        ] {
+            let mapped = Tonemap::AcesFitted.apply(input).clamp(Vec3::ZERO, Vec3::ONE);
+            assert!(mapped.abs_diff_eq(output, 1e-4), "{input}: {mapped}");
+        }
+    }
+
+    #[test]
+    fn no_tonemap_is_a_plain_clamp() {
+        let color = Vec3::new(0.25, 0.5, 2.0);
+        assert_eq!(Tonemap::None.apply(color), color);
+        let frame = [0.0, 0.5, 2.0, -1.0, 0.0031308, 1.0];
+        let image = to_rgb8(&frame, 2, 1, 0.0, Tonemap::None);
+        assert_eq!(image.get_pixel(0, 0).0, [0, 188, 255]);
+        assert_eq!(image.get_pixel(1, 0).0, [0, 10, 255]);
+    }
+}
@@ -1,21 +1,125 @@
 use {
     crate::{
-        bvh::{BVHBuilder, GpuBVH, BVH},
+        animation::Animation,
+        bvh::{self, BVHBuilder, GpuBVH, BVH, TLAS},
         compute::FW,
-        light,
+        gpu::{GpuBuffer, GpuConstImage},
+        light, output,
     },
-    glam::{Mat4, UVec4, Vec2, Vec3, Vec4},
-    gpgpu::{primitives::pixels::Rgba8UintNorm, BufOps, GpuBuffer, GpuConstImage, ImgOps},
-    image::{io::Reader, DynamicImage},
+    glam::{Mat3, Mat4, UVec4, Vec2, Vec3, Vec4},
+    image::{io::Reader, DynamicImage, RgbImage},
     russimp::{
+        light::LightSourceType,
         material::{DataContent, Material, PropertyTypeInfo, Texture, TextureType},
         node::Node,
-        scene::{PostProcess::*, Scene},
+        scene::{PostProcess, PostProcess::*, Scene},
+    },
+    shared::{
+        camera_basis, BVHNode, InstanceData, LightPick, MaterialData, PerVertexData, PunctualLight,
+        PunctualLightKind, TextureSlot, TracingConfig, UvTransform, WrapMode,
+    },
+    std::{
+        collections::{hash_map::DefaultHasher, HashMap},
+        fmt,
+        hash::{Hash, Hasher},
+        io::{self, Cursor},
+        rc::Rc,
+        sync::atomic::{AtomicU64, Ordering},
     },
-    shared::{LightPick, MaterialData, PerVertexData},
-    std::io::Cursor,
 };
 
+// KHR_materials_emissive_strength defaults to 1.0, but assimp 5.2.5 drops the extension
+// entirely, so existing scenes were tuned against a fixed x15 boost. Keep it when the
+// strength is missing so they don't go dark.
+const LEGACY_EMISSIVE_STRENGTH: f32 = 15.0;
+
+#[derive(Debug)]
+pub enum SceneLoadError {
+    Io(io::Error),
+    // assimp rejected the file, unsupported formats end up here too
+    Assimp(String),
+    TextureDecode { material: String, slot: TextureSlot },
+    // atlas sizes in texels
+    AtlasOverflow { needed: u32, available: u32 },
+    EmptyScene,
+    // storage buffer sizes in bytes
+    BufferTooLarge { buffer: &'static str, size: u64, limit: u64 },
+}
+
+impl fmt::Display for SceneLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneLoadError::Io(_) => write!(f, "failed to read the scene file"),
+            SceneLoadError::Assimp(err) => write!(f, "failed to import the scene: {err}"),
+            SceneLoadError::TextureDecode { material, slot } => {
+                write!(f, "failed to decode the {slot:?} texture of material `{material}`")
+            }
+            SceneLoadError::AtlasOverflow { needed, available } => write!(
+                f,
+                "textures need a {needed}x{needed} atlas, the device allows {available}x{available}"
+            ),
+            SceneLoadError::EmptyScene => write!(f, "the scene places no meshes"),
+            SceneLoadError::BufferTooLarge { buffer, size, limit } => write!(
+                f,
+                "{buffer} buffer {:.1} MiB exceeds device limit {:.1} MiB",
+                mib(*size),
+                mib(*limit)
+            ),
+        }
+    }
+}
+
+fn mib(bytes: u64) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0)
+}
+
+// Counts and upload sizes of a world, see `World::stats`
+pub struct WorldStats {
+    pub triangles: usize,
+    pub vertices: usize,
+    pub materials: usize,
+    pub emissive_triangles: usize,
+    pub punctual_lights: usize,
+    pub instances: usize,
+    // storage buffers by binding name, in bytes
+    pub buffers: Vec<(&'static str, u64)>,
+}
+
+impl fmt::Display for WorldStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} triangles, {} vertices, {} materials, {} emissive triangles, {} punctual lights, \
+             {} instances\nbuffers:",
+            self.triangles,
+            self.vertices,
+            self.materials,
+            self.emissive_triangles,
+            self.punctual_lights,
+            self.instances
+        )?;
+        for (name, size) in &self.buffers {
+            write!(f, " {name} {:.1} MiB", mib(*size))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SceneLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SceneLoadError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SceneLoadError {
+    fn from(err: io::Error) -> Self {
+        SceneLoadError::Io(err)
+    }
+}
+
 fn convert_texture(texture: &Texture) -> Option<DynamicImage> {
     let image = match &texture.data {
         DataContent::Texel(raw_data) => {
@@ -29,11 +133,145 @@ fn convert_texture(texture: &Texture) -> Option<DynamicImage> {
         }
     };
 
-    Some(image)
+    // nothing to sample or pack in the atlas
+    (image.width() > 0 && image.height() > 0).then_some(image)
+}
+
+// Material slots loaded from textures, glTF occlusion maps come through assimp as lightmaps
+const TEXTURE_TYPES: [(TextureSlot, TextureType); 6] = [
+    (TextureSlot::Albedo, TextureType::Diffuse),
+    (TextureSlot::Metallic, TextureType::Metalness),
+    (TextureSlot::Roughness, TextureType::Roughness),
+    (TextureSlot::Normals, TextureType::Normals),
+    (TextureSlot::Emissive, TextureType::Emissive),
+    (TextureSlot::Occlusion, TextureType::LightMap),
+];
+
+lazy_static::lazy_static! {
+    // The exact sRGB EOTF of every 8-bit value
+    static ref SRGB_TO_LINEAR: [f32; 256] =
+        std::array::from_fn(|value| output::srgb_to_linear(value as f32 / 255.0));
 }
 
-fn load_texture(material: &Material, texture_type: TextureType) -> Option<DynamicImage> {
-    material.textures.get(&texture_type).and_then(|texture| convert_texture(&texture.borrow()))
+// Texels are stored as authored. Colors (albedo, emissive) are sRGB and the kernel decodes
+// them after filtering, 8 bits of linear values would band in the darks. Data (normals,
+// metallic, roughness, occlusion) is linear.
+fn load_texture(
+    material: &Material,
+    slot: TextureSlot,
+    texture_type: TextureType,
+) -> Result<Option<DynamicImage>, SceneLoadError> {
+    let Some(texture) = material.textures.get(&texture_type) else {
+        return Ok(None);
+    };
+    let texture = convert_texture(&texture.borrow()).ok_or_else(|| {
+        let material = material_name(material).unwrap_or("unnamed").to_string();
+        SceneLoadError::TextureDecode { material, slot }
+    })?;
+    Ok(Some(texture))
+}
+
+// Decoded textures of a scene, identical images are stored once and their atlas rect is
+// shared by every material slot referencing them
+#[derive(Default)]
+struct TextureSet {
+    textures: Vec<DynamicImage>,
+    by_content: HashMap<u64, Vec<usize>>,
+    // (material, slot, texture) of every textured material slot
+    references: Vec<(usize, TextureSlot, usize)>,
+    duplicate_bytes: usize,
+}
+
+impl TextureSet {
+    fn add(&mut self, material: usize, slot: TextureSlot, texture: DynamicImage) -> usize {
+        let mut hasher = DefaultHasher::new();
+        let color = texture.color();
+        (texture.width(), texture.height(), color.channel_count(), color.bytes_per_pixel())
+            .hash(&mut hasher);
+        texture.as_bytes().hash(&mut hasher);
+
+        // equal hashes are compared in full, a collision must not merge different images
+        let candidates = self.by_content.entry(hasher.finish()).or_default();
+        let existing = candidates.iter().copied().find(|&index| {
+            let other = &self.textures[index];
+            other.dimensions() == texture.dimensions()
+                && other.color() == color
+                && other.as_bytes() == texture.as_bytes()
+        });
+        let index = match existing {
+            Some(index) => {
+                self.duplicate_bytes += texture.as_bytes().len();
+                index
+            }
+            None => {
+                candidates.push(self.textures.len());
+                self.textures.push(texture);
+                self.textures.len() - 1
+            }
+        };
+        self.share(material, slot, index);
+        index
+    }
+
+    fn share(&mut self, material: usize, slot: TextureSlot, texture: usize) {
+        self.references.push((material, slot, texture));
+    }
+}
+
+// Marks the slot as textured with the atlas location `rect`
+fn set_texture(material_data: &mut MaterialData, slot: TextureSlot, rect: Vec4) {
+    match slot {
+        TextureSlot::Albedo => {
+            material_data.albedo = rect;
+            material_data.set_has_albedo_texture(true);
+        }
+        TextureSlot::Metallic => {
+            material_data.metallic = rect;
+            material_data.set_has_metallic_texture(true);
+        }
+        TextureSlot::Roughness => {
+            material_data.roughness = rect;
+            material_data.set_has_roughness_texture(true);
+        }
+        TextureSlot::Normals => {
+            material_data.normals = rect;
+            material_data.set_has_normal_texture(true);
+        }
+        TextureSlot::Emissive => {
+            material_data.emissive_texture = rect;
+            material_data.set_has_emissive_texture(true);
+        }
+        TextureSlot::Occlusion => {
+            material_data.occlusion = rect;
+            material_data.set_has_occlusion_texture(true);
+        }
+        TextureSlot::ThinFilm => {
+            material_data.thin_film_texture = rect;
+            material_data.set_has_thin_film_texture(true);
+        }
+    }
+}
+
+// glTF packs metallic into blue and roughness into green of one texture
+fn read_packed_metallic_roughness(material_data: &mut MaterialData) {
+    material_data.set_texture_channel(TextureSlot::Metallic, 2);
+    material_data.set_texture_channel(TextureSlot::Roughness, 1);
+}
+
+// Whether both texture types point at the same source image
+fn is_same_texture(material: &Material, a: TextureType, b: TextureType) -> bool {
+    match (material.textures.get(&a), material.textures.get(&b)) {
+        (Some(a), Some(b)) => Rc::ptr_eq(a, b) || a.borrow().filename == b.borrow().filename,
+        _ => false,
+    }
+}
+
+// Average linear color of an sRGB texture
+fn average_color(texture: &RgbImage) -> Vec3 {
+    let sum = texture.pixels().fold(Vec3::ZERO, |sum, pixel| {
+        sum + Vec3::from_array(pixel.0.map(|channel| SRGB_TO_LINEAR[channel as usize]))
+    });
+    sum / (texture.width() * texture.height()).max(1) as f32
 }
 
 fn load_float_array(material: &Material, name: &str) -> Option<Vec<f32>> {
@@ -44,57 +282,471 @@ fn load_float_array(material: &Material, name: &str) -> Option<Vec<f32>> {
     }
 }
 
+// Stands in for missing materials, loud enough to notice
+fn default_material() -> MaterialData {
+    let mut material = MaterialData::default();
+    material.albedo = Vec4::new(1.0, 0.0, 1.0, 1.0);
+    material.roughness = Vec4::ONE;
+    material
+}
+
+// Flags are integers or raw bools depending on the importer
+fn load_flag(material: &Material, name: &str) -> bool {
+    let Some(prop) = material.properties.iter().find(|p| p.key == name) else {
+        return false;
+    };
+    match &prop.data {
+        PropertyTypeInfo::IntegerArray(values) => values.first().is_some_and(|&value| value != 0),
+        PropertyTypeInfo::Buffer(bytes) => bytes.iter().any(|&byte| byte != 0),
+        _ => false,
+    }
+}
+
+// There is no glTF extension for nested dielectric priorities, so they ride along in the
+// material name as a `#<priority>` suffix, e.g. "Glass#2" wins over "Water#1"
+fn material_name(material: &Material) -> Option<&str> {
+    let prop = material.properties.iter().find(|p| p.key == "?mat.name")?;
+    match &prop.data {
+        PropertyTypeInfo::String(name) => Some(name.as_str()),
+        _ => None,
+    }
+}
+
+fn load_priority(material: &Material) -> u32 {
+    material_name(material)
+        .and_then(|name| name.rsplit_once('#'))
+        .and_then(|(_, priority)| priority.parse().ok())
+        .unwrap_or(0)
+}
+
+fn load_texture_floats(
+    material: &Material,
+    name: &str,
+    texture_type: TextureType,
+) -> Option<&[f32]> {
+    let prop = material.properties.iter().find(|p| p.key == name && p.semantic == texture_type)?;
+    match &prop.data {
+        PropertyTypeInfo::FloatArray(col) => Some(col),
+        _ => None,
+    }
+}
+
+fn load_texture_float(material: &Material, name: &str, texture_type: TextureType) -> Option<f32> {
+    load_texture_floats(material, name, texture_type)?.first().copied()
+}
+
+// aiUVTransform is translation, scaling and rotation, with the glTF importer's conversion of
+// KHR_texture_transform into assimp's convention. That conversion is undone here, then the
+// glTF transform is applied with V flipped like assimp flips glTF UVs.
+fn load_uv_transform(material: &Material, texture_type: TextureType) -> UvTransform {
+    let Some(&[tx, ty, sx, sy, rotation]) =
+        load_texture_floats(material, "$tex.uvtrafo", texture_type)
+    else {
+        return UvTransform::IDENTITY;
+    };
+    let (sin, cos) = (-rotation).sin_cos();
+    let offset =
+        Vec2::new(tx - 0.5 * sx * (1.0 - cos + sin), 0.5 * sy * (sin + cos - 1.0) + 1.0 - sy - ty);
+    // KHR_texture_transform scales, then rotates counter-clockwise, then offsets
+    let rotation = Mat3::from_cols(Vec3::new(cos, -sin, 0.0), Vec3::new(sin, cos, 0.0), Vec3::Z);
+    let gltf = Mat3::from_translation(offset) * rotation * Mat3::from_scale(Vec2::new(sx, sy));
+    let flip_v = Mat3::from_cols(Vec3::X, Vec3::NEG_Y, Vec3::new(0.0, 1.0, 1.0));
+    let transform = flip_v * gltf * flip_v;
+    UvTransform {
+        x_axis: transform.x_axis.truncate(),
+        y_axis: transform.y_axis.truncate(),
+        offset: transform.z_axis.truncate(),
+        _padding: Vec2::ZERO,
+    }
+}
+
+fn load_texture_int(material: &Material, name: &str, texture_type: TextureType) -> Option<i32> {
+    let prop = material.properties.iter().find(|p| p.key == name && p.semantic == texture_type)?;
+    match &prop.data {
+        PropertyTypeInfo::IntegerArray(values) => values.first().copied(),
+        _ => None,
+    }
+}
+
+// aiTextureMapMode, decals are clamped as well since we have no border color
+fn load_wrap_modes(material: &Material, texture_type: TextureType) -> (WrapMode, WrapMode) {
+    let mode = |name| match load_texture_int(material, name, texture_type) {
+        Some(1 | 3) => WrapMode::Clamp,
+        Some(2) => WrapMode::Mirror,
+        _ => WrapMode::Repeat,
+    };
+    (mode("$tex.mapmodeu"), mode("$tex.mapmodev"))
+}
+
+#[derive(Clone)]
+pub struct EnvMap {
+    pub width: u32,
+    pub height: u32,
+    pub texels: Vec<Vec4>,
+    pub marginal_cdf: Vec<f32>,
+    pub conditional_cdf: Vec<f32>,
+    pub power: f32,
+}
+
+impl EnvMap {
+    // Expects an equirectangular (lat-long) image, usually a .hdr or .exr
+    pub fn from_path(path: &str) -> Option<Self> {
+        let image = Reader::open(path).ok()?.with_guessed_format().ok()?.decode().ok()?;
+        let image = image.into_rgb32f();
+        let (width, height) = image.dimensions();
+        let texels = image.pixels().map(|p| Vec4::new(p[0], p[1], p[2], 1.0)).collect::<Vec<_>>();
+        let (marginal_cdf, conditional_cdf, power) = light::build_env_cdf(width, height, &texels);
+        Some(Self { width, height, texels, marginal_cdf, conditional_cdf, power })
+    }
+}
+
+// One entry per mesh, see `BVH::serialize`. `keys` hold the geometry hash and triangle count
+// of every mesh, any mismatch or damage means the cache is rebuilt.
+fn load_bvh_cache(path: &str, keys: &[(u64, usize)]) -> Option<Vec<(BVH, Vec<UVec4>)>> {
+    let bytes = std::fs::read(path).ok()?;
+    let mut bytes = bytes.as_slice();
+    let built = keys
+        .iter()
+        .map(|&(hash, count)| BVH::deserialize(&mut bytes, hash, count))
+        .collect::<Option<Vec<_>>>()?;
+    // leftovers belong to meshes the scene doesn't have anymore
+    bytes.is_empty().then_some(built)
+}
+
+// KHR_lights_punctual light placed by `trs`, None for the light types assimp reads from other
+// formats. The glTF importer folds the intensity into the color and drops the range.
+fn load_punctual_light(light: &russimp::light::Light, trs: Mat4) -> Option<PunctualLight> {
+    let kind = match light.light_source_type {
+        LightSourceType::Point => PunctualLightKind::Point,
+        LightSourceType::Spot => PunctualLightKind::Spot,
+        LightSourceType::Directional => PunctualLightKind::Directional,
+        _ => return None,
+    };
+    let vector = |v: &russimp::Vector3D| Vec3::new(v.x, v.y, v.z);
+    let color = Vec3::new(light.diffuse_color.r, light.diffuse_color.g, light.diffuse_color.b);
+    let intensity = color.max_element();
+    let mut punctual = PunctualLight {
+        position: trs.transform_point3(vector(&light.pos)).extend(1.0),
+        direction: trs.transform_vector3(vector(&light.direction)).normalize().extend(0.0),
+        color: (color / intensity.max(f32::MIN_POSITIVE)).extend(intensity),
+        range: 0.0,
+        cos_inner_cone: light.angle_inner_cone.cos(),
+        cos_outer_cone: light.angle_outer_cone.cos(),
+        ..Default::default()
+    };
+    punctual.set_kind(kind);
+    Some(punctual)
+}
+
+// Scene camera converted to the conventions of `TracingConfig`
+#[derive(Clone, Copy)]
+pub struct Camera {
+    pub position: Vec3,
+    // pitch, yaw and roll like `TracingConfig::cam_rot`
+    pub rotation: Vec3,
+    pub vertical_fov: f32,
+}
+
+impl Camera {
+    fn new(camera: &russimp::camera::Camera, trs: Mat4) -> Self {
+        let vector = |v: &russimp::Vector3D| Vec3::new(v.x, v.y, v.z);
+        let position = trs.transform_point3(vector(&camera.position));
+        let forward = trs.transform_vector3(vector(&camera.look_at)).normalize();
+        let up = trs.transform_vector3(vector(&camera.up));
+        let pitch = (-forward.y).clamp(-1.0, 1.0).asin();
+        let yaw = forward.x.atan2(forward.z);
+        // the change of basis may turn the scene's up axis sideways, so roll is needed even
+        // for level cameras
+        let yaw_pitch = camera_basis(Vec3::new(pitch, yaw, 0.0));
+        let roll = (-up.dot(yaw_pitch * Vec3::X)).atan2(up.dot(yaw_pitch * Vec3::Y));
+        // assimp's glTF importer stores the full horizontal angle, derived from yfov and
+        // the aspect ratio
+        let aspect = if camera.aspect > 0.0 { camera.aspect } else { 1.0 };
+        let vertical_fov = 2.0 * ((camera.horizontal_fov * 0.5).tan() / aspect).atan();
+        Self { position, rotation: Vec3::new(pitch, yaw, roll), vertical_fov }
+    }
+
+    pub fn configure(&self, config: &mut TracingConfig) {
+        config.cam_pos = self.position.extend(0.0);
+        config.cam_rot = self.rotation.extend(0.0);
+        config.vertical_fov = self.vertical_fov;
+    }
+}
+
 pub struct World {
-    pub bvh: BVH,
+    pub bvh: TLAS,
     pub index_buffer: Vec<UVec4>,
     pub per_vertex_buffer: Vec<PerVertexData>,
     pub atlas: DynamicImage,
     pub material_data_buffer: Vec<MaterialData>,
     pub light_pick_buffer: Vec<LightPick>,
+    pub light_power: f32,
+    pub env_map: Option<EnvMap>,
+    // every camera of the scene file that is placed by a node
+    pub cameras: Vec<Camera>,
+    pub punctual_lights: Vec<PunctualLight>,
+    // None for static scenes
+    pub animation: Option<Animation>,
 }
 
 pub struct GpuWorld<'fw> {
     pub bvh: GpuBVH<'fw>,
     pub indices: GpuBuffer<'fw, UVec4>,
     pub per_vertex: GpuBuffer<'fw, PerVertexData>,
-    pub atlas: GpuConstImage<'fw, Rgba8UintNorm>,
+    pub atlas: GpuConstImage<'fw>,
     pub materials: GpuBuffer<'fw, MaterialData>,
+    // what `materials` holds, edited here and written back with `update_materials`
+    pub material_data: Vec<MaterialData>,
     pub lights: GpuBuffer<'fw, LightPick>,
+    // at least one entry, see `TracingConfig::punctual_light_count`
+    pub punctual_lights: GpuBuffer<'fw, PunctualLight>,
+    pub env_map: GpuBuffer<'fw, Vec4>,
+    pub env_marginal_cdf: GpuBuffer<'fw, f32>,
+    pub env_conditional_cdf: GpuBuffer<'fw, f32>,
+    // unique per upload, `Tracing` rebinds its kernel when it changes
+    pub generation: u64,
+}
+
+impl GpuWorld<'_> {
+    // Rewrites the materials in place, the count has to match the uploaded one
+    pub fn update_materials(&self, materials: &[MaterialData]) {
+        let _ = self.materials.write(materials);
+    }
+}
+
+// Stages of loading a scene, in order. Progress within a stage goes from 0 to 1, stages
+// without a measurable one only report both ends.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LoadPhase {
+    Import,
+    // per material
+    Textures,
+    Atlas,
+    // per triangle of the built meshes, cached ones finish at once
+    Bvh,
+    Lights,
+}
+
+// Axis of a scene file that points up
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Handedness {
+    Left,
+    Right,
+}
+
+// How the coordinates of a scene file map into world space, which is left-handed with X to
+// the right, Y up and Z forward. The default reads files as right-handed and Z-up, as the
+// renderer always did, glTF files authored Y-up need `up_axis: Axis::Y` to stand upright.
+#[derive(Copy, Clone, Debug)]
+pub struct SceneOptions {
+    pub up_axis: Axis,
+    pub handedness: Handedness,
+    // world units per scene unit, 0.01 for a file in centimeters
+    pub scale: f32,
+}
+
+impl Default for SceneOptions {
+    fn default() -> Self {
+        Self { up_axis: Axis::Z, handedness: Handedness::Right, scale: 1.0 }
+    }
+}
+
+impl SceneOptions {
+    // Change of basis from the scene file into world space, it places the root node so
+    // vertices, cameras and lights all go through it
+    pub fn basis(&self) -> Mat4 {
+        // turns the up axis onto Y, exact unlike a rotation by a float angle
+        let up = match self.up_axis {
+            Axis::X => Mat4::from_cols(Vec4::Y, Vec4::NEG_X, Vec4::Z, Vec4::W),
+            Axis::Y => Mat4::IDENTITY,
+            Axis::Z => Mat4::from_cols(Vec4::X, Vec4::NEG_Z, Vec4::Y, Vec4::W),
+        };
+        // right-handed files are mirrored along Z
+        let mirror = match self.handedness {
+            Handedness::Left => Mat4::IDENTITY,
+            Handedness::Right => Mat4::from_scale(Vec3::new(1.0, 1.0, -1.0)),
+        };
+        Mat4::from_scale(Vec3::splat(self.scale)) * mirror * up
+    }
+}
+
+// Format of a scene passed as bytes, assimp can't sniff every format from the content
+#[derive(Copy, Clone, Debug)]
+pub enum FormatHint {
+    Glb,
+    Gltf,
+    Obj,
+    Fbx,
+    Blend,
+}
+
+impl FormatHint {
+    fn extension(self) -> &'static str {
+        match self {
+            FormatHint::Glb => "glb",
+            FormatHint::Gltf => "gltf",
+            FormatHint::Obj => "obj",
+            FormatHint::Fbx => "fbx",
+            FormatHint::Blend => "blend",
+        }
+    }
+}
+
+fn post_process() -> Vec<PostProcess> {
+    vec![
+        JoinIdenticalVertices,
+        Triangulate,
+        SortByPrimitiveType,
+        GenerateSmoothNormals,
+        GenerateUVCoords,
+        CalculateTangentSpace,
+        EmbedTextures,
+        ImproveCacheLocality,
+    ]
 }
 
 impl World {
-    pub fn from_path(path: &str) -> Option<Self> {
-        let blend = Scene::from_file(
-            path,
-            vec![
-                JoinIdenticalVertices,
-                Triangulate,
-                SortByPrimitiveType,
-                GenerateSmoothNormals,
-                GenerateUVCoords,
-                TransformUVCoords,
-                CalculateTangentSpace,
-                EmbedTextures,
-                ImproveCacheLocality,
-            ],
-        )
-        .ok()?;
+    // Bottom level BVHs are cached next to the scene file
+    pub fn from_path(path: &str, options: SceneOptions) -> Result<Self, SceneLoadError> {
+        Self::from_path_with_progress(path, options, |_, _| {})
+    }
+
+    pub fn from_path_with_progress(
+        path: &str,
+        options: SceneOptions,
+        mut progress: impl FnMut(LoadPhase, f32),
+    ) -> Result<Self, SceneLoadError> {
+        // assimp reports missing files as generic import failures
+        std::fs::metadata(path)?;
+        progress(LoadPhase::Import, 0.0);
+        let blend = Scene::from_file(path, post_process())
+            .map_err(|err| SceneLoadError::Assimp(err.to_string()))?;
+        progress(LoadPhase::Import, 1.0);
+        Self::from_scene(&blend, Some(&format!("{path}.bvhcache")), options, &mut progress)
+    }
+
+    // For embedded or downloaded scenes, textures must be embedded too
+    pub fn from_bytes(
+        bytes: &[u8],
+        hint: FormatHint,
+        options: SceneOptions,
+    ) -> Result<Self, SceneLoadError> {
+        let blend = Scene::from_buffer(bytes, post_process(), hint.extension())
+            .map_err(|err| SceneLoadError::Assimp(err.to_string()))?;
+        Self::from_scene(&blend, None, options, &mut |_, _| {})
+    }
 
+    fn from_scene(
+        blend: &Scene,
+        cache_path: Option<&str>,
+        options: SceneOptions,
+        progress: &mut dyn FnMut(LoadPhase, f32),
+    ) -> Result<Self, SceneLoadError> {
         let mut vertices = Vec::new();
         let mut indices = Vec::new();
         let mut normals = Vec::new();
         let mut tangents = Vec::new();
         let mut uvs = Vec::new();
+        let mut uv1s = Vec::new();
+        let mut colors = Vec::new();
+        // materials of meshes with vertex colors other than white
+        let mut vertex_colored = vec![false; blend.materials.len() + 1];
+        // triangles of every mesh in `indices`, kept in object space
+        let mut meshes = Vec::new();
+        let mut mesh_vertices = Vec::new();
+
+        for mesh in &blend.meshes {
+            let vertex_offset = vertices.len() as u32;
+            let triangle_offset = indices.len();
+            // out of range indices get the default material after the scene's ones
+            let material_index = if (mesh.material_index as usize) < blend.materials.len() {
+                mesh.material_index
+            } else {
+                println!(
+                    "Mesh {} uses material {} of {}, falling back to the default material",
+                    mesh.name,
+                    mesh.material_index,
+                    blend.materials.len()
+                );
+                blend.materials.len() as u32
+            };
+            for v in &mesh.vertices {
+                vertices.push(Vec4::new(v.x, v.y, v.z, 1.0));
+            }
+            // SortByPrimitiveType leaves points and lines in meshes of their own, which end up
+            // without triangles and aren't placed
+            for f in mesh.faces.iter().filter(|f| f.0.len() == 3) {
+                indices.push(UVec4::new(
+                    vertex_offset + f.0[0],
+                    vertex_offset + f.0[2],
+                    vertex_offset + f.0[1],
+                    material_index,
+                ));
+            }
+            for n in &mesh.normals {
+                normals.push(Vec4::new(n.x, n.y, n.z, 0.0));
+            }
+            for (i, t) in mesh.tangents.iter().enumerate() {
+                let tan = Vec3::new(t.x, t.y, t.z);
+                // handedness of the tangent frame, flips on mirrored UV islands
+                let sign = match (mesh.normals.get(i), mesh.bitangents.get(i)) {
+                    (Some(n), Some(b)) => {
+                        let norm = Vec3::new(n.x, n.y, n.z);
+                        let bitan = Vec3::new(b.x, b.y, b.z);
+                        if norm.cross(tan).dot(bitan) < 0.0 {
+                            -1.0
+                        } else {
+                            1.0
+                        }
+                    }
+                    _ => 1.0,
+                };
+                tangents.push(tan.extend(sign));
+            }
+            if let Some(Some(uv_set)) = mesh.texture_coords.first() {
+                for uv in uv_set {
+                    uvs.push(Vec2::new(uv.x, uv.y));
+                }
+            } else {
+                uvs.resize(vertices.len(), Vec2::ZERO);
+            }
+            if let Some(Some(uv_set)) = mesh.texture_coords.get(1) {
+                for uv in uv_set {
+                    uv1s.push(Vec2::new(uv.x, uv.y));
+                }
+            } else {
+                uv1s.resize(vertices.len(), Vec2::ZERO);
+            }
+            if let Some(Some(color_set)) = mesh.colors.first() {
+                for c in color_set {
+                    colors.push(Vec4::new(c.r, c.g, c.b, c.a));
+                }
+                let white = color_set.iter().all(|c| c.r == 1.0 && c.g == 1.0 && c.b == 1.0);
+                if !white {
+                    vertex_colored[material_index as usize] = true;
+                }
+            } else {
+                colors.resize(vertices.len(), Vec4::ONE);
+            }
+            meshes.push(triangle_offset..indices.len());
+            mesh_vertices.push(vertex_offset as usize..vertices.len());
+        }
 
+        // Every node referencing a mesh places one instance of it, a node with one of `names`
+        // places the camera or light of that name
         fn walk_node_graph(
-            scene: &Scene,
             node: &Node,
             trs: Mat4,
-            vertices: &mut Vec<Vec4>,
-            indices: &mut Vec<UVec4>,
-            normals: &mut Vec<Vec4>,
-            tangents: &mut Vec<Vec4>,
-            uvs: &mut Vec<Vec2>,
+            instances: &mut Vec<(usize, Mat4)>,
+            names: &[&str],
+            placed: &mut Vec<(usize, Mat4)>,
         ) {
             let node_trs = Mat4::from_cols_array_2d(&[
                 [
@@ -123,96 +775,107 @@ impl World {
                 ],
             ]);
             let new_trs = trs * node_trs;
-            let (node_scale, node_quat, _) = new_trs.to_scale_rotation_translation();
 
             for mesh_idx in node.meshes.iter() {
-                let mesh = &scene.meshes[*mesh_idx as usize];
-                let triangle_offset = vertices.len() as u32;
-                for v in &mesh.vertices {
-                    let vert = new_trs.mul_vec4(Vec4::new(v.x, v.y, v.z, 1.0));
-                    vertices.push(Vec4::new(vert.x, vert.z, vert.y, 1.0));
-                }
-                for f in &mesh.faces {
-                    assert_eq!(f.0.len(), 3);
-                    indices.push(UVec4::new(
-                        triangle_offset + f.0[0],
-                        triangle_offset + f.0[2],
-                        triangle_offset + f.0[1],
-                        mesh.material_index,
-                    ));
-                }
-                for n in &mesh.normals {
-                    let norm =
-                        (node_quat.mul_vec3(Vec3::new(n.x, n.y, n.z) / node_scale)).normalize();
-                    normals.push(Vec4::new(norm.x, norm.z, norm.y, 0.0));
-                }
-                for t in &mesh.tangents {
-                    let tan =
-                        (node_quat.mul_vec3(Vec3::new(t.x, t.y, t.z) / node_scale)).normalize();
-                    tangents.push(Vec4::new(tan.x, tan.z, tan.y, 0.0));
-                }
-                if let Some(Some(uv_set)) = mesh.texture_coords.first() {
-                    for uv in uv_set {
-                        uvs.push(Vec2::new(uv.x, uv.y));
-                    }
-                } else {
-                    uvs.resize(vertices.len(), Vec2::ZERO);
-                }
+                instances.push((*mesh_idx as usize, new_trs));
+            }
+            if let Some(index) = names.iter().position(|name| *name == node.name) {
+                placed.push((index, new_trs));
             }
 
             for child in node.children.borrow().iter() {
-                walk_node_graph(scene, child, new_trs, vertices, indices, normals, tangents, uvs);
+                walk_node_graph(child, new_trs, instances, names, placed);
             }
         }
 
+        let basis = options.basis();
+        let mut instances = Vec::new();
+        // cameras first, then lights
+        let names = blend
+            .cameras
+            .iter()
+            .map(|camera| camera.name.as_str())
+            .chain(blend.lights.iter().map(|light| light.name.as_str()))
+            .collect::<Vec<_>>();
+        let mut placed = Vec::new();
         if let Some(root) = blend.root.as_ref() {
-            walk_node_graph(
-                &blend,
-                root,
-                Mat4::IDENTITY,
-                &mut vertices,
-                &mut indices,
-                &mut normals,
-                &mut tangents,
-                &mut uvs,
-            );
+            walk_node_graph(root, basis, &mut instances, &names, &mut placed);
+        }
+        // meshes no node places are not rendered either, nor are the ones without triangles
+        let kept = instances.iter().map(|&(mesh, _)| !meshes[mesh].is_empty()).collect::<Vec<_>>();
+        instances.retain(|&(mesh, _)| !meshes[mesh].is_empty());
+        if instances.is_empty() {
+            return Err(SceneLoadError::EmptyScene);
         }
+        let mut animation = Animation::new(blend, basis);
+        if let Some(animation) = &mut animation {
+            animation.retain_instances(&kept);
+        }
+        // in the order of the scene file, for `--camera`
+        placed.sort_by_key(|&(index, _)| index);
+        let (cameras, lights) =
+            placed.split_at(placed.partition_point(|&(index, _)| index < blend.cameras.len()));
+        let cameras =
+            cameras.iter().map(|&(camera, trs)| Camera::new(&blend.cameras[camera], trs)).collect();
+        let punctual_lights = lights
+            .iter()
+            .filter_map(|&(light, trs)| {
+                load_punctual_light(&blend.lights[light - blend.cameras.len()], trs)
+            })
+            .collect::<Vec<_>>();
 
-        // Gather material data
+        // Gather material data, the default material goes last so the buffer is never empty
         let mut material_datas = vec![MaterialData::default(); blend.materials.len()];
+        material_datas.push(default_material());
+
+        // average emissive texture color per material, weights textured lights in the pick table
+        let mut emissive_averages = vec![Vec3::ONE; blend.materials.len() + 1];
 
-        let mut textures = Vec::new();
+        let mut textures = TextureSet::default();
+        progress(LoadPhase::Textures, 0.0);
         for (material_index, material) in blend.materials.iter().enumerate() {
             let current_material_data = &mut material_datas[material_index];
-            if let Some(texture) = load_texture(material, TextureType::Diffuse) {
-                // Albedo data is stored in gamma space, but we atlas it with all the other textures
-                // which are stored in linear. Therefore, we convert here.
-                let mut texture = texture.into_rgb8();
-                for pixel in texture.iter_mut() {
-                    *pixel = ((*pixel as f32 / 255.0).powf(2.2) * 255.0) as u8;
+            current_material_data.set_has_vertex_colors(vertex_colored[material_index]);
+            // glTF's metallicRoughnessTexture comes through as both types, pack it once and
+            // read metallic from blue and roughness from green
+            let packed_metallic_roughness =
+                is_same_texture(material, TextureType::Metalness, TextureType::Roughness);
+            for (slot, texture_type) in TEXTURE_TYPES {
+                let (u, v) = load_wrap_modes(material, texture_type);
+                current_material_data.set_wrap_modes(slot, u, v);
+                current_material_data
+                    .set_uv_transform(slot, load_uv_transform(material, texture_type));
+
+                if packed_metallic_roughness && matches!(slot, TextureSlot::Roughness) {
+                    continue;
+                }
+                let Some(texture) = load_texture(material, slot, texture_type)? else {
+                    continue;
+                };
+                if matches!(slot, TextureSlot::Emissive) {
+                    emissive_averages[material_index] = average_color(&texture.to_rgb8());
+                    // glTF multiplies the texture by the factor, which may be missing
+                    current_material_data.emissive =
+                        Vec4::new(1.0, 1.0, 1.0, LEGACY_EMISSIVE_STRENGTH);
+                }
+                let index = textures.add(material_index, slot, texture);
+                if packed_metallic_roughness && matches!(slot, TextureSlot::Metallic) {
+                    textures.share(material_index, TextureSlot::Roughness, index);
+                    read_packed_metallic_roughness(current_material_data);
                 }
-                textures.push(DynamicImage::ImageRgb8(texture));
-                current_material_data.set_has_albedo_texture(true);
-            }
-            if let Some(texture) = load_texture(material, TextureType::Metalness) {
-                textures.push(texture);
-                current_material_data.set_has_metallic_texture(true);
-            }
-            if let Some(texture) = load_texture(material, TextureType::Roughness) {
-                textures.push(texture);
-                current_material_data.set_has_roughness_texture(true);
-            }
-            if let Some(texture) = load_texture(material, TextureType::Normals) {
-                textures.push(texture);
-                current_material_data.set_has_normal_texture(true);
             }
+            // glTF normalTexture.scale
+            current_material_data.normal_strength =
+                load_texture_float(material, "$tex.scale", TextureType::Normals).unwrap_or(1.0);
+            let uv_set = load_texture_int(material, "$tex.uvwsrc", TextureType::LightMap);
+            current_material_data.set_occlusion_uses_uv1(uv_set == Some(1));
             if let Some(col) = load_float_array(material, "$clr.diffuse") {
                 current_material_data.albedo = Vec4::new(col[0], col[1], col[2], col[3]);
             }
             if let Some(col) = load_float_array(material, "$clr.emissive") {
-                // HACK: Multiply by 15 since assimp 5.2.5 doesn't support emissive strength :(
-                current_material_data.emissive = Vec4::new(col[0], col[1], col[2], col[3]) * 15.0;
-                println!("EMISSIVE: {col:?}");
+                let strength = load_float_array(material, "$mat.emissiveIntensity")
+                    .map_or(LEGACY_EMISSIVE_STRENGTH, |strength| strength[0]);
+                current_material_data.emissive = Vec4::new(col[0], col[1], col[2], strength);
             }
             if let Some(col) = load_float_array(material, "$mat.metallicFactor") {
                 current_material_data.metallic = Vec4::splat(col[0]);
@@ -220,35 +883,163 @@ impl World {
             if let Some(col) = load_float_array(material, "$mat.roughnessFactor") {
                 current_material_data.roughness = Vec4::splat(col[0]);
             }
-        }
-
-        let (atlas_raw, mut sts) = crate::atlas::pack_textures(&textures, 4096, 4096);
-
-        for material_data in material_datas.iter_mut() {
-            if material_data.has_albedo_texture() {
-                material_data.albedo = sts.remove(0); // TODO: Optimize this
+            if let Some(col) = load_float_array(material, "$mat.transmission.factor") {
+                current_material_data.transmission = col[0];
+            }
+            current_material_data.ior =
+                load_float_array(material, "$mat.refracti").map_or(1.5, |col| col[0]);
+            if let Some(col) = load_float_array(material, "$clr.sheen.factor") {
+                current_material_data.sheen = Vec4::new(col[0], col[1], col[2], 0.0);
             }
-            if material_data.has_metallic_texture() {
-                material_data.metallic = sts.remove(0);
+            if let Some(col) = load_float_array(material, "$mat.sheen.roughnessFactor") {
+                current_material_data.sheen.w = col[0];
             }
-            if material_data.has_roughness_texture() {
-                material_data.roughness = sts.remove(0);
+            if let Some(col) = load_float_array(material, "$mat.anisotropyFactor") {
+                current_material_data.anisotropy = col[0];
             }
-            if material_data.has_normal_texture() {
-                material_data.normals = sts.remove(0);
+            if let Some(col) = load_float_array(material, "$mat.anisotropyRotation") {
+                current_material_data.anisotropy_rotation = col[0];
             }
+            current_material_data.priority = load_priority(material);
+            current_material_data.set_double_sided(load_flag(material, "$mat.twosided"));
+            // KHR_materials_dispersion stores 20 / the Abbe number
+            if let Some(col) = load_float_array(material, "$mat.dispersion") {
+                if col[0] > 0.0 {
+                    current_material_data.abbe_number = 20.0 / col[0];
+                }
+            }
+            // KHR_materials_iridescence, with the extension's defaults. The thickness texture
+            // has no assimp texture type, so films loaded here are always untextured.
+            let load_iridescence = |name, default| {
+                load_float_array(material, name).map_or(default, |col: Vec<f32>| col[0])
+            };
+            current_material_data.thin_film = Vec4::new(
+                load_iridescence("$mat.iridescence.factor", 0.0),
+                load_iridescence("$mat.iridescence.ior", 1.3),
+                load_iridescence("$mat.iridescence.thicknessMinimum", 100.0),
+                load_iridescence("$mat.iridescence.thicknessMaximum", 400.0),
+            );
+            progress(
+                LoadPhase::Textures,
+                (material_index + 1) as f32 / blend.materials.len() as f32,
+            );
         }
+        progress(LoadPhase::Textures, 1.0);
+
+        #[cfg(debug_assertions)]
+        println!(
+            "Textures: {} unique for {} material slots, {:.1} MiB of duplicates skipped",
+            textures.textures.len(),
+            textures.references.len(),
+            textures.duplicate_bytes as f64 / (1024.0 * 1024.0),
+        );
+        progress(LoadPhase::Atlas, 0.0);
+        // the viewer creates the device before loading, tests load scenes without one
+        let limits = FW.get().map_or_else(wgpu::Limits::default, |fw| fw.limits());
+        let max_atlas_size = limits.max_texture_dimension_2d;
+        let (atlas_raw, sts) =
+            crate::atlas::pack_textures(&textures.textures, max_atlas_size).map_err(|needed| {
+                SceneLoadError::AtlasOverflow { needed, available: max_atlas_size }
+            })?;
+        for &(material_index, slot, texture_index) in textures.references.iter() {
+            set_texture(&mut material_datas[material_index], slot, sts[texture_index]);
+        }
+        progress(LoadPhase::Atlas, 1.0);
 
         let now = std::time::Instant::now();
-        let bvh = BVHBuilder::new(&vertices, &mut indices).sah_samples(128).build();
+        progress(LoadPhase::Bvh, 0.0);
+        // Bottom levels are cached next to the scene, keyed by the geometry of every mesh
+        let keys = meshes
+            .iter()
+            .zip(&mesh_vertices)
+            .map(|(mesh, mesh_vertices)| {
+                let hash =
+                    bvh::geometry_hash(&vertices[mesh_vertices.clone()], &indices[mesh.clone()]);
+                (hash, mesh.len())
+            })
+            .collect::<Vec<_>>();
+        let cached = cache_path.and_then(|cache_path| load_bvh_cache(cache_path, &keys));
         #[cfg(debug_assertions)]
-        println!("BVH build time: {:?}", now.elapsed());
+        let source = if cached.is_some() { "load" } else { "build" };
+        let built = cached.unwrap_or_else(|| {
+            // spatial splits duplicate triangles into several leaves, the indices grow to match
+            let mut done = 0;
+            let built = meshes
+                .iter()
+                .map(|mesh| {
+                    let mut mesh_indices = indices[mesh.clone()].to_vec();
+                    let bvh = BVHBuilder::new(&vertices, &mut mesh_indices)
+                        .bins(32)
+                        .spatial_splits(1e-5)
+                        .build();
+                    done += mesh.len();
+                    progress(LoadPhase::Bvh, done as f32 / indices.len() as f32);
+                    (bvh, mesh_indices)
+                })
+                .collect::<Vec<_>>();
+            let cache = built
+                .iter()
+                .zip(&keys)
+                .flat_map(|((bvh, mesh_indices), &(hash, count))| {
+                    bvh.serialize(mesh_indices, hash, count)
+                })
+                .collect::<Vec<_>>();
+            if let Some(cache_path) = cache_path {
+                if let Err(err) = std::fs::write(cache_path, cache) {
+                    println!("Failed to write BVH cache {cache_path}: {err}");
+                }
+            }
+            built
+        });
+
+        let triangle_count = indices.len();
+        let mut references = Vec::with_capacity(indices.len());
+        let mut blas = Vec::with_capacity(meshes.len());
+        for (mesh_index, (mut bvh, mesh_indices)) in built.into_iter().enumerate() {
+            #[cfg(debug_assertions)]
+            if let Err(err) = bvh.validate(&vertices, &mesh_indices) {
+                panic!("BVH of mesh {mesh_index} is invalid: {err}");
+            }
+            bvh.offset_triangles(references.len() as u32);
+            meshes[mesh_index] = references.len()..references.len() + mesh_indices.len();
+            references.extend(mesh_indices);
+            blas.push(bvh);
+        }
+        let indices = references;
+        let instances = instances
+            .into_iter()
+            .map(|(mesh, trs)| (mesh, InstanceData::new(trs, 0, 0)))
+            .collect::<Vec<_>>();
+        let tlas = TLAS::new(blas, instances);
+        progress(LoadPhase::Bvh, 1.0);
+        #[cfg(debug_assertions)]
+        println!(
+            "BVH {} time: {:?}, depth: {}, references: {} for {triangle_count} triangles, \
+             instances: {} of {} meshes",
+            source,
+            now.elapsed(),
+            tlas.bvh.depth + tlas.blas.iter().map(|blas| blas.depth).max().unwrap_or(0),
+            indices.len(),
+            tlas.instances.len(),
+            tlas.blas.len()
+        );
 
         // Build light pick table
         let now = std::time::Instant::now();
-        let emissive_mask = light::compute_emissive_mask(&indices, &material_datas);
-        let light_pick_table =
-            light::build_light_pick_table(&vertices, &indices, &emissive_mask, &material_datas);
+        progress(LoadPhase::Lights, 0.0);
+        let emissions = material_datas
+            .iter()
+            .zip(&emissive_averages)
+            // double-sided emitters light both sides, twice the power
+            .map(|(material, average)| {
+                let sides = if material.double_sided() { 2.0 } else { 1.0 };
+                material.emission() * *average * sides
+            })
+            .collect::<Vec<_>>();
+        let emitters = light::collect_emitters(&vertices, &indices, &meshes, &tlas, &emissions);
+        let light_pick_table = light::build_light_pick_table(&emitters);
+        let light_power = light::total_emissive_power(&emitters);
+        progress(LoadPhase::Lights, 1.0);
         #[cfg(debug_assertions)]
         println!("Light pick table build time: {:?}", now.elapsed());
 
@@ -258,28 +1049,350 @@ impl World {
                 vertex: *vertices.get(i).unwrap_or(&Vec4::ZERO),
                 normal: *normals.get(i).unwrap_or(&Vec4::ZERO),
                 tangent: *tangents.get(i).unwrap_or(&Vec4::ZERO),
+                color: *colors.get(i).unwrap_or(&Vec4::ONE),
                 uv0: *uvs.get(i).unwrap_or(&Vec2::ZERO),
-                ..Default::default()
+                uv1: *uv1s.get(i).unwrap_or(&Vec2::ZERO),
             });
         }
-        Some(Self {
-            bvh,
+        let world = Self {
+            bvh: tlas,
             index_buffer: indices,
             per_vertex_buffer: per_vertex_data,
             atlas: atlas_raw,
             material_data_buffer: material_datas,
             light_pick_buffer: light_pick_table,
-        })
+            light_power,
+            env_map: None,
+            cameras,
+            punctual_lights,
+            animation,
+        };
+        #[cfg(debug_assertions)]
+        println!("Scene: {}", world.stats());
+        Ok(world)
     }
 
-    pub fn into_gpu<'fw>(self) -> GpuWorld<'fw> {
-        GpuWorld {
+    pub fn stats(&self) -> WorldStats {
+        fn bytes<T>(count: usize) -> u64 {
+            (count * std::mem::size_of::<T>()) as u64
+        }
+        // placeholders are uploaded for empty buffers, see `upload`
+        let env = self.env_map.as_ref();
+        let buffers = vec![
+            ("indices", bytes::<UVec4>(self.index_buffer.len())),
+            ("per_vertex", bytes::<PerVertexData>(self.per_vertex_buffer.len())),
+            ("materials", bytes::<MaterialData>(self.material_data_buffer.len())),
+            ("lights", bytes::<LightPick>(self.light_pick_buffer.len())),
+            ("punctual_lights", bytes::<PunctualLight>(self.punctual_lights.len().max(1))),
+            ("bvh_nodes", bytes::<BVHNode>(self.bvh.node_count())),
+            ("bvh_parents", bytes::<u32>(self.bvh.node_count())),
+            ("instances", bytes::<InstanceData>(self.bvh.instances.len())),
+            ("env_map", bytes::<Vec4>(env.map_or(1, |env| env.texels.len()))),
+            ("env_marginal_cdf", bytes::<f32>(env.map_or(1, |env| env.marginal_cdf.len()))),
+            ("env_conditional_cdf", bytes::<f32>(env.map_or(1, |env| env.conditional_cdf.len()))),
+        ];
+        WorldStats {
+            triangles: self.index_buffer.len(),
+            vertices: self.per_vertex_buffer.len(),
+            materials: self.material_data_buffer.len(),
+            emissive_triangles: self.light_pick_buffer.len(),
+            punctual_lights: self.punctual_lights.len(),
+            instances: self.bvh.instances.len(),
+            buffers,
+        }
+    }
+
+    // Moves the animated instances to where they are `time` seconds in and refits the top level
+    // BVH, None for static scenes. Emitters keep their area, scaling them is not supported.
+    pub fn sample_animation(&mut self, time: f32) -> Option<f32> {
+        let transforms = self.animation.as_ref()?.sample(time);
+        let placements =
+            self.bvh.order.iter().map(|&placed| transforms[placed]).collect::<Vec<_>>();
+        Some(self.bvh.place_all(placements.into_iter().enumerate()))
+    }
+
+    pub fn with_env_map(mut self, env_map: EnvMap) -> Self {
+        self.env_map = Some(env_map);
+        self
+    }
+
+    pub fn configure(&self, config: &mut TracingConfig) {
+        let (width, height) = self.env_map.as_ref().map_or((0, 0), |env| (env.width, env.height));
+        config.env_width = width;
+        config.env_height = height;
+        config.atlas_width = self.atlas.width();
+        config.atlas_height = self.atlas.height();
+        config.set_has_env_map(self.env_map.is_some());
+        config.punctual_light_count = self.punctual_lights.len() as u32;
+        // Split next event estimation between the environment and emissive triangles by power.
+        // Punctual lights have no power comparable to the environment's, so they get half.
+        config.env_pick_pdf = match &self.env_map {
+            None => 0.0,
+            Some(_) if !self.punctual_lights.is_empty() && self.light_power <= 0.0 => 0.5,
+            Some(_) if self.light_power <= 0.0 => 1.0,
+            Some(env) => env.power / (env.power + self.light_power),
+        };
+    }
+
+    // Buffers over the device limits are reported here, wgpu would panic on them
+    pub fn upload<'fw>(&self) -> Result<GpuWorld<'fw>, SceneLoadError> {
+        let limits = FW.limits();
+        let limit = (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size);
+        for (buffer, size) in self.stats().buffers {
+            if size > limit {
+                return Err(SceneLoadError::BufferTooLarge { buffer, size, limit });
+            }
+        }
+        static GENERATION: AtomicU64 = AtomicU64::new(0);
+        Ok(GpuWorld {
+            generation: GENERATION.fetch_add(1, Ordering::Relaxed),
             per_vertex: GpuBuffer::from_slice(&FW, &self.per_vertex_buffer),
-            atlas: GpuConstImage::from_bytes(&FW, &self.atlas.to_rgba8(), 4096, 4096),
+            atlas: GpuConstImage::from_bytes(
+                &FW,
+                &self.atlas.to_rgba8(),
+                self.atlas.width(),
+                self.atlas.height(),
+            ),
             materials: GpuBuffer::from_slice(&FW, &self.material_data_buffer),
+            material_data: self.material_data_buffer.clone(),
             indices: GpuBuffer::from_slice(&FW, &self.index_buffer),
-            bvh: self.bvh.into_gpu(),
+            bvh: self.bvh.upload(),
             lights: GpuBuffer::from_slice(&FW, &self.light_pick_buffer),
+            punctual_lights: match self.punctual_lights.is_empty() {
+                true => GpuBuffer::from_slice(&FW, &[PunctualLight::default()]),
+                false => GpuBuffer::from_slice(&FW, &self.punctual_lights),
+            },
+            // wgpu doesn't allow 0-sized buffers
+            env_map: match &self.env_map {
+                Some(env) => GpuBuffer::from_slice(&FW, &env.texels),
+                None => GpuBuffer::from_slice(&FW, &[Vec4::ZERO]),
+            },
+            env_marginal_cdf: match &self.env_map {
+                Some(env) => GpuBuffer::from_slice(&FW, &env.marginal_cdf),
+                None => GpuBuffer::from_slice(&FW, &[0.0]),
+            },
+            env_conditional_cdf: match &self.env_map {
+                Some(env) => GpuBuffer::from_slice(&FW, &env.conditional_cdf),
+                None => GpuBuffer::from_slice(&FW, &[0.0]),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srgb_codes_decode_without_rounding() {
+        for (code, linear) in [(0, 0.0), (1, 0.000303527), (128, 0.21586053), (255, 1.0)] {
+            let decoded = SRGB_TO_LINEAR[code];
+            assert!((decoded - linear).abs() < 1e-7, "{code} decodes to {decoded}, not {linear}");
+        }
+        // rounded back to 8 bits, only 183 of the 256 codes stayed apart
+        for code in 0..256 {
+            let encoded = output::linear_to_srgb(SRGB_TO_LINEAR[code]) * 255.0;
+            assert_eq!(encoded.round() as usize, code);
+        }
+    }
+
+    #[test]
+    fn missing_scene_is_an_io_error() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/NoSuchScene.glb");
+        let result = World::from_path(path, SceneOptions::default());
+        assert!(matches!(result, Err(SceneLoadError::Io(_))), "{:?}", result.err());
+    }
+
+    #[test]
+    fn corrupt_texture_names_its_material_and_slot() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/CorruptTextureTest.glb");
+        match World::from_path(path, SceneOptions::default()) {
+            Err(SceneLoadError::TextureDecode { material, slot }) => {
+                assert_eq!(material, "corrupt_metalness");
+                assert!(matches!(slot, TextureSlot::Metallic));
+            }
+            result => panic!("expected a texture decode error, got {:?}", result.err()),
+        }
+    }
+
+    #[test]
+    fn packed_metallic_roughness_reads_blue_and_green() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/MetallicRoughnessTest.glb");
+        let scene = Scene::from_file(path, post_process()).unwrap();
+        let material = scene.materials.iter().find(|m| material_name(m) == Some("packed")).unwrap();
+        assert!(is_same_texture(material, TextureType::Metalness, TextureType::Roughness));
+        let texture = load_texture(material, TextureSlot::Metallic, TextureType::Metalness);
+        let texture = texture.unwrap().unwrap().to_rgba8();
+
+        let mut material_data = MaterialData::default();
+        read_packed_metallic_roughness(&mut material_data);
+        let metallic = material_data.texture_channel(TextureSlot::Metallic) as usize;
+        let roughness = material_data.texture_channel(TextureSlot::Roughness) as usize;
+        // every texel is (0, 64, 204), metallic 0.8 and roughness about 0.25
+        assert_eq!(texture.dimensions(), (4, 4));
+        for texel in texture.pixels() {
+            assert_eq!((texel[metallic], texel[roughness]), (204, 64));
+        }
+    }
+
+    // Without the `.bvhcache` `World::from_path` would leave next to it
+    fn fixture(name: &str, options: SceneOptions) -> World {
+        let path = format!("{}/{name}", env!("CARGO_MANIFEST_DIR"));
+        World::from_bytes(&std::fs::read(path).unwrap(), FormatHint::Glb, options).unwrap()
+    }
+
+    #[test]
+    fn scene_camera_frames_the_quad() {
+        // a 1x1 quad at the origin, seen from 2 units away with a vertical fov of 2 atan(1/4)
+        let world = fixture("CameraTest.glb", SceneOptions::default());
+        let mut config = TracingConfig::soft();
+        (config.width, config.height) = (64, 64);
+        world.cameras[0].configure(&mut config);
+
+        let instance = &world.bvh.instances[0];
+        let corners = world
+            .per_vertex_buffer
+            .iter()
+            .map(|vertex| instance.point_to_world(vertex.vertex.truncate()))
+            .collect::<Vec<_>>();
+        assert_eq!(corners.len(), 4);
+        let normal = (corners[1] - corners[0]).cross(corners[2] - corners[0]);
+
+        // `inspect`'s and the kernel's `camera_ray`, at the outer corners of the corner pixels
+        let half_height = (config.vertical_fov * 0.5).tan();
+        let aspect = config.width as f32 / config.height as f32;
+        let size = Vec2::new(config.width as f32, config.height as f32);
+        let mut hit_corners = Vec::new();
+        for suv in [Vec2::ZERO, Vec2::new(size.x, 0.0), Vec2::new(0.0, size.y), size] {
+            let uv = Vec2::new(suv.x / size.x, 1.0 - suv.y / size.y) * 2.0 - 1.0;
+            let uv = uv * Vec2::new(aspect, 1.0) * half_height;
+            let ro = config.cam_pos.truncate();
+            let rd =
+                camera_basis(config.cam_rot.truncate()) * Vec3::new(uv.x, uv.y, 1.0).normalize();
+            let hit = ro + rd * (corners[0] - ro).dot(normal) / rd.dot(normal);
+            let nearest = (0..4)
+                .min_by(|&a, &b| corners[a].distance(hit).total_cmp(&corners[b].distance(hit)))
+                .unwrap();
+            assert!(corners[nearest].distance(hit) < 1e-4, "{hit} misses {}", corners[nearest]);
+            hit_corners.push(nearest);
+        }
+        hit_corners.sort();
+        assert_eq!(hit_corners, [0, 1, 2, 3]);
+    }
+
+    // Instance transforms in the order the nodes placed them, the TLAS may have reordered them
+    fn placements(world: &World) -> Vec<Mat4> {
+        let mut placements = vec![Mat4::IDENTITY; world.bvh.instances.len()];
+        for (instance, &placed) in world.bvh.order.iter().enumerate() {
+            placements[placed] = world.bvh.instances[instance].object_to_world;
+        }
+        placements
+    }
+
+    #[test]
+    fn turntable_matches_the_rotated_export() {
+        // the turntable spins a full turn a second, the export is it turned by 90 degrees
+        let mut world = fixture("TurntableTest.glb", SceneOptions::default());
+        let rotated = placements(&fixture("TurntableRotatedTest.glb", SceneOptions::default()));
+        let duration = world.animation.as_ref().unwrap().duration();
+        assert!((duration - 1.0).abs() < 1e-6, "{duration}");
+        assert_eq!(placements(&world).len(), rotated.len());
+
+        for time in [0.25, duration + 0.25] {
+            world.sample_animation(time).unwrap();
+            for (sampled, rotated) in placements(&world).iter().zip(&rotated) {
+                assert!(sampled.abs_diff_eq(*rotated, 1e-5), "at {time}: {sampled} vs {rotated}");
+            }
+        }
+    }
+
+    const AXES: [(Axis, Vec3); 3] = [(Axis::X, Vec3::X), (Axis::Y, Vec3::Y), (Axis::Z, Vec3::Z)];
+
+    #[test]
+    fn every_basis_puts_its_up_axis_on_y() {
+        for (up_axis, up) in AXES {
+            for handedness in [Handedness::Left, Handedness::Right] {
+                let basis = SceneOptions { up_axis, handedness, scale: 0.01 }.basis();
+                let up = basis.transform_vector3(up);
+                assert!(up.abs_diff_eq(Vec3::Y * 0.01, 1e-7), "{up_axis:?}, {handedness:?}: {up}");
+                // world space is left-handed, so only right-handed files are mirrored
+                let mirrored = basis.determinant() < 0.0;
+                assert_eq!(
+                    mirrored,
+                    handedness == Handedness::Right,
+                    "{up_axis:?}, {handedness:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn default_basis_is_the_old_swizzle() {
+        let basis = SceneOptions::default().basis();
+        let p = Vec3::new(1.0, 2.0, 3.0);
+        assert_eq!(basis.transform_point3(p), Vec3::new(p.x, p.z, p.y));
+        assert_eq!(basis.transform_vector3(p), Vec3::new(p.x, p.z, p.y));
+    }
+
+    #[test]
+    fn axis_cube_face_lands_on_top() {
+        // a unit cube at the origin, the file's up face has to end up facing +Y
+        for (up_axis, up) in AXES {
+            for handedness in [Handedness::Left, Handedness::Right] {
+                let options = SceneOptions { up_axis, handedness, scale: 1.0 };
+                let world = fixture("AxisCubeTest.glb", options);
+                let instance = &world.bvh.instances[0];
+                let top = world
+                    .per_vertex_buffer
+                    .iter()
+                    .filter(|vertex| vertex.normal.truncate().abs_diff_eq(up, 1e-6))
+                    .collect::<Vec<_>>();
+                assert_eq!(top.len(), 4, "{up_axis:?}, {handedness:?}");
+                for vertex in top {
+                    let normal = instance.normal_to_world(vertex.normal.truncate());
+                    let position = instance.point_to_world(vertex.vertex.truncate());
+                    assert!(normal.abs_diff_eq(Vec3::Y, 1e-6), "{up_axis:?}, {handedness:?}");
+                    assert!((position.y - 0.5).abs() < 1e-6, "{up_axis:?}, {handedness:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn broken_material_index_gets_the_default_material() {
+        // mesh "broken" asks for material 3 of 1
+        let world = fixture("BrokenMaterialTest.glb", SceneOptions::default());
+        let materials = &world.material_data_buffer;
+        assert_eq!(materials.len(), 2);
+        assert_eq!(materials[1].albedo, default_material().albedo);
+        let mut used = world.index_buffer.iter().map(|triangle| triangle.w).collect::<Vec<_>>();
+        used.sort();
+        used.dedup();
+        assert_eq!(used, [0, 1]);
+
+        // without a single material of its own, the scene still has the default one
+        let obj = b"v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n";
+        let world = World::from_bytes(obj, FormatHint::Obj, SceneOptions::default()).unwrap();
+        assert!(!world.material_data_buffer.is_empty());
+        for triangle in &world.index_buffer {
+            assert!((triangle.w as usize) < world.material_data_buffer.len());
+        }
+    }
+
+    #[test]
+    fn tiled_checker_albedo_is_scaled_tenfold() {
+        // KHR_texture_transform with a scale of 10 and no offset or rotation
+        let world = fixture("TiledCheckerTest.glb", SceneOptions::default());
+        let transform = world.material_data_buffer[0].uv_transform(TextureSlot::Albedo);
+        assert_eq!(
+            (transform.x_axis, transform.y_axis),
+            (Vec2::new(10.0, 0.0), Vec2::new(0.0, 10.0))
+        );
+        // the offset is zero in glTF's UVs, assimp's have V flipped
+        let flip_v = |uv: Vec2| Vec2::new(uv.x, 1.0 - uv.y);
+        for uv in [Vec2::ZERO, Vec2::ONE, Vec2::new(0.25, 0.75)] {
+            let tiled = flip_v(transform.apply(flip_v(uv)));
+            assert!(tiled.abs_diff_eq(uv * 10.0, 1e-5), "{uv} goes to {tiled}");
         }
     }
 }
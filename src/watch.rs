@@ -0,0 +1,98 @@
+use {
+    crate::scene::{EnvMap, SceneOptions, World},
+    notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher},
+    parking_lot::Mutex,
+    std::{
+        path::{Path, PathBuf},
+        sync::{mpsc, Arc},
+        thread,
+        time::Duration,
+    },
+};
+
+// Exporters write a file several times, a reload starts once it was left alone this long
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+// Reloads the scene file whenever it changes on disk, loading happens on a worker thread and
+// the render thread picks up the result between frames
+pub struct SceneWatcher {
+    watcher: RecommendedWatcher,
+    // absolute, events name files by the absolute path of the watched directory
+    path: Arc<Mutex<PathBuf>>,
+    // tagged with the path it was loaded from
+    reloaded: Arc<Mutex<Option<(PathBuf, World)>>>,
+}
+
+impl SceneWatcher {
+    pub fn new(
+        path: &Path,
+        env_map: Option<EnvMap>,
+        options: SceneOptions,
+    ) -> notify::Result<Self> {
+        let path = path.canonicalize()?;
+        let directory = directory(&path).to_path_buf();
+        let path = Arc::new(Mutex::new(path));
+        let reloaded = Arc::new(Mutex::new(None));
+
+        let (changes, changed) = mpsc::channel();
+        let watched = path.clone();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            let Ok(event) = event else {
+                return;
+            };
+            let path = watched.lock();
+            // renames cover exporters that write a temporary file and move it over the scene
+            let modified = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_));
+            if modified && event.paths.iter().any(|changed| *changed == *path) {
+                let _ = changes.send(());
+            }
+        })?;
+        // the directory rather than the file, replacing the file would end a watch on it
+        watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+
+        let (watched, slot) = (path.clone(), reloaded.clone());
+        thread::spawn(move || {
+            // ends with the watcher, which owns the sending half
+            while changed.recv().is_ok() {
+                while changed.recv_timeout(DEBOUNCE).is_ok() {}
+                let path = watched.lock().clone();
+                println!("Reloading {}", path.display());
+                match crate::load_world(&path.to_string_lossy(), env_map.clone(), options) {
+                    Ok(world) => *slot.lock() = Some((path, world)),
+                    Err(err) => {
+                        eprintln!(
+                            "Failed to reload {}: {}",
+                            path.display(),
+                            crate::error_chain(&err)
+                        )
+                    }
+                }
+            }
+        });
+
+        Ok(Self { watcher, path, reloaded })
+    }
+
+    // Follows another scene, like one dropped onto the window
+    pub fn set_path(&mut self, path: &Path) -> notify::Result<()> {
+        let path = path.canonicalize()?;
+        // not locked while (un)watching, the event handler locks it on the watcher's thread
+        let previous = self.path.lock().clone();
+        if directory(&path) != directory(&previous) {
+            self.watcher.watch(directory(&path), RecursiveMode::NonRecursive)?;
+            let _ = self.watcher.unwatch(directory(&previous));
+        }
+        *self.path.lock() = path;
+        Ok(())
+    }
+
+    // Reloads of a scene watched before are dropped, they finished after `set_path`
+    pub fn take(&self) -> Option<World> {
+        let (path, world) = self.reloaded.lock().take()?;
+        (path == *self.path.lock()).then_some(world)
+    }
+}
+
+fn directory(path: &Path) -> &Path {
+    path.parent().unwrap_or(path)
+}